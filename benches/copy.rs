@@ -0,0 +1,77 @@
+//! Compares the in-kernel copy path against the old 4 KiB read/write loop.
+//!
+//! Run with `cargo bench --bench copy`; `MIZZLE_BENCH_MB` sets the file size
+//! (default 256) and `MIZZLE_BENCH_DIR` where the files go.
+
+use mizzlestore::transfer;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 3;
+
+/// The copy loop `copy_file_to_mount` used before in-kernel copies.
+fn read_write_4k(source: &Path, destination: &Path) -> io::Result<()> {
+    let mut source = File::open(source)?;
+    let mut destination = File::create(destination)?;
+    let mut buffer = vec![0; 4096];
+    loop {
+        let n = source.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        destination.write_all(&buffer[..n])?;
+    }
+    Ok(())
+}
+
+fn kernel_copy(source: &Path, destination: &Path) -> io::Result<()> {
+    let input = File::open(source)?;
+    let output = File::create(destination)?;
+    transfer::copy_file(&input, &output, &mut |_| {}).map(drop)
+}
+
+/// Fills the file with non-zero data so nothing can take shortcuts on zeros.
+fn make_source(path: &Path, mb: usize) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    let mut block = vec![0u8; 1024 * 1024];
+    for (i, b) in block.iter_mut().enumerate() {
+        *b = (i * 31 + 7) as u8;
+    }
+    for i in 0..mb {
+        block[0] = i as u8;
+        file.write_all(&block)?;
+    }
+    file.sync_all()
+}
+
+fn bench(name: &str, mb: usize, copy: fn(&Path, &Path) -> io::Result<()>, source: &Path, destination: &Path) {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let _ = fs::remove_file(destination);
+        let start = Instant::now();
+        copy(source, destination).expect("copy failed");
+        best = best.min(start.elapsed());
+    }
+    let rate = mb as f64 / best.as_secs_f64();
+    println!("{:<20} {:>8.1} ms {:>10.1} MiB/s", name, best.as_secs_f64() * 1000.0, rate);
+}
+
+fn main() {
+    let mb = env::var("MIZZLE_BENCH_MB").ok().and_then(|v| v.parse().ok()).unwrap_or(256);
+    let base = env::var_os("MIZZLE_BENCH_DIR").map(Into::into).unwrap_or_else(env::temp_dir);
+    let dir = base.join(format!("mizzle-bench-{}", process::id()));
+    fs::create_dir_all(&dir).expect("cannot create bench directory");
+    let source = dir.join("source");
+    let destination = dir.join("destination");
+    make_source(&source, mb).expect("cannot create source file");
+
+    println!("copying {} MiB, best of {} runs", mb, RUNS);
+    bench("read/write 4 KiB", mb, read_write_4k, &source, &destination);
+    bench("copy_file_range", mb, kernel_copy, &source, &destination);
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,133 @@
+use crate::error::{MizzleError, Result};
+use nix::errno::Errno;
+use nix::fcntl::{fallocate, FallocateFlags};
+use nix::sys::statvfs::statvfs;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::str::FromStr;
+
+const ZERO_CHUNK: usize = 1024 * 1024;
+
+/// How the blocks behind a new image file are provided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocationPolicy {
+    /// Only set the file length; blocks are allocated as the guest writes.
+    Sparse,
+    /// Reserve every block with fallocate, zero-filling where that is unsupported.
+    #[default]
+    Preallocate,
+    /// Write zeros over the whole image.
+    ZeroFill,
+}
+
+impl AllocationPolicy {
+    pub fn reserves_space(&self) -> bool {
+        !matches!(self, AllocationPolicy::Sparse)
+    }
+}
+
+impl fmt::Display for AllocationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AllocationPolicy::Sparse => "sparse",
+            AllocationPolicy::Preallocate => "fallocate",
+            AllocationPolicy::ZeroFill => "zero",
+        })
+    }
+}
+
+impl FromStr for AllocationPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sparse" => Ok(AllocationPolicy::Sparse),
+            "fallocate" | "prealloc" | "preallocate" => Ok(AllocationPolicy::Preallocate),
+            "zero" | "zero-fill" => Ok(AllocationPolicy::ZeroFill),
+            _ => Err(format!("unknown allocation policy '{}' (expected sparse, fallocate or zero)", s)),
+        }
+    }
+}
+
+/// Outcome of creating an image: its logical size and what it really occupies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationReport {
+    pub policy: AllocationPolicy,
+    pub size: u64,
+    pub allocated: u64,
+}
+
+/// Bytes free for unprivileged users on the filesystem holding `path`.
+pub fn free_space(path: &Path) -> Result<u64> {
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let stat = statvfs(dir).map_err(|e| MizzleError::io(dir, e.into()))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Bytes currently backed by real blocks for `file`.
+pub fn allocated_bytes(file: &File) -> io::Result<u64> {
+    Ok(file.metadata()?.blocks() * 512)
+}
+
+/// Fails up front if reserving `size` bytes for `file` would exceed the host's free space.
+pub fn ensure_free_space(path: &Path, file: &File, size: u64) -> Result<()> {
+    let already = allocated_bytes(file).map_err(|e| MizzleError::io(path, e))?;
+    let required = size.saturating_sub(already);
+    let available = free_space(path)?;
+    if required > available {
+        return Err(MizzleError::InsufficientSpace { path: path.to_path_buf(), required, available });
+    }
+    Ok(())
+}
+
+/// Reserves every block of the first `size` bytes of `file`, using fallocate
+/// where the filesystem supports it and writing zeros otherwise.
+pub fn reserve(file: &mut File, size: u64) -> io::Result<()> {
+    reserve_range(file, 0, size)
+}
+
+/// Like [`reserve`] for the `len` bytes at `offset`, leaving the rest of
+/// the file untouched.
+pub fn reserve_range(file: &mut File, offset: u64, len: u64) -> io::Result<()> {
+    match fallocate(file.as_raw_fd(), FallocateFlags::empty(), offset as libc::off_t, len as libc::off_t) {
+        Ok(()) => Ok(()),
+        Err(Errno::EOPNOTSUPP) => zero_fill_range(file, offset, len),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes zeros over the whole file so every block is allocated.
+pub fn zero_fill(file: &mut File, size: u64) -> io::Result<()> {
+    zero_fill_range(file, 0, size)?;
+    file.set_len(size)?;
+    file.sync_all()
+}
+
+/// Writes zeros over the `len` bytes at `offset`.
+pub fn zero_fill_range(file: &mut File, offset: u64, len: u64) -> io::Result<()> {
+    let zeros = vec![0u8; ZERO_CHUNK];
+    file.seek(SeekFrom::Start(offset))?;
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = remaining.min(ZERO_CHUNK as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        remaining -= n as u64;
+    }
+    file.sync_all()
+}
+
+/// Checks through `st_blocks` that the file really holds `size` bytes of storage.
+pub fn verify_reserved(path: &Path, file: &File, size: u64) -> Result<()> {
+    let allocated = allocated_bytes(file).map_err(|e| MizzleError::io(path, e))?;
+    if allocated < size {
+        return Err(MizzleError::IncompleteAllocation { path: path.to_path_buf(), requested: size, allocated });
+    }
+    Ok(())
+}
@@ -0,0 +1,31 @@
+//! The daemon behind qcow2 mounts: serves an image to an NBD device until
+//! the device is disconnected. [`nbd::connect`] starts it from next to the
+//! running executable, so it is installed alongside mizzlestore.
+
+use clap::Parser;
+use mizzlestore::nbd;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(name = "mizzlestore-nbd", version, about = "Serve a qcow2 image to an NBD device until it is disconnected")]
+struct Cli {
+    /// NBD device, e.g. /dev/nbd0
+    device: PathBuf,
+    /// qcow2 image to serve
+    image: PathBuf,
+    /// Refuse writes to the device
+    #[arg(long)]
+    read_only: bool,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match nbd::run_daemon(&cli.device, &cli.image, cli.read_only) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("mizzlestore-nbd: {}: {}", cli.image.display(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,954 @@
+//! Our own encrypted container, for hosts without dm-crypt. The image is
+//! kept in 4 KiB sectors, each encrypted with AES-256-CTR under a fresh IV
+//! on every write and authenticated with HMAC-SHA256. The master key sits in
+//! up to eight key slots in the header, each wrapped with a key derived from
+//! one passphrase or key file.
+//!
+//! Layout: a header sector, then a 32-byte record (IV and tag) for every
+//! data sector, then the data sectors, then a write journal. A sector that
+//! has never been written has an all-zero IV and a tag over no data, so it
+//! only reads as zeros while the key vouches for that. Writes are gathered
+//! and committed in batches that go to the journal first and are put in
+//! place once the journal is on disk, so a crash leaves every sector with
+//! either its old or its new contents. Putting back an older copy of a
+//! sector and its record is not detected.
+
+use crate::blockdev::BlockDevice;
+use crate::bytes::{le32, le64, put_le32, put_le64};
+use crate::transfer::Progress;
+use crate::uuid;
+use aes::cipher::{Array, BlockCipherEncrypt, KeyInit};
+use aes::Aes256;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Instant;
+
+pub const SECTOR_SIZE: u64 = 4096;
+/// PBKDF2-HMAC-SHA256 rounds for new key slots.
+pub const DEFAULT_ITERATIONS: u32 = 600_000;
+/// What new key slots get; unit tests trade the work factor for speed.
+const ITERATIONS: u32 = if cfg!(test) { 1_000 } else { DEFAULT_ITERATIONS };
+
+const MAGIC: &[u8; 8] = b"MZCRYPT\0";
+const VERSION: u32 = 2;
+
+const BLOCK: usize = 16;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const RECORD_SIZE: u64 = (BLOCK + TAG_LEN) as u64;
+
+// Header fields
+const H_VERSION: usize = 8;
+const H_SECTOR_SIZE: usize = 12;
+const H_PAYLOAD: usize = 16;
+const H_MAC: usize = 32;
+const H_SLOTS: usize = 64;
+
+// Key slot fields
+pub const KEY_SLOTS: usize = 8;
+const SLOT_SIZE: usize = 160;
+const S_ACTIVE: usize = 0;
+const S_ITERATIONS: usize = 4;
+const S_SALT: usize = 8;
+const S_IV: usize = 40;
+const S_WRAPPED: usize = 56;
+const S_MAC: usize = 120;
+
+// Key rotation state, after the slots. The new master key is wrapped with
+// the old one; sectors below the progress mark are already under the new key.
+const H_ROTATION: usize = H_SLOTS + KEY_SLOTS * SLOT_SIZE;
+const ROTATION_SIZE: usize = 96;
+const R_ACTIVE: usize = 0;
+const R_JOURNAL: usize = 4;
+const R_PROGRESS: usize = 8;
+const R_IV: usize = 16;
+const R_WRAPPED: usize = 32;
+
+/// Sectors re-encrypted per step of a key rotation; each step goes through
+/// a journal past the end of the container first.
+const ROTATION_BATCH: u64 = 1024;
+
+/// Written sectors gathered before they are committed through the journal.
+const JOURNAL_SECTORS: usize = 256;
+const JOURNAL_MAGIC: &[u8; 8] = b"MZJOURNL";
+// Journal header fields; the header sector is followed by a table of the
+// sectors in the batch and their records, then by their data
+const J_COUNT: usize = 8;
+const J_MAC: usize = 16;
+const J_ENTRY_SIZE: usize = 8 + RECORD_SIZE as usize;
+const J_TABLE_SIZE: u64 = (JOURNAL_SECTORS * J_ENTRY_SIZE).next_multiple_of(SECTOR_SIZE as usize) as u64;
+
+/// The data key and MAC key every sector is protected with.
+struct MasterKey {
+    cipher: Aes256,
+    mac: [u8; KEY_LEN],
+    raw: [u8; 2 * KEY_LEN],
+}
+
+impl MasterKey {
+    fn new(raw: [u8; 2 * KEY_LEN]) -> MasterKey {
+        let cipher = Aes256::new(&Array::try_from(&raw[..KEY_LEN]).expect("key length"));
+        let mac = raw[KEY_LEN..].try_into().expect("key length");
+        MasterKey { cipher, mac, raw }
+    }
+
+    /// A fresh random key.
+    fn generate() -> io::Result<MasterKey> {
+        let mut raw = [0; 2 * KEY_LEN];
+        uuid::random_bytes(&mut raw)?;
+        Ok(MasterKey::new(raw))
+    }
+
+    fn tag(&self, sector: u64, iv: &[u8], data: &[u8]) -> Hmac<Sha256> {
+        hmac(&self.mac, &[&sector.to_le_bytes(), iv, data])
+    }
+
+    /// Encrypts `data`, the plaintext of `sector`, in place under a fresh IV
+    /// and returns the sector's new record.
+    fn seal(&self, sector: u64, data: &mut [u8]) -> io::Result<[u8; RECORD_SIZE as usize]> {
+        // An all-zero IV marks a sector that was never written
+        let mut iv = [0; BLOCK];
+        while iv == [0; BLOCK] {
+            uuid::random_bytes(&mut iv)?;
+        }
+        ctr(&self.cipher, &iv, data);
+        let tag = self.tag(sector, &iv, data).finalize().into_bytes();
+        let mut record = [0; RECORD_SIZE as usize];
+        record[..BLOCK].copy_from_slice(&iv);
+        record[BLOCK..].copy_from_slice(&tag[..TAG_LEN]);
+        Ok(record)
+    }
+
+    /// The record of `sector` before it is first written: no IV, and a tag
+    /// over no data.
+    fn blank(&self, sector: u64) -> [u8; RECORD_SIZE as usize] {
+        let mut record = [0; RECORD_SIZE as usize];
+        let tag = self.tag(sector, &[0; BLOCK], &[]).finalize().into_bytes();
+        record[BLOCK..].copy_from_slice(&tag[..TAG_LEN]);
+        record
+    }
+
+    /// Checks and decrypts `data`, the stored contents of `sector`, in place.
+    /// A sector never written reads as zeros.
+    fn open(&self, sector: u64, record: &[u8], data: &mut [u8]) -> io::Result<()> {
+        let (iv, tag) = record.split_at(BLOCK);
+        let written = is_written(record);
+        self.tag(sector, iv, if written { data } else { &[] })
+            .verify_truncated_left(tag)
+            .map_err(|_| invalid(format!("sector {} failed authentication", sector)))?;
+        if written {
+            ctr(&self.cipher, iv.try_into().expect("IV length"), data);
+        } else {
+            data.fill(0);
+        }
+        Ok(())
+    }
+}
+
+/// Whether the sector `record` belongs to has been written.
+fn is_written(record: &[u8]) -> bool {
+    record[..BLOCK].iter().any(|&b| b != 0)
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// XORs `data` with the AES-CTR keystream starting at counter block `iv`.
+fn ctr(cipher: &Aes256, iv: &[u8; BLOCK], data: &mut [u8]) {
+    let start = u128::from_be_bytes(*iv);
+    let mut stream: Vec<Array<u8, _>> = (0..data.len().div_ceil(BLOCK) as u128)
+        .map(|i| Array::from(start.wrapping_add(i).to_be_bytes()))
+        .collect();
+    cipher.encrypt_blocks(&mut stream);
+    for (chunk, key) in data.chunks_mut(BLOCK).zip(&stream) {
+        chunk.iter_mut().zip(key.iter()).for_each(|(b, k)| *b ^= k);
+    }
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as KeyInit>::new_from_slice(key).expect("HMAC takes any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+/// The key-encryption key and MAC key a passphrase stands for.
+fn derive(passphrase: &[u8], salt: &[u8], iterations: u32) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+    let mut out = [0; 2 * KEY_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, iterations, &mut out);
+    (out[..KEY_LEN].try_into().expect("key length"), out[KEY_LEN..].try_into().expect("key length"))
+}
+
+/// The MAC over the header fields and the rotation state.
+fn header_mac(key: &MasterKey, header: &[u8]) -> Hmac<Sha256> {
+    hmac(&key.mac, &[&header[..H_MAC], &header[H_ROTATION..H_ROTATION + ROTATION_SIZE]])
+}
+
+/// Number of data sectors and of record sectors for a payload of `payload` bytes.
+fn geometry(payload: u64) -> (u64, u64) {
+    let sectors = payload.div_ceil(SECTOR_SIZE);
+    (sectors, (sectors * RECORD_SIZE).div_ceil(SECTOR_SIZE))
+}
+
+/// Bytes of host storage a container holding `payload` bytes takes, not
+/// counting the journal after it.
+pub fn container_size(payload: u64) -> u64 {
+    let (sectors, records) = geometry(payload);
+    (1 + records + sectors) * SECTOR_SIZE
+}
+
+/// The MAC over a journal header and the batch it describes.
+fn journal_mac(key: &MasterKey, header: &[u8], table: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    hmac(&key.mac, &[JOURNAL_MAGIC, &header[..J_MAC], table, data])
+}
+
+/// Writes the records and data of a batch of sectors in place.
+fn write_entries<D: BlockDevice>(inner: &mut D, table: &[u8], data: &[u8], data_start: u64) -> io::Result<()> {
+    let sector = SECTOR_SIZE as usize;
+    for (i, entry) in table.chunks(J_ENTRY_SIZE).enumerate() {
+        let index = le64(entry, 0);
+        inner.write_at(data_start + index * SECTOR_SIZE, &data[i * sector..(i + 1) * sector])?;
+        inner.write_at(SECTOR_SIZE + index * RECORD_SIZE, &entry[8..])?;
+    }
+    Ok(())
+}
+
+/// Puts a batch that a crash left in the journal of the container on
+/// `inner` in place, if it is not there already. A batch whose MAC does not
+/// check out was torn before it was committed, and the sectors in it still
+/// have their old contents.
+fn replay<D: BlockDevice>(inner: &mut D, key: &MasterKey, payload: u64) -> io::Result<()> {
+    let journal = container_size(payload);
+    let len = inner.len()?;
+    if len < journal + SECTOR_SIZE {
+        return Ok(());
+    }
+    let mut header = vec![0; SECTOR_SIZE as usize];
+    inner.read_at(journal, &mut header)?;
+    let count = le32(&header, J_COUNT) as usize;
+    let data_at = journal + SECTOR_SIZE + J_TABLE_SIZE;
+    if !header.starts_with(JOURNAL_MAGIC) || !(1..=JOURNAL_SECTORS).contains(&count) {
+        return Ok(());
+    }
+    if len < data_at + count as u64 * SECTOR_SIZE {
+        return Ok(());
+    }
+    let mut table = vec![0; count * J_ENTRY_SIZE];
+    let mut data = vec![0; count * SECTOR_SIZE as usize];
+    inner.read_at(journal + SECTOR_SIZE, &mut table)?;
+    inner.read_at(data_at, &mut data)?;
+    if journal_mac(key, &header, &table, &data).verify_slice(&header[J_MAC..J_MAC + MAC_LEN]).is_err() {
+        return Ok(());
+    }
+
+    let (sectors, records) = geometry(payload);
+    let data_start = (1 + records) * SECTOR_SIZE;
+    let mut record = [0; RECORD_SIZE as usize];
+    let mut stored = vec![0; SECTOR_SIZE as usize];
+    let mut applied = true;
+    for (i, entry) in table.chunks(J_ENTRY_SIZE).enumerate() {
+        let index = le64(entry, 0);
+        if index >= sectors {
+            return Err(invalid("the container journal names a sector past the end"));
+        }
+        inner.read_at(SECTOR_SIZE + index * RECORD_SIZE, &mut record)?;
+        inner.read_at(data_start + index * SECTOR_SIZE, &mut stored)?;
+        let sector = SECTOR_SIZE as usize;
+        if record != entry[8..] || stored != data[i * sector..(i + 1) * sector] {
+            applied = false;
+            break;
+        }
+    }
+    if !applied {
+        let finish = |inner: &mut D| -> io::Result<()> {
+            write_entries(inner, &table, &data, data_start)?;
+            inner.flush()
+        };
+        finish(inner).map_err(|e| io::Error::other(format!("could not finish a write cut short by a crash: {}", e)))?;
+    }
+    Ok(())
+}
+
+/// The payload size of the container at `path`, or `None` if it is not one.
+/// Read straight from the header, before the passphrase vouches for it.
+pub fn payload_size(path: &Path) -> io::Result<Option<u64>> {
+    let mut header = [0; 24];
+    match File::open(path)?.read_exact(&mut header) {
+        Ok(()) => Ok(header.starts_with(MAGIC).then(|| le64(&header, H_PAYLOAD))),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `path` holds one of our encrypted containers.
+pub fn is_container(path: &Path) -> io::Result<bool> {
+    payload_size(path).map(|size| size.is_some())
+}
+
+/// The key slots in use in the container at `path`. Read straight from the
+/// header, like [`payload_size`].
+pub fn key_slots(path: &Path) -> io::Result<Vec<usize>> {
+    let mut file = File::open(path)?;
+    Ok(active_slots(&read_header(&mut file)?))
+}
+
+/// Whether the container at `path` has a key rotation in progress.
+pub fn rotation_pending(path: &Path) -> io::Result<bool> {
+    let mut file = File::open(path)?;
+    Ok(le32(&read_header(&mut file)?, H_ROTATION + R_ACTIVE) != 0)
+}
+
+/// Fills key slot `slot` with `key`, wrapped under `passphrase`.
+fn seal_slot(slot: &mut [u8], key: &MasterKey, passphrase: &[u8], iterations: u32) -> io::Result<()> {
+    let (mut salt, mut iv) = ([0; SALT_LEN], [0; BLOCK]);
+    uuid::random_bytes(&mut salt)?;
+    uuid::random_bytes(&mut iv)?;
+    let (kek, mac_key) = derive(passphrase, &salt, iterations);
+    let mut wrapped = key.raw;
+    ctr(&Aes256::new(&Array::from(kek)), &iv, &mut wrapped);
+
+    slot.fill(0);
+    put_le32(slot, S_ACTIVE, 1);
+    put_le32(slot, S_ITERATIONS, iterations);
+    slot[S_SALT..S_SALT + SALT_LEN].copy_from_slice(&salt);
+    slot[S_IV..S_IV + BLOCK].copy_from_slice(&iv);
+    slot[S_WRAPPED..S_WRAPPED + 2 * KEY_LEN].copy_from_slice(&wrapped);
+    let mac = hmac(&mac_key, &[&slot[..S_MAC]]).finalize().into_bytes();
+    slot[S_MAC..S_MAC + MAC_LEN].copy_from_slice(&mac);
+    Ok(())
+}
+
+/// The master key in `slot`, if `passphrase` opens it.
+fn open_slot(slot: &[u8], passphrase: &[u8]) -> Option<MasterKey> {
+    if le32(slot, S_ACTIVE) != 1 {
+        return None;
+    }
+    let (kek, mac_key) = derive(passphrase, &slot[S_SALT..S_SALT + SALT_LEN], le32(slot, S_ITERATIONS));
+    hmac(&mac_key, &[&slot[..S_MAC]]).verify_slice(&slot[S_MAC..S_MAC + MAC_LEN]).ok()?;
+    let iv = slot[S_IV..S_IV + BLOCK].try_into().expect("IV length");
+    let mut raw: [u8; 2 * KEY_LEN] = slot[S_WRAPPED..S_WRAPPED + 2 * KEY_LEN].try_into().expect("key length");
+    ctr(&Aes256::new(&Array::from(kek)), &iv, &mut raw);
+    Some(MasterKey::new(raw))
+}
+
+fn slot_range(index: usize) -> std::ops::Range<usize> {
+    H_SLOTS + index * SLOT_SIZE..H_SLOTS + (index + 1) * SLOT_SIZE
+}
+
+fn rotation_range() -> std::ops::Range<usize> {
+    H_ROTATION..H_ROTATION + ROTATION_SIZE
+}
+
+/// Reads and sanity-checks the header of the container on `inner`.
+fn read_header<D: BlockDevice>(inner: &mut D) -> io::Result<Vec<u8>> {
+    let mut header = vec![0; SECTOR_SIZE as usize];
+    inner.read_at(0, &mut header)?;
+    if !header.starts_with(MAGIC) {
+        return Err(invalid("not an encrypted container"));
+    }
+    if le32(&header, H_VERSION) != VERSION || u64::from(le32(&header, H_SECTOR_SIZE)) != SECTOR_SIZE {
+        return Err(invalid("unsupported container version"));
+    }
+    Ok(header)
+}
+
+/// Finds the key slot `passphrase` opens and checks the header against the
+/// master key in it.
+fn unlock(header: &[u8], passphrase: &[u8]) -> io::Result<(usize, MasterKey)> {
+    let (slot, key) = (0..KEY_SLOTS)
+        .find_map(|i| open_slot(&header[slot_range(i)], passphrase).map(|key| (i, key)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "wrong passphrase or key"))?;
+    header_mac(&key, header)
+        .verify_slice(&header[H_MAC..H_MAC + MAC_LEN])
+        .map_err(|_| invalid("the container header has been tampered with"))?;
+    Ok((slot, key))
+}
+
+/// MACs `header` with `key` and writes it out.
+fn write_header<D: BlockDevice>(inner: &mut D, header: &mut [u8], key: &MasterKey) -> io::Result<()> {
+    let mac = header_mac(key, header).finalize().into_bytes();
+    header[H_MAC..H_MAC + MAC_LEN].copy_from_slice(&mac);
+    inner.write_at(0, header)?;
+    inner.flush()
+}
+
+/// The active key slots in `header`.
+fn active_slots(header: &[u8]) -> Vec<usize> {
+    (0..KEY_SLOTS).filter(|&i| le32(&header[slot_range(i)], S_ACTIVE) == 1).collect()
+}
+
+/// A container opened with its passphrase, presenting the decrypted image
+/// as a block device.
+pub struct EncryptedDevice<D: BlockDevice> {
+    inner: D,
+    header: Vec<u8>,
+    key: MasterKey,
+    /// The key slot the container was opened with.
+    slot: usize,
+    payload: u64,
+    data_start: u64,
+    /// Plaintext of the sectors written since the last commit.
+    dirty: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<D: BlockDevice> EncryptedDevice<D> {
+    /// Turns `inner`, at least [`container_size`] bytes long, into an empty
+    /// container of `payload` bytes that `passphrase` opens.
+    pub fn create(mut inner: D, payload: u64, passphrase: &[u8]) -> io::Result<Self> {
+        if payload == 0 || !payload.is_multiple_of(SECTOR_SIZE) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("container size must be a positive multiple of {} bytes", SECTOR_SIZE),
+            ));
+        }
+        if inner.len()? < container_size(payload) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the image is too small for the container"));
+        }
+        let key = MasterKey::generate()?;
+        let mut header = vec![0; SECTOR_SIZE as usize];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        put_le32(&mut header, H_VERSION, VERSION);
+        put_le32(&mut header, H_SECTOR_SIZE, SECTOR_SIZE as u32);
+        put_le64(&mut header, H_PAYLOAD, payload);
+        seal_slot(&mut header[slot_range(0)], &key, passphrase, ITERATIONS)?;
+
+        // Every sector starts out unwritten, which its record vouches for
+        let (sectors, records) = geometry(payload);
+        let mut chunk = Vec::with_capacity(SECTOR_SIZE as usize * 256);
+        let mut offset = SECTOR_SIZE;
+        for sector in 0..sectors {
+            chunk.extend_from_slice(&key.blank(sector));
+            if chunk.len() == chunk.capacity() || sector + 1 == sectors {
+                inner.write_at(offset, &chunk)?;
+                offset += chunk.len() as u64;
+                chunk.clear();
+            }
+        }
+        write_header(&mut inner, &mut header, &key)?;
+        let data_start = (1 + records) * SECTOR_SIZE;
+        Ok(EncryptedDevice { inner, header, key, slot: 0, payload, data_start, dirty: BTreeMap::new() })
+    }
+
+    /// Opens the container on `inner` with `passphrase`.
+    pub fn open(mut inner: D, passphrase: &[u8]) -> io::Result<Self> {
+        let header = read_header(&mut inner)?;
+        let (slot, key) = unlock(&header, passphrase)?;
+        if le32(&header, H_ROTATION + R_ACTIVE) != 0 {
+            return Err(io::Error::other("a key rotation was interrupted and has to be finished first"));
+        }
+        let payload = le64(&header, H_PAYLOAD);
+        let data_start = (1 + geometry(payload).1) * SECTOR_SIZE;
+        if inner.len()? < container_size(payload) {
+            return Err(invalid("the container is truncated"));
+        }
+        replay(&mut inner, &key, payload)?;
+        Ok(EncryptedDevice { inner, header, key, slot, payload, data_start, dirty: BTreeMap::new() })
+    }
+
+    /// The key slots in use.
+    pub fn key_slots(&self) -> Vec<usize> {
+        active_slots(&self.header)
+    }
+
+    /// The key slot the passphrase the container was opened with sits in.
+    pub fn unlocked_slot(&self) -> usize {
+        self.slot
+    }
+
+    /// Adds `passphrase` to the first free key slot and returns its number.
+    pub fn add_key(&mut self, passphrase: &[u8]) -> io::Result<usize> {
+        let slot = (0..KEY_SLOTS)
+            .find(|&i| le32(&self.header[slot_range(i)], S_ACTIVE) != 1)
+            .ok_or_else(|| io::Error::other(format!("all {} key slots are in use", KEY_SLOTS)))?;
+        seal_slot(&mut self.header[slot_range(slot)], &self.key, passphrase, ITERATIONS)?;
+        write_header(&mut self.inner, &mut self.header, &self.key)?;
+        Ok(slot)
+    }
+
+    /// Wipes key slot `slot`. The last key cannot be removed.
+    pub fn remove_key(&mut self, slot: usize) -> io::Result<()> {
+        let slots = self.key_slots();
+        if !slots.contains(&slot) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("key slot {} is empty", slot)));
+        }
+        if slots.len() == 1 {
+            return Err(io::Error::other("it holds the only key; add another one first"));
+        }
+        self.header[slot_range(slot)].fill(0);
+        write_header(&mut self.inner, &mut self.header, &self.key)
+    }
+
+    fn read_sector(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        if let Some(plain) = self.dirty.get(&sector) {
+            buf.copy_from_slice(plain);
+            return Ok(());
+        }
+        let mut record = [0; RECORD_SIZE as usize];
+        self.inner.read_at(SECTOR_SIZE + sector * RECORD_SIZE, &mut record)?;
+        if is_written(&record) {
+            self.inner.read_at(self.data_start + sector * SECTOR_SIZE, buf)?;
+        }
+        self.key.open(sector, &record, buf)
+    }
+
+    fn write_sector(&mut self, sector: u64, plain: &[u8]) -> io::Result<()> {
+        self.dirty.insert(sector, plain.to_vec());
+        if self.dirty.len() >= JOURNAL_SECTORS {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Encrypts the sectors written since the last commit and puts them on
+    /// `inner`: into the journal first, then, once that is on disk, in place.
+    fn commit(&mut self) -> io::Result<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        let sector = SECTOR_SIZE as usize;
+        let mut table = vec![0; self.dirty.len() * J_ENTRY_SIZE];
+        let mut data = vec![0; self.dirty.len() * sector];
+        for (i, (&index, plain)) in self.dirty.iter().enumerate() {
+            let stored = &mut data[i * sector..(i + 1) * sector];
+            stored.copy_from_slice(plain);
+            let record = self.key.seal(index, stored)?;
+            put_le64(&mut table, i * J_ENTRY_SIZE, index);
+            table[i * J_ENTRY_SIZE + 8..(i + 1) * J_ENTRY_SIZE].copy_from_slice(&record);
+        }
+        let mut header = vec![0; sector];
+        header[..JOURNAL_MAGIC.len()].copy_from_slice(JOURNAL_MAGIC);
+        put_le32(&mut header, J_COUNT, self.dirty.len() as u32);
+        let mac = journal_mac(&self.key, &header, &table, &data).finalize().into_bytes();
+        header[J_MAC..J_MAC + MAC_LEN].copy_from_slice(&mac);
+
+        let journal = container_size(self.payload);
+        self.inner.write_at(journal + SECTOR_SIZE, &table)?;
+        self.inner.write_at(journal + SECTOR_SIZE + J_TABLE_SIZE, &data)?;
+        self.inner.write_at(journal, &header)?;
+        self.inner.flush()?;
+        write_entries(&mut self.inner, &table, &data, self.data_start)?;
+        self.inner.flush()?;
+        self.dirty.clear();
+        Ok(())
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> io::Result<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.payload => Ok(()),
+            _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        }
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for EncryptedDevice<D> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.check_range(offset, buf.len())?;
+        let mut sector_buf = vec![0; SECTOR_SIZE as usize];
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let (sector, within) = (pos / SECTOR_SIZE, (pos % SECTOR_SIZE) as usize);
+            let len = (SECTOR_SIZE as usize - within).min(buf.len() - done);
+            self.read_sector(sector, &mut sector_buf)?;
+            buf[done..done + len].copy_from_slice(&sector_buf[within..within + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.check_range(offset, buf.len())?;
+        let mut sector_buf = vec![0; SECTOR_SIZE as usize];
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let (sector, within) = (pos / SECTOR_SIZE, (pos % SECTOR_SIZE) as usize);
+            let len = (SECTOR_SIZE as usize - within).min(buf.len() - done);
+            if len == SECTOR_SIZE as usize {
+                self.write_sector(sector, &buf[done..done + len])?;
+            } else {
+                // Part of a sector: the rest of it has to be re-encrypted too
+                self.read_sector(sector, &mut sector_buf)?;
+                sector_buf[within..within + len].copy_from_slice(&buf[done..done + len]);
+                self.write_sector(sector, &sector_buf)?;
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.payload)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.dirty.is_empty() {
+            return self.inner.flush();
+        }
+        self.commit()
+    }
+}
+
+impl<D: BlockDevice> Drop for EncryptedDevice<D> {
+    fn drop(&mut self) {
+        // Callers flush to hear about errors; this only keeps a forgotten
+        // flush from losing data
+        let _ = self.commit();
+    }
+}
+
+/// What a key rotation did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RotateReport {
+    /// The key slot that holds the passphrase under the new master key.
+    pub slot: usize,
+    /// Other key slots, wiped because they still held the old master key.
+    pub removed: Vec<usize>,
+    /// Whether an interrupted rotation was picked up.
+    pub resumed: bool,
+}
+
+/// Writes a batch of sector records and the data of the sectors they say
+/// were written, leaving unwritten sectors alone so the image stays sparse.
+fn write_batch(file: &mut File, records: &[u8], records_at: u64, data: &[u8], data_at: u64) -> io::Result<()> {
+    file.write_at(records_at, records)?;
+    let sector = SECTOR_SIZE as usize;
+    for (i, record) in records.chunks(RECORD_SIZE as usize).enumerate() {
+        if is_written(record) {
+            file.write_at(data_at + (i * sector) as u64, &data[i * sector..(i + 1) * sector])?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the master key of the container in `file`, which `passphrase`
+/// opens, and re-encrypts every written sector under the new one.
+///
+/// Runs in batches that first go to a journal past the end of the container,
+/// with the progress kept in the header, so a rotation cut short by a crash
+/// is finished by running it again. Key slots other than the passphrase's
+/// cannot be carried over and are wiped.
+pub fn rotate(file: &mut File, passphrase: &[u8], progress: &mut dyn FnMut(&Progress)) -> io::Result<RotateReport> {
+    let mut header = read_header(file)?;
+    let (slot, old) = unlock(&header, passphrase)?;
+    let payload = le64(&header, H_PAYLOAD);
+    let (sectors, records) = geometry(payload);
+    let data_start = (1 + records) * SECTOR_SIZE;
+    let journal = container_size(payload);
+    if file.len()? < journal {
+        return Err(invalid("the container is truncated"));
+    }
+
+    let resumed = le32(&header, H_ROTATION + R_ACTIVE) != 0;
+    if !resumed {
+        // The rotation journal takes the place of the write journal
+        replay(file, &old, payload)?;
+    }
+    let new = if resumed {
+        let iv = header[H_ROTATION + R_IV..H_ROTATION + R_IV + BLOCK].try_into().expect("IV length");
+        let mut raw: [u8; 2 * KEY_LEN] =
+            header[H_ROTATION + R_WRAPPED..H_ROTATION + R_WRAPPED + 2 * KEY_LEN].try_into().expect("key length");
+        ctr(&old.cipher, &iv, &mut raw);
+        MasterKey::new(raw)
+    } else {
+        let new = MasterKey::generate()?;
+        let mut iv = [0; BLOCK];
+        uuid::random_bytes(&mut iv)?;
+        let mut wrapped = new.raw;
+        ctr(&old.cipher, &iv, &mut wrapped);
+        header[rotation_range()].fill(0);
+        put_le32(&mut header, H_ROTATION + R_ACTIVE, 1);
+        header[H_ROTATION + R_IV..H_ROTATION + R_IV + BLOCK].copy_from_slice(&iv);
+        header[H_ROTATION + R_WRAPPED..H_ROTATION + R_WRAPPED + 2 * KEY_LEN].copy_from_slice(&wrapped);
+        write_header(file, &mut header, &old)?;
+        new
+    };
+
+    let mut done = le64(&header, H_ROTATION + R_PROGRESS);
+    let journaled = u64::from(le32(&header, H_ROTATION + R_JOURNAL));
+    if journaled > 0 {
+        // The batch in the journal is complete; it may not have reached its place
+        let mut batch_records = vec![0; (journaled * RECORD_SIZE) as usize];
+        let mut batch_data = vec![0; (journaled * SECTOR_SIZE) as usize];
+        file.read_at(journal, &mut batch_records)?;
+        file.read_at(journal + journaled * RECORD_SIZE, &mut batch_data)?;
+        let (records_at, data_at) = (SECTOR_SIZE + done * RECORD_SIZE, data_start + done * SECTOR_SIZE);
+        write_batch(file, &batch_records, records_at, &batch_data, data_at)?;
+        file.flush()?;
+        done += journaled;
+        put_le64(&mut header, H_ROTATION + R_PROGRESS, done);
+        put_le32(&mut header, H_ROTATION + R_JOURNAL, 0);
+        write_header(file, &mut header, &old)?;
+    }
+
+    let start = Instant::now();
+    let mut report = |done: u64| {
+        progress(&Progress { copied: done * SECTOR_SIZE, total: payload, elapsed: start.elapsed() });
+    };
+    report(done);
+    while done < sectors {
+        let count = (sectors - done).min(ROTATION_BATCH);
+        let mut batch_records = vec![0; (count * RECORD_SIZE) as usize];
+        let mut batch_data = vec![0; (count * SECTOR_SIZE) as usize];
+        file.read_at(SECTOR_SIZE + done * RECORD_SIZE, &mut batch_records)?;
+        file.read_at(data_start + done * SECTOR_SIZE, &mut batch_data)?;
+        let sector = SECTOR_SIZE as usize;
+        for (i, record) in batch_records.chunks_mut(RECORD_SIZE as usize).enumerate() {
+            let index = done + i as u64;
+            let data = &mut batch_data[i * sector..(i + 1) * sector];
+            old.open(index, record, data)?;
+            let sealed = if is_written(record) { new.seal(index, data)? } else { new.blank(index) };
+            record.copy_from_slice(&sealed);
+        }
+
+        write_batch(file, &batch_records, journal, &batch_data, journal + count * RECORD_SIZE)?;
+        file.flush()?;
+        put_le32(&mut header, H_ROTATION + R_JOURNAL, count as u32);
+        write_header(file, &mut header, &old)?;
+
+        let (records_at, data_at) = (SECTOR_SIZE + done * RECORD_SIZE, data_start + done * SECTOR_SIZE);
+        write_batch(file, &batch_records, records_at, &batch_data, data_at)?;
+        file.flush()?;
+        done += count;
+        put_le64(&mut header, H_ROTATION + R_PROGRESS, done);
+        put_le32(&mut header, H_ROTATION + R_JOURNAL, 0);
+        write_header(file, &mut header, &old)?;
+        report(done);
+    }
+
+    // Every sector is under the new key; hand it to the passphrase's slot
+    let iterations = le32(&header[slot_range(slot)], S_ITERATIONS);
+    seal_slot(&mut header[slot_range(slot)], &new, passphrase, iterations)?;
+    let removed: Vec<usize> = active_slots(&header).into_iter().filter(|&i| i != slot).collect();
+    for &i in &removed {
+        header[slot_range(i)].fill(0);
+    }
+    header[rotation_range()].fill(0);
+    write_header(file, &mut header, &new)?;
+    file.set_len(journal)?;
+    file.flush()?;
+    Ok(RotateReport { slot, removed, resumed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{pattern, scratch_file};
+    use std::os::unix::fs::FileExt;
+
+    const PAYLOAD: u64 = 64 * SECTOR_SIZE;
+    const PASSPHRASE: &[u8] = b"correct horse";
+
+    /// An empty container in an unlinked scratch file.
+    fn scratch(name: &str) -> File {
+        let file = scratch_file(&format!("container-{}", name));
+        file.set_len(container_size(PAYLOAD)).unwrap();
+        drop(EncryptedDevice::create(file.try_clone().unwrap(), PAYLOAD, PASSPHRASE).unwrap());
+        file
+    }
+
+    fn open(file: &File) -> io::Result<EncryptedDevice<File>> {
+        EncryptedDevice::open(file.try_clone().unwrap(), PASSPHRASE)
+    }
+
+    fn data_offset(sector: u64) -> u64 {
+        (1 + geometry(PAYLOAD).1 + sector) * SECTOR_SIZE
+    }
+
+    fn record_offset(sector: u64) -> u64 {
+        SECTOR_SIZE + sector * RECORD_SIZE
+    }
+
+    #[test]
+    fn data_survives_reopening() {
+        let file = scratch("reopen");
+        let data = pattern(3 * SECTOR_SIZE as usize, 7);
+        let mut device = open(&file).unwrap();
+        device.write_at(SECTOR_SIZE + 100, &data).unwrap();
+        device.flush().unwrap();
+        drop(device);
+
+        let mut device = open(&file).unwrap();
+        let mut back = vec![0; data.len()];
+        device.read_at(SECTOR_SIZE + 100, &mut back).unwrap();
+        assert_eq!(back, data);
+        let mut unwritten = vec![1; SECTOR_SIZE as usize];
+        device.read_at(40 * SECTOR_SIZE, &mut unwritten).unwrap();
+        assert!(unwritten.iter().all(|&b| b == 0));
+        assert!(device.read_at(PAYLOAD - 1, &mut [0; 2]).is_err());
+    }
+
+    #[test]
+    fn sectors_are_stored_encrypted() {
+        let file = scratch("encrypted");
+        let data = vec![0x5a; SECTOR_SIZE as usize];
+        let mut device = open(&file).unwrap();
+        device.write_at(2 * SECTOR_SIZE, &data).unwrap();
+        device.flush().unwrap();
+        let mut stored = vec![0; SECTOR_SIZE as usize];
+        file.read_exact_at(&mut stored, data_offset(2)).unwrap();
+        assert_ne!(stored, data);
+    }
+
+    #[test]
+    fn a_dropped_device_commits_its_writes() {
+        let file = scratch("drop");
+        let mut device = open(&file).unwrap();
+        device.write_at(0, b"kept").unwrap();
+        drop(device);
+        let mut back = [0; 4];
+        open(&file).unwrap().read_at(0, &mut back).unwrap();
+        assert_eq!(&back, b"kept");
+    }
+
+    #[test]
+    fn tampered_sectors_fail_authentication() {
+        let file = scratch("tamper");
+        let mut device = open(&file).unwrap();
+        device.write_at(3 * SECTOR_SIZE, &pattern(SECTOR_SIZE as usize, 1)).unwrap();
+        device.flush().unwrap();
+        // Push sector 3 out of the journal, which would otherwise put it back
+        device.write_at(20 * SECTOR_SIZE, b"later").unwrap();
+        device.flush().unwrap();
+        drop(device);
+
+        let mut byte = [0; 1];
+        file.read_exact_at(&mut byte, data_offset(3) + 10).unwrap();
+        file.write_all_at(&[byte[0] ^ 1], data_offset(3) + 10).unwrap();
+        // An unwritten sector's record cannot be swapped for an all-zero one
+        file.write_all_at(&[0; RECORD_SIZE as usize], record_offset(10)).unwrap();
+        // Nor a written sector's record moved to another sector
+        let mut record = [0; RECORD_SIZE as usize];
+        file.read_exact_at(&mut record, record_offset(3)).unwrap();
+        file.write_all_at(&record, record_offset(11)).unwrap();
+
+        let mut device = open(&file).unwrap();
+        let mut buf = vec![0; SECTOR_SIZE as usize];
+        for sector in [3, 10, 11] {
+            assert!(device.read_at(sector * SECTOR_SIZE, &mut buf).is_err(), "sector {} read", sector);
+        }
+        device.read_at(4 * SECTOR_SIZE, &mut buf).unwrap();
+    }
+
+    #[test]
+    fn a_tampered_header_or_wrong_passphrase_is_refused() {
+        let file = scratch("header");
+        let wrong = EncryptedDevice::open(file.try_clone().unwrap(), b"wrong horse");
+        assert_eq!(wrong.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+
+        file.write_all_at(&(PAYLOAD * 2).to_le_bytes(), H_PAYLOAD as u64).unwrap();
+        assert!(open(&file).is_err());
+    }
+
+    #[test]
+    fn a_write_cut_short_in_place_is_finished_from_the_journal() {
+        let file = scratch("replay");
+        let data = pattern(SECTOR_SIZE as usize, 9);
+        let mut device = open(&file).unwrap();
+        device.write_at(5 * SECTOR_SIZE, &data).unwrap();
+        device.flush().unwrap();
+        drop(device);
+
+        // As if the crash came after the journal but before the sector's data
+        file.write_all_at(&vec![0xee; SECTOR_SIZE as usize], data_offset(5)).unwrap();
+        let mut back = vec![0; data.len()];
+        open(&file).unwrap().read_at(5 * SECTOR_SIZE, &mut back).unwrap();
+        assert_eq!(back, data);
+    }
+
+    #[test]
+    fn a_torn_journal_leaves_the_old_contents() {
+        let file = scratch("torn");
+        let old = pattern(SECTOR_SIZE as usize, 2);
+        let mut device = open(&file).unwrap();
+        device.write_at(6 * SECTOR_SIZE, &old).unwrap();
+        device.flush().unwrap();
+        drop(device);
+        let mut record = [0; RECORD_SIZE as usize];
+        let mut stored = vec![0; SECTOR_SIZE as usize];
+        file.read_exact_at(&mut record, record_offset(6)).unwrap();
+        file.read_exact_at(&mut stored, data_offset(6)).unwrap();
+
+        let mut device = open(&file).unwrap();
+        device.write_at(6 * SECTOR_SIZE, &pattern(SECTOR_SIZE as usize, 3)).unwrap();
+        device.flush().unwrap();
+        drop(device);
+        // As if the crash came while the journal was written: nothing moved yet
+        file.write_all_at(&record, record_offset(6)).unwrap();
+        file.write_all_at(&stored, data_offset(6)).unwrap();
+        let journal_data = container_size(PAYLOAD) + SECTOR_SIZE + J_TABLE_SIZE;
+        file.write_all_at(&[0xee; 16], journal_data).unwrap();
+
+        let mut back = vec![0; old.len()];
+        open(&file).unwrap().read_at(6 * SECTOR_SIZE, &mut back).unwrap();
+        assert_eq!(back, old);
+    }
+
+    #[test]
+    fn keys_can_be_added_and_removed() {
+        let file = scratch("keys");
+        let mut device = open(&file).unwrap();
+        assert_eq!(device.key_slots(), [0]);
+        assert!(device.remove_key(0).is_err());
+        assert_eq!(device.remove_key(3).err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+        assert_eq!(device.add_key(b"second").unwrap(), 1);
+        drop(device);
+
+        let mut device = EncryptedDevice::open(file.try_clone().unwrap(), b"second").unwrap();
+        assert_eq!(device.unlocked_slot(), 1);
+        assert_eq!(device.key_slots(), [0, 1]);
+        device.remove_key(0).unwrap();
+        drop(device);
+        assert!(open(&file).is_err());
+        EncryptedDevice::open(file.try_clone().unwrap(), b"second").unwrap();
+
+        let mut device = EncryptedDevice::open(file.try_clone().unwrap(), b"second").unwrap();
+        for slot in [0, 2, 3, 4, 5, 6, 7] {
+            assert_eq!(device.add_key(format!("key {}", slot).as_bytes()).unwrap(), slot);
+        }
+        assert!(device.add_key(b"one too many").is_err());
+    }
+
+    #[test]
+    fn rotation_re_encrypts_under_a_new_key_and_drops_the_others() {
+        let mut file = scratch("rotate");
+        let data = pattern(2 * SECTOR_SIZE as usize, 4);
+        let mut device = open(&file).unwrap();
+        device.write_at(8 * SECTOR_SIZE, &data).unwrap();
+        device.add_key(b"second").unwrap();
+        device.flush().unwrap();
+        drop(device);
+        let mut before = vec![0; data.len()];
+        file.read_exact_at(&mut before, data_offset(8)).unwrap();
+
+        let report = rotate(&mut file, PASSPHRASE, &mut |_| {}).unwrap();
+        assert_eq!(report, RotateReport { slot: 0, removed: vec![1], resumed: false });
+        assert!(!rotation_pending_in(&mut file));
+        assert_eq!(file.metadata().unwrap().len(), container_size(PAYLOAD));
+        let mut after = vec![0; data.len()];
+        file.read_exact_at(&mut after, data_offset(8)).unwrap();
+        assert_ne!(after, before);
+
+        assert!(EncryptedDevice::open(file.try_clone().unwrap(), b"second").is_err());
+        let mut device = open(&file).unwrap();
+        assert_eq!(device.key_slots(), [0]);
+        let mut back = vec![0; data.len()];
+        device.read_at(8 * SECTOR_SIZE, &mut back).unwrap();
+        assert_eq!(back, data);
+        let mut unwritten = vec![1; SECTOR_SIZE as usize];
+        device.read_at(30 * SECTOR_SIZE, &mut unwritten).unwrap();
+        assert!(unwritten.iter().all(|&b| b == 0));
+    }
+
+    fn rotation_pending_in(file: &mut File) -> bool {
+        le32(&read_header(file).unwrap(), H_ROTATION + R_ACTIVE) != 0
+    }
+}
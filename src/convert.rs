@@ -0,0 +1,312 @@
+//! Converting raw disk images to and from the formats other virtualisation
+//! stacks use: VMDK, VHD, VHDX and zstd- or xz-compressed raw.
+
+use crate::blockdev::BlockDevice;
+use crate::transfer::{Metered, Progress, Tracker};
+use crate::vhd::{self, VhdImage};
+use crate::vhdx::{self, VhdxImage};
+use crate::vmdk::{self, VmdkImage};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Size of the pieces a disk is read in; pieces of zeros are holes.
+pub(crate) const CHUNK: u64 = 64 * 1024;
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+const XZ_MAGIC: &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_LEVEL: i32 = 3;
+const XZ_LEVEL: u32 = 6;
+
+/// Reads all of `source` in [`CHUNK`]s, handing `data` the offset and
+/// bytes of every chunk that is not all zeros. Returns the size read.
+pub(crate) fn scan(
+    source: &mut dyn BlockDevice,
+    progress: &mut dyn FnMut(&Progress),
+    mut data: impl FnMut(u64, &[u8]) -> io::Result<()>,
+) -> io::Result<u64> {
+    let size = source.len()?;
+    let mut tracker = Tracker::new(size, progress);
+    let mut buf = vec![0; CHUNK as usize];
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(CHUNK) as usize;
+        source.read_at(offset, &mut buf[..len])?;
+        if !buf[..len].iter().all(|&b| b == 0) {
+            data(offset, &buf[..len])?;
+        }
+        offset += len as u64;
+        tracker.advance(len as u64);
+    }
+    Ok(size)
+}
+
+/// A format a disk can be exported to or imported from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A monolithic sparse VMDK.
+    Vmdk,
+    VhdFixed,
+    VhdDynamic,
+    Vhdx,
+    /// Raw, compressed with zstd.
+    Zstd,
+    /// Raw, compressed with xz.
+    Xz,
+}
+
+impl Format {
+    /// The format a file name's extension suggests.
+    pub fn from_extension(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "vmdk" => Some(Format::Vmdk),
+            "vhd" => Some(Format::VhdDynamic),
+            "vhdx" => Some(Format::Vhdx),
+            "zst" => Some(Format::Zstd),
+            "xz" => Some(Format::Xz),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Format::Vmdk => "vmdk",
+            Format::VhdFixed => "vhd-fixed",
+            Format::VhdDynamic => "vhd-dynamic",
+            Format::Vhdx => "vhdx",
+            Format::Zstd => "zstd",
+            Format::Xz => "xz",
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "vmdk" => Ok(Format::Vmdk),
+            "vhd-fixed" => Ok(Format::VhdFixed),
+            "vhd" | "vhd-dynamic" => Ok(Format::VhdDynamic),
+            "vhdx" => Ok(Format::Vhdx),
+            "zst" | "zstd" => Ok(Format::Zstd),
+            "xz" => Ok(Format::Xz),
+            _ => Err(format!(
+                "unknown image format '{}' (expected vmdk, vhd, vhd-fixed, vhdx, zstd or xz)",
+                s
+            )),
+        }
+    }
+}
+
+fn starts_with(path: &Path, magic: &[u8]) -> io::Result<bool> {
+    let mut start = vec![0; magic.len()];
+    match File::open(path)?.read_exact_at(&mut start, 0) {
+        Ok(()) => Ok(start == magic),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The format of the image at `path`, going by its contents.
+pub fn detect(path: &Path) -> io::Result<Option<Format>> {
+    Ok(if vhdx::is_vhdx(path)? {
+        Some(Format::Vhdx)
+    } else if vhd::is_vhd(path)? {
+        // Fixed or dynamic, it is read the same way
+        Some(Format::VhdDynamic)
+    } else if vmdk::is_vmdk(path)? || vmdk::is_descriptor(path)? {
+        Some(Format::Vmdk)
+    } else if starts_with(path, ZSTD_MAGIC)? {
+        Some(Format::Zstd)
+    } else if starts_with(path, XZ_MAGIC)? {
+        Some(Format::Xz)
+    } else {
+        None
+    })
+}
+
+/// Compresses all of `source` into `out`, zeros included.
+fn compress(source: &mut dyn BlockDevice, out: impl Write, progress: &mut dyn FnMut(&Progress)) -> io::Result<()> {
+    let mut out = io::BufWriter::new(out);
+    let zeros = vec![0; CHUNK as usize];
+    let mut end = 0;
+    let size = scan(source, progress, |offset, data| {
+        while end < offset {
+            let len = (offset - end).min(CHUNK) as usize;
+            out.write_all(&zeros[..len])?;
+            end += len as u64;
+        }
+        out.write_all(data)?;
+        end += data.len() as u64;
+        Ok(())
+    })?;
+    while end < size {
+        let len = (size - end).min(CHUNK) as usize;
+        out.write_all(&zeros[..len])?;
+        end += len as u64;
+    }
+    out.flush()
+}
+
+/// Writes `source` to `out` in `format`; `name` is the file name the image
+/// will have, which a VMDK records.
+pub fn export(
+    source: &mut dyn BlockDevice,
+    out: &File,
+    name: &str,
+    format: Format,
+    progress: &mut dyn FnMut(&Progress),
+) -> io::Result<()> {
+    match format {
+        Format::Vmdk => vmdk::write(source, out, name, progress),
+        Format::VhdFixed => vhd::write_fixed(source, out, progress),
+        Format::VhdDynamic => vhd::write_dynamic(source, out, progress),
+        Format::Vhdx => vhdx::write(source, out, progress),
+        Format::Zstd => {
+            let mut encoder = zstd::Encoder::new(out, ZSTD_LEVEL)?;
+            encoder.include_checksum(true)?;
+            encoder.set_pledged_src_size(Some(source.len()?))?;
+            compress(source, &mut encoder, progress)?;
+            encoder.finish().map(drop)
+        }
+        Format::Xz => {
+            // xz checks the stream with CRC-64 by default
+            let mut encoder = xz2::write::XzEncoder::new(out, XZ_LEVEL);
+            compress(source, &mut encoder, progress)?;
+            encoder.finish().map(drop)
+        }
+    }
+}
+
+/// Decompresses `input` into `out`, leaving holes for runs of zeros.
+/// Returns the size of the raw image.
+fn decompress(mut input: impl Read, out: &File) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK as usize];
+    let mut offset = 0;
+    loop {
+        let mut len = 0;
+        while len < buf.len() {
+            match input.read(&mut buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if len == 0 {
+            break;
+        }
+        if !buf[..len].iter().all(|&b| b == 0) {
+            out.write_all_at(&buf[..len], offset)?;
+        }
+        offset += len as u64;
+    }
+    out.set_len(offset)?;
+    Ok(offset)
+}
+
+/// Converts the image at `source` to raw in `out`, leaving holes for runs
+/// of zeros, after checking the image's footers, headers and checksums.
+/// Returns the size of the raw image.
+pub fn import(source: &Path, out: &File, progress: &mut dyn FnMut(&Progress)) -> io::Result<u64> {
+    let mut image: Box<dyn BlockDevice> = match detect(source)? {
+        Some(Format::Vmdk) => Box::new(VmdkImage::open(source)?),
+        Some(Format::VhdFixed | Format::VhdDynamic) => Box::new(VhdImage::open(source)?),
+        Some(Format::Vhdx) => Box::new(VhdxImage::open(source)?),
+        Some(format @ (Format::Zstd | Format::Xz)) => {
+            let file = File::open(source)?;
+            let len = file.metadata()?.len();
+            let input = io::BufReader::new(Metered::new(file, len, progress));
+            // Both decoders verify the stream's checksum at its end
+            return match format {
+                Format::Zstd => decompress(zstd::Decoder::with_buffer(input)?, out),
+                _ => decompress(xz2::read::XzDecoder::new_multi_decoder(input), out),
+            };
+        }
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a VMDK, VHD, VHDX or zstd- or xz-compressed image",
+            ))
+        }
+    };
+    let size = scan(&mut image, progress, |offset, data| out.write_all_at(data, offset))?;
+    out.set_len(size)?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{raw_disk, scratch_file, ScratchDir};
+    use std::fs;
+
+    /// Whole sectors, but not whole chunks, grains or blocks of any format.
+    const SIZE: u64 = 5 * 1024 * 1024 + 3 * 512;
+
+    const FORMATS: [Format; 6] =
+        [Format::Vmdk, Format::VhdFixed, Format::VhdDynamic, Format::Vhdx, Format::Zstd, Format::Xz];
+
+    /// A raw disk with data at the start, in the middle of a chunk, across
+    /// a block boundary and in the very last sector, and holes in between.
+    fn disk() -> File {
+        let extents = [(0, 4096, 1), (CHUNK * 5 + 1000, 3000, 2), ((2 << 20) - 700, 1400, 3), (SIZE - 512, 512, 4)];
+        raw_disk("convert-disk", SIZE, &extents)
+    }
+
+    #[test]
+    fn every_format_round_trips_byte_for_byte() {
+        let mut source = disk();
+        let expected = {
+            let mut buf = vec![0; SIZE as usize];
+            source.read_exact_at(&mut buf, 0).unwrap();
+            buf
+        };
+        for format in FORMATS {
+            let dir = ScratchDir::new(&format!("convert-{}", format));
+            let path = dir.join("image");
+            let out = File::create(&path).unwrap();
+            let result = (|| {
+                export(&mut source, &out, "image", format, &mut |_| {})?;
+                let detected = detect(&path)?;
+                let raw = scratch_file("convert-raw");
+                let size = import(&path, &raw, &mut |_| {})?;
+                let mut buf = vec![0; SIZE as usize];
+                raw.read_exact_at(&mut buf, 0)?;
+                Ok::<_, io::Error>((detected, size, raw.metadata()?.len(), buf))
+            })();
+            let (detected, size, len, buf) = result.unwrap_or_else(|e| panic!("{}: {}", format, e));
+            let kind = if format == Format::VhdFixed { Format::VhdDynamic } else { format };
+            assert_eq!(detected, Some(kind), "{}", format);
+            assert_eq!((size, len), (SIZE, SIZE), "{}", format);
+            assert!(buf == expected, "{} does not round-trip", format);
+        }
+    }
+
+    #[test]
+    fn raw_images_are_not_imported() {
+        let dir = ScratchDir::new("convert-plain");
+        let path = dir.join("plain.img");
+        fs::write(&path, vec![0x55; 4096]).unwrap();
+        let imported = import(&path, &scratch_file("convert-out"), &mut |_| {});
+        assert_eq!(detect(&path).unwrap(), None);
+        assert_eq!(imported.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn format_names_and_extensions() {
+        for format in FORMATS {
+            assert_eq!(format.to_string().parse::<Format>(), Ok(format));
+        }
+        assert_eq!("VHD".parse::<Format>(), Ok(Format::VhdDynamic));
+        assert!("qcow2".parse::<Format>().is_err());
+        assert_eq!(Format::from_extension(Path::new("disk.VMDK")), Some(Format::Vmdk));
+        assert_eq!(Format::from_extension(Path::new("disk.img")), None);
+    }
+}
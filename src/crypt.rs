@@ -0,0 +1,370 @@
+//! LUKS2 encryption under the filesystem, set up with cryptsetup(8) and
+//! opened through device-mapper, and the keys that unlock encrypted disks.
+
+use crate::error::{MizzleError, Result};
+use crate::uuid;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const LUKS_MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+/// Where LUKS1 and LUKS2 headers both keep the volume UUID.
+const LUKS_UUID_OFFSET: usize = 168;
+const LUKS_UUID_LEN: usize = 40;
+
+const MAPPER_DIR: &str = "/dev/mapper";
+
+/// Key slots in a LUKS2 header.
+pub const LUKS_KEY_SLOTS: usize = 32;
+
+/// The alphabet recovery keys are written in: no letters that look like
+/// digits or like each other, and the same keys on most keyboard layouts.
+const RECOVERY_ALPHABET: &[u8; 16] = b"cbdefghijklnrtuv";
+
+/// How to unlock an encrypted disk.
+#[derive(Clone, PartialEq, Eq, Default)]
+pub enum KeySource {
+    /// Let cryptsetup ask for the passphrase on the terminal.
+    #[default]
+    Prompt,
+    /// A passphrase handed over by the caller.
+    Passphrase(String),
+    /// A file whose whole contents are the key.
+    KeyFile(PathBuf),
+}
+
+/// Keeps passphrases out of debug output.
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::Prompt => f.write_str("Prompt"),
+            KeySource::Passphrase(_) => f.write_str("Passphrase(..)"),
+            KeySource::KeyFile(path) => f.debug_tuple("KeyFile").field(path).finish(),
+        }
+    }
+}
+
+impl KeySource {
+    /// The key itself: the passphrase, the key file's contents, or for
+    /// `Prompt` a passphrase asked for on the terminal, twice if `confirm`.
+    pub fn read(&self, prompt: &str, confirm: bool) -> io::Result<Vec<u8>> {
+        match self {
+            KeySource::Passphrase(passphrase) => Ok(passphrase.as_bytes().to_vec()),
+            KeySource::KeyFile(path) => fs::read(path),
+            KeySource::Prompt => {
+                let passphrase = ask(&format!("{}: ", prompt))?;
+                if confirm && ask("Repeat the passphrase: ")? != passphrase {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "the passphrases do not match"));
+                }
+                if passphrase.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "the passphrase is empty"));
+                }
+                Ok(passphrase.into_bytes())
+            }
+        }
+    }
+}
+
+/// What kind of key sits in a key slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyKind {
+    Passphrase,
+    KeyFile,
+    Recovery,
+}
+
+impl fmt::Display for KeyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyKind::Passphrase => "passphrase",
+            KeyKind::KeyFile => "key file",
+            KeyKind::Recovery => "recovery key",
+        })
+    }
+}
+
+/// What the registry remembers about one key slot of an encrypted disk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRecord {
+    pub kind: KeyKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+    /// Seconds since the Unix epoch.
+    pub added: u64,
+}
+
+impl KeyRecord {
+    /// A record of a key added just now.
+    pub fn new(kind: KeyKind, key_file: Option<PathBuf>) -> KeyRecord {
+        let added = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        KeyRecord { kind, key_file, added }
+    }
+
+    /// A record of a passphrase or key file added just now from `key`.
+    pub fn from_source(key: &KeySource) -> KeyRecord {
+        match key {
+            KeySource::KeyFile(path) => {
+                KeyRecord::new(KeyKind::KeyFile, Some(fs::canonicalize(path).unwrap_or_else(|_| path.clone())))
+            }
+            _ => KeyRecord::new(KeyKind::Passphrase, None),
+        }
+    }
+}
+
+/// A new recovery key: 256 random bits as eight dash-separated groups of
+/// eight letters, short enough to print and type back in.
+pub fn recovery_key() -> io::Result<String> {
+    let mut bytes = [0u8; 32];
+    uuid::random_bytes(&mut bytes)?;
+    let letters: Vec<u8> = bytes
+        .iter()
+        .flat_map(|b| [RECOVERY_ALPHABET[usize::from(b >> 4)], RECOVERY_ALPHABET[usize::from(b & 0xf)]])
+        .collect();
+    let groups: Vec<&str> =
+        letters.chunks(8).map(|group| std::str::from_utf8(group).expect("recovery letters are ASCII")).collect();
+    Ok(groups.join("-"))
+}
+
+/// Reads a line from the terminal without echoing it, or from standard
+/// input when there is no terminal.
+fn ask(prompt: &str) -> io::Result<String> {
+    let mut line = String::new();
+    match fs::OpenOptions::new().read(true).write(true).open("/dev/tty") {
+        Ok(mut tty) => {
+            tty.write_all(prompt.as_bytes())?;
+            let fd = tty.as_raw_fd();
+            // SAFETY: termios is plain data and `fd` stays open for both calls
+            let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+            let echo_off = unsafe { libc::tcgetattr(fd, &mut saved) } == 0 && {
+                let mut quiet = saved;
+                quiet.c_lflag &= !libc::ECHO;
+                unsafe { libc::tcsetattr(fd, libc::TCSANOW, &quiet) == 0 }
+            };
+            let read = io::BufReader::new(&tty).read_line(&mut line);
+            if echo_off {
+                unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+                tty.write_all(b"\n")?;
+            }
+            read?;
+        }
+        Err(_) => {
+            io::stdin().lock().read_line(&mut line)?;
+        }
+    }
+    Ok(line.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Reads the start of a LUKS header, or `None` if `image` does not begin with one.
+fn header(image: &Path) -> io::Result<Option<[u8; LUKS_UUID_OFFSET + LUKS_UUID_LEN]>> {
+    let mut header = [0; LUKS_UUID_OFFSET + LUKS_UUID_LEN];
+    match File::open(image)?.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    Ok(header.starts_with(LUKS_MAGIC).then_some(header))
+}
+
+/// Whether `image` starts with a LUKS header.
+pub fn is_luks(image: &Path) -> io::Result<bool> {
+    header(image).map(|h| h.is_some())
+}
+
+/// The UUID in the LUKS header of `image`.
+pub fn luks_uuid(image: &Path) -> io::Result<Option<String>> {
+    Ok(header(image)?.map(|h| {
+        let uuid = &h[LUKS_UUID_OFFSET..];
+        let len = uuid.iter().position(|&b| b == 0).unwrap_or(uuid.len());
+        String::from_utf8_lossy(&uuid[..len]).into_owned()
+    }))
+}
+
+/// The device-mapper name the LUKS volume on `device` is opened under,
+/// derived from its UUID so the same image always gets the same name.
+pub fn mapping_name(device: &Path) -> Result<String> {
+    match luks_uuid(device).map_err(|e| MizzleError::io(device, e))? {
+        Some(uuid) => Ok(format!("mizzle-{}", uuid)),
+        None => Err(MizzleError::io(device, io::Error::new(io::ErrorKind::InvalidData, "no LUKS header"))),
+    }
+}
+
+/// Whether `device` is a device-mapper device.
+pub fn is_mapping(device: &Path) -> bool {
+    device.starts_with(MAPPER_DIR) || device.file_name().is_some_and(|n| n.to_string_lossy().starts_with("dm-"))
+}
+
+/// The device a device-mapper device sits on, e.g. the loop device under an
+/// opened image, from `/sys/block/dm-N/slaves`.
+pub fn backing_device(device: &Path) -> Option<PathBuf> {
+    let dm = fs::canonicalize(device).ok()?;
+    let slaves = Path::new("/sys/block").join(dm.file_name()?).join("slaves");
+    let slave = fs::read_dir(slaves).ok()?.next()?.ok()?;
+    Some(Path::new("/dev").join(slave.file_name()))
+}
+
+/// Runs cryptsetup with `options` followed by `device` and any further
+/// `operands`, handing it the key as `key` says.
+fn cryptsetup(options: &[&str], device: &Path, operands: &[&str], key: Option<&KeySource>) -> Result<()> {
+    run(options, device, operands, key, false).map(drop)
+}
+
+/// Like [`cryptsetup`], returning what it printed when `capture` is set and
+/// leaving its output on the terminal otherwise.
+fn run(options: &[&str], device: &Path, operands: &[&str], key: Option<&KeySource>, capture: bool) -> Result<String> {
+    let mut args: Vec<OsString> = options.iter().map(OsString::from).collect();
+    let stdin = match key {
+        Some(KeySource::Passphrase(_)) => {
+            args.extend(["--key-file".into(), "-".into()]);
+            Stdio::piped()
+        }
+        Some(KeySource::KeyFile(path)) => {
+            args.extend(["--key-file".into(), path.into()]);
+            Stdio::null()
+        }
+        Some(KeySource::Prompt) => Stdio::inherit(),
+        None => Stdio::null(),
+    };
+    args.push(device.into());
+    args.extend(operands.iter().map(OsString::from));
+    let spawn_err = |source| MizzleError::Spawn { program: "cryptsetup".to_string(), source };
+    let mut child = Command::new("cryptsetup")
+        .args(&args)
+        .stdin(stdin)
+        .stdout(if capture { Stdio::piped() } else { Stdio::inherit() })
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(spawn_err)?;
+    if let (Some(KeySource::Passphrase(passphrase)), Some(mut input)) = (key, child.stdin.take()) {
+        // A broken pipe means cryptsetup gave up early; its exit status says why
+        let _ = input.write_all(passphrase.as_bytes());
+    }
+    let output = child.wait_with_output().map_err(spawn_err)?;
+    if !output.status.success() {
+        return Err(MizzleError::Tool {
+            path: device.to_path_buf(),
+            program: "cryptsetup".to_string(),
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Writes a new LUKS2 header to `device`, destroying whatever it held.
+pub fn format(device: &Path, key: &KeySource) -> Result<()> {
+    let mut options = vec!["luksFormat", "--type", "luks2", "--batch-mode"];
+    if *key == KeySource::Prompt {
+        options.push("--verify-passphrase");
+    }
+    cryptsetup(&options, device, &[], Some(key))
+}
+
+/// Opens the LUKS volume on `device` as `/dev/mapper/<name>` and returns
+/// that path.
+pub fn open(device: &Path, name: &str, key: &KeySource, read_only: bool) -> Result<PathBuf> {
+    let mut options = vec!["open", "--type", "luks"];
+    if read_only {
+        options.push("--readonly");
+    }
+    cryptsetup(&options, device, &[name], Some(key))?;
+    Ok(Path::new(MAPPER_DIR).join(name))
+}
+
+/// Closes the device-mapper device `device`.
+pub fn close(device: &Path) -> Result<()> {
+    cryptsetup(&["close"], device, &[], None)
+}
+
+/// The active key slots of the LUKS volume on `device`, from `luksDump`.
+pub fn key_slots(device: &Path) -> Result<Vec<usize>> {
+    let dump = run(&["luksDump"], device, &[], None, true)?;
+    // "Keyslots:" is followed by one "  3: luks2" line per slot, and their details
+    let slots = dump
+        .lines()
+        .skip_while(|line| *line != "Keyslots:")
+        .skip(1)
+        .take_while(|line| line.starts_with(char::is_whitespace))
+        .filter_map(|line| line.trim().split_once(": luks2"))
+        .filter_map(|(slot, _)| slot.parse().ok())
+        .collect();
+    Ok(slots)
+}
+
+/// The key slot `key` opens on `device`.
+pub fn unlocked_slot(device: &Path, key: &KeySource) -> Result<usize> {
+    let output = run(&["open", "--test-passphrase", "--verbose"], device, &[], Some(key), true)?;
+    // "Key slot 2 unlocked."
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("Key slot ")?.strip_suffix(" unlocked.")?.parse().ok())
+        .ok_or_else(|| {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "cryptsetup did not say which key slot opened");
+            MizzleError::io(device, e)
+        })
+}
+
+/// A pipe already holding `key`, for handing a second key to cryptsetup as
+/// `/dev/fd/N` while the first goes through standard input. The read end is
+/// left open across exec; the write end is closed so the key ends there.
+/// Passphrases fit in the pipe buffer without a reader.
+fn key_pipe(key: &[u8]) -> io::Result<OwnedFd> {
+    if key.len() > libc::PIPE_BUF {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the new key is too long"));
+    }
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors pipe(2) returns
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both descriptors were just opened and nothing else owns them
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    (&write).write_all(key)?;
+    Ok(read)
+}
+
+/// Puts the key `new` into key slot `slot` of the LUKS volume on `device`,
+/// which `key` opens. A new passphrase has to be given, not prompted for.
+pub fn add_key(device: &Path, key: &KeySource, slot: usize, new: &KeySource) -> Result<()> {
+    let (new_key_file, _pipe) = match new {
+        KeySource::KeyFile(path) => (path.to_string_lossy().into_owned(), None),
+        KeySource::Passphrase(passphrase) => {
+            let pipe = key_pipe(passphrase.as_bytes()).map_err(|e| MizzleError::io(device, e))?;
+            (format!("/dev/fd/{}", pipe.as_raw_fd()), Some(pipe))
+        }
+        KeySource::Prompt => {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, "the new passphrase has to be read first");
+            return Err(MizzleError::io(device, e));
+        }
+    };
+    let slot = slot.to_string();
+    cryptsetup(&["luksAddKey", "--key-slot", &slot], device, &[&new_key_file], Some(key))
+}
+
+/// Wipes key slot `slot` of the LUKS volume on `device`, which `key` opens.
+pub fn remove_key(device: &Path, key: &KeySource, slot: usize) -> Result<()> {
+    cryptsetup(&["luksKillSlot"], device, &[&slot.to_string()], Some(key))
+}
+
+/// Whether the LUKS volume on `device` has a re-encryption in progress.
+pub fn reencrypt_pending(device: &Path) -> Result<bool> {
+    let dump = run(&["luksDump"], device, &[], None, true)?;
+    Ok(dump.lines().any(|line| line.trim_start().starts_with("Requirements:") && line.contains("reencrypt")))
+}
+
+/// Re-encrypts the LUKS volume on `device` under a new volume key, keeping
+/// only key slot `slot`, which `key` opens. cryptsetup keeps its progress
+/// in the header and prints it as it goes; an interrupted run is resumed by
+/// running it again.
+pub fn reencrypt(device: &Path, key: &KeySource, slot: usize) -> Result<()> {
+    let slot = slot.to_string();
+    let options = ["reencrypt", "--key-slot", &slot, "--resilience", "checksum", "--progress-frequency", "1"];
+    cryptsetup(&options, device, &[], Some(key))
+}
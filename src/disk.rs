@@ -0,0 +1,1284 @@
+use crate::alloc::{self, AllocationPolicy, AllocationReport};
+use crate::blockdev::BlockDevice;
+use crate::container::{self, EncryptedDevice, RotateReport};
+use crate::convert::{self, Format};
+use crate::crypt::{self, KeySource};
+use crate::error::{MizzleError, Result};
+use crate::filesystem::{Filesystem, FormatOptions};
+use crate::fsck::{self, CheckMode, CheckReport};
+use crate::imagefs::{self, ImageFs, NodeMeta};
+use crate::integrity::{self, HashAlgorithm, Manifest, ManifestEntry, VerifyStatus};
+use crate::guard::{self, MountGuard};
+use crate::loopdev::{self, LoopDevice, LoopOptions};
+use crate::nbd;
+use crate::ops::{self, DirEntry, Overwrite, Placed};
+use crate::qcow2::{self, Qcow2Image};
+use crate::registry::DiskRecord;
+use crate::snapshot::{self, Frozen, Snapshot};
+use crate::transfer::{Metered, Progress};
+use crate::tree::{self, CopyOptions, CopyReport};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskState {
+    /// No image file exists yet.
+    Absent,
+    /// The image file exists but has not been formatted by us.
+    Created,
+    Formatted,
+    Mounted,
+}
+
+impl fmt::Display for DiskState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DiskState::Absent => "absent",
+            DiskState::Created => "created",
+            DiskState::Formatted => "formatted",
+            DiskState::Mounted => "mounted",
+        })
+    }
+}
+
+/// How the image file holds the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageFormat {
+    /// The disk byte for byte, at its full size.
+    #[default]
+    Raw,
+    /// A qcow2 image, which grows as it is written and may read through to
+    /// a backing file. The kernel sees it through an NBD device.
+    Qcow2,
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::Qcow2 => "qcow2",
+        })
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "raw" | "img" => Ok(ImageFormat::Raw),
+            "qcow2" => Ok(ImageFormat::Qcow2),
+            _ => Err(format!("unknown image format '{}' (expected raw or qcow2)", s)),
+        }
+    }
+}
+
+/// A disk image on the host together with where it gets mounted.
+#[derive(Debug, Clone)]
+pub struct VirtualDisk {
+    image: PathBuf,
+    size: u64,
+    filesystem: Filesystem,
+    format_options: FormatOptions,
+    allocation: AllocationPolicy,
+    mount_point: PathBuf,
+    loop_options: LoopOptions,
+    loop_device: Option<PathBuf>,
+    encrypt: bool,
+    container: bool,
+    image_format: ImageFormat,
+    backing_file: Option<PathBuf>,
+    key: KeySource,
+    force: bool,
+    state: DiskState,
+}
+
+impl VirtualDisk {
+    /// Describes a disk at `image`; the initial state is taken from the host.
+    pub fn new(image: impl Into<PathBuf>, size: u64) -> Self {
+        let mut disk = VirtualDisk {
+            image: image.into(),
+            size,
+            filesystem: Filesystem::default(),
+            format_options: FormatOptions::default(),
+            allocation: AllocationPolicy::default(),
+            mount_point: PathBuf::from(crate::MOUNT_POINT),
+            loop_options: LoopOptions::default(),
+            loop_device: None,
+            encrypt: false,
+            container: false,
+            image_format: ImageFormat::default(),
+            backing_file: None,
+            key: KeySource::default(),
+            force: false,
+            state: DiskState::Absent,
+        };
+        disk.refresh_state();
+        disk
+    }
+
+    /// Rebuilds a disk from its registry entry. The recorded state only fills
+    /// in what cannot be seen from the host, i.e. whether it was formatted.
+    pub fn from_record(record: &DiskRecord) -> Self {
+        let mut disk = VirtualDisk::new(&record.image, record.size)
+            .with_mount_point(&record.mount_point)
+            .with_filesystem(record.filesystem);
+        if disk.state == DiskState::Created && record.state == DiskState::Formatted {
+            disk.state = DiskState::Formatted;
+        }
+        disk
+    }
+
+    /// The registry entry describing this disk as it is now.
+    pub fn record(&self) -> DiskRecord {
+        DiskRecord {
+            image: fs::canonicalize(&self.image).unwrap_or_else(|_| self.image.clone()),
+            size: self.capacity().unwrap_or(self.size),
+            filesystem: self.filesystem,
+            uuid: self.uuid(),
+            mount_point: fs::canonicalize(&self.mount_point).unwrap_or_else(|_| self.mount_point.clone()),
+            loop_device: self.loop_device.clone(),
+            state: self.state,
+            snapshots: Default::default(),
+            keys: Default::default(),
+        }
+    }
+
+    /// The filesystem UUID read from the image, if it holds one.
+    pub fn uuid(&self) -> Option<String> {
+        if self.is_container() {
+            return None;
+        }
+        if self.is_qcow2() {
+            let mut image = Qcow2Image::open(&self.image, false).ok()?;
+            return self.filesystem.read_device_uuid(&mut image).ok().flatten();
+        }
+        self.filesystem.read_uuid(&self.image).ok().flatten()
+    }
+
+    pub fn with_mount_point(mut self, mount_point: impl Into<PathBuf>) -> Self {
+        self.mount_point = mount_point.into();
+        self.refresh_state();
+        self
+    }
+
+    pub fn with_filesystem(mut self, filesystem: Filesystem) -> Self {
+        self.filesystem = filesystem;
+        self
+    }
+
+    pub fn with_format_options(mut self, options: FormatOptions) -> Self {
+        self.format_options = options;
+        self
+    }
+
+    pub fn with_allocation(mut self, allocation: AllocationPolicy) -> Self {
+        self.allocation = allocation;
+        self
+    }
+
+    pub fn with_loop_options(mut self, options: LoopOptions) -> Self {
+        self.loop_options = options;
+        self
+    }
+
+    /// Makes format put the filesystem inside a LUKS2 volume, unlocked with
+    /// the key from [`with_key`](Self::with_key).
+    pub fn with_encryption(mut self, encrypt: bool) -> Self {
+        self.encrypt = encrypt;
+        self
+    }
+
+    /// Makes create store the image in an encrypted
+    /// [`container`](crate::container), which is opened in-process with the
+    /// key from [`with_key`](Self::with_key) and needs no dm-crypt.
+    pub fn with_container(mut self, container: bool) -> Self {
+        self.container = container;
+        self
+    }
+
+    /// Makes create write a qcow2 image rather than a raw one.
+    pub fn with_image_format(mut self, format: ImageFormat) -> Self {
+        self.image_format = format;
+        self
+    }
+
+    /// Makes create start the qcow2 image as an overlay on `backing`, a raw
+    /// or qcow2 image that is only ever read.
+    pub fn with_backing_file(mut self, backing: Option<PathBuf>) -> Self {
+        self.backing_file = backing;
+        self
+    }
+
+    /// How to unlock the disk if it is encrypted; by default we or
+    /// cryptsetup ask for the passphrase.
+    pub fn with_key(mut self, key: KeySource) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Lets create and format replace an existing image or filesystem
+    /// instead of refusing.
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn image(&self) -> &Path {
+        &self.image
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn filesystem(&self) -> Filesystem {
+        self.filesystem
+    }
+
+    pub fn format_options(&self) -> &FormatOptions {
+        &self.format_options
+    }
+
+    pub fn allocation(&self) -> AllocationPolicy {
+        self.allocation
+    }
+
+    pub fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    pub fn state(&self) -> DiskState {
+        self.state
+    }
+
+    /// The loop device the image is mounted through, or the device-mapper
+    /// device for an encrypted image, when known.
+    pub fn loop_device(&self) -> Option<&Path> {
+        self.loop_device.as_deref()
+    }
+
+    /// Whether the image holds a LUKS volume rather than a bare filesystem.
+    pub fn is_encrypted(&self) -> bool {
+        crypt::is_luks(&self.image).unwrap_or(false)
+    }
+
+    /// Whether the image is one of our encrypted containers.
+    pub fn is_container(&self) -> bool {
+        container::is_container(&self.image).unwrap_or(false)
+    }
+
+    /// Whether the image is a qcow2 image.
+    pub fn is_qcow2(&self) -> bool {
+        qcow2::is_qcow2(&self.image).unwrap_or(false)
+    }
+
+    /// The size of the disk as its filesystem sees it: the image's size, or
+    /// what an encrypted container or a qcow2 image holds.
+    fn capacity(&self) -> io::Result<u64> {
+        if self.is_qcow2() {
+            return qcow2::info(&self.image).map(|info| info.virtual_size);
+        }
+        match container::payload_size(&self.image)? {
+            Some(payload) => Ok(payload),
+            None => fs::metadata(&self.image).map(|m| m.len()),
+        }
+    }
+
+    /// Whether the filesystem can be read and written in the image file
+    /// itself, without mounting it.
+    fn direct_access(&self) -> bool {
+        imagefs::is_supported(self.filesystem) && !self.is_encrypted()
+    }
+
+    fn refresh_state(&mut self) {
+        let source = ops::mount_source(&self.mount_point).unwrap_or(None);
+        let ours = source.filter(|s| self.backs(s));
+        self.loop_device = ours
+            .clone()
+            .filter(|s| s.to_string_lossy().starts_with("/dev/loop") || crypt::is_mapping(s) || nbd::is_nbd(s));
+        self.state = if ours.is_some() {
+            DiskState::Mounted
+        } else if !self.image.exists() {
+            DiskState::Absent
+        } else if self.is_container() {
+            // Only the recorded state can tell whether it was formatted
+            DiskState::Created
+        } else if matches!(self.probe(), Ok(Some(_))) || self.is_encrypted() {
+            DiskState::Formatted
+        } else {
+            DiskState::Created
+        };
+    }
+
+    /// The filesystem in the image, looked for through qcow2 if it is one.
+    fn probe(&self) -> io::Result<Option<Filesystem>> {
+        if self.is_qcow2() {
+            return Filesystem::probe_device(&mut Qcow2Image::open(&self.image, false)?);
+        }
+        Filesystem::probe(&self.image)
+    }
+
+    /// Whether the mount source `source` is this image, directly, through a
+    /// loop or NBD device or through an encrypted mapping on one.
+    fn backs(&self, source: &Path) -> bool {
+        if crypt::is_mapping(source) {
+            return crypt::backing_device(source).is_some_and(|device| self.backs(&device));
+        }
+        let Ok(image) = fs::canonicalize(&self.image) else {
+            return false;
+        };
+        if source == image {
+            return true;
+        }
+        if nbd::is_nbd(source) {
+            return nbd::served_image(source) == Some(image);
+        }
+        source.to_string_lossy().starts_with("/dev/loop")
+            && LoopDevice::open(source).and_then(|dev| dev.info()).is_ok_and(|info| info.backing_file == image)
+    }
+
+    /// Where this image is mounted, wherever that is.
+    fn mounted_at(&self) -> Result<Option<PathBuf>> {
+        let mounts = ops::mounts().map_err(|e| MizzleError::io("/proc/self/mountinfo", e))?;
+        Ok(mounts.into_iter().find(|m| self.backs(&m.source)).map(|m| m.target))
+    }
+
+    fn refuse(&self, operation: &'static str, reason: String) -> MizzleError {
+        MizzleError::Refused { path: self.image.clone(), operation, reason }
+    }
+
+    fn require(&self, operation: &'static str, allowed: &[DiskState]) -> Result<()> {
+        if allowed.contains(&self.state) {
+            Ok(())
+        } else {
+            Err(MizzleError::State { path: self.image.clone(), operation, state: self.state })
+        }
+    }
+
+    /// Creates the image. An existing image of the requested size is kept as
+    /// it is and `None` returned; one of another size is only replaced when
+    /// forced.
+    pub fn create(&mut self) -> Result<Option<AllocationReport>> {
+        self.require("create", &[DiskState::Absent, DiskState::Created, DiskState::Formatted])?;
+        self.filesystem.check_size(self.size)?;
+        if self.container && !self.size.is_multiple_of(container::SECTOR_SIZE) {
+            let reason = format!("an encrypted container holds whole {}-byte sectors", container::SECTOR_SIZE);
+            return Err(MizzleError::InvalidSize { size: self.size, reason });
+        }
+        if self.image_format == ImageFormat::Qcow2 && !self.size.is_multiple_of(512) {
+            let reason = "a qcow2 image holds whole 512-byte sectors".to_string();
+            return Err(MizzleError::InvalidSize { size: self.size, reason });
+        }
+        if self.container && self.image_format != ImageFormat::Raw {
+            return Err(self.refuse("create", "an encrypted container is always a raw image".to_string()));
+        }
+        if self.backing_file.is_some() && self.image_format != ImageFormat::Qcow2 {
+            return Err(self.refuse("create", "only a qcow2 image can have a backing file".to_string()));
+        }
+        if self.state != DiskState::Absent {
+            let len = self.capacity().map_err(|e| MizzleError::io(&self.image, e))?;
+            if !self.force {
+                if len == self.size {
+                    return Ok(None);
+                }
+                return Err(self.refuse("create", format!("an image of {} bytes already exists", len)));
+            }
+            // Start from an empty file so nothing of the old image survives
+            fs::remove_file(&self.image).map_err(|e| MizzleError::io(&self.image, e))?;
+        }
+        if self.image_format == ImageFormat::Qcow2 {
+            return self.create_qcow2().map(Some);
+        }
+        if !self.container {
+            let report = ops::create_image(&self.image, self.size, self.allocation)?;
+            self.state = DiskState::Created;
+            return Ok(Some(report));
+        }
+        let report = ops::create_image(&self.image, container::container_size(self.size), self.allocation)?;
+        let seal = || -> io::Result<u64> {
+            let passphrase = self.key.read("Passphrase for the new disk", true)?;
+            let file = OpenOptions::new().read(true).write(true).open(&self.image)?;
+            drop(EncryptedDevice::create(file.try_clone()?, self.size, &passphrase)?);
+            alloc::allocated_bytes(&file)
+        };
+        let allocated = seal().map_err(|e| {
+            // A plain image of the wrong size would only confuse later commands
+            let _ = fs::remove_file(&self.image);
+            MizzleError::io(&self.image, e)
+        })?;
+        self.state = DiskState::Created;
+        Ok(Some(AllocationReport { size: self.size, allocated, ..report }))
+    }
+
+    /// Writes a new qcow2 image, on the backing file if there is one. Only
+    /// the metadata is allocated, whatever the allocation policy.
+    fn create_qcow2(&mut self) -> Result<AllocationReport> {
+        let backing = match &self.backing_file {
+            Some(backing) => Some(fs::canonicalize(backing).map_err(|e| MizzleError::io(backing, e))?),
+            None => None,
+        };
+        if let Some(backing) = &backing {
+            if fs::canonicalize(&self.image).is_ok_and(|image| image == *backing) {
+                return Err(self.refuse("create", "an image cannot be its own backing file".to_string()));
+            }
+        }
+        let create = || -> io::Result<u64> {
+            qcow2::create(&self.image, self.size, backing.as_deref())?;
+            alloc::allocated_bytes(&File::open(&self.image)?)
+        };
+        let allocated = create().map_err(|e| MizzleError::Allocate { path: self.image.clone(), source: e })?;
+        self.state = DiskState::Created;
+        Ok(AllocationReport { policy: AllocationPolicy::Sparse, size: self.size, allocated })
+    }
+
+    /// Grows or shrinks the image to `size` bytes, taking the filesystem on
+    /// it along. A mounted disk can grow: its loop device picks up the new
+    /// size and the filesystem is grown in place. Shrinking is for unmounted
+    /// ext4 only, and never below what the data on it needs. Returns `None`
+    /// if the image already has that size.
+    pub fn resize(&mut self, size: u64) -> Result<Option<AllocationReport>> {
+        self.require("resize", &[DiskState::Created, DiskState::Formatted, DiskState::Mounted])?;
+        if self.is_encrypted() || self.is_container() {
+            return Err(self.refuse("resize", "it is encrypted".to_string()));
+        }
+        if self.is_qcow2() {
+            return Err(self.refuse("resize", "it is a qcow2 image".to_string()));
+        }
+        if self.state != DiskState::Created {
+            self.filesystem.check_size(size)?;
+        }
+        let current = fs::metadata(&self.image).map_err(|e| MizzleError::io(&self.image, e))?.len();
+        if size == current {
+            self.size = size;
+            return Ok(None);
+        }
+        if self.state != DiskState::Mounted {
+            if let Some(target) = self.mounted_at()? {
+                return Err(self.refuse("resize", format!("it is mounted on {}", target.display())));
+            }
+        }
+        let loops = loopdev::find(&self.image).map_err(|e| MizzleError::io(&self.image, e))?;
+
+        let report = if size < current {
+            if self.state == DiskState::Mounted || !loops.is_empty() {
+                return Err(self.refuse("shrink", "it is in use; unmount and detach it first".to_string()));
+            }
+            if self.state == DiskState::Formatted {
+                self.shrink_filesystem(size)?;
+            }
+            ops::resize_image(&self.image, size, self.allocation)?
+        } else {
+            let report = ops::resize_image(&self.image, size, self.allocation)?;
+            let set_capacity = || -> Result<()> {
+                for info in &loops {
+                    LoopDevice::open(&info.device).and_then(|dev| dev.set_capacity()).map_err(|source| {
+                        MizzleError::Loop { path: info.device.clone(), operation: "resize", source }
+                    })?;
+                }
+                Ok(())
+            };
+            let grown = set_capacity().and_then(|_| match self.state {
+                DiskState::Created => Ok(()),
+                _ => self.grow_filesystem(),
+            });
+            if let Err(e) = grown {
+                // The filesystem is still the old size, so give the space back
+                // rather than leave a disk that looks resized but is not
+                let _ = ops::resize_image(&self.image, current, AllocationPolicy::Sparse);
+                let _ = set_capacity();
+                return Err(e);
+            }
+            report
+        };
+        self.size = size;
+        Ok(Some(report))
+    }
+
+    fn shrink_filesystem(&self, size: u64) -> Result<()> {
+        if self.filesystem != Filesystem::Ext4 {
+            return Err(MizzleError::Unsupported { filesystem: self.filesystem, feature: "shrinking".to_string() });
+        }
+        ops::check_ext4(&self.image)?;
+        let minimum = ops::ext4_minimum_size(&self.image)?;
+        if size < minimum {
+            return Err(self.refuse("shrink", format!("the data on it needs at least {} bytes", minimum)));
+        }
+        ops::resize_ext4(&self.image, size)
+    }
+
+    fn grow_filesystem(&self) -> Result<()> {
+        if self.state == DiskState::Mounted {
+            let device = self.loop_device.as_deref().unwrap_or(&self.image);
+            return ops::grow_filesystem(self.filesystem, device, &self.mount_point);
+        }
+        if self.filesystem.grows_offline() {
+            ops::check_ext4(&self.image)?;
+            return ops::grow_filesystem(self.filesystem, &self.image, &self.mount_point);
+        }
+        self.with_mount(|mnt| ops::grow_filesystem(self.filesystem, &self.image, mnt))
+    }
+
+    /// Refuses to format over an existing filesystem unless forced. Returns
+    /// false if the image already holds the requested filesystem and
+    /// `reuse` allows keeping it.
+    fn check_format(&self, reuse: bool) -> Result<bool> {
+        self.require("format", &[DiskState::Created, DiskState::Formatted])?;
+        let size = self.capacity().map_err(|e| MizzleError::io(&self.image, e))?;
+        self.filesystem.check_size(size)?;
+        if self.force {
+            return Ok(true);
+        }
+        if self.is_container() {
+            return match self.state {
+                DiskState::Formatted if reuse => Ok(false),
+                DiskState::Formatted => Err(self.refuse("format", "it already holds a filesystem".to_string())),
+                _ => Ok(true),
+            };
+        }
+        if self.is_encrypted() {
+            // What is inside cannot be seen without the key
+            if self.encrypt && reuse {
+                return Ok(false);
+            }
+            return Err(self.refuse("format", "it already contains a LUKS-encrypted volume".to_string()));
+        }
+        match self.probe().map_err(|e| MizzleError::io(&self.image, e))? {
+            None => Ok(true),
+            Some(found) if found == self.filesystem && reuse && !self.encrypt => Ok(false),
+            Some(found) => Err(self.refuse("format", format!("it already contains {}", found))),
+        }
+    }
+
+    /// Formats the image; returns false when it already held the requested
+    /// filesystem and was left alone. With [`with_encryption`](Self::with_encryption)
+    /// a LUKS2 header goes on the image first and the filesystem inside it.
+    pub fn format(&mut self) -> Result<bool> {
+        if self.is_container() {
+            // mkfs needs a block device; the container is only readable here
+            return self.format_in_process(None);
+        }
+        if !self.check_format(true)? {
+            return Ok(false);
+        }
+        if self.is_qcow2() {
+            if self.encrypt {
+                return Err(self.refuse("encrypt", "LUKS cannot go on a qcow2 image".to_string()));
+            }
+            self.with_nbd(false, |device| ops::format_virtual_disk(device, self.filesystem, &self.format_options))?;
+        } else if self.encrypt {
+            let device = self.attach()?;
+            let formatted = crypt::format(device.path(), &self.key).and_then(|_| {
+                self.unlocked(device.path(), |mapped| {
+                    ops::format_virtual_disk(mapped, self.filesystem, &self.format_options)
+                })
+            });
+            let _ = device.detach();
+            formatted?;
+        } else {
+            ops::format_virtual_disk(&self.image, self.filesystem, &self.format_options)?;
+        }
+        self.state = DiskState::Formatted;
+        Ok(true)
+    }
+
+    /// Formats the image without mkfs or root privileges, optionally filling
+    /// it from the host directory `source`. An existing filesystem is reused
+    /// like in [`format`](Self::format), unless there is a source to copy in.
+    pub fn format_in_process(&mut self, source: Option<&Path>) -> Result<bool> {
+        if self.encrypt {
+            return Err(MizzleError::Unsupported {
+                filesystem: self.filesystem,
+                feature: "encryption when formatting in-process".to_string(),
+            });
+        }
+        if !self.check_format(source.is_none())? {
+            return Ok(false);
+        }
+        if self.is_container() {
+            let device = self.open_container(true)?;
+            imagefs::format_device(device, self.filesystem, &self.format_options, source)
+        } else if self.is_qcow2() {
+            let device = self.open_qcow2(true)?;
+            imagefs::format_device(device, self.filesystem, &self.format_options, source)
+        } else {
+            imagefs::format_image(&self.image, self.filesystem, &self.format_options, source)
+        }
+        .map_err(|e| self.image_error(e))?;
+        self.state = DiskState::Formatted;
+        Ok(true)
+    }
+
+    /// Checks the filesystem on the image, or repairs it in
+    /// [`CheckMode::Repair`]. Refused while the image is mounted anywhere.
+    pub fn check(&self, mode: CheckMode) -> Result<CheckReport> {
+        if let Some(target) = self.mounted_at()? {
+            return Err(self.refuse("check", format!("it is mounted on {}", target.display())));
+        }
+        self.require("check", &[DiskState::Formatted])?;
+        if self.is_container() {
+            return Err(self.refuse("check", "the checker cannot read an encrypted container".to_string()));
+        }
+        if self.is_encrypted() {
+            return self.with_unlocked(|mapped| fsck::check(mapped, self.filesystem, mode));
+        }
+        if self.is_qcow2() {
+            return self.with_nbd(mode != CheckMode::Repair, |device| fsck::check(device, self.filesystem, mode));
+        }
+        fsck::check(&self.image, self.filesystem, mode)
+    }
+
+    /// Takes a snapshot of the image under `name`, kept in the directory
+    /// [`snapshot_dir`](snapshot::snapshot_dir) names. A mounted filesystem
+    /// is frozen while it is copied so the snapshot is consistent.
+    pub fn snapshot(&self, name: &str) -> Result<Snapshot> {
+        self.require("snapshot", &[DiskState::Created, DiskState::Formatted, DiskState::Mounted])?;
+        snapshot::check_name(name).map_err(|reason| self.refuse("snapshot", reason))?;
+        let image = fs::canonicalize(&self.image).map_err(|e| MizzleError::io(&self.image, e))?;
+        let path = snapshot::snapshot_dir(&image).join(name);
+        if path.exists() {
+            return Err(self.refuse("snapshot", format!("a snapshot named '{}' already exists", name)));
+        }
+        let _frozen = match self.mounted_at()? {
+            Some(target) => Some(Frozen::freeze(&target).map_err(|e| MizzleError::io(&target, e))?),
+            None => None,
+        };
+        snapshot::take(&image, &path).map_err(|source| MizzleError::Copy { from: image.clone(), to: path, source })
+    }
+
+    /// Rolls the image back to `snapshot`; returns whether the restored image
+    /// shares its blocks with the snapshot. Refused while the image is in use,
+    /// i.e. mounted anywhere or attached to a loop device.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<bool> {
+        self.require("restore", &[DiskState::Absent, DiskState::Created, DiskState::Formatted])?;
+        if self.state != DiskState::Absent {
+            self.refuse_if_in_use("restore")?;
+        }
+        let reflink = snapshot::restore(snapshot, &self.image).map_err(|source| MizzleError::Copy {
+            from: snapshot.path.clone(),
+            to: self.image.clone(),
+            source,
+        })?;
+        self.size = snapshot.size;
+        self.refresh_state();
+        Ok(reflink)
+    }
+
+    /// Refuses `operation` while the image is mounted anywhere or attached
+    /// to a loop device.
+    fn refuse_if_in_use(&self, operation: &'static str) -> Result<()> {
+        if let Some(target) = self.mounted_at()? {
+            return Err(self.refuse(operation, format!("it is mounted on {}", target.display())));
+        }
+        let loops = loopdev::find(&self.image).map_err(|e| MizzleError::io(&self.image, e))?;
+        if let Some(info) = loops.first() {
+            return Err(self.refuse(operation, format!("it is attached to {}", info.device.display())));
+        }
+        Ok(())
+    }
+
+    /// Writes the disk to `destination` in `format`, decrypting a container
+    /// or flattening a qcow2 chain on the way. Blocks of zeros are left out
+    /// where the format allows. An existing file is only replaced when
+    /// forced; refused while the disk is mounted.
+    pub fn export(&self, destination: &Path, format: Format, progress: &mut dyn FnMut(&Progress)) -> Result<()> {
+        if !self.force && destination.exists() {
+            let reason = format!("{} already exists; pass --force to replace it", destination.display());
+            return Err(self.refuse("export", reason));
+        }
+        let mut device = self.open_block_device(false)?;
+        let name = destination.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        ops::write_atomically(destination, self.force, |out| {
+            convert::export(&mut device, out, &name, format, progress)
+        })
+        .map_err(|source| MizzleError::Copy { from: self.image.clone(), to: destination.to_path_buf(), source })
+    }
+
+    /// Makes the image a raw copy of the VMDK, VHD, VHDX or compressed
+    /// image at `source`, once its footers, headers and checksums check
+    /// out. Runs of zeros become holes. An existing image is only replaced
+    /// when forced, and never while in use. Returns the size of the disk.
+    pub fn import(&mut self, source: &Path, progress: &mut dyn FnMut(&Progress)) -> Result<u64> {
+        self.require("import", &[DiskState::Absent, DiskState::Created, DiskState::Formatted])?;
+        let replace = self.state != DiskState::Absent;
+        if replace {
+            if !self.force {
+                return Err(self.refuse("import", "an image already exists; pass --force to replace it".to_string()));
+            }
+            self.refuse_if_in_use("import")?;
+        }
+        let size = ops::write_atomically(&self.image, replace, |out| convert::import(source, out, progress))
+            .map_err(|e| MizzleError::Copy { from: source.to_path_buf(), to: self.image.clone(), source: e })?;
+        self.size = size;
+        self.image_format = ImageFormat::Raw;
+        self.backing_file = None;
+        self.container = false;
+        self.encrypt = false;
+        self.refresh_state();
+        if let Ok(Some(filesystem)) = self.probe() {
+            self.filesystem = filesystem;
+        }
+        Ok(size)
+    }
+
+    /// The key slots in use on an encrypted disk.
+    pub fn key_slots(&self) -> Result<Vec<usize>> {
+        if self.is_container() {
+            container::key_slots(&self.image).map_err(|e| MizzleError::io(&self.image, e))
+        } else if self.is_encrypted() {
+            crypt::key_slots(&self.image)
+        } else {
+            Err(self.refuse("list the keys of", "it is not encrypted".to_string()))
+        }
+    }
+
+    /// Adds `new` as another key of an encrypted disk, unlocking it with the
+    /// disk's own key, and returns the key slot it went into. A new
+    /// passphrase is asked for twice.
+    pub fn add_key(&self, new: &KeySource) -> Result<usize> {
+        self.require("add a key to", &[DiskState::Created, DiskState::Formatted, DiskState::Mounted])?;
+        if self.is_container() {
+            let mut device = self.open_container(true)?;
+            let passphrase = new.read("New passphrase", true).map_err(|e| MizzleError::io(&self.image, e))?;
+            return device.add_key(&passphrase).map_err(|e| MizzleError::io(&self.image, e));
+        }
+        if !self.is_encrypted() {
+            return Err(self.refuse("add a key to", "it is not encrypted".to_string()));
+        }
+        let new = match new {
+            KeySource::Prompt => {
+                let passphrase = new.read("New passphrase", true).map_err(|e| MizzleError::io(&self.image, e))?;
+                KeySource::Passphrase(String::from_utf8_lossy(&passphrase).into_owned())
+            }
+            new => new.clone(),
+        };
+        self.with_luks_device(|device| {
+            let used = crypt::key_slots(device)?;
+            let Some(slot) = (0..crypt::LUKS_KEY_SLOTS).find(|slot| !used.contains(slot)) else {
+                return Err(self.refuse("add a key to", format!("all {} key slots are in use", crypt::LUKS_KEY_SLOTS)));
+            };
+            crypt::add_key(device, &self.key, slot, &new)?;
+            Ok(slot)
+        })
+    }
+
+    /// Generates a recovery key and adds it like [`add_key`](Self::add_key).
+    /// Returns its key slot and the key, which is kept nowhere else.
+    pub fn add_recovery_key(&self) -> Result<(usize, String)> {
+        let key = crypt::recovery_key().map_err(|e| MizzleError::io(&self.image, e))?;
+        let slot = self.add_key(&KeySource::Passphrase(key.clone()))?;
+        Ok((slot, key))
+    }
+
+    /// Removes the key in key slot `slot` of an encrypted disk, once the
+    /// disk's own key has proved it may. The last key is never removed.
+    pub fn remove_key(&self, slot: usize) -> Result<()> {
+        self.require("remove a key from", &[DiskState::Created, DiskState::Formatted, DiskState::Mounted])?;
+        let check = |slots: &[usize]| {
+            if !slots.contains(&slot) {
+                Err(self.refuse("remove a key from", format!("key slot {} is empty", slot)))
+            } else if slots.len() == 1 {
+                Err(self.refuse("remove a key from", format!("key slot {} holds its only key", slot)))
+            } else {
+                Ok(())
+            }
+        };
+        if self.is_container() {
+            check(&self.key_slots()?)?;
+            let mut device = self.open_container(true)?;
+            return device.remove_key(slot).map_err(|e| MizzleError::io(&self.image, e));
+        }
+        if !self.is_encrypted() {
+            return Err(self.refuse("remove a key from", "it is not encrypted".to_string()));
+        }
+        self.with_luks_device(|device| {
+            check(&crypt::key_slots(device)?)?;
+            crypt::remove_key(device, &self.key, slot)
+        })
+    }
+
+    /// Replaces the master key of an encrypted disk and re-encrypts all of it
+    /// under the new one, calling `progress` as it goes (cryptsetup prints
+    /// its own progress for LUKS). Only the key slot the disk's key opens is
+    /// kept; other keys, recovery keys included, cannot be carried over, so
+    /// the rotation is refused while there are any unless `drop_keys` is set.
+    /// A rotation that was cut short picks up where it stopped when run again.
+    pub fn rotate_key(&self, drop_keys: bool, progress: &mut dyn FnMut(&Progress)) -> Result<RotateReport> {
+        self.require("rotate the key of", &[DiskState::Created, DiskState::Formatted])?;
+        if let Some(target) = self.mounted_at()? {
+            return Err(self.refuse("rotate the key of", format!("it is mounted on {}", target.display())));
+        }
+        // A resumed rotation has already committed to dropping them
+        let check_slots = |slots: &[usize], resumed: bool| -> Result<()> {
+            if drop_keys || resumed || slots.len() <= 1 {
+                return Ok(());
+            }
+            let slots: Vec<String> = slots.iter().map(usize::to_string).collect();
+            let reason = format!(
+                "it has keys in slots {} and all but the one used would be removed; pass --drop-keys to go ahead",
+                slots.join(", ")
+            );
+            Err(self.refuse("rotate the key of", reason))
+        };
+        if self.is_container() {
+            let pending = container::rotation_pending(&self.image).map_err(|e| MizzleError::io(&self.image, e))?;
+            let slots = container::key_slots(&self.image).map_err(|e| MizzleError::io(&self.image, e))?;
+            check_slots(&slots, pending)?;
+            let rotate = |progress: &mut dyn FnMut(&Progress)| -> io::Result<RotateReport> {
+                let passphrase = self.key.read(&format!("Passphrase for {}", self.image.display()), false)?;
+                let mut file = OpenOptions::new().read(true).write(true).open(&self.image)?;
+                container::rotate(&mut file, &passphrase, progress)
+            };
+            return rotate(progress).map_err(|e| MizzleError::io(&self.image, e));
+        }
+        if !self.is_encrypted() {
+            return Err(self.refuse("rotate the key of", "it is not encrypted".to_string()));
+        }
+        // cryptsetup is run several times; ask for a passphrase only once
+        let key = match &self.key {
+            KeySource::Prompt => {
+                let prompt = format!("Passphrase for {}", self.image.display());
+                let passphrase = self.key.read(&prompt, false).map_err(|e| MizzleError::io(&self.image, e))?;
+                KeySource::Passphrase(String::from_utf8_lossy(&passphrase).into_owned())
+            }
+            key => key.clone(),
+        };
+        let device = self.attach()?;
+        let rotate = |device: &Path| -> Result<RotateReport> {
+            let slots = crypt::key_slots(device)?;
+            let resumed = crypt::reencrypt_pending(device)?;
+            check_slots(&slots, resumed)?;
+            crypt::reencrypt(device, &key, crypt::unlocked_slot(device, &key)?)?;
+            // cryptsetup may have moved the key to another slot
+            let kept = crypt::key_slots(device)?;
+            let slot = match kept.as_slice() {
+                [slot] => *slot,
+                _ => crypt::unlocked_slot(device, &key)?,
+            };
+            let removed = slots.into_iter().filter(|s| !kept.contains(s)).collect();
+            Ok(RotateReport { slot, removed, resumed })
+        };
+        let out = rotate(device.path());
+        let _ = device.detach();
+        out
+    }
+
+    /// Runs `op` on the loop device under the image's LUKS volume: the one
+    /// it is mounted through, or one attached just for the call.
+    fn with_luks_device<T>(&self, op: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+        if let Some(device) = self.loop_device.as_deref().and_then(crypt::backing_device) {
+            return op(&device);
+        }
+        let device = self.attach()?;
+        let out = op(device.path());
+        let _ = device.detach();
+        out
+    }
+
+    /// Attaches the image to a loop device without mounting it.
+    pub fn attach(&self) -> Result<LoopDevice> {
+        self.require("attach", &[DiskState::Created, DiskState::Formatted])?;
+        if self.is_qcow2() {
+            return Err(self.refuse("attach", "a qcow2 image needs an NBD device, not a loop device".to_string()));
+        }
+        LoopDevice::attach(&self.image, &self.loop_options)
+            .map_err(|source| MizzleError::Loop { path: self.image.clone(), operation: "attach", source })
+    }
+
+    /// Mounts the image through a loop device for as long as the returned
+    /// guard lives. If the image is already mounted at the mount point the
+    /// guard leaves that mount alone. Mounting over something else, or a
+    /// second time elsewhere, is refused even when forced: two loop devices
+    /// on one image would corrupt it.
+    pub fn mount_scoped(&self) -> Result<MountGuard> {
+        if self.state == DiskState::Mounted {
+            return Ok(MountGuard::new(&self.image, &self.mount_point, self.loop_device.clone(), false));
+        }
+        let existing = ops::mount_source(&self.mount_point).map_err(|e| MizzleError::io(&self.mount_point, e))?;
+        if let Some(source) = existing {
+            return Err(MizzleError::MountPointBusy { target: self.mount_point.clone(), source });
+        }
+        if let Some(target) = self.mounted_at()? {
+            return Err(self.refuse("mount", format!("it is already mounted on {}", target.display())));
+        }
+        if self.is_container() {
+            return Err(self.refuse("mount", "the kernel cannot read an encrypted container".to_string()));
+        }
+        if self.is_qcow2() {
+            let read_only = self.loop_options.read_only;
+            let device = self.connect_nbd(read_only)?;
+            let fstype = self.filesystem.mount_type();
+            if let Err(e) = ops::mount_virtual_disk(&device, &self.mount_point, fstype, read_only) {
+                let _ = nbd::disconnect(&device);
+                return Err(e);
+            }
+            return Ok(MountGuard::new(&self.image, &self.mount_point, Some(device), true));
+        }
+        let device = self.attach()?;
+        let source = if self.is_encrypted() { self.unlock(device.path()) } else { Ok(device.path().to_path_buf()) };
+        let mounted = source.and_then(|source| {
+            let fstype = self.filesystem.mount_type();
+            match ops::mount_virtual_disk(&source, &self.mount_point, fstype, self.loop_options.read_only) {
+                Ok(()) => Ok(source),
+                Err(e) => {
+                    if crypt::is_mapping(&source) {
+                        let _ = crypt::close(&source);
+                    }
+                    Err(e)
+                }
+            }
+        });
+        match mounted {
+            Ok(source) => Ok(MountGuard::new(&self.image, &self.mount_point, Some(source), true)),
+            Err(e) => {
+                let _ = device.detach();
+                Err(e)
+            }
+        }
+    }
+
+    /// Opens the LUKS volume on `device`, the image's loop device, and
+    /// returns the device-mapper device.
+    fn unlock(&self, device: &Path) -> Result<PathBuf> {
+        // Read through the device: a header just written to it may not have reached the image yet
+        let name = crypt::mapping_name(device)?;
+        crypt::open(device, &name, &self.key, self.loop_options.read_only)
+    }
+
+    /// Runs `op` on the unlocked volume on `device`, closing it afterwards.
+    fn unlocked<T>(&self, device: &Path, op: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+        let mapped = self.unlock(device)?;
+        let out = op(&mapped);
+        let closed = crypt::close(&mapped);
+        let out = out?;
+        closed?;
+        Ok(out)
+    }
+
+    /// Runs `op` on the unlocked volume, attaching the image to a loop
+    /// device just for the call.
+    fn with_unlocked<T>(&self, op: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+        let device = self.attach()?;
+        let out = self.unlocked(device.path(), op);
+        let _ = device.detach();
+        out
+    }
+
+    /// Serves the qcow2 image to a free NBD device and returns the device.
+    fn connect_nbd(&self, read_only: bool) -> Result<PathBuf> {
+        // Open it here first for errors the daemon could only pass on as text
+        drop(self.open_qcow2(!read_only)?);
+        nbd::connect(&self.image, read_only)
+            .map_err(|source| MizzleError::Nbd { path: self.image.clone(), operation: "connect", source })
+    }
+
+    /// Runs `op` on an NBD device serving the qcow2 image, disconnecting it
+    /// afterwards.
+    fn with_nbd<T>(&self, read_only: bool, op: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+        let device = self.connect_nbd(read_only)?;
+        let out = op(&device);
+        let disconnected = nbd::disconnect(&device)
+            .map_err(|source| MizzleError::Nbd { path: device.clone(), operation: "disconnect", source });
+        let out = out?;
+        disconnected?;
+        Ok(out)
+    }
+
+    /// Mounts the image and leaves it mounted; returns false if it already
+    /// was.
+    pub fn mount(&mut self) -> Result<bool> {
+        let guard = self.mount_scoped()?;
+        let mounted = guard.is_owned();
+        self.loop_device = guard.loop_device().map(Path::to_path_buf);
+        guard.keep();
+        self.state = DiskState::Mounted;
+        Ok(mounted)
+    }
+
+    pub fn unmount(&mut self) -> Result<()> {
+        self.require("unmount", &[DiskState::Mounted])?;
+        guard::teardown(&self.image, &self.mount_point, self.loop_device.as_deref())?;
+        self.loop_device = None;
+        self.state = DiskState::Formatted;
+        Ok(())
+    }
+
+    /// Runs `op` against the mount point, mounting the disk just for the
+    /// call when it is not mounted already. Used wherever editing the image
+    /// directly falls short.
+    fn with_mount<T>(&self, op: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+        let guard = self.mount_scoped()?;
+        let out = op(guard.mount_point())?;
+        guard.release()?;
+        Ok(out)
+    }
+
+    /// Opens the filesystem inside the image directly, for use while it is
+    /// not mounted.
+    fn open_image(&self, writable: bool) -> Result<Box<dyn ImageFs>> {
+        if self.is_container() {
+            let device = self.open_container(writable)?;
+            return imagefs::open_device(device, self.filesystem, writable).map_err(|e| self.image_error(e));
+        }
+        if self.is_qcow2() {
+            let device = self.open_qcow2(writable)?;
+            return imagefs::open_device(device, self.filesystem, writable).map_err(|e| self.image_error(e));
+        }
+        imagefs::open_image(&self.image, self.filesystem, writable).map_err(|e| self.image_error(e))
+    }
+
+    fn open_qcow2(&self, writable: bool) -> Result<Qcow2Image> {
+        Qcow2Image::open(&self.image, writable).map_err(|e| MizzleError::io(&self.image, e))
+    }
+
+    /// Opens the disk's bytes as a block device in-process, decrypting a
+    /// container or translating a qcow2 image, e.g. to serve it over NBD.
+    /// Refused while the image is mounted anywhere.
+    pub fn open_block_device(&self, writable: bool) -> Result<Box<dyn BlockDevice>> {
+        self.require("open", &[DiskState::Created, DiskState::Formatted])?;
+        if let Some(target) = self.mounted_at()? {
+            return Err(self.refuse("open", format!("it is mounted on {}", target.display())));
+        }
+        if self.is_container() {
+            return Ok(Box::new(self.open_container(writable)?));
+        }
+        if self.is_qcow2() {
+            return Ok(Box::new(self.open_qcow2(writable)?));
+        }
+        if self.is_encrypted() {
+            return Err(self.refuse("open", "a LUKS volume can only be unlocked through dm-crypt".to_string()));
+        }
+        let file = OpenOptions::new().read(true).write(writable).open(&self.image);
+        Ok(Box::new(file.map_err(|e| MizzleError::io(&self.image, e))?))
+    }
+
+    /// Opens the encrypted container the image is, asking for the key.
+    fn open_container(&self, writable: bool) -> Result<EncryptedDevice<File>> {
+        let open = || -> io::Result<EncryptedDevice<File>> {
+            let passphrase = self.key.read(&format!("Passphrase for {}", self.image.display()), false)?;
+            let file = OpenOptions::new().read(true).write(writable).open(&self.image)?;
+            EncryptedDevice::open(file, &passphrase)
+        };
+        open().map_err(|e| MizzleError::io(&self.image, e))
+    }
+
+    fn image_error(&self, e: io::Error) -> MizzleError {
+        if e.kind() == io::ErrorKind::Unsupported {
+            MizzleError::Unsupported { filesystem: self.filesystem, feature: e.to_string() }
+        } else {
+            MizzleError::io(&self.image, e)
+        }
+    }
+
+    /// Copies a host file to `destination`, relative to the mount point,
+    /// replacing any file already there. When the disk is not mounted the file
+    /// is written into the image directly, or through a temporary mount if
+    /// the filesystem needs one.
+    pub fn put(&self, source: &Path, destination: &Path) -> Result<()> {
+        self.put_with_progress(source, destination, Overwrite::Replace, &mut |_| {}).map(drop)
+    }
+
+    /// Like [`put`](Self::put), handling an existing destination as
+    /// `overwrite` says and calling `progress` as the data is copied.
+    /// A file that replaces another is written under a temporary name and
+    /// renamed into place.
+    pub fn put_with_progress(
+        &self,
+        source: &Path,
+        destination: &Path,
+        overwrite: Overwrite,
+        progress: &mut dyn FnMut(&Progress),
+    ) -> Result<Placed> {
+        if self.state == DiskState::Mounted {
+            return ops::copy_file_to_mount(&self.mount_point, source, destination, overwrite, progress);
+        }
+        self.require("copy onto", &[DiskState::Created, DiskState::Formatted])?;
+        if !self.direct_access() {
+            return self.with_mount(|mnt| ops::copy_file_to_mount(mnt, source, destination, overwrite, progress));
+        }
+        let destination = ops::image_path(destination)?;
+        let file = File::open(source).map_err(|e| MizzleError::io(source, e))?;
+        let meta = file.metadata().map_err(|e| MizzleError::io(source, e))?;
+        let mut image = self.open_image(true)?;
+
+        let copy_err = |to: &Path, e| MizzleError::Copy { from: source.to_path_buf(), to: to.to_path_buf(), source: e };
+        let exists = |image: &mut Box<dyn ImageFs>, path: &Path| match image.metadata(path) {
+            Ok(existing) => Ok(Some(existing.is_dir)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+        let (target, placed) = match exists(&mut image, &destination).map_err(|e| copy_err(&destination, e))? {
+            None => (destination.clone(), Placed::Created),
+            Some(true) => return Err(copy_err(&destination, io::Error::from_raw_os_error(libc::EISDIR))),
+            Some(false) => match overwrite {
+                Overwrite::Replace => {
+                    // Written beside the old file and renamed over it, so a failed copy leaves it intact
+                    let temp = ops::free_name(&destination, |p| exists(&mut image, p).map(|e| e.is_some()))
+                        .map_err(|e| copy_err(&destination, e))?;
+                    (temp, Placed::Replaced)
+                }
+                Overwrite::Skip => return Ok(Placed::Skipped),
+                Overwrite::Fail => return Err(ops::already_exists(&destination)),
+                Overwrite::Rename => {
+                    let renamed = ops::free_name(&destination, |p| exists(&mut image, p).map(|e| e.is_some()))
+                        .map_err(|e| copy_err(&destination, e))?;
+                    (renamed.clone(), Placed::Renamed(renamed))
+                }
+            },
+        };
+        let replacing = placed == Placed::Replaced;
+        let mut reader = Metered::new(&file, meta.len(), progress);
+        let mut copy = || -> io::Result<()> {
+            image.create_file(&target, &mut reader, &NodeMeta::from_host(&meta))?;
+            if replacing {
+                image.sync()?;
+                image.rename(&target, &destination)?;
+            }
+            image.sync()
+        };
+        if let Err(e) = copy() {
+            if replacing {
+                let _ = image.remove(&target).and_then(|_| image.sync());
+            }
+            return Err(copy_err(&destination, e));
+        }
+        Ok(placed)
+    }
+
+    /// Copies `source`, relative to the mount point, to a host file,
+    /// replacing any file already there. When the disk is not mounted the
+    /// file is read from the image directly, or through a temporary mount if
+    /// the filesystem needs one.
+    pub fn get(&self, source: &Path, destination: &Path) -> Result<()> {
+        self.get_with_progress(source, destination, Overwrite::Replace, &mut |_| {}).map(drop)
+    }
+
+    /// Like [`get`](Self::get), handling an existing destination as
+    /// `overwrite` says and calling `progress` as the data is copied. The
+    /// host file is written under a temporary name and renamed into place.
+    pub fn get_with_progress(
+        &self,
+        source: &Path,
+        destination: &Path,
+        overwrite: Overwrite,
+        progress: &mut dyn FnMut(&Progress),
+    ) -> Result<Placed> {
+        if self.state == DiskState::Mounted {
+            return ops::copy_file_from_mount(&self.mount_point, source, destination, overwrite, progress);
+        }
+        self.require("copy from", &[DiskState::Created, DiskState::Formatted])?;
+        if !self.direct_access() {
+            return self.with_mount(|mnt| ops::copy_file_from_mount(mnt, source, destination, overwrite, progress));
+        }
+        let source = ops::image_path(source)?;
+        let mut image = self.open_image(false)?;
+        let Some((target, placed)) = ops::place(destination, overwrite)? else {
+            return Ok(Placed::Skipped);
+        };
+        let mut copy = || -> io::Result<()> {
+            let total = image.metadata(&source)?.len;
+            ops::write_atomically(&target, placed == Placed::Replaced, |file| {
+                let mut out = Metered::new(BufWriter::new(file), total, progress);
+                image.read_file(&source, &mut out)?;
+                out.flush()
+            })
+        };
+        copy().map_err(|e| MizzleError::Copy { from: source.clone(), to: target.clone(), source: e })?;
+        Ok(placed)
+    }
+
+    pub fn list(&self, dir: &Path) -> Result<Vec<DirEntry>> {
+        if self.state == DiskState::Mounted {
+            return ops::list_mount(&self.mount_point, dir);
+        }
+        self.require("list", &[DiskState::Created, DiskState::Formatted])?;
+        if !self.direct_access() {
+            return self.with_mount(|mnt| ops::list_mount(mnt, dir));
+        }
+        let dir = ops::image_path(dir)?;
+        self.open_image(false)?.list(&dir).map_err(|e| MizzleError::io(&dir, e))
+    }
+
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        if self.state == DiskState::Mounted {
+            return ops::remove_from_mount(&self.mount_point, path);
+        }
+        self.require("remove from", &[DiskState::Created, DiskState::Formatted])?;
+        if !self.direct_access() {
+            return self.with_mount(|mnt| ops::remove_from_mount(mnt, path));
+        }
+        let path = ops::image_path(path)?;
+        let mut image = self.open_image(true)?;
+        image.remove(&path).and_then(|_| image.sync()).map_err(|e| MizzleError::io(&path, e))
+    }
+
+    /// Copies a host file onto the disk while checksumming it, reads the copy
+    /// back from the device to compare, and records the digest in the disk's
+    /// manifest. The copy is only renamed into place once it checks out.
+    /// Mounts the disk for the duration if it is not mounted. There is no
+    /// manifest entry when the copy is skipped.
+    pub fn put_verified(
+        &self,
+        source: &Path,
+        destination: &Path,
+        algorithm: HashAlgorithm,
+        overwrite: Overwrite,
+        progress: &mut dyn FnMut(&Progress),
+    ) -> Result<(Placed, Option<ManifestEntry>)> {
+        self.require("copy onto", &[DiskState::Created, DiskState::Formatted, DiskState::Mounted])?;
+        self.with_mount(|mnt| {
+            let Some((target, placed)) = ops::place(&ops::disk_path(mnt, destination)?, overwrite)? else {
+                return Ok((Placed::Skipped, None));
+            };
+            let entry = ops::write_atomically(&target, placed == Placed::Replaced, |file| {
+                integrity::copy_verified(source, file, algorithm, progress)
+            })
+            .map_err(|e| MizzleError::Copy { from: source.to_path_buf(), to: target.clone(), source: e })?;
+
+            let on_disk = target.strip_prefix(mnt).unwrap_or(&target);
+            let placed = match placed {
+                Placed::Renamed(_) => Placed::Renamed(Path::new("/").join(on_disk)),
+                other => other,
+            };
+            let manifest_err = |e| MizzleError::io(mnt.join(integrity::MANIFEST_NAME), e);
+            let mut manifest = Manifest::load(mnt).map_err(manifest_err)?;
+            manifest.files.insert(integrity::manifest_key(on_disk), entry.clone());
+            manifest.save(mnt).map_err(manifest_err)?;
+            Ok((placed, Some(entry)))
+        })
+    }
+
+    /// Checks `paths` on the disk, or every file in its manifest when none
+    /// are given, against their recorded digests.
+    pub fn verify(&self, paths: &[&Path]) -> Result<Vec<(String, VerifyStatus)>> {
+        self.require("verify", &[DiskState::Created, DiskState::Formatted, DiskState::Mounted])?;
+        let paths = paths.iter().map(|p| ops::disk_relative(p)).collect::<Result<Vec<_>>>()?;
+        let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        self.with_mount(|mnt| integrity::verify(mnt, &paths).map_err(|e| MizzleError::io(mnt, e)))
+    }
+
+    /// Copies the host file or directory tree `source` to `destination` on
+    /// the disk, mounting it for the duration if it is not mounted.
+    pub fn put_tree(&self, source: &Path, destination: &Path, options: &CopyOptions) -> Result<CopyReport> {
+        self.require("copy onto", &[DiskState::Created, DiskState::Formatted, DiskState::Mounted])?;
+        self.with_mount(|mnt| tree::copy_tree(source, &ops::disk_path(mnt, destination)?, options))
+    }
+
+    /// Copies the tree at `source` on the disk to `destination` on the host.
+    pub fn get_tree(&self, source: &Path, destination: &Path, options: &CopyOptions) -> Result<CopyReport> {
+        self.require("copy from", &[DiskState::Created, DiskState::Formatted, DiskState::Mounted])?;
+        self.with_mount(|mnt| tree::copy_tree(&ops::disk_path(mnt, source)?, destination, options))
+    }
+
+    /// Bytes actually allocated to the image file on the host.
+    pub fn allocated(&self) -> Result<u64> {
+        let meta = fs::metadata(&self.image).map_err(|e| MizzleError::io(&self.image, e))?;
+        Ok(meta.blocks() * 512)
+    }
+}
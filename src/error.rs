@@ -0,0 +1,162 @@
+use crate::{DiskState, Filesystem};
+use nix::errno::Errno;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, MizzleError>;
+
+#[derive(Debug)]
+pub enum MizzleError {
+    /// The requested size cannot work for an image or its filesystem.
+    InvalidSize { size: u64, reason: String },
+    /// Creating or sizing the image file failed.
+    Allocate { path: PathBuf, source: io::Error },
+    /// The host filesystem cannot hold the requested image.
+    InsufficientSpace { path: PathBuf, required: u64, available: u64 },
+    /// The image was sized but fewer blocks than requested are actually reserved.
+    IncompleteAllocation { path: PathBuf, requested: u64, allocated: u64 },
+    /// An external tool such as mkfs could not be started.
+    Spawn { program: String, source: io::Error },
+    /// mkfs ran but exited unsuccessfully.
+    Format { path: PathBuf, program: String, code: Option<i32>, stderr: String },
+    /// Another filesystem tool such as resize2fs or e2fsck failed.
+    Tool { path: PathBuf, program: String, code: Option<i32>, stderr: String },
+    /// The filesystem does not support a requested option.
+    Unsupported { filesystem: Filesystem, feature: String },
+    /// Binding, querying or releasing a loop device failed.
+    Loop { path: PathBuf, operation: &'static str, source: io::Error },
+    /// Serving an image to an NBD device, or disconnecting it, failed.
+    Nbd { path: PathBuf, operation: &'static str, source: io::Error },
+    Mount { image: PathBuf, target: PathBuf, source: Errno },
+    /// Something other than this disk is already mounted at the mount point.
+    MountPointBusy { target: PathBuf, source: PathBuf },
+    /// Going ahead would destroy existing data; only done when forced.
+    Refused { path: PathBuf, operation: &'static str, reason: String },
+    Unmount { target: PathBuf, source: Errno },
+    Copy { from: PathBuf, to: PathBuf, source: io::Error },
+    /// A path given for the disk leads outside it.
+    OutsideDisk { path: PathBuf },
+    /// Files on the disk no longer match the checksums in its manifest.
+    Verify { failed: usize, checked: usize },
+    /// Any other filesystem access on the host or the mounted disk.
+    Io { path: PathBuf, source: io::Error },
+    /// The disk registry could not be read or written.
+    Registry { path: PathBuf, source: io::Error },
+    /// No disk is registered under this name.
+    UnknownDisk { name: String },
+    /// The disk has no snapshot by this name.
+    UnknownSnapshot { disk: String, name: String },
+    /// The operation is not valid for the disk's current state.
+    State { path: PathBuf, operation: &'static str, state: DiskState },
+}
+
+impl MizzleError {
+    pub(crate) fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        MizzleError::Io { path: path.into(), source }
+    }
+}
+
+impl fmt::Display for MizzleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MizzleError::InvalidSize { size, reason } => write!(f, "invalid disk size of {} bytes: {}", size, reason),
+            MizzleError::Allocate { path, source } => {
+                write!(f, "failed to allocate {}: {}", path.display(), source)
+            }
+            MizzleError::InsufficientSpace { path, required, available } => write!(
+                f,
+                "not enough free space for {}: {} bytes required, {} available",
+                path.display(),
+                required,
+                available
+            ),
+            MizzleError::IncompleteAllocation { path, requested, allocated } => write!(
+                f,
+                "{} is only partially allocated: {} of {} bytes reserved",
+                path.display(),
+                allocated,
+                requested
+            ),
+            MizzleError::Spawn { program, source } => write!(f, "failed to run {}: {}", program, source),
+            MizzleError::Format { path, program, code, stderr } | MizzleError::Tool { path, program, code, stderr } => {
+                match code {
+                    Some(code) => write!(f, "{} on {} exited with status {}", program, path.display(), code)?,
+                    None => write!(f, "{} on {} was killed by a signal", program, path.display())?,
+                }
+                if !stderr.trim().is_empty() {
+                    write!(f, ": {}", stderr.trim())?;
+                }
+                Ok(())
+            }
+            MizzleError::Unsupported { filesystem, feature } => {
+                write!(f, "{} does not support {}", filesystem, feature)
+            }
+            MizzleError::Loop { path, operation, source } => {
+                write!(f, "failed to {} loop device for {}: {}", operation, path.display(), source)
+            }
+            MizzleError::Nbd { path, operation, source } => {
+                write!(f, "failed to {} NBD device for {}: {}", operation, path.display(), source)
+            }
+            MizzleError::Mount { image, target, source } => {
+                write!(f, "failed to mount {} on {}: {}", image.display(), target.display(), source)
+            }
+            MizzleError::Unmount { target, source } => {
+                write!(f, "failed to unmount {}: {}", target.display(), source)
+            }
+            MizzleError::Copy { from, to, source } => {
+                write!(f, "failed to copy {} to {}: {}", from.display(), to.display(), source)
+            }
+            MizzleError::OutsideDisk { path } => write!(f, "{} leads outside the disk", path.display()),
+            MizzleError::MountPointBusy { target, source } => {
+                write!(f, "{} is already mounted from {}", target.display(), source.display())
+            }
+            MizzleError::Refused { path, operation, reason } => {
+                write!(f, "refusing to {} {}: {}", operation, path.display(), reason)
+            }
+            MizzleError::Verify { failed, checked } => {
+                write!(f, "{} of {} files failed verification", failed, checked)
+            }
+            MizzleError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            MizzleError::Registry { path, source } => {
+                write!(f, "disk registry {}: {}", path.display(), source)
+            }
+            MizzleError::UnknownDisk { name } => write!(f, "no disk named '{}' is registered", name),
+            MizzleError::UnknownSnapshot { disk, name } => {
+                write!(f, "disk '{}' has no snapshot named '{}'", disk, name)
+            }
+            MizzleError::State { path, operation, state } => {
+                write!(f, "cannot {} {}: disk is {}", operation, path.display(), state)
+            }
+        }
+    }
+}
+
+impl Error for MizzleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MizzleError::Allocate { source, .. }
+            | MizzleError::Spawn { source, .. }
+            | MizzleError::Loop { source, .. }
+            | MizzleError::Nbd { source, .. }
+            | MizzleError::Copy { source, .. }
+            | MizzleError::Io { source, .. }
+            | MizzleError::Registry { source, .. } => Some(source),
+            MizzleError::Mount { source, .. } | MizzleError::Unmount { source, .. } => Some(source),
+            MizzleError::InvalidSize { .. }
+            | MizzleError::InsufficientSpace { .. }
+            | MizzleError::IncompleteAllocation { .. }
+            | MizzleError::Format { .. }
+            | MizzleError::Tool { .. }
+            | MizzleError::Unsupported { .. }
+            | MizzleError::OutsideDisk { .. }
+            | MizzleError::MountPointBusy { .. }
+            | MizzleError::Refused { .. }
+            | MizzleError::Verify { .. }
+            | MizzleError::UnknownDisk { .. }
+            | MizzleError::UnknownSnapshot { .. }
+            | MizzleError::State { .. } => None,
+        }
+    }
+}
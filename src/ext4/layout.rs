@@ -0,0 +1,665 @@
+//! On-disk ext4 structures, kept as raw little-endian buffers so fields we do
+//! not understand survive a read-modify-write cycle untouched.
+
+use crate::bytes::{le16, le32, put_le16, put_le32};
+use crate::checksum::{crc16, crc32c_raw};
+
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+pub const SUPERBLOCK_SIZE: usize = 1024;
+pub const MAGIC: u16 = 0xEF53;
+pub const ROOT_INO: u32 = 2;
+pub const LOST_FOUND_INO: u32 = 11;
+pub const GOOD_OLD_INODE_SIZE: usize = 128;
+/// Largest `s_log_block_size`, for 64 KiB blocks.
+pub const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+pub const INCOMPAT_FILETYPE: u32 = 0x2;
+pub const INCOMPAT_RECOVER: u32 = 0x4;
+pub const INCOMPAT_EXTENTS: u32 = 0x40;
+pub const INCOMPAT_64BIT: u32 = 0x80;
+pub const INCOMPAT_MMP: u32 = 0x100;
+pub const INCOMPAT_FLEX_BG: u32 = 0x200;
+pub const INCOMPAT_EA_INODE: u32 = 0x400;
+pub const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+pub const INCOMPAT_LARGEDIR: u32 = 0x4000;
+pub const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+pub const INCOMPAT_ENCRYPT: u32 = 0x10000;
+pub const INCOMPAT_CASEFOLD: u32 = 0x20000;
+
+pub const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+pub const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+pub const RO_COMPAT_BTREE_DIR: u32 = 0x4;
+pub const RO_COMPAT_HUGE_FILE: u32 = 0x8;
+pub const RO_COMPAT_GDT_CSUM: u32 = 0x10;
+pub const RO_COMPAT_DIR_NLINK: u32 = 0x20;
+pub const RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+pub const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+
+/// Incompatible features whose on-disk layout we can read.
+pub const INCOMPAT_READ: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_EA_INODE
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR
+    | INCOMPAT_INLINE_DATA
+    | INCOMPAT_ENCRYPT
+    | INCOMPAT_CASEFOLD;
+/// Incompatible features we keep consistent when modifying an image.
+pub const INCOMPAT_WRITE: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR
+    | INCOMPAT_INLINE_DATA
+    | INCOMPAT_ENCRYPT
+    | INCOMPAT_CASEFOLD;
+/// Read-only-compatible features we keep consistent when modifying an image.
+pub const RO_COMPAT_WRITE: u32 = RO_COMPAT_SPARSE_SUPER
+    | RO_COMPAT_LARGE_FILE
+    | RO_COMPAT_BTREE_DIR
+    | RO_COMPAT_HUGE_FILE
+    | RO_COMPAT_GDT_CSUM
+    | RO_COMPAT_DIR_NLINK
+    | RO_COMPAT_EXTRA_ISIZE
+    | RO_COMPAT_METADATA_CSUM;
+
+pub const BG_INODE_UNINIT: u16 = 0x1;
+pub const BG_BLOCK_UNINIT: u16 = 0x2;
+pub const BG_INODE_ZEROED: u16 = 0x4;
+
+pub const INODE_FLAG_INDEX: u32 = 0x1000;
+pub const INODE_FLAG_HUGE_FILE: u32 = 0x40000;
+pub const INODE_FLAG_EXTENTS: u32 = 0x80000;
+pub const INODE_FLAG_INLINE_DATA: u32 = 0x1000_0000;
+pub const INODE_FLAG_ENCRYPT: u32 = 0x800;
+pub const INODE_FLAG_CASEFOLD: u32 = 0x4000_0000;
+
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFSOCK: u16 = 0o140000;
+pub const S_IFLNK: u16 = 0o120000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFBLK: u16 = 0o060000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFCHR: u16 = 0o020000;
+pub const S_IFIFO: u16 = 0o010000;
+
+pub const FT_REG: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_CHR: u8 = 3;
+pub const FT_BLK: u8 = 4;
+pub const FT_FIFO: u8 = 5;
+pub const FT_SOCK: u8 = 6;
+pub const FT_SYMLINK: u8 = 7;
+
+pub const EXTENT_MAGIC: u16 = 0xF30A;
+/// Longest initialized extent.
+pub const EXTENT_MAX_LEN: u32 = 32768;
+pub const DIR_TAIL_SIZE: usize = 12;
+pub const XATTR_MAGIC: u32 = 0xEA02_0000;
+
+pub fn file_type_for_mode(mode: u16) -> u8 {
+    match mode & S_IFMT {
+        S_IFREG => FT_REG,
+        S_IFDIR => FT_DIR,
+        S_IFCHR => FT_CHR,
+        S_IFBLK => FT_BLK,
+        S_IFIFO => FT_FIFO,
+        S_IFSOCK => FT_SOCK,
+        S_IFLNK => FT_SYMLINK,
+        _ => 0,
+    }
+}
+
+#[derive(Clone)]
+pub struct Superblock {
+    pub raw: Vec<u8>,
+}
+
+impl Superblock {
+    pub fn new() -> Self {
+        Superblock { raw: vec![0; SUPERBLOCK_SIZE] }
+    }
+
+    pub fn magic(&self) -> u16 {
+        le16(&self.raw, 0x38)
+    }
+
+    pub fn inodes_count(&self) -> u32 {
+        le32(&self.raw, 0x0)
+    }
+
+    pub fn blocks_count(&self) -> u64 {
+        self.lo_hi(0x4, 0x150)
+    }
+
+    pub fn free_blocks(&self) -> u64 {
+        self.lo_hi(0xC, 0x158)
+    }
+
+    pub fn set_free_blocks(&mut self, v: u64) {
+        self.set_lo_hi(0xC, 0x158, v)
+    }
+
+    pub fn free_inodes(&self) -> u32 {
+        le32(&self.raw, 0x10)
+    }
+
+    pub fn set_free_inodes(&mut self, v: u32) {
+        put_le32(&mut self.raw, 0x10, v)
+    }
+
+    pub fn first_data_block(&self) -> u32 {
+        le32(&self.raw, 0x14)
+    }
+
+    pub fn log_block_size(&self) -> u32 {
+        le32(&self.raw, 0x18)
+    }
+
+    pub fn log_cluster_size(&self) -> u32 {
+        le32(&self.raw, 0x1C)
+    }
+
+    /// The block size; a larger one than [`MAX_LOG_BLOCK_SIZE`] allows is
+    /// refused when the filesystem is opened.
+    pub fn block_size(&self) -> u64 {
+        1024u64 << self.log_block_size().min(MAX_LOG_BLOCK_SIZE)
+    }
+
+    pub fn blocks_per_group(&self) -> u32 {
+        le32(&self.raw, 0x20)
+    }
+
+    pub fn inodes_per_group(&self) -> u32 {
+        le32(&self.raw, 0x28)
+    }
+
+    pub fn rev_level(&self) -> u32 {
+        le32(&self.raw, 0x4C)
+    }
+
+    pub fn first_ino(&self) -> u32 {
+        if self.rev_level() == 0 {
+            11
+        } else {
+            le32(&self.raw, 0x54)
+        }
+    }
+
+    pub fn inode_size(&self) -> usize {
+        if self.rev_level() == 0 {
+            GOOD_OLD_INODE_SIZE
+        } else {
+            le16(&self.raw, 0x58) as usize
+        }
+    }
+
+    pub fn feature_incompat(&self) -> u32 {
+        le32(&self.raw, 0x60)
+    }
+
+    pub fn feature_ro_compat(&self) -> u32 {
+        le32(&self.raw, 0x64)
+    }
+
+    pub fn has_incompat(&self, f: u32) -> bool {
+        self.feature_incompat() & f != 0
+    }
+
+    pub fn has_ro_compat(&self, f: u32) -> bool {
+        self.feature_ro_compat() & f != 0
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.raw[0x68..0x78].try_into().unwrap()
+    }
+
+    pub fn volume_name(&self) -> String {
+        let raw = &self.raw[0x78..0x88];
+        let end = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+        String::from_utf8_lossy(&raw[..end]).into_owned()
+    }
+
+    pub fn desc_size(&self) -> usize {
+        if self.has_incompat(INCOMPAT_64BIT) {
+            le16(&self.raw, 0xFE) as usize
+        } else {
+            32
+        }
+    }
+
+    pub fn reserved_gdt_blocks(&self) -> u64 {
+        le16(&self.raw, 0xCE) as u64
+    }
+
+    pub fn group_count(&self) -> u32 {
+        let data_blocks = self.blocks_count().saturating_sub(self.first_data_block() as u64);
+        data_blocks.div_ceil(self.blocks_per_group().max(1) as u64) as u32
+    }
+
+    pub fn gdt_blocks(&self) -> u64 {
+        (self.group_count() as u64 * self.desc_size() as u64).div_ceil(self.block_size())
+    }
+
+    pub fn has_metadata_csum(&self) -> bool {
+        self.has_ro_compat(RO_COMPAT_METADATA_CSUM)
+    }
+
+    /// Seed mixed into every metadata checksum.
+    pub fn csum_seed(&self) -> u32 {
+        if self.has_incompat(INCOMPAT_CSUM_SEED) {
+            le32(&self.raw, 0x270)
+        } else {
+            crc32c_raw(!0, &self.uuid())
+        }
+    }
+
+    /// Whether group `group` carries a superblock and descriptor backup.
+    pub fn group_has_super(&self, group: u32) -> bool {
+        if group <= 1 || !self.has_ro_compat(RO_COMPAT_SPARSE_SUPER) {
+            return true;
+        }
+        [3u32, 5, 7].iter().any(|&base| {
+            let mut n = base;
+            while n < group {
+                n *= base;
+            }
+            n == group
+        })
+    }
+
+    pub fn update_checksum(&mut self) {
+        if self.has_metadata_csum() {
+            let csum = crc32c_raw(!0, &self.raw[..0x3FC]);
+            put_le32(&mut self.raw, 0x3FC, csum);
+        }
+    }
+
+    fn lo_hi(&self, lo: usize, hi: usize) -> u64 {
+        let mut v = le32(&self.raw, lo) as u64;
+        if self.has_incompat(INCOMPAT_64BIT) {
+            v |= (le32(&self.raw, hi) as u64) << 32;
+        }
+        v
+    }
+
+    fn set_lo_hi(&mut self, lo: usize, hi: usize, v: u64) {
+        put_le32(&mut self.raw, lo, v as u32);
+        if self.has_incompat(INCOMPAT_64BIT) {
+            put_le32(&mut self.raw, hi, (v >> 32) as u32);
+        }
+    }
+}
+
+/// The whole group descriptor table.
+pub struct GroupDescs {
+    pub raw: Vec<u8>,
+    pub desc_size: usize,
+}
+
+impl GroupDescs {
+    fn desc(&self, g: u32) -> &[u8] {
+        let off = g as usize * self.desc_size;
+        &self.raw[off..off + self.desc_size]
+    }
+
+    fn desc_mut(&mut self, g: u32) -> &mut [u8] {
+        let off = g as usize * self.desc_size;
+        &mut self.raw[off..off + self.desc_size]
+    }
+
+    fn get32(&self, g: u32, lo: usize, hi: usize) -> u64 {
+        let d = self.desc(g);
+        let mut v = le32(d, lo) as u64;
+        if self.desc_size >= 64 {
+            v |= (le32(d, hi) as u64) << 32;
+        }
+        v
+    }
+
+    fn set32(&mut self, g: u32, lo: usize, hi: usize, v: u64) {
+        let wide = self.desc_size >= 64;
+        let d = self.desc_mut(g);
+        put_le32(d, lo, v as u32);
+        if wide {
+            put_le32(d, hi, (v >> 32) as u32);
+        }
+    }
+
+    fn get16(&self, g: u32, lo: usize, hi: usize) -> u32 {
+        let d = self.desc(g);
+        let mut v = le16(d, lo) as u32;
+        if self.desc_size >= 64 {
+            v |= (le16(d, hi) as u32) << 16;
+        }
+        v
+    }
+
+    fn set16(&mut self, g: u32, lo: usize, hi: usize, v: u32) {
+        let wide = self.desc_size >= 64;
+        let d = self.desc_mut(g);
+        put_le16(d, lo, v as u16);
+        if wide {
+            put_le16(d, hi, (v >> 16) as u16);
+        }
+    }
+
+    pub fn block_bitmap(&self, g: u32) -> u64 {
+        self.get32(g, 0x0, 0x20)
+    }
+
+    pub fn set_block_bitmap(&mut self, g: u32, v: u64) {
+        self.set32(g, 0x0, 0x20, v)
+    }
+
+    pub fn inode_bitmap(&self, g: u32) -> u64 {
+        self.get32(g, 0x4, 0x24)
+    }
+
+    pub fn set_inode_bitmap(&mut self, g: u32, v: u64) {
+        self.set32(g, 0x4, 0x24, v)
+    }
+
+    pub fn inode_table(&self, g: u32) -> u64 {
+        self.get32(g, 0x8, 0x28)
+    }
+
+    pub fn set_inode_table(&mut self, g: u32, v: u64) {
+        self.set32(g, 0x8, 0x28, v)
+    }
+
+    pub fn free_blocks(&self, g: u32) -> u32 {
+        self.get16(g, 0xC, 0x2C)
+    }
+
+    pub fn set_free_blocks(&mut self, g: u32, v: u32) {
+        self.set16(g, 0xC, 0x2C, v)
+    }
+
+    pub fn free_inodes(&self, g: u32) -> u32 {
+        self.get16(g, 0xE, 0x2E)
+    }
+
+    pub fn set_free_inodes(&mut self, g: u32, v: u32) {
+        self.set16(g, 0xE, 0x2E, v)
+    }
+
+    pub fn used_dirs(&self, g: u32) -> u32 {
+        self.get16(g, 0x10, 0x30)
+    }
+
+    pub fn set_used_dirs(&mut self, g: u32, v: u32) {
+        self.set16(g, 0x10, 0x30, v)
+    }
+
+    pub fn flags(&self, g: u32) -> u16 {
+        le16(self.desc(g), 0x12)
+    }
+
+    pub fn set_flags(&mut self, g: u32, v: u16) {
+        put_le16(self.desc_mut(g), 0x12, v)
+    }
+
+    pub fn itable_unused(&self, g: u32) -> u32 {
+        self.get16(g, 0x1C, 0x32)
+    }
+
+    pub fn set_itable_unused(&mut self, g: u32, v: u32) {
+        self.set16(g, 0x1C, 0x32, v)
+    }
+
+    /// Stores the bitmap checksums for group `g` (metadata_csum only).
+    pub fn set_bitmap_csums(&mut self, g: u32, block: Option<u32>, inode: Option<u32>) {
+        if let Some(c) = block {
+            self.set16(g, 0x18, 0x38, c);
+        }
+        if let Some(c) = inode {
+            self.set16(g, 0x1A, 0x3A, c);
+        }
+    }
+
+    pub fn update_checksum(&mut self, g: u32, sb: &Superblock) {
+        let size = self.desc_size;
+        let d = self.desc(g);
+        let csum = if sb.has_metadata_csum() {
+            let mut c = crc32c_raw(sb.csum_seed(), &g.to_le_bytes());
+            c = crc32c_raw(c, &d[..0x1E]);
+            c = crc32c_raw(c, &[0, 0]);
+            c = crc32c_raw(c, &d[0x20..size]);
+            (c & 0xFFFF) as u16
+        } else if sb.has_ro_compat(RO_COMPAT_GDT_CSUM) {
+            let mut c = crc16(!0, &sb.uuid());
+            c = crc16(c, &g.to_le_bytes());
+            c = crc16(c, &d[..0x1E]);
+            if size > 0x20 {
+                c = crc16(c, &d[0x20..size]);
+            }
+            c
+        } else {
+            return;
+        };
+        put_le16(self.desc_mut(g), 0x1E, csum);
+    }
+}
+
+#[derive(Clone)]
+pub struct Inode {
+    pub raw: Vec<u8>,
+}
+
+impl Inode {
+    pub fn new(size: usize) -> Self {
+        let mut inode = Inode { raw: vec![0; size] };
+        if size > GOOD_OLD_INODE_SIZE {
+            // i_extra_isize: cover the checksum, timestamp extras and crtime
+            put_le16(&mut inode.raw, 0x80, 32);
+        }
+        inode
+    }
+
+    pub fn mode(&self) -> u16 {
+        le16(&self.raw, 0x0)
+    }
+
+    pub fn set_mode(&mut self, v: u16) {
+        put_le16(&mut self.raw, 0x0, v)
+    }
+
+    pub fn file_type(&self) -> u16 {
+        self.mode() & S_IFMT
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == S_IFDIR
+    }
+
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
+        put_le16(&mut self.raw, 0x2, uid as u16);
+        put_le16(&mut self.raw, 0x78, (uid >> 16) as u16);
+        put_le16(&mut self.raw, 0x18, gid as u16);
+        put_le16(&mut self.raw, 0x7A, (gid >> 16) as u16);
+    }
+
+    pub fn size(&self) -> u64 {
+        le32(&self.raw, 0x4) as u64 | (le32(&self.raw, 0x6C) as u64) << 32
+    }
+
+    pub fn set_size(&mut self, v: u64) {
+        put_le32(&mut self.raw, 0x4, v as u32);
+        put_le32(&mut self.raw, 0x6C, (v >> 32) as u32);
+    }
+
+    /// Sets atime, ctime, mtime and (when there is room) crtime, in seconds
+    /// and nanoseconds since the epoch.
+    pub fn set_times(&mut self, atime: (i64, u32), ctime: (i64, u32), mtime: (i64, u32)) {
+        let extra = self.extra_isize();
+        let mut put = |base: usize, extra_off: usize, (secs, nanos): (i64, u32)| {
+            put_le32(&mut self.raw, base, secs as u32);
+            if extra_off + 4 <= GOOD_OLD_INODE_SIZE + extra {
+                let epoch = ((secs - secs as i32 as i64) >> 32) as u32 & 0x3;
+                put_le32(&mut self.raw, extra_off, (nanos << 2) | epoch);
+            }
+        };
+        put(0x8, 0x8C, atime);
+        put(0xC, 0x84, ctime);
+        put(0x10, 0x88, mtime);
+        if GOOD_OLD_INODE_SIZE + extra >= 0x98 {
+            put(0x90, 0x94, ctime);
+        }
+    }
+
+    pub fn links(&self) -> u16 {
+        le16(&self.raw, 0x1A)
+    }
+
+    pub fn set_links(&mut self, v: u16) {
+        put_le16(&mut self.raw, 0x1A, v)
+    }
+
+    pub fn set_dtime(&mut self, v: u32) {
+        put_le32(&mut self.raw, 0x14, v)
+    }
+
+    /// Allocated 512-byte sectors, including extent tree and xattr blocks.
+    pub fn sectors(&self, block_size: u64) -> u64 {
+        let v = le32(&self.raw, 0x1C) as u64 | (le16(&self.raw, 0x74) as u64) << 32;
+        if self.flags() & INODE_FLAG_HUGE_FILE != 0 {
+            v * (block_size / 512)
+        } else {
+            v
+        }
+    }
+
+    pub fn set_sectors(&mut self, v: u64, block_size: u64) {
+        let v = if self.flags() & INODE_FLAG_HUGE_FILE != 0 { v / (block_size / 512) } else { v };
+        put_le32(&mut self.raw, 0x1C, v as u32);
+        put_le16(&mut self.raw, 0x74, (v >> 32) as u16);
+    }
+
+    pub fn flags(&self) -> u32 {
+        le32(&self.raw, 0x20)
+    }
+
+    pub fn set_flags(&mut self, v: u32) {
+        put_le32(&mut self.raw, 0x20, v)
+    }
+
+    pub fn block(&self) -> &[u8] {
+        &self.raw[0x28..0x64]
+    }
+
+    pub fn block_mut(&mut self) -> &mut [u8] {
+        &mut self.raw[0x28..0x64]
+    }
+
+    pub fn generation(&self) -> u32 {
+        le32(&self.raw, 0x64)
+    }
+
+    pub fn file_acl(&self) -> u64 {
+        le32(&self.raw, 0x68) as u64 | (le16(&self.raw, 0x76) as u64) << 32
+    }
+
+    pub fn set_file_acl(&mut self, v: u64) {
+        put_le32(&mut self.raw, 0x68, v as u32);
+        put_le16(&mut self.raw, 0x76, (v >> 32) as u16);
+    }
+
+    pub fn extra_isize(&self) -> usize {
+        if self.raw.len() > GOOD_OLD_INODE_SIZE {
+            le16(&self.raw, 0x80) as usize
+        } else {
+            0
+        }
+    }
+
+    fn has_csum_hi(&self) -> bool {
+        self.extra_isize() >= 4
+    }
+
+    /// Seed for checksums of blocks owned by this inode (directories, extent tree).
+    pub fn csum_seed(&self, ino: u32, fs_seed: u32) -> u32 {
+        let c = crc32c_raw(fs_seed, &ino.to_le_bytes());
+        crc32c_raw(c, &self.generation().to_le_bytes())
+    }
+
+    pub fn update_checksum(&mut self, ino: u32, fs_seed: u32) {
+        let hi = self.has_csum_hi();
+        put_le16(&mut self.raw, 0x7C, 0);
+        if hi {
+            put_le16(&mut self.raw, 0x82, 0);
+        }
+        let csum = crc32c_raw(self.csum_seed(ino, fs_seed), &self.raw);
+        put_le16(&mut self.raw, 0x7C, csum as u16);
+        if hi {
+            put_le16(&mut self.raw, 0x82, (csum >> 16) as u16);
+        }
+    }
+}
+
+/// One mapping from file blocks to disk blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u32,
+    pub len: u32,
+    pub start: u64,
+    /// Allocated but unwritten; reads as zeros.
+    pub uninit: bool,
+}
+
+pub fn extent_header(buf: &mut [u8], entries: u16, max: u16, depth: u16) {
+    put_le16(buf, 0, EXTENT_MAGIC);
+    put_le16(buf, 2, entries);
+    put_le16(buf, 4, max);
+    put_le16(buf, 6, depth);
+    put_le32(buf, 8, 0);
+}
+
+pub fn put_extent(buf: &mut [u8], off: usize, e: &Extent) {
+    put_le32(buf, off, e.logical);
+    let len = if e.uninit { e.len + EXTENT_MAX_LEN } else { e.len };
+    put_le16(buf, off + 4, len as u16);
+    put_le16(buf, off + 6, (e.start >> 32) as u16);
+    put_le32(buf, off + 8, e.start as u32);
+}
+
+pub fn put_extent_index(buf: &mut [u8], off: usize, logical: u32, leaf: u64) {
+    put_le32(buf, off, logical);
+    put_le32(buf, off + 4, leaf as u32);
+    put_le16(buf, off + 8, (leaf >> 32) as u16);
+    put_le16(buf, off + 10, 0);
+}
+
+/// Round a directory entry's name length up to its minimum record length.
+pub fn dirent_len(name_len: usize) -> usize {
+    (8 + name_len + 3) & !3
+}
+
+pub fn put_dirent(buf: &mut [u8], off: usize, ino: u32, rec_len: usize, name: &[u8], file_type: u8) {
+    put_le32(buf, off, ino);
+    put_le16(buf, off + 4, rec_len as u16);
+    buf[off + 6] = name.len() as u8;
+    buf[off + 7] = file_type;
+    buf[off + 8..off + 8 + name.len()].copy_from_slice(name);
+}
+
+/// Writes the checksum tail that closes a directory leaf block.
+pub fn put_dir_tail(block: &mut [u8], inode_seed: u32) {
+    let off = block.len() - DIR_TAIL_SIZE;
+    put_le32(block, off, 0);
+    put_le16(block, off + 4, DIR_TAIL_SIZE as u16);
+    block[off + 6] = 0;
+    block[off + 7] = 0xDE;
+    let csum = crc32c_raw(inode_seed, &block[..off]);
+    put_le32(block, off + 8, csum);
+}
+
+pub fn has_dir_tail(block: &[u8]) -> bool {
+    let off = block.len() - DIR_TAIL_SIZE;
+    le32(block, off) == 0 && le16(block, off + 4) == DIR_TAIL_SIZE as u16 && block[off + 7] == 0xDE
+}
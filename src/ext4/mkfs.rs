@@ -0,0 +1,315 @@
+//! Formats a block device as ext4 without calling out to mke2fs.
+
+use super::layout::*;
+use super::Ext4;
+use crate::blockdev::BlockDevice;
+use crate::bytes::{put_le16, put_le32};
+use crate::checksum::crc32c_raw;
+use crate::filesystem::FormatOptions;
+use crate::imagefs::NodeMeta;
+use crate::uuid;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_BLOCK_SIZE: u32 = 4096;
+const DEFAULT_INODE_RATIO: u32 = 16384;
+const DEFAULT_RESERVED_PERCENT: u8 = 5;
+const INODE_SIZE: usize = 256;
+const DESC_SIZE: usize = 32;
+
+fn invalid_input(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
+
+/// Writes a fresh ext4 filesystem (no journal, metadata checksums on) to
+/// `dev` and returns it opened for writing with `/` and `/lost+found` in place.
+pub fn format<D: BlockDevice>(mut dev: D, options: &FormatOptions) -> io::Result<Ext4<D>> {
+    let bs = options.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    if !bs.is_power_of_two() || !(1024..=65536).contains(&bs) {
+        return Err(invalid_input(format!("invalid ext4 block size {}", bs)));
+    }
+    let bs64 = bs as u64;
+    let ratio = options.inode_ratio.unwrap_or(DEFAULT_INODE_RATIO);
+    if ratio < bs || ratio > 64 * 1024 * 1024 {
+        return Err(invalid_input(format!("invalid inode ratio {}", ratio)));
+    }
+    let reserved = options.reserved_percent.unwrap_or(DEFAULT_RESERVED_PERCENT);
+    if reserved > 50 {
+        return Err(invalid_input(format!("reserved percentage {} exceeds 50", reserved)));
+    }
+    let label = options.label.as_deref().unwrap_or("");
+    if label.len() > 16 {
+        return Err(invalid_input("ext4 labels are at most 16 bytes"));
+    }
+    let fs_uuid = match &options.uuid {
+        Some(s) => uuid::parse(s).ok_or_else(|| invalid_input(format!("invalid UUID {}", s)))?,
+        None => uuid::random()?,
+    };
+
+    let first_data_block = (bs == 1024) as u64;
+    let bpg = 8 * bs64;
+    let mut blocks = dev.len()? / bs64;
+    if blocks >= 1 << 32 {
+        return Err(invalid_input("images over 2^32 blocks need a larger block size"));
+    }
+
+    let inodes_per_block = bs64 / INODE_SIZE as u64;
+    let size_bytes = blocks * bs64;
+    let mut groups = (blocks - first_data_block.min(blocks)).div_ceil(bpg);
+    if groups == 0 {
+        return Err(invalid_input("image is too small for ext4"));
+    }
+    let wanted = (size_bytes / ratio as u64).max(16);
+    let ipg = wanted
+        .div_ceil(groups)
+        .next_multiple_of(inodes_per_block.max(8))
+        .clamp(16, bpg);
+    let itb = ipg / inodes_per_block;
+
+    let mut sb = Superblock::new();
+    let sparse = |g: u64| {
+        g <= 1 || [3u64, 5, 7].iter().any(|&base| {
+            let mut n = base;
+            while n < g {
+                n *= base;
+            }
+            n == g
+        })
+    };
+    let gdt_blocks = |groups: u64| (groups * DESC_SIZE as u64).div_ceil(bs64);
+    let overhead = |g: u64, groups: u64| if sparse(g) { 1 + gdt_blocks(groups) } else { 0 } + 2 + itb;
+
+    // A trailing group too small for its own metadata plus a little data is dropped
+    let last = groups - 1;
+    let last_len = blocks - first_data_block - last * bpg;
+    if last_len < overhead(last, groups) + 50 {
+        if groups == 1 {
+            return Err(invalid_input("image is too small for ext4"));
+        }
+        groups -= 1;
+        blocks = first_data_block + groups * bpg;
+    }
+    let group_len = |g: u64| (blocks - first_data_block - g * bpg).min(bpg);
+
+    // --- group descriptors and bitmaps ---
+    let mut gds = GroupDescs { raw: vec![0u8; groups as usize * DESC_SIZE], desc_size: DESC_SIZE };
+    let mut block_bitmaps = Vec::with_capacity(groups as usize);
+    let mut free_blocks = 0u64;
+    for g in 0..groups {
+        let start = first_data_block + g * bpg;
+        let meta = if sparse(g) { 1 + gdt_blocks(groups) } else { 0 };
+        let bb = start + meta;
+        let g32 = g as u32;
+        gds.set_block_bitmap(g32, bb);
+        gds.set_inode_bitmap(g32, bb + 1);
+        gds.set_inode_table(g32, bb + 2);
+
+        let used = overhead(g, groups);
+        let len = group_len(g);
+        let mut bm = vec![0u8; bs as usize];
+        for i in (0..used).chain(len..bs64 * 8) {
+            bm[(i / 8) as usize] |= 1 << (i % 8);
+        }
+        block_bitmaps.push(bm);
+        free_blocks += len - used;
+        gds.set_free_blocks(g32, (len - used) as u32);
+        gds.set_free_inodes(g32, ipg as u32);
+        if g == 0 {
+            gds.set_flags(g32, BG_INODE_ZEROED);
+        } else {
+            gds.set_flags(g32, BG_INODE_UNINIT);
+            gds.set_itable_unused(g32, ipg as u32);
+        }
+    }
+    // Inodes 1..10 are reserved
+    let reserved_inodes = 10;
+    gds.set_free_inodes(0, (ipg - reserved_inodes) as u32);
+    gds.set_itable_unused(0, (ipg - reserved_inodes) as u32);
+
+    // --- superblock ---
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0);
+    let raw = &mut sb.raw;
+    let inodes = groups * ipg;
+    put_le32(raw, 0x00, inodes as u32);
+    put_le32(raw, 0x04, blocks as u32);
+    put_le32(raw, 0x08, (blocks * reserved as u64 / 100) as u32);
+    put_le32(raw, 0x0C, free_blocks as u32);
+    put_le32(raw, 0x10, (inodes - reserved_inodes) as u32);
+    put_le32(raw, 0x14, first_data_block as u32);
+    let log = bs.trailing_zeros() - 10;
+    put_le32(raw, 0x18, log);
+    put_le32(raw, 0x1C, log);
+    put_le32(raw, 0x20, bpg as u32);
+    put_le32(raw, 0x24, bpg as u32);
+    put_le32(raw, 0x28, ipg as u32);
+    put_le32(raw, 0x30, now);
+    put_le16(raw, 0x36, 0xFFFF);
+    put_le16(raw, 0x38, MAGIC);
+    put_le16(raw, 0x3A, 1); // cleanly unmounted
+    put_le16(raw, 0x3C, 1); // continue on errors
+    put_le32(raw, 0x40, now);
+    put_le32(raw, 0x4C, 1); // dynamic revision
+    put_le32(raw, 0x54, LOST_FOUND_INO);
+    put_le16(raw, 0x58, INODE_SIZE as u16);
+    put_le32(raw, 0x60, INCOMPAT_FILETYPE | INCOMPAT_EXTENTS);
+    put_le32(
+        raw,
+        0x64,
+        RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_DIR_NLINK | RO_COMPAT_EXTRA_ISIZE | RO_COMPAT_METADATA_CSUM,
+    );
+    raw[0x68..0x78].copy_from_slice(&fs_uuid);
+    raw[0x78..0x78 + label.len()].copy_from_slice(label.as_bytes());
+    put_le16(raw, 0xFE, DESC_SIZE as u16);
+    put_le32(raw, 0x108, now);
+    put_le16(raw, 0x15C, 32);
+    put_le16(raw, 0x15E, 32);
+    raw[0x175] = 1; // crc32c
+    sb.update_checksum();
+
+    // --- bitmap and descriptor checksums ---
+    let seed = sb.csum_seed();
+    let mut inode_bitmap = vec![0u8; bs as usize];
+    for i in (0..reserved_inodes).chain(ipg..bs64 * 8) {
+        inode_bitmap[(i / 8) as usize] |= 1 << (i % 8);
+    }
+    let empty_inode_bitmap = {
+        let mut bm = vec![0u8; bs as usize];
+        for i in ipg..bs64 * 8 {
+            bm[(i / 8) as usize] |= 1 << (i % 8);
+        }
+        bm
+    };
+    for g in 0..groups as u32 {
+        let ibm = if g == 0 { &inode_bitmap } else { &empty_inode_bitmap };
+        let bcsum = crc32c_raw(seed, &block_bitmaps[g as usize][..(bpg / 8) as usize]);
+        let icsum = crc32c_raw(seed, &ibm[..(ipg / 8) as usize]);
+        gds.set_bitmap_csums(g, Some(bcsum), Some(icsum));
+        gds.update_checksum(g, &sb);
+    }
+
+    // --- write it all out ---
+    let zeros = vec![0u8; 128 * 1024];
+    dev.write_at(0, &zeros[..(128 * 1024).min(size_bytes as usize)])?;
+    let mut gdt = gds.raw.clone();
+    gdt.resize((gdt_blocks(groups) * bs64) as usize, 0);
+    for g in 0..groups {
+        let start = first_data_block + g * bpg;
+        if sparse(g) {
+            let mut copy = sb.clone();
+            if g > 0 {
+                put_le16(&mut copy.raw, 0x5A, g as u16);
+                copy.update_checksum();
+            }
+            let sb_off = if g == 0 { SUPERBLOCK_OFFSET } else { start * bs64 };
+            dev.write_at(sb_off, &copy.raw)?;
+            dev.write_at((start + 1) * bs64, &gdt)?;
+        }
+        let g32 = g as u32;
+        dev.write_at(gds.block_bitmap(g32) * bs64, &block_bitmaps[g as usize])?;
+        let ibm = if g == 0 { &inode_bitmap } else { &empty_inode_bitmap };
+        dev.write_at(gds.inode_bitmap(g32) * bs64, ibm)?;
+    }
+    let table = vec![0u8; (itb * bs64) as usize];
+    dev.write_at(gds.inode_table(0) * bs64, &table)?;
+    dev.flush()?;
+
+    let mut fs = Ext4::open(dev, true)?;
+    fs.init_root(&NodeMeta::now(0o755))?;
+    fs.mkdir_in(ROOT_INO, b"lost+found", &NodeMeta::now(0o700))?;
+    fs.sync()?;
+    Ok(fs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::Filesystem;
+    use crate::imagefs::{format_image, ImageFs};
+    use crate::testutil::{fsck, gapped_data, host_tree, pattern, scratch_file, ScratchDir};
+    use std::fs::File;
+    use std::path::Path;
+
+    fn read(fs: &mut Ext4<File>, path: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        fs.read_file(Path::new(path), &mut out).unwrap();
+        out
+    }
+
+    fn inode(fs: &mut Ext4<File>, path: &str) -> Inode {
+        let ino = fs.lookup(Path::new(path)).unwrap();
+        fs.read_inode(ino).unwrap()
+    }
+
+    #[test]
+    fn images_built_from_a_tree_read_back() {
+        let dir = ScratchDir::new("ext4-mkfs-tree");
+        let tree = dir.join("tree");
+        host_tree(&tree, true);
+        let image = dir.join("image");
+        File::create(&image).unwrap().set_len(16 << 20).unwrap();
+        format_image(&image, Filesystem::Ext4, &FormatOptions::default(), Some(&tree)).unwrap();
+
+        let mut fs = Ext4::open(File::open(&image).unwrap(), false).unwrap();
+        assert_eq!(read(&mut fs, "/top.txt"), b"hello");
+        assert_eq!(read(&mut fs, "/a/b/c/deep.txt"), pattern(5000, 9));
+        assert_eq!(read(&mut fs, "/big.bin"), gapped_data());
+        let mut names: Vec<_> = fs.list(Path::new("/a")).unwrap().into_iter().map(|e| e.name).collect();
+        names.sort();
+        assert_eq!(names, ["b", "empty", "hard.txt"]);
+        assert!(fs.list(Path::new("/a/empty")).unwrap().is_empty());
+
+        // Six runs of data are more extents than fit in the inode
+        let big = inode(&mut fs, "/big.bin");
+        let (extents, tree_blocks) = fs.file_extents(&big).unwrap();
+        assert_eq!(extents.len(), 6);
+        assert!(!tree_blocks.is_empty());
+
+        for (link, target) in [("/link", "top.txt"), ("/dirlink", "a/b")] {
+            let link = inode(&mut fs, link);
+            assert_eq!(fs.read_link(&link).unwrap(), target.as_bytes());
+        }
+        let hard = fs.lookup(Path::new("/a/hard.txt")).unwrap();
+        assert_eq!(hard, fs.lookup(Path::new("/top.txt")).unwrap());
+        assert_eq!(fs.read_inode(hard).unwrap().links(), 2);
+        drop(fs);
+        fsck("e2fsck", &["-fn"], &image);
+    }
+
+    #[test]
+    fn options_reach_the_superblock() {
+        let id = "0f1e2d3c-4b5a-6978-8796-a5b4c3d2e1f0";
+        for block_size in [1024, 2048, 4096] {
+            let file = scratch_file(&format!("ext4-mkfs-options-{}", block_size));
+            file.set_len(8 << 20).unwrap();
+            let options = FormatOptions {
+                label: Some("scratch".into()),
+                uuid: Some(id.into()),
+                block_size: Some(block_size),
+                ..FormatOptions::default()
+            };
+            let fs = format(file.try_clone().unwrap(), &options).unwrap();
+            assert_eq!(fs.block_size(), block_size as u64);
+            drop(fs);
+            let fs = Ext4::open(file, false).unwrap();
+            assert_eq!(fs.label(), "scratch");
+            assert_eq!(fs.uuid(), uuid::parse(id).unwrap());
+        }
+    }
+
+    #[test]
+    fn impossible_options_are_refused() {
+        let file = scratch_file("ext4-mkfs-refused");
+        file.set_len(8 << 20).unwrap();
+        let refused = [
+            FormatOptions { block_size: Some(3000), ..FormatOptions::default() },
+            FormatOptions { block_size: Some(512), ..FormatOptions::default() },
+            FormatOptions { label: Some("a label over sixteen".into()), ..FormatOptions::default() },
+            FormatOptions { uuid: Some("not-a-uuid".into()), ..FormatOptions::default() },
+            FormatOptions { reserved_percent: Some(60), ..FormatOptions::default() },
+        ];
+        for options in &refused {
+            let e = format(file.try_clone().unwrap(), options).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,1475 @@
+//! A userspace ext4 implementation covering the subset needed to build and
+//! edit disk images: extent-mapped files, linear directories, symlinks and
+//! special files, with metadata checksums kept up to date.
+
+pub mod mkfs;
+mod layout;
+
+use crate::blockdev::BlockDevice;
+use crate::bytes::{le16, le32, put_le16, put_le32};
+use crate::checksum::crc32c_raw;
+use crate::imagefs::{split_parent, ImageFs, NodeMeta, SpecialKind};
+use crate::ops::DirEntry;
+use layout::*;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Bitmap {
+    Block,
+    Inode,
+}
+
+/// One name in a directory.
+#[derive(Debug, Clone)]
+pub struct RawDirEntry {
+    pub ino: u32,
+    pub name: Vec<u8>,
+    pub file_type: u8,
+}
+
+pub struct Ext4<D: BlockDevice> {
+    dev: D,
+    sb: Superblock,
+    gds: GroupDescs,
+    block_size: u64,
+    groups: u32,
+    bitmaps: HashMap<(Bitmap, u32), Vec<u8>>,
+    dirty_bitmaps: HashSet<(Bitmap, u32)>,
+    dirty_meta: bool,
+    writable: bool,
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn unsupported(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, msg.into())
+}
+
+/// A free block or inode count that disagrees with the bitmaps.
+fn miscounted(what: &str) -> io::Error {
+    invalid(format!("the free {} count is wrong; run e2fsck", what))
+}
+
+/// Refuses a superblock whose geometry does not add up, before anything
+/// is sized or indexed from it.
+fn check_geometry(sb: &Superblock, device_len: u64) -> io::Result<()> {
+    if sb.log_block_size() > MAX_LOG_BLOCK_SIZE {
+        return Err(invalid(format!("bad block size 2^{}", u64::from(sb.log_block_size()) + 10)));
+    }
+    let block_size = sb.block_size();
+    let (bpg, ipg) = (u64::from(sb.blocks_per_group()), u64::from(sb.inodes_per_group()));
+    if bpg == 0 || bpg > block_size * 8 {
+        return Err(invalid(format!("bad blocks per group {}", bpg)));
+    }
+    if ipg == 0 || ipg > block_size * 8 {
+        return Err(invalid(format!("bad inodes per group {}", ipg)));
+    }
+    let inode_size = sb.inode_size();
+    if !inode_size.is_power_of_two() || inode_size < GOOD_OLD_INODE_SIZE || inode_size as u64 > block_size {
+        return Err(invalid(format!("bad inode size {}", inode_size)));
+    }
+    let desc_size = sb.desc_size();
+    if !desc_size.is_power_of_two() || !(32..=1024).contains(&desc_size) {
+        return Err(invalid(format!("bad group descriptor size {}", desc_size)));
+    }
+    let first = u64::from(sb.first_data_block());
+    if sb.blocks_count() <= first {
+        return Err(invalid(format!("{} blocks leave no room for data", sb.blocks_count())));
+    }
+    // Every group has the same number of inodes, which also keeps the group count within 32 bits
+    let groups = (sb.blocks_count() - first).div_ceil(bpg);
+    if groups.checked_mul(ipg) != Some(u64::from(sb.inodes_count())) {
+        return Err(invalid(format!("{} inodes do not fill {} groups", sb.inodes_count(), groups)));
+    }
+    if (first + 1) * block_size + groups * desc_size as u64 > device_len {
+        return Err(invalid("the group descriptor table runs past the end of the device"));
+    }
+    Ok(())
+}
+
+fn bit(bm: &[u8], i: u64) -> bool {
+    bm[(i / 8) as usize] & (1 << (i % 8)) != 0
+}
+
+fn set_bit(bm: &mut [u8], i: u64, on: bool) {
+    if on {
+        bm[(i / 8) as usize] |= 1 << (i % 8);
+    } else {
+        bm[(i / 8) as usize] &= !(1 << (i % 8));
+    }
+}
+
+/// Decodes a directory record length, which for 64 KiB blocks can overflow 16 bits.
+fn rec_len(block: &[u8], off: usize) -> usize {
+    let len = le16(block, off + 4) as usize;
+    if block.len() >= 65536 && (len == 65535 || len == 0) {
+        65536
+    } else {
+        len
+    }
+}
+
+fn put_rec_len(block: &mut [u8], off: usize, len: usize) {
+    let stored = if len == 65536 { 65535 } else { len as u16 };
+    put_le16(block, off + 4, stored);
+}
+
+impl<D: BlockDevice> Ext4<D> {
+    /// Opens the filesystem on `dev`. With `writable` the image's features are
+    /// checked against what this implementation can modify safely.
+    pub fn open(mut dev: D, writable: bool) -> io::Result<Self> {
+        let mut sb = Superblock::new();
+        dev.read_at(SUPERBLOCK_OFFSET, &mut sb.raw)?;
+        if sb.magic() != MAGIC {
+            return Err(invalid("not an ext2/3/4 filesystem"));
+        }
+        let incompat = sb.feature_incompat();
+        if incompat & !INCOMPAT_READ != 0 {
+            return Err(unsupported(format!("unsupported ext4 features {:#x}", incompat & !INCOMPAT_READ)));
+        }
+        check_geometry(&sb, dev.len()?)?;
+        if sb.log_cluster_size() != sb.log_block_size() {
+            return Err(unsupported("bigalloc filesystems are not supported"));
+        }
+        if writable {
+            if incompat & INCOMPAT_RECOVER != 0 {
+                return Err(unsupported("journal needs recovery; mount the image or run e2fsck first"));
+            }
+            if incompat & !INCOMPAT_WRITE != 0 || sb.feature_ro_compat() & !RO_COMPAT_WRITE != 0 {
+                return Err(unsupported("image uses ext4 features that cannot be modified in userspace"));
+            }
+            if incompat & INCOMPAT_EXTENTS == 0 || incompat & INCOMPAT_FILETYPE == 0 {
+                return Err(unsupported("writing requires the extent and filetype features"));
+            }
+        }
+
+        let block_size = sb.block_size();
+        let groups = sb.group_count();
+        let desc_size = sb.desc_size();
+        let mut raw = vec![0u8; groups as usize * desc_size];
+        dev.read_at((sb.first_data_block() as u64 + 1) * block_size, &mut raw)?;
+        let gds = GroupDescs { raw, desc_size };
+        let table_blocks = (sb.inodes_per_group() as u64 * sb.inode_size() as u64).div_ceil(block_size);
+        for g in 0..groups {
+            let table_end = gds.inode_table(g).checked_add(table_blocks);
+            if gds.block_bitmap(g) >= sb.blocks_count()
+                || gds.inode_bitmap(g) >= sb.blocks_count()
+                || table_end.is_none_or(|end| end > sb.blocks_count())
+            {
+                return Err(invalid(format!("group {} has metadata outside the filesystem", g)));
+            }
+        }
+
+        Ok(Ext4 {
+            dev,
+            sb,
+            gds,
+            block_size,
+            groups,
+            bitmaps: HashMap::new(),
+            dirty_bitmaps: HashSet::new(),
+            dirty_meta: false,
+            writable,
+        })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn blocks_count(&self) -> u64 {
+        self.sb.blocks_count()
+    }
+
+    pub fn free_blocks(&self) -> u64 {
+        self.sb.free_blocks()
+    }
+
+    pub fn label(&self) -> String {
+        self.sb.volume_name()
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.sb.uuid()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "filesystem opened read-only"))
+        }
+    }
+
+    pub(crate) fn read_block(&mut self, block: u64) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.block_size as usize];
+        self.dev.read_at(block * self.block_size, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub(crate) fn write_block(&mut self, block: u64, buf: &[u8]) -> io::Result<()> {
+        self.dev.write_at(block * self.block_size, buf)
+    }
+
+    fn group_start(&self, g: u32) -> u64 {
+        self.sb.first_data_block() as u64 + g as u64 * self.sb.blocks_per_group() as u64
+    }
+
+    fn blocks_in_group(&self, g: u32) -> u64 {
+        let start = self.group_start(g);
+        (self.sb.blocks_count() - start).min(self.sb.blocks_per_group() as u64)
+    }
+
+    fn group_of_block(&self, block: u64) -> u32 {
+        ((block - self.sb.first_data_block() as u64) / self.sb.blocks_per_group() as u64) as u32
+    }
+
+    fn inode_table_blocks(&self) -> u64 {
+        (self.sb.inodes_per_group() as u64 * self.sb.inode_size() as u64).div_ceil(self.block_size)
+    }
+
+    fn inode_offset(&self, ino: u32) -> io::Result<u64> {
+        if ino == 0 || ino > self.sb.inodes_count() {
+            return Err(invalid(format!("inode {} out of range", ino)));
+        }
+        let ipg = self.sb.inodes_per_group();
+        let g = (ino - 1) / ipg;
+        let index = (ino - 1) % ipg;
+        Ok(self.gds.inode_table(g) * self.block_size + index as u64 * self.sb.inode_size() as u64)
+    }
+
+    pub(crate) fn read_inode(&mut self, ino: u32) -> io::Result<Inode> {
+        let off = self.inode_offset(ino)?;
+        let mut inode = Inode { raw: vec![0; self.sb.inode_size()] };
+        self.dev.read_at(off, &mut inode.raw)?;
+        Ok(inode)
+    }
+
+    pub(crate) fn write_inode(&mut self, ino: u32, inode: &mut Inode) -> io::Result<()> {
+        if self.sb.has_metadata_csum() {
+            inode.update_checksum(ino, self.sb.csum_seed());
+        }
+        let off = self.inode_offset(ino)?;
+        self.dev.write_at(off, &inode.raw)
+    }
+
+    fn inode_seed(&self, ino: u32, inode: &Inode) -> u32 {
+        inode.csum_seed(ino, self.sb.csum_seed())
+    }
+
+    // --- bitmaps and allocation ---
+
+    fn load_bitmap(&mut self, kind: Bitmap, g: u32) -> io::Result<()> {
+        if self.bitmaps.contains_key(&(kind, g)) {
+            return Ok(());
+        }
+        let flags = self.gds.flags(g);
+        let buf = match kind {
+            Bitmap::Block if flags & BG_BLOCK_UNINIT != 0 => self.init_block_bitmap(g),
+            Bitmap::Block => self.read_block(self.gds.block_bitmap(g))?,
+            Bitmap::Inode if flags & BG_INODE_UNINIT != 0 => {
+                let mut bm = vec![0u8; self.block_size as usize];
+                for i in self.sb.inodes_per_group() as u64..self.block_size * 8 {
+                    set_bit(&mut bm, i, true);
+                }
+                bm
+            }
+            Bitmap::Inode => self.read_block(self.gds.inode_bitmap(g))?,
+        };
+        self.bitmaps.insert((kind, g), buf);
+        Ok(())
+    }
+
+    /// Reconstructs the block bitmap of a group flagged BLOCK_UNINIT: only its
+    /// static metadata is in use.
+    fn init_block_bitmap(&self, g: u32) -> Vec<u8> {
+        let mut bm = vec![0u8; self.block_size as usize];
+        let start = self.group_start(g);
+        let len = self.blocks_in_group(g);
+        let mut mark = |block: u64, count: u64| {
+            for b in block..block + count {
+                if b >= start && b < start + len {
+                    set_bit(&mut bm, b - start, true);
+                }
+            }
+        };
+        if self.sb.group_has_super(g) {
+            mark(start, 1 + self.sb.gdt_blocks() + self.sb.reserved_gdt_blocks());
+        }
+        let itb = self.inode_table_blocks();
+        for h in 0..self.groups {
+            mark(self.gds.block_bitmap(h), 1);
+            mark(self.gds.inode_bitmap(h), 1);
+            mark(self.gds.inode_table(h), itb);
+        }
+        for i in len..self.block_size * 8 {
+            set_bit(&mut bm, i, true);
+        }
+        bm
+    }
+
+    fn mark_bitmap_dirty(&mut self, kind: Bitmap, g: u32) {
+        self.dirty_bitmaps.insert((kind, g));
+        self.dirty_meta = true;
+    }
+
+    /// Allocates up to `max` contiguous blocks, preferring `goal`.
+    pub(crate) fn alloc_blocks(&mut self, goal: u64, max: u64) -> io::Result<(u64, u64)> {
+        self.check_writable()?;
+        let first = self.sb.first_data_block() as u64;
+        let goal = goal.clamp(first, self.sb.blocks_count() - 1);
+        let g0 = self.group_of_block(goal);
+
+        for i in 0..=self.groups {
+            let g = (g0 + i) % self.groups;
+            if self.gds.free_blocks(g) == 0 {
+                continue;
+            }
+            let from = if i == 0 { goal - self.group_start(g) } else { 0 };
+            let len = self.blocks_in_group(g);
+            self.load_bitmap(Bitmap::Block, g)?;
+            let bm = self.bitmaps.get_mut(&(Bitmap::Block, g)).unwrap();
+
+            let mut b = from;
+            while b < len && bit(bm, b) {
+                if b % 8 == 0 && b + 8 <= len && bm[(b / 8) as usize] == 0xFF {
+                    b += 8;
+                } else {
+                    b += 1;
+                }
+            }
+            if b >= len {
+                continue;
+            }
+            let mut run = 0;
+            while b + run < len && run < max && !bit(bm, b + run) {
+                run += 1;
+            }
+            let group_free = self.gds.free_blocks(g).checked_sub(run as u32).ok_or_else(|| miscounted("block"))?;
+            let free = self.sb.free_blocks().checked_sub(run).ok_or_else(|| miscounted("block"))?;
+            for i in b..b + run {
+                set_bit(bm, i, true);
+            }
+
+            self.gds.set_free_blocks(g, group_free);
+            self.gds.set_flags(g, self.gds.flags(g) & !BG_BLOCK_UNINIT);
+            self.sb.set_free_blocks(free);
+            self.mark_bitmap_dirty(Bitmap::Block, g);
+            return Ok((self.group_start(g) + b, run));
+        }
+        Err(io::Error::from_raw_os_error(libc::ENOSPC))
+    }
+
+    pub(crate) fn free_block_range(&mut self, start: u64, count: u64) -> io::Result<()> {
+        let mut block = start;
+        let end = start + count;
+        while block < end {
+            let g = self.group_of_block(block);
+            let gstart = self.group_start(g);
+            let gend = (gstart + self.blocks_in_group(g)).min(end);
+            self.load_bitmap(Bitmap::Block, g)?;
+            let bm = self.bitmaps.get_mut(&(Bitmap::Block, g)).unwrap();
+            let mut freed = 0;
+            for b in block..gend {
+                if bit(bm, b - gstart) {
+                    set_bit(bm, b - gstart, false);
+                    freed += 1;
+                }
+            }
+            let group_free = self.gds.free_blocks(g).checked_add(freed as u32).ok_or_else(|| miscounted("block"))?;
+            let free = self.sb.free_blocks().checked_add(freed).ok_or_else(|| miscounted("block"))?;
+            self.gds.set_free_blocks(g, group_free);
+            self.sb.set_free_blocks(free);
+            self.mark_bitmap_dirty(Bitmap::Block, g);
+            block = gend;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn alloc_inode(&mut self, is_dir: bool, goal_group: u32) -> io::Result<u32> {
+        self.check_writable()?;
+        let ipg = self.sb.inodes_per_group();
+        let first_ino = self.sb.first_ino();
+        for i in 0..self.groups {
+            let g = (goal_group + i) % self.groups;
+            if self.gds.free_inodes(g) == 0 {
+                continue;
+            }
+            self.load_bitmap(Bitmap::Inode, g)?;
+            let bm = self.bitmaps.get_mut(&(Bitmap::Inode, g)).unwrap();
+            let Some(index) = (0..ipg).find(|&b| !bit(bm, b as u64) && g * ipg + b + 1 >= first_ino) else {
+                continue;
+            };
+            let free = self.sb.free_inodes().checked_sub(1).ok_or_else(|| miscounted("inode"))?;
+            set_bit(bm, index as u64, true);
+
+            let flags = self.gds.flags(g);
+            let unused = if flags & BG_INODE_UNINIT != 0 { ipg } else { self.gds.itable_unused(g).min(ipg) };
+            let high_water = ipg - unused;
+            if flags & BG_INODE_ZEROED == 0 && index > high_water {
+                // Slots below the new high-water mark may hold stale data
+                let size = self.sb.inode_size() as u64;
+                let zeros = vec![0u8; ((index - high_water) as u64 * size) as usize];
+                let off = self.gds.inode_table(g) * self.block_size + high_water as u64 * size;
+                self.dev.write_at(off, &zeros)?;
+            }
+            self.gds.set_itable_unused(g, unused.min(ipg - index - 1));
+            self.gds.set_flags(g, flags & !BG_INODE_UNINIT);
+            self.gds.set_free_inodes(g, self.gds.free_inodes(g) - 1);
+            if is_dir {
+                self.gds.set_used_dirs(g, self.gds.used_dirs(g) + 1);
+            }
+            self.sb.set_free_inodes(free);
+            self.mark_bitmap_dirty(Bitmap::Inode, g);
+            return Ok(g * ipg + index + 1);
+        }
+        Err(io::Error::from_raw_os_error(libc::ENOSPC))
+    }
+
+    pub(crate) fn free_inode(&mut self, ino: u32, is_dir: bool) -> io::Result<()> {
+        let ipg = self.sb.inodes_per_group();
+        let g = (ino - 1) / ipg;
+        self.load_bitmap(Bitmap::Inode, g)?;
+        let bm = self.bitmaps.get_mut(&(Bitmap::Inode, g)).unwrap();
+        let group_free = self.gds.free_inodes(g).checked_add(1).ok_or_else(|| miscounted("inode"))?;
+        let free = self.sb.free_inodes().checked_add(1).ok_or_else(|| miscounted("inode"))?;
+        set_bit(bm, ((ino - 1) % ipg) as u64, false);
+        self.gds.set_free_inodes(g, group_free);
+        if is_dir {
+            self.gds.set_used_dirs(g, self.gds.used_dirs(g).saturating_sub(1));
+        }
+        self.sb.set_free_inodes(free);
+        self.mark_bitmap_dirty(Bitmap::Inode, g);
+        Ok(())
+    }
+
+    /// Writes cached bitmaps, group descriptors and the superblock.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if !self.dirty_meta {
+            return self.dev.flush();
+        }
+        let csum = self.sb.has_metadata_csum();
+        let seed = self.sb.csum_seed();
+        let mut dirty: Vec<_> = self.dirty_bitmaps.drain().collect();
+        dirty.sort_by_key(|&(kind, g)| (g, kind == Bitmap::Inode));
+        for (kind, g) in dirty {
+            let bm = self.bitmaps[&(kind, g)].clone();
+            match kind {
+                Bitmap::Block => {
+                    self.write_block(self.gds.block_bitmap(g), &bm)?;
+                    if csum {
+                        let len = self.sb.blocks_per_group() as usize / 8;
+                        self.gds.set_bitmap_csums(g, Some(crc32c_raw(seed, &bm[..len])), None);
+                    }
+                }
+                Bitmap::Inode => {
+                    self.write_block(self.gds.inode_bitmap(g), &bm)?;
+                    if csum {
+                        let len = self.sb.inodes_per_group() as usize / 8;
+                        self.gds.set_bitmap_csums(g, None, Some(crc32c_raw(seed, &bm[..len])));
+                    }
+                }
+            }
+        }
+        for g in 0..self.groups {
+            self.gds.update_checksum(g, &self.sb);
+        }
+        let gdt_off = (self.sb.first_data_block() as u64 + 1) * self.block_size;
+        self.dev.write_at(gdt_off, &self.gds.raw)?;
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0);
+        put_le32(&mut self.sb.raw, 0x30, now);
+        self.sb.update_checksum();
+        self.dev.write_at(SUPERBLOCK_OFFSET, &self.sb.raw)?;
+        self.dirty_meta = false;
+        self.dev.flush()
+    }
+
+    // --- block mapping ---
+
+    /// All extents of `inode`, sorted by logical block, plus the blocks that
+    /// hold the mapping tree itself.
+    pub(crate) fn file_extents(&mut self, inode: &Inode) -> io::Result<(Vec<Extent>, Vec<u64>)> {
+        let mut extents = Vec::new();
+        let mut tree = Vec::new();
+        if inode.flags() & INODE_FLAG_INLINE_DATA != 0 {
+            return Err(unsupported("inline-data files are not supported"));
+        }
+        if inode.flags() & INODE_FLAG_EXTENTS != 0 {
+            let root = inode.block().to_vec();
+            self.walk_extents(&root, &mut extents, &mut tree, 0)?;
+        } else {
+            self.walk_block_map(inode, &mut extents, &mut tree)?;
+        }
+        extents.sort_by_key(|e| e.logical);
+        Ok((extents, tree))
+    }
+
+    fn walk_extents(&mut self, node: &[u8], out: &mut Vec<Extent>, tree: &mut Vec<u64>, level: u32) -> io::Result<()> {
+        if le16(node, 0) != EXTENT_MAGIC || level > 5 {
+            return Err(invalid("corrupt extent tree"));
+        }
+        let entries = le16(node, 2) as usize;
+        let depth = le16(node, 6);
+        for i in 0..entries {
+            let off = 12 + i * 12;
+            if off + 12 > node.len() {
+                return Err(invalid("corrupt extent tree"));
+            }
+            if depth == 0 {
+                let raw_len = le16(node, off + 4) as u32;
+                let (len, uninit) = if raw_len > EXTENT_MAX_LEN {
+                    (raw_len - EXTENT_MAX_LEN, true)
+                } else {
+                    (raw_len, false)
+                };
+                let start = (le16(node, off + 6) as u64) << 32 | le32(node, off + 8) as u64;
+                out.push(Extent { logical: le32(node, off), len, start, uninit });
+            } else {
+                let leaf = le32(node, off + 4) as u64 | (le16(node, off + 8) as u64) << 32;
+                tree.push(leaf);
+                let child = self.read_block(leaf)?;
+                self.walk_extents(&child, out, tree, level + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps an ext2/ext3 style indirect block map into extents.
+    fn walk_block_map(&mut self, inode: &Inode, out: &mut Vec<Extent>, tree: &mut Vec<u64>) -> io::Result<()> {
+        let per_block = self.block_size / 4;
+        let mut push = |out: &mut Vec<Extent>, logical: u64, block: u64| {
+            if block == 0 {
+                return;
+            }
+            if let Some(last) = out.last_mut() {
+                if last.logical as u64 + last.len as u64 == logical
+                    && last.start + last.len as u64 == block
+                    && last.len < EXTENT_MAX_LEN
+                {
+                    last.len += 1;
+                    return;
+                }
+            }
+            out.push(Extent { logical: logical as u32, len: 1, start: block, uninit: false });
+        };
+        let i_block = inode.block().to_vec();
+        for i in 0..12 {
+            push(out, i as u64, le32(&i_block, i * 4) as u64);
+        }
+        let mut logical = 12u64;
+        for (slot, levels) in [(12usize, 1u32), (13, 2), (14, 3)] {
+            let root = le32(&i_block, slot * 4) as u64;
+            let span = per_block.pow(levels);
+            if root != 0 {
+                self.walk_indirect(root, levels, logical, out, tree, &mut push)?;
+            }
+            logical += span;
+        }
+        Ok(())
+    }
+
+    fn walk_indirect(
+        &mut self,
+        block: u64,
+        levels: u32,
+        logical: u64,
+        out: &mut Vec<Extent>,
+        tree: &mut Vec<u64>,
+        push: &mut dyn FnMut(&mut Vec<Extent>, u64, u64),
+    ) -> io::Result<()> {
+        tree.push(block);
+        let buf = self.read_block(block)?;
+        let per_block = self.block_size / 4;
+        let span = per_block.pow(levels - 1);
+        for i in 0..per_block {
+            let child = le32(&buf, i as usize * 4) as u64;
+            if child == 0 {
+                continue;
+            }
+            let l = logical + i * span;
+            if levels == 1 {
+                push(out, l, child);
+            } else {
+                self.walk_indirect(child, levels - 1, l, out, tree, push)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the block mapping of `inode` with `extents`, building index
+    /// blocks when they do not fit in the inode, and updates `i_blocks`.
+    pub(crate) fn set_extents(&mut self, ino: u32, inode: &mut Inode, extents: &[Extent], old_tree: &[u64]) -> io::Result<()> {
+        for &b in old_tree {
+            self.free_block_range(b, 1)?;
+        }
+        let per_leaf = (self.block_size as usize - 12) / 12;
+        let i_block = inode.block_mut();
+        i_block.fill(0);
+
+        let mut tree_blocks = 0u64;
+        if extents.len() <= 4 {
+            extent_header(i_block, extents.len() as u16, 4, 0);
+            for (i, e) in extents.iter().enumerate() {
+                put_extent(i_block, 12 + i * 12, e);
+            }
+        } else {
+            let leaves: Vec<&[Extent]> = extents.chunks(per_leaf).collect();
+            if leaves.len() > 4 {
+                return Err(unsupported("file is too fragmented for a two-level extent tree"));
+            }
+            let seed = self.inode_seed(ino, inode);
+            let goal = extents[0].start;
+            let mut index = Vec::new();
+            for leaf in &leaves {
+                let (block, _) = self.alloc_blocks(goal, 1)?;
+                let mut buf = vec![0u8; self.block_size as usize];
+                extent_header(&mut buf, leaf.len() as u16, per_leaf as u16, 0);
+                for (i, e) in leaf.iter().enumerate() {
+                    put_extent(&mut buf, 12 + i * 12, e);
+                }
+                if self.sb.has_metadata_csum() {
+                    let tail = 12 + per_leaf * 12;
+                    let csum = crc32c_raw(seed, &buf[..tail]);
+                    put_le32(&mut buf, tail, csum);
+                }
+                self.write_block(block, &buf)?;
+                index.push((leaf[0].logical, block));
+            }
+            tree_blocks = index.len() as u64;
+            let i_block = inode.block_mut();
+            extent_header(i_block, index.len() as u16, 4, 1);
+            for (i, (logical, block)) in index.iter().enumerate() {
+                put_extent_index(i_block, 12 + i * 12, *logical, *block);
+            }
+        }
+        inode.set_flags(inode.flags() | INODE_FLAG_EXTENTS);
+
+        let data: u64 = extents.iter().map(|e| e.len as u64).sum();
+        let xattr = (inode.file_acl() != 0) as u64;
+        inode.set_sectors((data + tree_blocks + xattr) * (self.block_size / 512), self.block_size);
+        Ok(())
+    }
+
+    // --- reading ---
+
+    pub(crate) fn read_dir(&mut self, ino: u32) -> io::Result<Vec<RawDirEntry>> {
+        let inode = self.read_inode(ino)?;
+        if !inode.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        let (extents, _) = self.file_extents(&inode)?;
+        let mut entries = Vec::new();
+        for e in extents.iter().filter(|e| !e.uninit) {
+            for i in 0..e.len as u64 {
+                let block = self.read_block(e.start + i)?;
+                let mut off = 0;
+                while off + 8 <= block.len() {
+                    let len = rec_len(&block, off);
+                    if len < 8 || off + len > block.len() {
+                        return Err(invalid(format!("corrupt directory entry in inode {}", ino)));
+                    }
+                    let child = le32(&block, off);
+                    let name_len = block[off + 6] as usize;
+                    if child != 0 && name_len > 0 && off + 8 + name_len <= block.len() {
+                        entries.push(RawDirEntry {
+                            ino: child,
+                            name: block[off + 8..off + 8 + name_len].to_vec(),
+                            file_type: block[off + 7],
+                        });
+                    }
+                    off += len;
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn find_entry(&mut self, dir: u32, name: &[u8]) -> io::Result<Option<u32>> {
+        Ok(self.read_dir(dir)?.into_iter().find(|e| e.name == name).map(|e| e.ino))
+    }
+
+    /// Resolves `path` (relative to the root) to an inode number, following
+    /// symlinks in every component but the last.
+    pub(crate) fn lookup(&mut self, path: &Path) -> io::Result<u32> {
+        self.resolve(ROOT_INO, path, false, &mut 40)
+    }
+
+    /// Like `lookup`, but also follows a symlink in the final component.
+    pub(crate) fn lookup_follow(&mut self, path: &Path) -> io::Result<u32> {
+        self.resolve(ROOT_INO, path, true, &mut 40)
+    }
+
+    fn resolve(&mut self, start: u32, path: &Path, follow_last: bool, budget: &mut u32) -> io::Result<u32> {
+        let components: Vec<_> = path.components().collect();
+        let mut ino = start;
+        for (i, component) in components.iter().enumerate() {
+            let name = match component {
+                Component::RootDir => {
+                    ino = ROOT_INO;
+                    continue;
+                }
+                Component::CurDir => continue,
+                Component::ParentDir => &b".."[..],
+                Component::Normal(name) => name.as_bytes(),
+                Component::Prefix(_) => return Err(invalid("unexpected path prefix")),
+            };
+            let child = self
+                .find_entry(ino, name)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display())))?;
+            let inode = self.read_inode(child)?;
+            if inode.file_type() == S_IFLNK && (follow_last || i + 1 < components.len()) {
+                if *budget == 0 {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                }
+                *budget -= 1;
+                let target = self.read_link(&inode)?;
+                ino = self.resolve(ino, Path::new(OsStr::from_bytes(&target)), true, budget)?;
+            } else {
+                ino = child;
+            }
+        }
+        Ok(ino)
+    }
+
+    fn is_fast_symlink(&self, inode: &Inode) -> bool {
+        let xattr_sectors = if inode.file_acl() != 0 { self.block_size / 512 } else { 0 };
+        inode.file_type() == S_IFLNK
+            && inode.flags() & INODE_FLAG_INLINE_DATA == 0
+            && inode.sectors(self.block_size) == xattr_sectors
+    }
+
+    pub(crate) fn read_link(&mut self, inode: &Inode) -> io::Result<Vec<u8>> {
+        if self.is_fast_symlink(inode) {
+            let size = (inode.size() as usize).min(inode.block().len());
+            return Ok(inode.block()[..size].to_vec());
+        }
+        let mut out = Vec::new();
+        self.read_data(inode, &mut out)?;
+        Ok(out)
+    }
+
+    /// Streams the contents of a regular file (or slow symlink) into `out`.
+    pub(crate) fn read_data(&mut self, inode: &Inode, out: &mut dyn Write) -> io::Result<u64> {
+        let size = inode.size();
+        // Holes are written out as zeros, so a damaged size could go on for ever
+        if size > self.dev.len()? {
+            return Err(invalid(format!("a file of {} bytes is larger than the filesystem", size)));
+        }
+        let (extents, _) = self.file_extents(inode)?;
+        let bs = self.block_size;
+        let mut pos = 0u64;
+        let zeros = vec![0u8; 64 * 1024];
+        let write_zeros = |out: &mut dyn Write, mut n: u64| -> io::Result<()> {
+            while n > 0 {
+                let chunk = n.min(zeros.len() as u64) as usize;
+                out.write_all(&zeros[..chunk])?;
+                n -= chunk as u64;
+            }
+            Ok(())
+        };
+
+        for e in extents {
+            let start = e.logical as u64 * bs;
+            if start >= size {
+                break;
+            }
+            if start > pos {
+                write_zeros(out, start - pos)?;
+                pos = start;
+            }
+            let end = (start + e.len as u64 * bs).min(size);
+            if e.uninit {
+                write_zeros(out, end - pos)?;
+                pos = end;
+                continue;
+            }
+            let mut block = e.start;
+            while pos < end {
+                let chunk = (end - pos).min(256 * bs);
+                let mut buf = vec![0u8; chunk as usize];
+                self.dev.read_at(block * bs, &mut buf)?;
+                out.write_all(&buf)?;
+                pos += chunk;
+                block += chunk.div_ceil(bs);
+            }
+        }
+        if pos < size {
+            write_zeros(out, size - pos)?;
+        }
+        Ok(size)
+    }
+
+    // --- writing ---
+
+    fn new_inode(&self, mode: u16, meta: &NodeMeta) -> Inode {
+        let mut inode = Inode::new(self.sb.inode_size());
+        inode.set_mode(mode | (meta.mode & 0o7777) as u16);
+        inode.set_owner(meta.uid, meta.gid);
+        inode.set_times(meta.atime, meta.ctime, meta.mtime);
+        inode
+    }
+
+    /// Inserts a name into directory `dir`, growing it by a block if needed.
+    pub(crate) fn add_entry(&mut self, dir: u32, name: &[u8], child: u32, file_type: u8) -> io::Result<()> {
+        if name.is_empty() || name.len() > 255 || name.contains(&b'/') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"));
+        }
+        if self.find_entry(dir, name)?.is_some() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        let mut inode = self.read_inode(dir)?;
+        if inode.flags() & INODE_FLAG_INDEX != 0 {
+            return Err(unsupported("cannot add to a hash-indexed directory"));
+        }
+        if inode.flags() & (INODE_FLAG_ENCRYPT | INODE_FLAG_CASEFOLD) != 0 {
+            return Err(unsupported("cannot add to an encrypted or case-folded directory"));
+        }
+        let csum = self.sb.has_metadata_csum();
+        let seed = self.inode_seed(dir, &inode);
+        let bs = self.block_size as usize;
+        let usable = if csum { bs - DIR_TAIL_SIZE } else { bs };
+        let need = dirent_len(name.len());
+
+        let (mut extents, tree) = self.file_extents(&inode)?;
+        for e in extents.iter().filter(|e| !e.uninit) {
+            for i in 0..e.len as u64 {
+                let mut block = self.read_block(e.start + i)?;
+                let mut off = 0;
+                while off < usable {
+                    let len = rec_len(&block, off);
+                    if len < 8 {
+                        return Err(invalid(format!("corrupt directory entry in inode {}", dir)));
+                    }
+                    let used = if le32(&block, off) == 0 { 0 } else { dirent_len(block[off + 6] as usize) };
+                    if len - used >= need && off + len <= usable {
+                        if used == 0 {
+                            put_dirent(&mut block, off, child, len, name, file_type);
+                            put_rec_len(&mut block, off, len);
+                        } else {
+                            put_rec_len(&mut block, off, used);
+                            put_dirent(&mut block, off + used, child, len - used, name, file_type);
+                            put_rec_len(&mut block, off + used, len - used);
+                        }
+                        if csum {
+                            put_dir_tail(&mut block, seed);
+                        }
+                        return self.write_block(e.start + i, &block);
+                    }
+                    off += len;
+                }
+            }
+        }
+
+        // No slack anywhere: append a fresh block
+        let logical = (inode.size() / bs as u64) as u32;
+        let goal = extents.last().map(|e| e.start + e.len as u64).unwrap_or(0);
+        let (block, _) = self.alloc_blocks(goal, 1)?;
+        let mut buf = vec![0u8; bs];
+        put_dirent(&mut buf, 0, child, usable, name, file_type);
+        put_rec_len(&mut buf, 0, usable);
+        if csum {
+            put_dir_tail(&mut buf, seed);
+        }
+        self.write_block(block, &buf)?;
+
+        match extents.last_mut() {
+            Some(last)
+                if !last.uninit
+                    && last.logical + last.len == logical
+                    && last.start + last.len as u64 == block
+                    && last.len < EXTENT_MAX_LEN =>
+            {
+                last.len += 1
+            }
+            _ => extents.push(Extent { logical, len: 1, start: block, uninit: false }),
+        }
+        self.set_extents(dir, &mut inode, &extents, &tree)?;
+        inode.set_size(inode.size() + bs as u64);
+        self.write_inode(dir, &mut inode)
+    }
+
+    fn bump_links(&mut self, ino: u32, delta: i32) -> io::Result<()> {
+        let mut inode = self.read_inode(ino)?;
+        if inode.is_dir() && inode.links() == 1 {
+            // dir_nlink: the count overflowed and is no longer tracked
+            return Ok(());
+        }
+        let links = inode.links() as i32 + delta;
+        // With dir_nlink a directory past the 16-bit limit just reports 1
+        let links = if inode.is_dir() && links >= 65000 { 1 } else { links.max(0) as u16 };
+        inode.set_links(links);
+        self.write_inode(ino, &mut inode)
+    }
+
+    fn resolve_parent<'a>(&mut self, path: &'a Path) -> io::Result<(u32, &'a [u8])> {
+        let (parent, name) = split_parent(path)?;
+        let dir = self.lookup(parent)?;
+        Ok((dir, name.as_bytes()))
+    }
+
+    /// Lays down the root directory of a freshly formatted filesystem.
+    pub(crate) fn init_root(&mut self, meta: &NodeMeta) -> io::Result<()> {
+        let mut inode = self.new_inode(S_IFDIR, meta);
+        self.write_dir_block(ROOT_INO, &mut inode, ROOT_INO)?;
+        self.gds.set_used_dirs(0, self.gds.used_dirs(0) + 1);
+        self.dirty_meta = true;
+        self.write_inode(ROOT_INO, &mut inode)
+    }
+
+    /// Gives a new directory inode its first block holding "." and "..".
+    fn write_dir_block(&mut self, ino: u32, inode: &mut Inode, parent: u32) -> io::Result<()> {
+        let bs = self.block_size as usize;
+        let goal = self.gds.inode_table((ino - 1) / self.sb.inodes_per_group()) + self.inode_table_blocks();
+        let (block, _) = self.alloc_blocks(goal, 1)?;
+        let csum = self.sb.has_metadata_csum();
+        let usable = if csum { bs - DIR_TAIL_SIZE } else { bs };
+        let mut buf = vec![0u8; bs];
+        put_dirent(&mut buf, 0, ino, 12, b".", FT_DIR);
+        put_dirent(&mut buf, 12, parent, usable - 12, b"..", FT_DIR);
+        put_rec_len(&mut buf, 12, usable - 12);
+        if csum {
+            put_dir_tail(&mut buf, self.inode_seed(ino, inode));
+        }
+        self.write_block(block, &buf)?;
+        inode.set_links(2);
+        inode.set_size(bs as u64);
+        self.set_extents(ino, inode, &[Extent { logical: 0, len: 1, start: block, uninit: false }], &[])
+    }
+
+    pub(crate) fn mkdir_in(&mut self, parent: u32, name: &[u8], meta: &NodeMeta) -> io::Result<u32> {
+        self.check_writable()?;
+        let goal_group = (parent - 1) / self.sb.inodes_per_group();
+        let ino = self.alloc_inode(true, goal_group)?;
+        let mut inode = self.new_inode(S_IFDIR, meta);
+        self.write_dir_block(ino, &mut inode, parent)?;
+        self.write_inode(ino, &mut inode)?;
+        self.add_entry(parent, name, ino, FT_DIR)?;
+        self.bump_links(parent, 1)?;
+        Ok(ino)
+    }
+
+    /// Writes `data` as the body of a new regular-file inode, leaving
+    /// all-zero blocks as holes.
+    fn write_new_data(&mut self, ino: u32, inode: &mut Inode, data: &mut dyn Read) -> io::Result<()> {
+        let bs = self.block_size as usize;
+        let chunk_blocks = 256;
+        let mut buf = vec![0u8; bs * chunk_blocks];
+        let mut extents: Vec<Extent> = Vec::new();
+        let mut logical = 0u64;
+        let mut size = 0u64;
+        let mut goal = self.gds.inode_table((ino - 1) / self.sb.inodes_per_group()) + self.inode_table_blocks();
+        let limit = self.dev.len()?;
+
+        loop {
+            let mut filled = 0;
+            while filled < buf.len() {
+                let n = data.read(&mut buf[filled..])?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            if filled == 0 {
+                break;
+            }
+            size += filled as u64;
+            // Files cannot be read back if they are larger than the device, holes or not
+            if size > limit {
+                return Err(io::Error::from_raw_os_error(libc::EFBIG));
+            }
+            buf[filled..].fill(0);
+            let blocks = filled.div_ceil(bs);
+
+            let mut i = 0;
+            while i < blocks {
+                if buf[i * bs..(i + 1) * bs].iter().all(|&b| b == 0) {
+                    i += 1;
+                    continue;
+                }
+                let mut j = i + 1;
+                while j < blocks && buf[j * bs..(j + 1) * bs].iter().any(|&b| b != 0) {
+                    j += 1;
+                }
+                // Blocks i..j hold data; allocate and write them, possibly in pieces
+                while i < j {
+                    let (start, got) = self.alloc_blocks(goal, (j - i) as u64)?;
+                    let got = got as usize;
+                    self.dev.write_at(start * bs as u64, &buf[i * bs..(i + got) * bs])?;
+                    let l = (logical + i as u64) as u32;
+                    if l as u64 != logical + i as u64 {
+                        return Err(io::Error::from_raw_os_error(libc::EFBIG));
+                    }
+                    let mut remaining = got as u32;
+                    let mut phys = start;
+                    let mut l = l;
+                    while remaining > 0 {
+                        match extents.last_mut() {
+                            Some(last)
+                                if last.logical + last.len == l
+                                    && last.start + last.len as u64 == phys
+                                    && last.len < EXTENT_MAX_LEN =>
+                            {
+                                let add = remaining.min(EXTENT_MAX_LEN - last.len);
+                                last.len += add;
+                                remaining -= add;
+                                phys += add as u64;
+                                l += add;
+                            }
+                            _ => {
+                                let add = remaining.min(EXTENT_MAX_LEN);
+                                extents.push(Extent { logical: l, len: add, start: phys, uninit: false });
+                                remaining -= add;
+                                phys += add as u64;
+                                l += add;
+                            }
+                        }
+                    }
+                    goal = start + got as u64;
+                    i += got;
+                }
+            }
+            logical += blocks as u64;
+            if filled < buf.len() {
+                break;
+            }
+        }
+
+        self.set_extents(ino, inode, &extents, &[])?;
+        inode.set_size(size);
+        Ok(())
+    }
+
+    pub(crate) fn create_file_in(&mut self, parent: u32, name: &[u8], data: &mut dyn Read, meta: &NodeMeta) -> io::Result<u32> {
+        self.check_writable()?;
+        if self.find_entry(parent, name)?.is_some() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        let goal_group = (parent - 1) / self.sb.inodes_per_group();
+        let ino = self.alloc_inode(false, goal_group)?;
+        let mut inode = self.new_inode(S_IFREG, meta);
+        inode.set_links(1);
+        self.write_new_data(ino, &mut inode, data)?;
+        self.write_inode(ino, &mut inode)?;
+        self.add_entry(parent, name, ino, FT_REG)?;
+        Ok(ino)
+    }
+
+    fn symlink_in(&mut self, parent: u32, name: &[u8], target: &[u8], meta: &NodeMeta) -> io::Result<u32> {
+        self.check_writable()?;
+        if self.find_entry(parent, name)?.is_some() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        let goal_group = (parent - 1) / self.sb.inodes_per_group();
+        let ino = self.alloc_inode(false, goal_group)?;
+        let mut inode = self.new_inode(S_IFLNK, &NodeMeta { mode: 0o777, ..*meta });
+        inode.set_links(1);
+        if target.len() < 60 {
+            inode.block_mut()[..target.len()].copy_from_slice(target);
+            inode.set_size(target.len() as u64);
+        } else {
+            self.write_new_data(ino, &mut inode, &mut &target[..])?;
+        }
+        self.write_inode(ino, &mut inode)?;
+        self.add_entry(parent, name, ino, FT_SYMLINK)?;
+        Ok(ino)
+    }
+
+    fn special_in(&mut self, parent: u32, name: &[u8], kind: SpecialKind, meta: &NodeMeta) -> io::Result<u32> {
+        self.check_writable()?;
+        if self.find_entry(parent, name)?.is_some() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        let goal_group = (parent - 1) / self.sb.inodes_per_group();
+        let ino = self.alloc_inode(false, goal_group)?;
+        let (mode, dev) = match kind {
+            SpecialKind::Fifo => (S_IFIFO, None),
+            SpecialKind::Socket => (S_IFSOCK, None),
+            SpecialKind::CharDevice { major, minor } => (S_IFCHR, Some((major, minor))),
+            SpecialKind::BlockDevice { major, minor } => (S_IFBLK, Some((major, minor))),
+        };
+        let mut inode = self.new_inode(mode, meta);
+        inode.set_links(1);
+        if let Some((major, minor)) = dev {
+            let i_block = inode.block_mut();
+            if major < 256 && minor < 256 {
+                put_le32(i_block, 0, major << 8 | minor);
+            } else {
+                put_le32(i_block, 4, (minor & 0xFF) | (major << 8) | ((minor & !0xFF) << 12));
+            }
+        }
+        self.write_inode(ino, &mut inode)?;
+        self.add_entry(parent, name, ino, file_type_for_mode(mode))?;
+        Ok(ino)
+    }
+}
+
+impl<D: BlockDevice> Ext4<D> {
+    /// Drops the name `name` from directory `dir`, folding its record into
+    /// the previous one in the same block.
+    fn remove_entry(&mut self, dir: u32, name: &[u8]) -> io::Result<()> {
+        let inode = self.read_inode(dir)?;
+        let seed = self.inode_seed(dir, &inode);
+        let (extents, _) = self.file_extents(&inode)?;
+        for e in extents.iter().filter(|e| !e.uninit) {
+            for i in 0..e.len as u64 {
+                let mut block = self.read_block(e.start + i)?;
+                let mut prev = None;
+                let mut off = 0;
+                while off + 8 <= block.len() {
+                    let len = rec_len(&block, off);
+                    if len < 8 || off + len > block.len() {
+                        return Err(invalid(format!("corrupt directory entry in inode {}", dir)));
+                    }
+                    let name_len = block[off + 6] as usize;
+                    if le32(&block, off) != 0 && name_len + 8 <= len && &block[off + 8..off + 8 + name_len] == name {
+                        match prev {
+                            Some(p) => {
+                                let merged = rec_len(&block, p) + len;
+                                put_rec_len(&mut block, p, merged);
+                            }
+                            None => put_le32(&mut block, off, 0),
+                        }
+                        if self.sb.has_metadata_csum() && has_dir_tail(&block) {
+                            put_dir_tail(&mut block, seed);
+                        }
+                        return self.write_block(e.start + i, &block);
+                    }
+                    prev = Some(off);
+                    off += len;
+                }
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    /// Points the existing name `name` in directory `dir` at `child`.
+    fn retarget_entry(&mut self, dir: u32, name: &[u8], child: u32, file_type: u8) -> io::Result<()> {
+        let inode = self.read_inode(dir)?;
+        let seed = self.inode_seed(dir, &inode);
+        let (extents, _) = self.file_extents(&inode)?;
+        for e in extents.iter().filter(|e| !e.uninit) {
+            for i in 0..e.len as u64 {
+                let mut block = self.read_block(e.start + i)?;
+                let mut off = 0;
+                while off + 8 <= block.len() {
+                    let len = rec_len(&block, off);
+                    if len < 8 || off + len > block.len() {
+                        return Err(invalid(format!("corrupt directory entry in inode {}", dir)));
+                    }
+                    let name_len = block[off + 6] as usize;
+                    if le32(&block, off) != 0 && name_len + 8 <= len && &block[off + 8..off + 8 + name_len] == name {
+                        put_le32(&mut block, off, child);
+                        block[off + 7] = file_type;
+                        if self.sb.has_metadata_csum() && has_dir_tail(&block) {
+                            put_dir_tail(&mut block, seed);
+                        }
+                        return self.write_block(e.start + i, &block);
+                    }
+                    off += len;
+                }
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    /// Drops one link to a non-directory, freeing it with the last.
+    fn drop_link(&mut self, ino: u32, inode: &mut Inode) -> io::Result<()> {
+        if inode.links() <= 1 {
+            self.release_inode(ino, inode)
+        } else {
+            inode.set_links(inode.links() - 1);
+            self.write_inode(ino, inode)
+        }
+    }
+
+    /// Frees the blocks and inode of a file whose last link is gone.
+    fn release_inode(&mut self, ino: u32, inode: &mut Inode) -> io::Result<()> {
+        let owns_blocks = matches!(inode.file_type(), S_IFREG | S_IFDIR | S_IFLNK)
+            && !self.is_fast_symlink(inode)
+            && inode.flags() & INODE_FLAG_INLINE_DATA == 0;
+        if owns_blocks {
+            let (extents, tree) = self.file_extents(inode)?;
+            for e in &extents {
+                self.free_block_range(e.start, e.len as u64)?;
+            }
+            for &b in &tree {
+                self.free_block_range(b, 1)?;
+            }
+        }
+        if inode.file_acl() != 0 {
+            self.release_xattr_block(inode.file_acl())?;
+            inode.set_file_acl(0);
+        }
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0);
+        inode.set_links(0);
+        inode.set_dtime(now);
+        self.write_inode(ino, inode)?;
+        self.free_inode(ino, inode.is_dir())
+    }
+
+    /// Drops one reference to a shared extended-attribute block.
+    fn release_xattr_block(&mut self, block: u64) -> io::Result<()> {
+        let mut buf = self.read_block(block)?;
+        if le32(&buf, 0) != XATTR_MAGIC {
+            return Err(invalid(format!("corrupt xattr block {}", block)));
+        }
+        let refs = le32(&buf, 4);
+        if refs <= 1 {
+            return self.free_block_range(block, 1);
+        }
+        put_le32(&mut buf, 4, refs - 1);
+        if self.sb.has_metadata_csum() {
+            put_le32(&mut buf, 0x10, 0);
+            let c = crc32c_raw(self.sb.csum_seed(), &block.to_le_bytes());
+            let c = crc32c_raw(c, &buf);
+            put_le32(&mut buf, 0x10, c);
+        }
+        self.write_block(block, &buf)
+    }
+
+    /// Removes a file, symlink, special file or empty directory.
+    pub(crate) fn unlink(&mut self, path: &Path) -> io::Result<()> {
+        self.check_writable()?;
+        let (parent, name) = self.resolve_parent(path)?;
+        if name == b"." || name == b".." {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let ino = self
+            .find_entry(parent, name)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display())))?;
+        let mut inode = self.read_inode(ino)?;
+        let is_dir = inode.is_dir();
+        if is_dir && self.read_dir(ino)?.iter().any(|e| e.name != b"." && e.name != b"..") {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+
+        self.remove_entry(parent, name)?;
+        if is_dir {
+            self.bump_links(parent, -1)?;
+            self.release_inode(ino, &mut inode)
+        } else {
+            self.drop_link(ino, &mut inode)
+        }
+    }
+
+    /// Moves a non-directory; an existing file at `to` is swapped out by
+    /// rewriting its directory entry in place.
+    pub(crate) fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check_writable()?;
+        let (from_parent, from_name) = self.resolve_parent(from)?;
+        let ino = self
+            .find_entry(from_parent, from_name)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", from.display())))?;
+        let inode = self.read_inode(ino)?;
+        if inode.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        let file_type = file_type_for_mode(inode.mode());
+        let (to_parent, to_name) = self.resolve_parent(to)?;
+        match self.find_entry(to_parent, to_name)? {
+            Some(old) if old == ino => Ok(()),
+            Some(old) => {
+                let mut replaced = self.read_inode(old)?;
+                if replaced.is_dir() {
+                    return Err(io::Error::from_raw_os_error(libc::EISDIR));
+                }
+                self.retarget_entry(to_parent, to_name, ino, file_type)?;
+                self.remove_entry(from_parent, from_name)?;
+                self.drop_link(old, &mut replaced)
+            }
+            None => {
+                self.add_entry(to_parent, to_name, ino, file_type)?;
+                self.remove_entry(from_parent, from_name)
+            }
+        }
+    }
+
+    pub(crate) fn list_dir(&mut self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let dir = self.lookup_follow(path)?;
+        let mut out = Vec::new();
+        for entry in self.read_dir(dir)? {
+            if entry.name == b"." || entry.name == b".." {
+                continue;
+            }
+            let inode = self.read_inode(entry.ino)?;
+            out.push(DirEntry {
+                name: String::from_utf8_lossy(&entry.name).into_owned(),
+                is_dir: inode.is_dir(),
+                is_symlink: inode.file_type() == S_IFLNK,
+                len: inode.size(),
+            });
+        }
+        Ok(out)
+    }
+}
+
+impl<D: BlockDevice> ImageFs for Ext4<D> {
+    fn create_dir(&mut self, path: &Path, meta: &NodeMeta) -> io::Result<()> {
+        let (parent, name) = self.resolve_parent(path)?;
+        self.mkdir_in(parent, name, meta).map(drop)
+    }
+
+    fn create_file(&mut self, path: &Path, data: &mut dyn Read, meta: &NodeMeta) -> io::Result<()> {
+        let (parent, name) = self.resolve_parent(path)?;
+        self.create_file_in(parent, name, data, meta).map(drop)
+    }
+
+    fn create_symlink(&mut self, path: &Path, target: &Path, meta: &NodeMeta) -> io::Result<()> {
+        let (parent, name) = self.resolve_parent(path)?;
+        self.symlink_in(parent, name, target.as_os_str().as_bytes(), meta).map(drop)
+    }
+
+    fn create_hardlink(&mut self, path: &Path, existing: &Path) -> io::Result<()> {
+        self.check_writable()?;
+        let target = self.lookup(existing)?;
+        let inode = self.read_inode(target)?;
+        if inode.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        let (parent, name) = self.resolve_parent(path)?;
+        self.add_entry(parent, name, target, file_type_for_mode(inode.mode()))?;
+        self.bump_links(target, 1)
+    }
+
+    fn create_special(&mut self, path: &Path, kind: SpecialKind, meta: &NodeMeta) -> io::Result<()> {
+        let (parent, name) = self.resolve_parent(path)?;
+        self.special_in(parent, name, kind, meta).map(drop)
+    }
+
+    fn read_file(&mut self, path: &Path, out: &mut dyn Write) -> io::Result<u64> {
+        let ino = self.lookup_follow(path)?;
+        let inode = self.read_inode(ino)?;
+        match inode.file_type() {
+            S_IFREG => self.read_data(&inode, out),
+            S_IFDIR => Err(io::Error::from_raw_os_error(libc::EISDIR)),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    fn list(&mut self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        self.list_dir(path)
+    }
+
+    fn metadata(&mut self, path: &Path) -> io::Result<DirEntry> {
+        let ino = self.lookup(path)?;
+        let inode = self.read_inode(ino)?;
+        Ok(DirEntry {
+            name: path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+            is_dir: inode.is_dir(),
+            is_symlink: inode.file_type() == S_IFLNK,
+            len: inode.size(),
+        })
+    }
+
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        self.unlink(path)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        Ext4::rename(self, from, to)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ext4::sync(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::FormatOptions;
+    use crate::testutil::scratch_file;
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+
+    /// A fresh filesystem with 1 KiB blocks in a scratch file.
+    fn image(name: &str) -> File {
+        let file = scratch_file(&format!("ext4-{}", name));
+        file.set_len(4 << 20).unwrap();
+        let options = FormatOptions { block_size: Some(1024), ..FormatOptions::default() };
+        mkfs::format(file.try_clone().unwrap(), &options).unwrap();
+        file
+    }
+
+    fn open(file: &File, writable: bool) -> io::Result<Ext4<File>> {
+        Ext4::open(file.try_clone().unwrap(), writable)
+    }
+
+    fn poke(file: &File, offset: u64, bytes: &[u8]) {
+        file.write_all_at(bytes, offset).unwrap();
+    }
+
+    #[test]
+    fn superblocks_with_impossible_geometry_are_refused() {
+        let file = image("geometry");
+        let mut original = vec![0; SUPERBLOCK_SIZE];
+        file.read_exact_at(&mut original, SUPERBLOCK_OFFSET).unwrap();
+        let fields: [(usize, &[u8]); 10] = [
+            (0x18, &40u32.to_le_bytes()),
+            (0x18, &7u32.to_le_bytes()),
+            (0x20, &0u32.to_le_bytes()),
+            (0x20, &u32::MAX.to_le_bytes()),
+            (0x28, &0u32.to_le_bytes()),
+            (0x28, &9000u32.to_le_bytes()),
+            (0x58, &100u16.to_le_bytes()),
+            (0x04, &1u32.to_le_bytes()),
+            (0x04, &u32::MAX.to_le_bytes()),
+            (0x00, &7u32.to_le_bytes()),
+        ];
+        for (offset, bytes) in fields {
+            poke(&file, SUPERBLOCK_OFFSET + offset as u64, bytes);
+            let e = open(&file, false).err().unwrap_or_else(|| panic!("field {:#x} accepted", offset));
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "field {:#x}", offset);
+            poke(&file, SUPERBLOCK_OFFSET, &original);
+        }
+        assert!(open(&file, false).is_ok());
+
+        // An inode table beyond the last block
+        let fs = open(&file, false).unwrap();
+        let gdt = (fs.sb.first_data_block() as u64 + 1) * fs.block_size;
+        poke(&file, gdt + 8, &u32::MAX.to_le_bytes());
+        assert_eq!(open(&file, false).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn wrong_counts_and_sizes_are_errors() {
+        let file = image("counts");
+        let mut fs = open(&file, true).unwrap();
+        fs.create_file(Path::new("/a"), &mut &b"hello"[..], &NodeMeta::now(0o644)).unwrap();
+        fs.sync().unwrap();
+
+        // A file far larger than the device
+        let ino = fs.lookup(Path::new("/a")).unwrap();
+        let mut inode = fs.read_inode(ino).unwrap();
+        inode.set_size(1 << 50);
+        fs.write_inode(ino, &mut inode).unwrap();
+        let e = fs.read_file(Path::new("/a"), &mut io::sink()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // No free inodes or blocks left, going by the superblock
+        fs.sb.set_free_inodes(0);
+        fs.sb.set_free_blocks(0);
+        for path in ["/b", "/c"] {
+            let e = fs.create_file(Path::new(path), &mut &b"data"[..], &NodeMeta::now(0o644)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            fs.sb.set_free_inodes(100);
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! Formats a block device as FAT12, FAT16 or FAT32 without mkfs.vfat.
+
+use super::{Fat, FatType, Geometry};
+use crate::blockdev::BlockDevice;
+use crate::bytes::{put_le16, put_le32};
+use crate::filesystem::FormatOptions;
+use crate::uuid;
+use std::io;
+
+const SECTOR_SIZE: u32 = 512;
+const ROOT_ENTRIES: u32 = 512;
+const MEDIA_FIXED: u8 = 0xF8;
+
+fn invalid_input(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
+
+/// Cluster size mkfs.vfat would roughly pick for a volume of `bytes`.
+fn default_cluster_size(bytes: u64) -> u32 {
+    const MIB: u64 = 1024 * 1024;
+    match bytes {
+        b if b <= 256 * MIB => 2048,
+        b if b <= 8192 * MIB => 4096,
+        b if b <= 16384 * MIB => 8192,
+        b if b <= 32768 * MIB => 16384,
+        _ => 32768,
+    }
+}
+
+/// Lays out a volume of the given kind, or `None` if the cluster count it
+/// ends up with belongs to a different FAT width.
+fn plan(kind: FatType, total_sectors: u32, sectors_per_cluster: u32) -> Option<Geometry> {
+    let (reserved_sectors, root_entries) = match kind {
+        FatType::Fat32 => (32, 0),
+        _ => (1, ROOT_ENTRIES),
+    };
+    let mut geo = Geometry {
+        kind,
+        sector_size: SECTOR_SIZE,
+        sectors_per_cluster,
+        reserved_sectors,
+        fats: 2,
+        fat_sectors: 1,
+        root_entries,
+        total_sectors,
+        root_cluster: if kind == FatType::Fat32 { 2 } else { 0 },
+        fsinfo_sector: if kind == FatType::Fat32 { 1 } else { 0 },
+    };
+    // Growing the FAT shrinks the data area, so iterate to a fixed point
+    loop {
+        if geo.data_sector() + sectors_per_cluster > total_sectors {
+            return None;
+        }
+        let needed = kind.table_bytes(geo.clusters() as u64 + 2).div_ceil(SECTOR_SIZE as u64) as u32;
+        if needed <= geo.fat_sectors {
+            break;
+        }
+        geo.fat_sectors = needed;
+    }
+    (FatType::for_clusters(geo.clusters()) == kind).then_some(geo)
+}
+
+fn volume_id(options: &FormatOptions) -> io::Result<u32> {
+    match &options.uuid {
+        Some(s) => {
+            let hex = s.replace('-', "");
+            if hex.len() != 8 {
+                return Err(invalid_input(format!("invalid FAT volume id {}", s)));
+            }
+            u32::from_str_radix(&hex, 16).map_err(|_| invalid_input(format!("invalid FAT volume id {}", s)))
+        }
+        None => {
+            let mut b = [0u8; 4];
+            uuid::random_bytes(&mut b)?;
+            Ok(u32::from_le_bytes(b))
+        }
+    }
+}
+
+fn volume_label(options: &FormatOptions) -> io::Result<Option<[u8; 11]>> {
+    let Some(label) = &options.label else {
+        return Ok(None);
+    };
+    let upper = label.to_ascii_uppercase();
+    if upper.is_empty() || upper.len() > 11 || !upper.bytes().all(|c| c.is_ascii_graphic() || c == b' ') {
+        return Err(invalid_input(format!("invalid FAT label {:?}", label)));
+    }
+    let mut out = [b' '; 11];
+    out[..upper.len()].copy_from_slice(upper.as_bytes());
+    Ok(Some(out))
+}
+
+fn boot_sector(geo: &Geometry, id: u32, label: &[u8; 11]) -> [u8; 512] {
+    let mut b = [0u8; 512];
+    let fat32 = geo.kind == FatType::Fat32;
+    b[..3].copy_from_slice(if fat32 { &[0xEB, 0x58, 0x90] } else { &[0xEB, 0x3C, 0x90] });
+    b[3..11].copy_from_slice(b"MIZZLE  ");
+    put_le16(&mut b, 0x0B, geo.sector_size as u16);
+    b[0x0D] = geo.sectors_per_cluster as u8;
+    put_le16(&mut b, 0x0E, geo.reserved_sectors as u16);
+    b[0x10] = geo.fats as u8;
+    put_le16(&mut b, 0x11, geo.root_entries as u16);
+    if geo.total_sectors < 65536 && !fat32 {
+        put_le16(&mut b, 0x13, geo.total_sectors as u16);
+    } else {
+        put_le32(&mut b, 0x20, geo.total_sectors);
+    }
+    b[0x15] = MEDIA_FIXED;
+    put_le16(&mut b, 0x18, 32);
+    put_le16(&mut b, 0x1A, 64);
+
+    let ext = if fat32 {
+        put_le32(&mut b, 0x24, geo.fat_sectors);
+        put_le32(&mut b, 0x2C, geo.root_cluster);
+        put_le16(&mut b, 0x30, geo.fsinfo_sector as u16);
+        put_le16(&mut b, 0x32, 6);
+        0x40
+    } else {
+        put_le16(&mut b, 0x16, geo.fat_sectors as u16);
+        0x24
+    };
+    b[ext] = 0x80;
+    b[ext + 2] = 0x29;
+    put_le32(&mut b, ext + 3, id);
+    b[ext + 7..ext + 18].copy_from_slice(label);
+    b[ext + 18..ext + 26].copy_from_slice(match geo.kind {
+        FatType::Fat12 => b"FAT12   ",
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    });
+    b[510] = 0x55;
+    b[511] = 0xAA;
+    b
+}
+
+/// Writes an empty FAT filesystem to `dev`, picking FAT12, 16 or 32 from
+/// the resulting cluster count, and returns it opened.
+pub fn format<D: BlockDevice>(mut dev: D, options: &FormatOptions) -> io::Result<Fat<D>> {
+    if options.inode_ratio.is_some() || options.reserved_percent.is_some() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "FAT has no inode ratio or reserved blocks"));
+    }
+    let bytes = dev.len()?;
+    let total_sectors = u32::try_from(bytes / SECTOR_SIZE as u64)
+        .map_err(|_| invalid_input("image is too large for FAT"))?;
+    let cluster = options.block_size.unwrap_or_else(|| default_cluster_size(bytes));
+    if !cluster.is_power_of_two() || !(SECTOR_SIZE..=65536).contains(&cluster) {
+        return Err(invalid_input(format!("invalid FAT cluster size {}", cluster)));
+    }
+    let spc = cluster / SECTOR_SIZE;
+    let geo = [FatType::Fat12, FatType::Fat16, FatType::Fat32]
+        .into_iter()
+        .find_map(|kind| plan(kind, total_sectors, spc))
+        .ok_or_else(|| invalid_input(format!("no FAT layout fits {} bytes with {}-byte clusters", bytes, cluster)))?;
+
+    let id = volume_id(options)?;
+    let label = volume_label(options)?;
+    let boot = boot_sector(&geo, id, label.as_ref().unwrap_or(b"NO NAME    "));
+    let ss = SECTOR_SIZE as u64;
+
+    // Reserved area, both FATs and the fixed root directory start out zeroed
+    let zeros = vec![0u8; 64 * 1024];
+    let mut off = 0;
+    let end = geo.data_sector() as u64 * ss;
+    while off < end {
+        let n = (end - off).min(zeros.len() as u64);
+        dev.write_at(off, &zeros[..n as usize])?;
+        off += n;
+    }
+    dev.write_at(0, &boot)?;
+
+    let mut fat = vec![0u8; (ss * 2) as usize];
+    match geo.kind {
+        FatType::Fat12 => fat[..3].copy_from_slice(&[MEDIA_FIXED, 0xFF, 0xFF]),
+        FatType::Fat16 => {
+            put_le16(&mut fat, 0, 0xFF00 | MEDIA_FIXED as u16);
+            put_le16(&mut fat, 2, 0xFFFF);
+        }
+        FatType::Fat32 => {
+            put_le32(&mut fat, 0, 0x0FFF_FF00 | MEDIA_FIXED as u32);
+            put_le32(&mut fat, 4, 0x0FFF_FFFF);
+            // Root directory cluster
+            put_le32(&mut fat, 8, 0x0FFF_FFFF);
+        }
+    }
+    for i in 0..geo.fats {
+        dev.write_at((geo.reserved_sectors + i * geo.fat_sectors) as u64 * ss, &fat[..ss as usize])?;
+    }
+
+    if geo.kind == FatType::Fat32 {
+        let mut info = [0u8; 512];
+        put_le32(&mut info, 0, 0x4161_5252);
+        put_le32(&mut info, 0x1E4, 0x6141_7272);
+        put_le32(&mut info, 0x1E8, geo.clusters() - 1);
+        put_le32(&mut info, 0x1EC, 3);
+        put_le32(&mut info, 0x1FC, 0xAA55_0000);
+        dev.write_at(ss, &info)?;
+        dev.write_at(6 * ss, &boot)?;
+        dev.write_at(7 * ss, &info)?;
+        let root = vec![0u8; geo.cluster_size() as usize];
+        dev.write_at(geo.data_sector() as u64 * ss, &root)?;
+    }
+
+    if let Some(label) = label {
+        let mut entry = [0u8; 32];
+        entry[..11].copy_from_slice(&label);
+        entry[11] = super::ATTR_VOLUME_ID;
+        let root = if geo.kind == FatType::Fat32 {
+            geo.data_sector() as u64 * ss
+        } else {
+            (geo.reserved_sectors + geo.fats * geo.fat_sectors) as u64 * ss
+        };
+        dev.write_at(root, &entry)?;
+    }
+    dev.flush()?;
+
+    Fat::open(dev)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::Filesystem;
+    use crate::imagefs::{format_image, ImageFs};
+    use crate::testutil::{fsck, gapped_data, host_tree, pattern, scratch_file, ScratchDir};
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    fn read(fs: &mut Fat<File>, path: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        fs.read_file(Path::new(path), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn images_built_from_a_tree_read_back() {
+        let layouts = [
+            (2 << 20, None, FatType::Fat12),
+            (16 << 20, None, FatType::Fat16),
+            (64 << 20, Some(512), FatType::Fat32),
+        ];
+        for (size, block_size, kind) in layouts {
+            let dir = ScratchDir::new(&format!("fat-mkfs-tree-{:?}", kind));
+            let tree = dir.join("tree");
+            host_tree(&tree, false);
+            let image = dir.join("image");
+            File::create(&image).unwrap().set_len(size).unwrap();
+            let options = FormatOptions { block_size, ..FormatOptions::default() };
+            format_image(&image, Filesystem::Vfat, &options, Some(&tree)).unwrap();
+
+            let mut fs = Fat::open(File::open(&image).unwrap()).unwrap();
+            assert_eq!(fs.fat_type(), kind);
+            assert_eq!(read(&mut fs, "/top.txt"), b"hello");
+            assert_eq!(read(&mut fs, "/a/b/c/deep.txt"), pattern(5000, 9));
+            assert_eq!(read(&mut fs, "/big.bin"), gapped_data());
+            // FAT has no links, so both kinds become copies
+            assert_eq!(read(&mut fs, "/a/hard.txt"), b"hello");
+            assert_eq!(read(&mut fs, "/link"), b"hello");
+            let mut names: Vec<_> = fs.list(Path::new("/a")).unwrap().into_iter().map(|e| e.name).collect();
+            names.sort();
+            assert_eq!(names, ["b", "empty", "hard.txt"]);
+            assert!(fs.list(Path::new("/a/empty")).unwrap().is_empty());
+
+            let big = fs.lookup(Path::new("/big.bin")).unwrap().unwrap();
+            let clusters = fs.chain(big.cluster).unwrap();
+            assert_eq!(clusters.len() as u64, (gapped_data().len() as u64).div_ceil(fs.cluster_size()));
+            drop(fs);
+            fsck("fsck.vfat", &["-n"], &image);
+        }
+    }
+
+    #[test]
+    fn label_and_volume_id_reach_the_boot_sector() {
+        for (size, offset) in [(16 << 20, 0x27), (64 << 20, 0x43)] {
+            let file = scratch_file(&format!("fat-mkfs-label-{}", size));
+            file.set_len(size).unwrap();
+            let block_size = (size > 32 << 20).then_some(512);
+            let options = FormatOptions {
+                label: Some("scratch".into()),
+                uuid: Some("1234-ABCD".into()),
+                block_size,
+                ..FormatOptions::default()
+            };
+            format(file.try_clone().unwrap(), &options).unwrap();
+            let mut fields = [0u8; 15];
+            file.read_exact_at(&mut fields, offset).unwrap();
+            assert_eq!(fields[..4], 0x1234ABCDu32.to_le_bytes());
+            assert_eq!(&fields[4..], b"SCRATCH    ");
+        }
+    }
+
+    #[test]
+    fn impossible_options_are_refused() {
+        let file = scratch_file("fat-mkfs-refused");
+        file.set_len(16 << 20).unwrap();
+        let refused = [
+            FormatOptions { block_size: Some(3000), ..FormatOptions::default() },
+            FormatOptions { block_size: Some(256), ..FormatOptions::default() },
+            FormatOptions { label: Some("a label too long".into()), ..FormatOptions::default() },
+            FormatOptions { uuid: Some("1234".into()), ..FormatOptions::default() },
+        ];
+        for options in &refused {
+            let e = format(file.try_clone().unwrap(), options).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+        let options = FormatOptions { inode_ratio: Some(4096), ..FormatOptions::default() };
+        let e = format(file, &options).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,910 @@
+//! A userspace FAT12/16/32 implementation with long file names, enough to
+//! build and edit vfat images.
+
+pub mod mkfs;
+
+use crate::blockdev::BlockDevice;
+use crate::bytes::{le16, le32, put_le16, put_le32};
+use crate::imagefs::{split_parent, ImageFs, NodeMeta};
+use crate::ops::DirEntry;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path};
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0F;
+const DELETED: u8 = 0xE5;
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+const LFN_CHARS: usize = 13;
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Picks the FAT width the way every implementation does: by cluster count.
+    pub fn for_clusters(clusters: u32) -> Self {
+        if clusters < 4085 {
+            FatType::Fat12
+        } else if clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    fn eoc(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    fn is_eoc(self, v: u32) -> bool {
+        v >= self.eoc() - 7
+    }
+
+    /// Bytes needed for `entries` FAT entries.
+    fn table_bytes(self, entries: u64) -> u64 {
+        match self {
+            FatType::Fat12 => (entries * 3).div_ceil(2),
+            FatType::Fat16 => entries * 2,
+            FatType::Fat32 => entries * 4,
+        }
+    }
+}
+
+/// Where things live on a FAT volume, as described by its boot sector.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Geometry {
+    pub kind: FatType,
+    pub sector_size: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fats: u32,
+    pub fat_sectors: u32,
+    pub root_entries: u32,
+    pub total_sectors: u32,
+    pub root_cluster: u32,
+    pub fsinfo_sector: u32,
+}
+
+impl Geometry {
+    fn parse(boot: &[u8]) -> io::Result<Self> {
+        if boot[510] != 0x55 || boot[511] != 0xAA {
+            return Err(invalid("no FAT boot signature"));
+        }
+        let sector_size = le16(boot, 0x0B) as u32;
+        let sectors_per_cluster = boot[0x0D] as u32;
+        if !sector_size.is_power_of_two() || !(512..=4096).contains(&sector_size) || !sectors_per_cluster.is_power_of_two() {
+            return Err(invalid("not a FAT filesystem"));
+        }
+        let total16 = le16(boot, 0x13) as u32;
+        let fat16 = le16(boot, 0x16) as u32;
+        let mut g = Geometry {
+            kind: FatType::Fat12,
+            sector_size,
+            sectors_per_cluster,
+            reserved_sectors: le16(boot, 0x0E) as u32,
+            fats: boot[0x10] as u32,
+            fat_sectors: if fat16 != 0 { fat16 } else { le32(boot, 0x24) },
+            root_entries: le16(boot, 0x11) as u32,
+            total_sectors: if total16 != 0 { total16 } else { le32(boot, 0x20) },
+            root_cluster: 0,
+            fsinfo_sector: 0,
+        };
+        if g.fats == 0 || g.fat_sectors == 0 || g.data_sector() >= g.total_sectors {
+            return Err(invalid("corrupt FAT boot sector"));
+        }
+        g.kind = FatType::for_clusters(g.clusters());
+        if g.kind == FatType::Fat32 {
+            g.root_cluster = le32(boot, 0x2C);
+            g.fsinfo_sector = le16(boot, 0x30) as u32;
+        }
+        Ok(g)
+    }
+
+    pub fn root_dir_sectors(&self) -> u32 {
+        (self.root_entries * DIR_ENTRY_SIZE as u32).div_ceil(self.sector_size)
+    }
+
+    pub fn data_sector(&self) -> u32 {
+        self.reserved_sectors + self.fats * self.fat_sectors + self.root_dir_sectors()
+    }
+
+    pub fn clusters(&self) -> u32 {
+        (self.total_sectors - self.data_sector()) / self.sectors_per_cluster
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        (self.sector_size * self.sectors_per_cluster) as u64
+    }
+}
+
+/// A directory: the fixed FAT12/16 root region or a cluster chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Dir {
+    Root,
+    Cluster(u32),
+}
+
+/// A parsed directory entry together with the slots it occupies.
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub name: String,
+    pub short: [u8; 11],
+    pub attr: u8,
+    pub cluster: u32,
+    pub size: u32,
+    /// Byte offset of the first slot (LFN or short) within the directory.
+    pub first_slot: usize,
+    /// Byte offset of the short entry.
+    pub slot: usize,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+pub struct Fat<D: BlockDevice> {
+    dev: D,
+    geo: Geometry,
+    fat: Vec<u8>,
+    fat_dirty: bool,
+    next_free: u32,
+    free_count: u32,
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+impl<D: BlockDevice> Fat<D> {
+    pub fn open(mut dev: D) -> io::Result<Self> {
+        let mut boot = [0u8; 512];
+        dev.read_at(0, &mut boot)?;
+        let geo = Geometry::parse(&boot)?;
+        let entries = geo.clusters() as u64 + 2;
+        let mut fat = vec![0u8; geo.kind.table_bytes(entries) as usize];
+        dev.read_at(geo.reserved_sectors as u64 * geo.sector_size as u64, &mut fat)?;
+
+        let mut fs = Fat { dev, geo, fat, fat_dirty: false, next_free: 2, free_count: 0 };
+        fs.free_count = (2..entries as u32).filter(|&c| fs.get(c) == 0).count() as u32;
+        Ok(fs)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.geo.kind
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.geo.cluster_size()
+    }
+
+    pub fn free_clusters(&self) -> u32 {
+        self.free_count
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    // --- allocation table ---
+
+    fn get(&self, n: u32) -> u32 {
+        let n = n as usize;
+        match self.geo.kind {
+            FatType::Fat12 => {
+                let off = n + n / 2;
+                let v = le16(&self.fat, off) as u32;
+                if n.is_multiple_of(2) {
+                    v & 0xFFF
+                } else {
+                    v >> 4
+                }
+            }
+            FatType::Fat16 => le16(&self.fat, n * 2) as u32,
+            FatType::Fat32 => le32(&self.fat, n * 4) & 0x0FFF_FFFF,
+        }
+    }
+
+    fn set(&mut self, n: u32, v: u32) {
+        let n = n as usize;
+        match self.geo.kind {
+            FatType::Fat12 => {
+                let off = n + n / 2;
+                let old = le16(&self.fat, off);
+                let new = if n.is_multiple_of(2) {
+                    (old & 0xF000) | (v as u16 & 0xFFF)
+                } else {
+                    (old & 0x000F) | ((v as u16) << 4)
+                };
+                put_le16(&mut self.fat, off, new);
+            }
+            FatType::Fat16 => put_le16(&mut self.fat, n * 2, v as u16),
+            FatType::Fat32 => {
+                let old = le32(&self.fat, n * 4);
+                put_le32(&mut self.fat, n * 4, (old & 0xF000_0000) | (v & 0x0FFF_FFFF));
+            }
+        }
+        self.fat_dirty = true;
+    }
+
+    /// Allocates one cluster, linking it after `prev` when given.
+    fn alloc_cluster(&mut self, prev: Option<u32>) -> io::Result<u32> {
+        let end = self.geo.clusters() + 2;
+        let start = prev.map(|p| p + 1).filter(|&c| c < end).unwrap_or(self.next_free);
+        let found = (start..end).chain(2..start).find(|&c| self.get(c) == 0);
+        let Some(cluster) = found else {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        };
+        self.set(cluster, self.geo.kind.eoc());
+        if let Some(p) = prev {
+            self.set(p, cluster);
+        }
+        self.free_count -= 1;
+        self.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    fn chain(&self, start: u32) -> io::Result<Vec<u32>> {
+        let mut out = Vec::new();
+        let mut c = start;
+        let end = self.geo.clusters() + 2;
+        while c >= 2 && !self.geo.kind.is_eoc(c) {
+            if c >= end || out.len() as u32 >= end {
+                return Err(invalid(format!("corrupt cluster chain at {}", start)));
+            }
+            out.push(c);
+            c = self.get(c);
+        }
+        Ok(out)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.geo.data_sector() as u64 * self.geo.sector_size as u64 + (cluster as u64 - 2) * self.geo.cluster_size()
+    }
+
+    // --- directories ---
+
+    fn root(&self) -> Dir {
+        match self.geo.kind {
+            FatType::Fat32 => Dir::Cluster(self.geo.root_cluster),
+            _ => Dir::Root,
+        }
+    }
+
+    fn root_offset(&self) -> u64 {
+        (self.geo.reserved_sectors + self.geo.fats * self.geo.fat_sectors) as u64 * self.geo.sector_size as u64
+    }
+
+    fn read_dir_raw(&mut self, dir: Dir) -> io::Result<Vec<u8>> {
+        match dir {
+            Dir::Root => {
+                let mut buf = vec![0u8; self.geo.root_entries as usize * DIR_ENTRY_SIZE];
+                self.dev.read_at(self.root_offset(), &mut buf)?;
+                Ok(buf)
+            }
+            Dir::Cluster(start) => {
+                let cs = self.geo.cluster_size() as usize;
+                let chain = self.chain(start)?;
+                let mut buf = vec![0u8; chain.len() * cs];
+                for (i, &c) in chain.iter().enumerate() {
+                    self.dev.read_at(self.cluster_offset(c), &mut buf[i * cs..(i + 1) * cs])?;
+                }
+                Ok(buf)
+            }
+        }
+    }
+
+    /// Writes whole slots back into a directory at byte offset `pos`.
+    fn write_dir_slots(&mut self, dir: Dir, pos: usize, slots: &[u8]) -> io::Result<()> {
+        match dir {
+            Dir::Root => self.dev.write_at(self.root_offset() + pos as u64, slots),
+            Dir::Cluster(start) => {
+                let cs = self.geo.cluster_size() as usize;
+                let chain = self.chain(start)?;
+                for (i, slot) in slots.chunks(DIR_ENTRY_SIZE).enumerate() {
+                    let p = pos + i * DIR_ENTRY_SIZE;
+                    let off = self.cluster_offset(chain[p / cs]) + (p % cs) as u64;
+                    self.dev.write_at(off, slot)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn read_dir(&mut self, dir: Dir) -> io::Result<Vec<Entry>> {
+        Ok(parse_dir(&self.read_dir_raw(dir)?))
+    }
+
+    /// Resolves `path` to its entry; `None` means the root directory.
+    pub(crate) fn lookup(&mut self, path: &Path) -> io::Result<Option<Entry>> {
+        let mut dir = self.root();
+        let mut found = None;
+        for component in path.components() {
+            let name = match component {
+                Component::RootDir | Component::CurDir => continue,
+                Component::Normal(name) => name.to_string_lossy(),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported path component")),
+            };
+            if let Some(Entry { attr, .. }) = &found {
+                if attr & ATTR_DIRECTORY == 0 {
+                    return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+                }
+            }
+            let entry = self.find(dir, &name)?.ok_or_else(|| not_found(path))?;
+            dir = self.dir_of(&entry);
+            found = Some(entry);
+        }
+        Ok(found)
+    }
+
+    fn dir_of(&self, entry: &Entry) -> Dir {
+        if entry.cluster == 0 {
+            self.root()
+        } else {
+            Dir::Cluster(entry.cluster)
+        }
+    }
+
+    fn lookup_dir(&mut self, path: &Path) -> io::Result<Dir> {
+        match self.lookup(path)? {
+            None => Ok(self.root()),
+            Some(e) if e.is_dir() => Ok(self.dir_of(&e)),
+            Some(_) => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        }
+    }
+
+    fn find(&mut self, dir: Dir, name: &str) -> io::Result<Option<Entry>> {
+        let entries = self.read_dir(dir)?;
+        Ok(entries.into_iter().find(|e| {
+            e.name.eq_ignore_ascii_case(name) || short_display(&e.short, 0).eq_ignore_ascii_case(name)
+        }))
+    }
+
+    /// Adds `name` to `dir`, returning the offset of its short entry.
+    fn add_entry(&mut self, dir: Dir, name: &str, attr: u8, cluster: u32, size: u32, meta: &NodeMeta) -> io::Result<usize> {
+        validate_name(name)?;
+        let raw = self.read_dir_raw(dir)?;
+        let entries = parse_dir(&raw);
+        if entries.iter().any(|e| e.name.eq_ignore_ascii_case(name)) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+
+        let (short, case, lossless) = short_name(name, |s| entries.iter().any(|e| &e.short == s));
+        let mut slots = if lossless { Vec::new() } else { lfn_slots(name, lfn_checksum(&short)) };
+        let mut sfn = [0u8; DIR_ENTRY_SIZE];
+        sfn[..11].copy_from_slice(&short);
+        if sfn[0] == DELETED {
+            sfn[0] = 0x05;
+        }
+        sfn[11] = attr;
+        sfn[12] = case;
+        let (ctime, cdate) = dos_time(meta.ctime.0);
+        let (mtime, mdate) = dos_time(meta.mtime.0);
+        let (_, adate) = dos_time(meta.atime.0);
+        put_le16(&mut sfn, 14, ctime);
+        put_le16(&mut sfn, 16, cdate);
+        put_le16(&mut sfn, 18, adate);
+        put_le16(&mut sfn, 20, (cluster >> 16) as u16);
+        put_le16(&mut sfn, 22, mtime);
+        put_le16(&mut sfn, 24, mdate);
+        put_le16(&mut sfn, 26, cluster as u16);
+        put_le32(&mut sfn, 28, size);
+        slots.extend_from_slice(&sfn);
+
+        let pos = match find_free_run(&raw, slots.len() / DIR_ENTRY_SIZE) {
+            Some(pos) => pos,
+            None => match dir {
+                Dir::Root => return Err(io::Error::new(io::ErrorKind::StorageFull, "FAT root directory is full")),
+                Dir::Cluster(start) => {
+                    // Grow the directory; the run may start in the old tail
+                    let last = *self.chain(start)?.last().unwrap();
+                    let cluster = self.alloc_cluster(Some(last))?;
+                    self.zero_cluster(cluster)?;
+                    let mut grown = raw;
+                    grown.resize(grown.len() + self.geo.cluster_size() as usize, 0);
+                    find_free_run(&grown, slots.len() / DIR_ENTRY_SIZE).unwrap()
+                }
+            },
+        };
+        self.write_dir_slots(dir, pos, &slots)?;
+        Ok(pos + slots.len() - DIR_ENTRY_SIZE)
+    }
+
+    fn zero_cluster(&mut self, cluster: u32) -> io::Result<()> {
+        let zeros = vec![0u8; self.geo.cluster_size() as usize];
+        self.dev.write_at(self.cluster_offset(cluster), &zeros)
+    }
+
+    fn mkdir(&mut self, parent: Dir, name: &str, meta: &NodeMeta) -> io::Result<u32> {
+        let cluster = self.alloc_cluster(None)?;
+        self.zero_cluster(cluster)?;
+        let parent_cluster = match parent {
+            Dir::Cluster(c) if c != self.geo.root_cluster => c,
+            _ => 0,
+        };
+        let mut dots = [0u8; 2 * DIR_ENTRY_SIZE];
+        for (i, (short, target)) in [(*b".          ", cluster), (*b"..         ", parent_cluster)].iter().enumerate() {
+            let e = &mut dots[i * DIR_ENTRY_SIZE..(i + 1) * DIR_ENTRY_SIZE];
+            e[..11].copy_from_slice(short);
+            e[11] = ATTR_DIRECTORY;
+            let (time, date) = dos_time(meta.mtime.0);
+            put_le16(e, 14, time);
+            put_le16(e, 16, date);
+            put_le16(e, 22, time);
+            put_le16(e, 24, date);
+            put_le16(e, 20, (target >> 16) as u16);
+            put_le16(e, 26, *target as u16);
+        }
+        self.dev.write_at(self.cluster_offset(cluster), &dots)?;
+        self.add_entry(parent, name, ATTR_DIRECTORY, cluster, 0, meta)?;
+        Ok(cluster)
+    }
+
+    /// Writes `data` into a fresh cluster chain, returning its first cluster and length.
+    fn write_chain(&mut self, data: &mut dyn Read) -> io::Result<(u32, u64)> {
+        let cs = self.geo.cluster_size() as usize;
+        let mut buf = vec![0u8; cs];
+        let mut first = 0;
+        let mut prev = None;
+        let mut size = 0u64;
+        loop {
+            let mut filled = 0;
+            while filled < cs {
+                let n = data.read(&mut buf[filled..])?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            if filled == 0 {
+                break;
+            }
+            size += filled as u64;
+            if size > MAX_FILE_SIZE {
+                return Err(io::Error::from_raw_os_error(libc::EFBIG));
+            }
+            buf[filled..].fill(0);
+            let cluster = self.alloc_cluster(prev)?;
+            if first == 0 {
+                first = cluster;
+            }
+            self.dev.write_at(self.cluster_offset(cluster), &buf)?;
+            prev = Some(cluster);
+            if filled < cs {
+                break;
+            }
+        }
+        Ok((first, size))
+    }
+
+    fn free_chain(&mut self, start: u32) -> io::Result<()> {
+        for c in self.chain(start)? {
+            self.set(c, 0);
+            self.free_count += 1;
+            self.next_free = self.next_free.min(c);
+        }
+        Ok(())
+    }
+
+    fn read_chain(&mut self, start: u32, len: u64, out: &mut dyn Write) -> io::Result<u64> {
+        let cs = self.geo.cluster_size();
+        let mut buf = vec![0u8; cs as usize];
+        let mut left = len;
+        for c in self.chain(start)? {
+            if left == 0 {
+                break;
+            }
+            let n = left.min(cs) as usize;
+            self.dev.read_at(self.cluster_offset(c), &mut buf[..n])?;
+            out.write_all(&buf[..n])?;
+            left -= n as u64;
+        }
+        if left > 0 {
+            return Err(invalid("file is shorter than its directory entry"));
+        }
+        Ok(len)
+    }
+
+    fn unlink(&mut self, path: &Path) -> io::Result<()> {
+        let (parent, name) = split_parent(path)?;
+        let parent = self.lookup_dir(parent)?;
+        let entry = self.find(parent, &name.to_string_lossy())?.ok_or_else(|| not_found(path))?;
+        if entry.is_dir() && !self.read_dir(self.dir_of(&entry))?.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+        self.drop_slots(parent, &entry)?;
+        if entry.cluster != 0 {
+            self.free_chain(entry.cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Marks the slots of `entry` deleted, leaving its clusters alone.
+    fn drop_slots(&mut self, dir: Dir, entry: &Entry) -> io::Result<()> {
+        let mut raw = self.read_dir_raw(dir)?;
+        for slot in raw[entry.first_slot..entry.slot + DIR_ENTRY_SIZE].chunks_exact_mut(DIR_ENTRY_SIZE) {
+            slot[0] = DELETED;
+        }
+        self.write_dir_slots(dir, entry.first_slot, &raw[entry.first_slot..entry.slot + DIR_ENTRY_SIZE])
+    }
+
+    /// Moves a file. An existing file at `to` is swapped out by pointing its
+    /// short entry at the moved file's clusters.
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        let (parent, name) = split_parent(from)?;
+        let from_dir = self.lookup_dir(parent)?;
+        let source = self.find(from_dir, &name.to_string_lossy())?.ok_or_else(|| not_found(from))?;
+        if source.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        let (parent, name) = split_parent(to)?;
+        let to_dir = self.lookup_dir(parent)?;
+        let name = name.to_string_lossy();
+        let (slot, replaced) = match self.find(to_dir, &name)? {
+            Some(e) if e.is_dir() => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+            Some(e) if to_dir == from_dir && e.slot == source.slot => return Ok(()),
+            Some(e) => (e.slot, Some(e.cluster)),
+            None => (self.add_entry(to_dir, &name, source.attr, 0, 0, &NodeMeta::now(0))?, None),
+        };
+
+        // Everything after the name and case flags comes from the source
+        let moved = self.read_dir_raw(from_dir)?[source.slot..source.slot + DIR_ENTRY_SIZE].to_vec();
+        let mut short = self.read_dir_raw(to_dir)?[slot..slot + DIR_ENTRY_SIZE].to_vec();
+        short[11] = moved[11];
+        short[13..].copy_from_slice(&moved[13..]);
+        self.write_dir_slots(to_dir, slot, &short)?;
+        self.drop_slots(from_dir, &source)?;
+        match replaced {
+            Some(cluster) if cluster != 0 => self.free_chain(cluster),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the FAT copies and, on FAT32, the FSInfo hints.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.fat_dirty {
+            let ss = self.geo.sector_size as u64;
+            for i in 0..self.geo.fats {
+                let off = (self.geo.reserved_sectors + i * self.geo.fat_sectors) as u64 * ss;
+                self.dev.write_at(off, &self.fat)?;
+            }
+            if self.geo.kind == FatType::Fat32 && self.geo.fsinfo_sector != 0 {
+                let off = self.geo.fsinfo_sector as u64 * ss;
+                let mut info = [0u8; 512];
+                self.dev.read_at(off, &mut info)?;
+                if le32(&info, 0) == 0x4161_5252 {
+                    put_le32(&mut info, 0x1E8, self.free_count);
+                    put_le32(&mut info, 0x1EC, self.next_free);
+                    self.dev.write_at(off, &info)?;
+                }
+            }
+            self.fat_dirty = false;
+        }
+        self.dev.flush()
+    }
+}
+
+impl<D: BlockDevice> ImageFs for Fat<D> {
+    fn create_dir(&mut self, path: &Path, meta: &NodeMeta) -> io::Result<()> {
+        let (parent, name) = split_parent(path)?;
+        let parent = self.lookup_dir(parent)?;
+        self.mkdir(parent, &name.to_string_lossy(), meta).map(drop)
+    }
+
+    fn create_file(&mut self, path: &Path, data: &mut dyn Read, meta: &NodeMeta) -> io::Result<()> {
+        let (parent, name) = split_parent(path)?;
+        let parent = self.lookup_dir(parent)?;
+        let name = name.to_string_lossy();
+        validate_name(&name)?;
+        if self.find(parent, &name)?.is_some() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        let (cluster, size) = self.write_chain(data)?;
+        let mut attr = ATTR_ARCHIVE;
+        if meta.mode & 0o200 == 0 {
+            attr |= ATTR_READ_ONLY;
+        }
+        self.add_entry(parent, &name, attr, cluster, size as u32, meta).map(drop)
+    }
+
+    fn create_symlink(&mut self, _path: &Path, _target: &Path, _meta: &NodeMeta) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "FAT has no symbolic links"))
+    }
+
+    fn read_file(&mut self, path: &Path, out: &mut dyn Write) -> io::Result<u64> {
+        match self.lookup(path)? {
+            Some(e) if !e.is_dir() => self.read_chain(e.cluster, e.size as u64, out),
+            _ => Err(io::Error::from_raw_os_error(libc::EISDIR)),
+        }
+    }
+
+    fn list(&mut self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let dir = self.lookup_dir(path)?;
+        Ok(self
+            .read_dir(dir)?
+            .into_iter()
+            .map(|e| DirEntry { is_dir: e.is_dir(), is_symlink: false, len: e.size as u64, name: e.name })
+            .collect())
+    }
+
+    fn metadata(&mut self, path: &Path) -> io::Result<DirEntry> {
+        Ok(match self.lookup(path)? {
+            Some(e) => DirEntry { is_dir: e.is_dir(), is_symlink: false, len: e.size as u64, name: e.name },
+            None => DirEntry { name: String::new(), is_dir: true, is_symlink: false, len: 0 },
+        })
+    }
+
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        self.unlink(path)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        Fat::rename(self, from, to)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Fat::sync(self)
+    }
+}
+
+fn parse_dir(raw: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut lfn: Vec<u16> = Vec::new();
+    let mut lfn_sum = None;
+    let mut lfn_start = 0;
+    for (i, slot) in raw.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+        let pos = i * DIR_ENTRY_SIZE;
+        match slot[0] {
+            0x00 => break,
+            DELETED => {
+                lfn.clear();
+                lfn_sum = None;
+                continue;
+            }
+            _ => {}
+        }
+        let attr = slot[11];
+        if attr & 0x3F == ATTR_LFN {
+            let ord = slot[0];
+            if ord & 0x40 != 0 {
+                lfn.clear();
+                lfn_start = pos;
+                lfn_sum = Some(slot[13]);
+            }
+            let mut part: Vec<u16> = Vec::with_capacity(LFN_CHARS);
+            for &off in &[1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30] {
+                part.push(le16(slot, off));
+            }
+            // Parts are stored last-first
+            part.extend_from_slice(&lfn);
+            lfn = part;
+            continue;
+        }
+        if attr & ATTR_VOLUME_ID != 0 {
+            lfn.clear();
+            lfn_sum = None;
+            continue;
+        }
+        let mut short = [0u8; 11];
+        short.copy_from_slice(&slot[..11]);
+        if short[0] == 0x05 {
+            short[0] = DELETED;
+        }
+        let long = match lfn_sum {
+            Some(sum) if sum == lfn_checksum(&short) && !lfn.is_empty() => {
+                let end = lfn.iter().position(|&c| c == 0).unwrap_or(lfn.len());
+                Some(String::from_utf16_lossy(&lfn[..end]))
+            }
+            _ => None,
+        };
+        let first_slot = if long.is_some() { lfn_start } else { pos };
+        lfn.clear();
+        lfn_sum = None;
+        if &short == b".          " || &short == b"..         " {
+            continue;
+        }
+        let cluster = (le16(slot, 20) as u32) << 16 | le16(slot, 26) as u32;
+        entries.push(Entry {
+            name: long.unwrap_or_else(|| short_display(&short, slot[12])),
+            short,
+            attr,
+            cluster,
+            size: le32(slot, 28),
+            first_slot,
+            slot: pos,
+        });
+    }
+    entries
+}
+
+/// Finds `count` consecutive free slots, treating everything past the end
+/// marker as free.
+fn find_free_run(raw: &[u8], count: usize) -> Option<usize> {
+    let mut run = 0;
+    for (i, slot) in raw.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+        if slot[0] == 0x00 {
+            let remaining = raw.len() / DIR_ENTRY_SIZE - i;
+            return (run + remaining >= count).then(|| (i - run) * DIR_ENTRY_SIZE);
+        }
+        if slot[0] == DELETED {
+            run += 1;
+            if run == count {
+                return Some((i + 1 - run) * DIR_ENTRY_SIZE);
+            }
+        } else {
+            run = 0;
+        }
+    }
+    None
+}
+
+fn short_display(short: &[u8; 11], case: u8) -> String {
+    let mut base: String = String::from_utf8_lossy(&short[..8]).trim_end().to_string();
+    let mut ext: String = String::from_utf8_lossy(&short[8..]).trim_end().to_string();
+    if case & CASE_LOWER_BASE != 0 {
+        base = base.to_ascii_lowercase();
+    }
+    if case & CASE_LOWER_EXT != 0 {
+        ext = ext.to_ascii_lowercase();
+    }
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+fn validate_name(name: &str) -> io::Result<()> {
+    let bad = name.is_empty()
+        || name.encode_utf16().count() > 255
+        || name == "."
+        || name == ".."
+        || name.ends_with('.')
+        || name.ends_with(' ')
+        || name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c));
+    if bad {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not a valid FAT name", name)));
+    }
+    Ok(())
+}
+
+fn short_char_ok(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// Builds the 8.3 name for `name`. Returns the name, the case flags and
+/// whether it round-trips without a long-name entry.
+fn short_name(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> ([u8; 11], u8, bool) {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    };
+
+    // Exact fit, possibly all lower-case within each part
+    let part_case = |s: &str| -> Option<bool> {
+        let upper = s.to_ascii_uppercase();
+        if !s.is_ascii() || !upper.bytes().all(short_char_ok) {
+            return None;
+        }
+        if s == upper {
+            Some(false)
+        } else if s == s.to_ascii_lowercase() {
+            Some(true)
+        } else {
+            None
+        }
+    };
+    if !base.is_empty() && base.len() <= 8 && ext.len() <= 3 && !base.contains('.') {
+        if let (Some(lower_base), Some(lower_ext)) = (part_case(base), part_case(ext)) {
+            let mut short = [b' '; 11];
+            short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+            short[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+            if !taken(&short) {
+                let case = if lower_base { CASE_LOWER_BASE } else { 0 } | if lower_ext { CASE_LOWER_EXT } else { 0 };
+                return (short, case, true);
+            }
+        }
+    }
+
+    let clean = |s: &str, max: usize| -> Vec<u8> {
+        s.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c.is_ascii() && short_char_ok(c as u8) {
+                    c as u8
+                } else {
+                    b'_'
+                }
+            })
+            .take(max)
+            .collect()
+    };
+    let base = clean(base.trim_start_matches('.'), 8);
+    let ext = clean(ext, 3);
+    for n in 1u32.. {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+        if !taken(&short) {
+            return (short, 0, false);
+        }
+    }
+    unreachable!()
+}
+
+fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b))
+}
+
+/// Long-name slots for `name`, in on-disk order.
+fn lfn_slots(name: &str, checksum: u8) -> Vec<u8> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if !units.len().is_multiple_of(LFN_CHARS) {
+        units.push(0);
+        while !units.len().is_multiple_of(LFN_CHARS) {
+            units.push(0xFFFF);
+        }
+    }
+    let count = units.len() / LFN_CHARS;
+    let mut out = Vec::with_capacity(count * DIR_ENTRY_SIZE);
+    for seq in (1..=count).rev() {
+        let mut slot = [0u8; DIR_ENTRY_SIZE];
+        slot[0] = seq as u8 | if seq == count { 0x40 } else { 0 };
+        slot[11] = ATTR_LFN;
+        slot[13] = checksum;
+        let chunk = &units[(seq - 1) * LFN_CHARS..seq * LFN_CHARS];
+        for (&off, &unit) in [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30].iter().zip(chunk) {
+            put_le16(&mut slot, off, unit);
+        }
+        out.extend_from_slice(&slot);
+    }
+    out
+}
+
+/// DOS time and date fields for a Unix timestamp, in UTC.
+fn dos_time(secs: i64) -> (u16, u16) {
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    if year < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let year = year.min(2107);
+    let time = ((rem / 3600) << 11 | (rem % 3600 / 60) << 5 | (rem % 60 / 2)) as u16;
+    let date = ((year - 1980) << 9 | (month as i64) << 5 | day as i64) as u16;
+    (time, date)
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
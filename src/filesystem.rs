@@ -0,0 +1,275 @@
+use crate::bytes::le32;
+use crate::blockdev::BlockDevice;
+use crate::error::{MizzleError, Result};
+use crate::size::{self, DiskSize, EIB, KIB, MAX_FILE_SIZE, MIB, PIB, TIB};
+use crate::uuid;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    #[default]
+    Ext4,
+    Xfs,
+    Btrfs,
+    Vfat,
+    Exfat,
+}
+
+/// Optional mkfs settings; anything left as `None` uses the tool's default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatOptions {
+    pub label: Option<String>,
+    /// Filesystem UUID; for vfat this is the `XXXX-XXXX` volume id.
+    pub uuid: Option<String>,
+    pub block_size: Option<u32>,
+    /// Bytes per inode (ext4 only).
+    pub inode_ratio: Option<u32>,
+    /// Percentage of blocks reserved for root (ext4 only).
+    pub reserved_percent: Option<u8>,
+}
+
+impl Filesystem {
+    /// Filesystem type passed to mount(2).
+    pub fn mount_type(&self) -> &'static str {
+        match self {
+            Filesystem::Ext4 => "ext4",
+            Filesystem::Xfs => "xfs",
+            Filesystem::Btrfs => "btrfs",
+            Filesystem::Vfat => "vfat",
+            Filesystem::Exfat => "exfat",
+        }
+    }
+
+    pub fn mkfs_program(&self) -> &'static str {
+        match self {
+            Filesystem::Ext4 => "mkfs.ext4",
+            Filesystem::Xfs => "mkfs.xfs",
+            Filesystem::Btrfs => "mkfs.btrfs",
+            Filesystem::Vfat => "mkfs.vfat",
+            Filesystem::Exfat => "mkfs.exfat",
+        }
+    }
+
+    /// Command-line arguments for `mkfs_program` to format `device` with `options`.
+    pub fn mkfs_args(&self, device: &Path, options: &FormatOptions) -> Result<Vec<OsString>> {
+        let unsupported = |option: &str| MizzleError::Unsupported {
+            filesystem: *self,
+            feature: option.to_string(),
+        };
+        let mut args: Vec<OsString> = Vec::new();
+        let mut push = |flag: &str, value: String| {
+            args.push(flag.into());
+            args.push(value.into());
+        };
+
+        if let Some(label) = &options.label {
+            match self {
+                Filesystem::Vfat => push("-n", label.clone()),
+                _ => push("-L", label.clone()),
+            }
+        }
+
+        if let Some(uuid) = &options.uuid {
+            match self {
+                Filesystem::Ext4 => push("-U", uuid.clone()),
+                Filesystem::Xfs => push("-m", format!("uuid={}", uuid)),
+                Filesystem::Btrfs => push("-U", uuid.clone()),
+                Filesystem::Vfat => {
+                    let id = uuid.replace('-', "");
+                    if id.len() != 8 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return Err(unsupported("volume id other than XXXX-XXXX"));
+                    }
+                    push("-i", id)
+                }
+                Filesystem::Exfat => return Err(unsupported("uuid")),
+            }
+        }
+
+        if let Some(block_size) = options.block_size {
+            match self {
+                Filesystem::Ext4 => push("-b", block_size.to_string()),
+                Filesystem::Xfs => push("-b", format!("size={}", block_size)),
+                Filesystem::Btrfs => push("-s", block_size.to_string()),
+                // FAT sizes clusters in 512-byte sectors
+                Filesystem::Vfat => push("-s", (block_size / 512).max(1).to_string()),
+                Filesystem::Exfat => push("-c", block_size.to_string()),
+            }
+        }
+
+        if let Some(ratio) = options.inode_ratio {
+            match self {
+                Filesystem::Ext4 => push("-i", ratio.to_string()),
+                _ => return Err(unsupported("inode ratio")),
+            }
+        }
+
+        if let Some(percent) = options.reserved_percent {
+            match self {
+                Filesystem::Ext4 => push("-m", percent.to_string()),
+                _ => return Err(unsupported("reserved blocks")),
+            }
+        }
+
+        // Images are regular files, so skip the interactive "are you sure" checks
+        match self {
+            Filesystem::Ext4 => args.push("-F".into()),
+            Filesystem::Xfs | Filesystem::Btrfs => args.push("-f".into()),
+            Filesystem::Vfat | Filesystem::Exfat => {}
+        }
+
+        args.push(device.into());
+        Ok(args)
+    }
+
+    /// Smallest and largest images mkfs makes a working filesystem on.
+    pub fn size_limits(&self) -> (u64, u64) {
+        match self {
+            Filesystem::Ext4 => (128 * KIB, EIB),
+            Filesystem::Xfs => (300 * MIB, MAX_FILE_SIZE),
+            Filesystem::Btrfs => (109 * MIB, MAX_FILE_SIZE),
+            // FAT32 counts at most 2^32 512-byte sectors
+            Filesystem::Vfat => (64 * KIB, 2 * TIB),
+            Filesystem::Exfat => (MIB, 128 * PIB),
+        }
+    }
+
+    /// Checks that `size` is a valid image size that can hold this filesystem.
+    pub fn check_size(&self, size: u64) -> Result<()> {
+        let (min, max) = self.size_limits();
+        let reason = if let Err(reason) = size::validate(size) {
+            reason
+        } else if size < min {
+            format!("{} needs at least {} ({})", self, DiskSize(min), size::human(min))
+        } else if size > max {
+            format!("{} supports at most {} ({})", self, DiskSize(max), size::human(max))
+        } else {
+            return Ok(());
+        };
+        Err(MizzleError::InvalidSize { size, reason })
+    }
+
+    /// Program and arguments that grow the filesystem to fill its device.
+    /// resize2fs works on the device, mounted or not; the others only grow
+    /// a mounted filesystem, through its mount point.
+    pub fn grow_command(&self, device: &Path, mount_point: &Path) -> Result<(&'static str, Vec<OsString>)> {
+        match self {
+            Filesystem::Ext4 => Ok(("resize2fs", vec![device.into()])),
+            Filesystem::Xfs => Ok(("xfs_growfs", vec![mount_point.into()])),
+            Filesystem::Btrfs => Ok(("btrfs", vec!["filesystem".into(), "resize".into(), "max".into(), mount_point.into()])),
+            Filesystem::Vfat | Filesystem::Exfat => {
+                Err(MizzleError::Unsupported { filesystem: *self, feature: "resizing".to_string() })
+            }
+        }
+    }
+
+    /// Whether the filesystem can be grown without mounting it.
+    pub fn grows_offline(&self) -> bool {
+        matches!(self, Filesystem::Ext4)
+    }
+
+    /// Block size recorded in the superblock. Only ext4 is supported.
+    pub fn block_size(&self, image: &Path) -> io::Result<u64> {
+        if *self != Filesystem::Ext4 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("block size of {}", self)));
+        }
+        let mut sb = [0u8; 4];
+        // s_log_block_size, in the superblock 1024 bytes in
+        File::open(image)?.read_exact_at(&mut sb, 1024 + 0x18)?;
+        match le32(&sb, 0) {
+            log @ 0..=6 => Ok(1024 << log),
+            log => {
+                let message = format!("bad ext4 block size 2^{}", u64::from(log) + 10);
+                Err(io::Error::new(io::ErrorKind::InvalidData, message))
+            }
+        }
+    }
+
+    /// Detects which supported filesystem `image` holds from its superblock signature.
+    pub fn probe(image: &Path) -> io::Result<Option<Filesystem>> {
+        Filesystem::probe_device(&mut File::open(image)?)
+    }
+
+    /// Like [`probe`](Self::probe), for a filesystem on `device`.
+    pub fn probe_device(device: &mut dyn BlockDevice) -> io::Result<Option<Filesystem>> {
+        for filesystem in [Filesystem::Ext4, Filesystem::Xfs, Filesystem::Btrfs, Filesystem::Exfat, Filesystem::Vfat] {
+            if filesystem.read_device_uuid(device)?.is_some() {
+                return Ok(Some(filesystem));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the filesystem UUID (volume id for vfat and exfat) from the
+    /// superblock in `image`, or `None` if no such filesystem is there.
+    pub fn read_uuid(&self, image: &Path) -> io::Result<Option<String>> {
+        self.read_device_uuid(&mut File::open(image)?)
+    }
+
+    /// Like [`read_uuid`](Self::read_uuid), for a filesystem on `device`.
+    pub fn read_device_uuid(&self, device: &mut dyn BlockDevice) -> io::Result<Option<String>> {
+        let mut read = |offset: u64, len: usize| -> io::Result<Option<Vec<u8>>> {
+            let mut buf = vec![0u8; len];
+            match device.read_at(offset, &mut buf) {
+                Ok(()) => Ok(Some(buf)),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                Err(e) => Err(e),
+            }
+        };
+        let mut uuid_at = |offset: u64, magic_at: u64, magic: &[u8]| -> io::Result<Option<String>> {
+            match (read(magic_at, magic.len())?, read(offset, 16)?) {
+                (Some(m), Some(u)) if m == magic => Ok(Some(uuid::format(u.as_slice().try_into().unwrap()))),
+                _ => Ok(None),
+            }
+        };
+        let serial = |id: u32| format!("{:04X}-{:04X}", id >> 16, id & 0xFFFF);
+
+        match self {
+            Filesystem::Ext4 => uuid_at(1024 + 0x68, 1024 + 0x38, &[0x53, 0xEF]),
+            Filesystem::Xfs => uuid_at(32, 0, b"XFSB"),
+            Filesystem::Btrfs => uuid_at(0x10020, 0x10040, b"_BHRfS_M"),
+            Filesystem::Vfat => {
+                let Some(boot) = read(0, 512)? else {
+                    return Ok(None);
+                };
+                // FAT32 has a zero 16-bit FAT size and a longer BPB
+                let ext = if boot[0x16..0x18] == [0, 0] { 0x40 } else { 0x24 };
+                Ok((boot[510..] == [0x55, 0xAA] && boot[ext + 2] == 0x29).then(|| serial(le32(&boot, ext + 3))))
+            }
+            Filesystem::Exfat => {
+                let Some(boot) = read(0, 512)? else {
+                    return Ok(None);
+                };
+                Ok((&boot[3..11] == b"EXFAT   ").then(|| serial(le32(&boot, 0x64))))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.mount_type())
+    }
+}
+
+impl FromStr for Filesystem {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ext4" => Ok(Filesystem::Ext4),
+            "xfs" => Ok(Filesystem::Xfs),
+            "btrfs" => Ok(Filesystem::Btrfs),
+            "vfat" | "fat" | "fat32" => Ok(Filesystem::Vfat),
+            "exfat" => Ok(Filesystem::Exfat),
+            _ => Err(format!("unknown filesystem '{}' (expected ext4, xfs, btrfs, vfat or exfat)", s)),
+        }
+    }
+}
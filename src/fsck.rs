@@ -0,0 +1,260 @@
+//! Checking and repairing the filesystem in an unmounted image with the
+//! filesystem's own checker.
+
+use crate::error::{MizzleError, Result};
+use crate::filesystem::Filesystem;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Whether a check may change the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CheckMode {
+    /// Report problems without touching anything.
+    #[default]
+    ReadOnly,
+    /// Fix whatever the checker can fix.
+    Repair,
+}
+
+/// What a check concluded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    /// No problems found.
+    Clean,
+    /// Problems were found and repaired.
+    Fixed,
+    /// Problems were found; a read-only check leaves them alone.
+    Damaged,
+    /// Problems remain after repairing.
+    Unrecoverable,
+}
+
+impl CheckStatus {
+    /// Exit status in the style of fsck(8): 0 clean, 1 errors corrected,
+    /// 4 errors left uncorrected.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CheckStatus::Clean => 0,
+            CheckStatus::Fixed => 1,
+            CheckStatus::Damaged | CheckStatus::Unrecoverable => 4,
+        }
+    }
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CheckStatus::Clean => "clean",
+            CheckStatus::Fixed => "errors fixed",
+            CheckStatus::Damaged => "errors found",
+            CheckStatus::Unrecoverable => "errors could not be fixed",
+        })
+    }
+}
+
+/// Inode and block counts from the checker's summary (ext4 only).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub inodes_used: u64,
+    pub inodes_total: u64,
+    pub blocks_used: u64,
+    pub blocks_total: u64,
+}
+
+/// The outcome of checking one image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckReport {
+    pub filesystem: Filesystem,
+    pub mode: CheckMode,
+    pub status: CheckStatus,
+    /// Problems the first, read-only pass reported, one per entry.
+    pub problems: Vec<String>,
+    pub usage: Option<Usage>,
+    /// Everything the checker printed, pass by pass.
+    pub output: String,
+}
+
+/// How to run a checker and read its exit status.
+struct Checker {
+    program: &'static str,
+    read_only: &'static [&'static str],
+    repair: &'static [&'static str],
+    /// Read-only exit codes meaning problems were found.
+    found: &'static [i32],
+    /// Repair exit codes meaning the repair went through.
+    repaired: &'static [i32],
+    /// Repair exit codes meaning problems were left behind.
+    uncorrected: &'static [i32],
+}
+
+fn checker(filesystem: Filesystem) -> Checker {
+    match filesystem {
+        Filesystem::Ext4 => Checker {
+            program: "e2fsck",
+            read_only: &["-f", "-n"],
+            repair: &["-f", "-y"],
+            found: &[4],
+            repaired: &[0, 1, 2],
+            uncorrected: &[4],
+        },
+        Filesystem::Xfs => Checker {
+            program: "xfs_repair",
+            read_only: &["-n"],
+            repair: &[],
+            found: &[1],
+            repaired: &[0],
+            // 2: the log is dirty and has to be replayed by mounting first
+            uncorrected: &[1, 2],
+        },
+        Filesystem::Btrfs => Checker {
+            program: "btrfs",
+            read_only: &["check", "--readonly"],
+            repair: &["check", "--repair"],
+            found: &[1],
+            repaired: &[0],
+            uncorrected: &[1],
+        },
+        Filesystem::Vfat => Checker {
+            program: "fsck.vfat",
+            read_only: &["-n"],
+            repair: &["-a", "-w"],
+            found: &[1],
+            // 1: recoverable errors were found, and -a recovered them
+            repaired: &[0, 1],
+            uncorrected: &[],
+        },
+        Filesystem::Exfat => Checker {
+            program: "fsck.exfat",
+            read_only: &["-n"],
+            repair: &["-y"],
+            found: &[1, 4],
+            repaired: &[0, 1],
+            uncorrected: &[4],
+        },
+    }
+}
+
+/// Runs `program` and returns its exit code with stdout and stderr combined.
+fn run(program: &str, args: &[&str], image: &Path) -> Result<(Option<i32>, String)> {
+    let args: Vec<OsString> = args.iter().map(OsString::from).chain([image.into()]).collect();
+    let output = Command::new(program)
+        .args(&args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|source| MizzleError::Spawn { program: program.to_string(), source })?;
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok((output.status.code(), text))
+}
+
+fn failed(program: &str, image: &Path, code: Option<i32>, output: &str) -> MizzleError {
+    MizzleError::Tool { path: image.to_path_buf(), program: program.to_string(), code, stderr: output.to_string() }
+}
+
+/// Whether a read-only pass found problems.
+fn read_only_pass(checker: &Checker, image: &Path, output: &mut String) -> Result<bool> {
+    let (code, text) = run(checker.program, checker.read_only, image)?;
+    output.push_str(&text);
+    match code {
+        Some(0) => Ok(false),
+        Some(c) if checker.found.contains(&c) => Ok(true),
+        _ => Err(failed(checker.program, image, code, &text)),
+    }
+}
+
+/// Checks the filesystem in the unmounted `image`. A repair first checks
+/// read-only, repairs only if that found problems, and checks again
+/// afterwards to tell a fixed filesystem from one still broken.
+pub fn check(image: &Path, filesystem: Filesystem, mode: CheckMode) -> Result<CheckReport> {
+    let checker = checker(filesystem);
+    let mut output = String::new();
+    let damaged = read_only_pass(&checker, image, &mut output)?;
+    let problems = problems(&output);
+
+    let status = match (damaged, mode) {
+        (false, _) => CheckStatus::Clean,
+        (true, CheckMode::ReadOnly) => CheckStatus::Damaged,
+        (true, CheckMode::Repair) => {
+            let (code, text) = run(checker.program, checker.repair, image)?;
+            output.push_str(&text);
+            match code {
+                Some(c) if checker.repaired.contains(&c) => {
+                    if read_only_pass(&checker, image, &mut output)? {
+                        CheckStatus::Unrecoverable
+                    } else {
+                        CheckStatus::Fixed
+                    }
+                }
+                Some(c) if checker.uncorrected.contains(&c) => CheckStatus::Unrecoverable,
+                _ => return Err(failed(checker.program, image, code, &text)),
+            }
+        }
+    };
+    // The last summary describes the filesystem as it is now
+    let usage = usage(&output);
+    Ok(CheckReport { filesystem, mode, status, problems, usage, output })
+}
+
+/// Progress chatter every checker prints even when nothing is wrong.
+fn is_progress(line: &str) -> bool {
+    const PREFIXES: [&str; 10] = [
+        "Pass ",
+        "Phase ",
+        "- ",
+        "[",
+        "Opening filesystem",
+        "Checking filesystem",
+        "UUID:",
+        "found ",
+        "No modify flag set",
+        "fsck.",
+    ];
+    let line = line.trim();
+    PREFIXES.iter().any(|p| line.starts_with(p))
+        || line.contains("WARNING: Filesystem still has errors")
+        || line.contains(" files (")
+        || line.starts_with("e2fsck ")
+        || line.starts_with("xfs_repair version")
+        || line.starts_with("btrfs-progs")
+        || line.starts_with("exfatprogs")
+}
+
+/// The problems a read-only pass reported. Checkers print one paragraph per
+/// problem, ending with the question they would have asked ("Fix? no"),
+/// which is dropped.
+fn problems(output: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    for paragraph in output.split("\n\n") {
+        let mut lines: Vec<&str> =
+            paragraph.lines().map(str::trim_end).filter(|l| !l.trim().is_empty() && !is_progress(l)).collect();
+        if let Some(last) = lines.pop() {
+            let asks = last.ends_with("? no") || last.ends_with("? yes");
+            match last.split_once("  ") {
+                Some((statement, _)) if asks => lines.push(statement),
+                _ if asks => {}
+                _ => lines.push(last),
+            }
+        }
+        if !lines.is_empty() {
+            problems.push(lines.iter().map(|l| l.trim()).collect::<Vec<_>>().join(" "));
+        }
+    }
+    problems
+}
+
+/// Parses e2fsck's closing "12/4096 files (0.0% non-contiguous), 2346/16384 blocks".
+fn usage(output: &str) -> Option<Usage> {
+    let line = output.lines().rev().find(|l| l.contains(" files (") && l.ends_with(" blocks"))?;
+    let fraction = |s: &str| -> Option<(u64, u64)> {
+        let (used, total) = s.trim().split_once('/')?;
+        Some((used.parse().ok()?, total.parse().ok()?))
+    };
+    let after_name = line.rsplit_once(": ").map_or(line, |(_, rest)| rest);
+    let (inodes, rest) = after_name.split_once(" files (")?;
+    let blocks = rest.split_once("), ")?.1.strip_suffix(" blocks")?;
+    let (inodes_used, inodes_total) = fraction(inodes)?;
+    let (blocks_used, blocks_total) = fraction(blocks)?;
+    Some(Usage { inodes_used, inodes_total, blocks_used, blocks_total })
+}
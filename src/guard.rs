@@ -0,0 +1,176 @@
+//! Scoped mounts that are torn down on drop, on panic, and on SIGINT/SIGTERM.
+
+use crate::crypt;
+use crate::error::{MizzleError, Result};
+use crate::loopdev::LoopDevice;
+use crate::nbd;
+use crate::ops;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, Once};
+use std::thread;
+
+/// What it takes to undo one mount.
+struct Active {
+    id: u64,
+    image: PathBuf,
+    mount_point: PathBuf,
+    device: Option<PathBuf>,
+}
+
+/// Guards that are still mounted, for the signal handler to clean up.
+static ACTIVE: Mutex<Vec<Active>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static HANDLER: Once = Once::new();
+
+/// Tears down every live guard when SIGINT or SIGTERM arrives, then lets the
+/// signal terminate the process as it normally would.
+fn install_handler() {
+    HANDLER.call_once(|| {
+        let Ok(mut signals) = Signals::new([SIGINT, SIGTERM]) else {
+            return;
+        };
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                let active = std::mem::take(&mut *ACTIVE.lock().unwrap_or_else(|e| e.into_inner()));
+                for mount in active {
+                    let _ = teardown(&mount.image, &mount.mount_point, mount.device.as_deref());
+                }
+                let _ = signal_hook::low_level::emulate_default_handler(signal);
+            }
+        });
+    });
+}
+
+/// Removes the guard from the active list; false if the signal handler
+/// already took care of it.
+fn unregister(id: u64) -> bool {
+    let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+    match active.iter().position(|m| m.id == id) {
+        Some(i) => {
+            active.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Unmounts `mount_point` and releases the loop or NBD device behind it,
+/// closing the encrypted mapping in between if there is one.
+pub(crate) fn teardown(image: &Path, mount_point: &Path, device: Option<&Path>) -> Result<()> {
+    ops::unmount_with_retry(mount_point)?;
+    match device {
+        Some(mapped) if crypt::is_mapping(mapped) => {
+            let device = crypt::backing_device(mapped);
+            crypt::close(mapped)?;
+            match device {
+                Some(device) => release_loop(image, &device),
+                None => Ok(()),
+            }
+        }
+        Some(device) if nbd::is_nbd(device) => release_nbd(image, device),
+        Some(device) => release_loop(image, device),
+        None => Ok(()),
+    }
+}
+
+/// Disconnects NBD device `device` if it still serves `image`.
+fn release_nbd(image: &Path, device: &Path) -> Result<()> {
+    let release = || -> io::Result<()> {
+        if nbd::served_image(device) == Some(fs::canonicalize(image)?) {
+            nbd::disconnect(device)?;
+        }
+        Ok(())
+    };
+    release().map_err(|source| MizzleError::Nbd { path: device.to_path_buf(), operation: "disconnect", source })
+}
+
+/// Detaches `device` if it still belongs to `image`; an autoclear device may
+/// already have been released by the unmount.
+fn release_loop(image: &Path, device: &Path) -> Result<()> {
+    let release = || -> io::Result<()> {
+        let loop_dev = LoopDevice::open(device)?;
+        let info = match loop_dev.info() {
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => return Ok(()),
+            other => other?,
+        };
+        if info.backing_file == fs::canonicalize(image)? {
+            loop_dev.detach()?;
+        }
+        Ok(())
+    };
+    release().map_err(|source| MizzleError::Loop { path: device.to_path_buf(), operation: "detach", source })
+}
+
+/// A mounted disk that is unmounted, and its loop device (or encrypted
+/// mapping) released, when the guard is dropped. Errors during drop are
+/// ignored; call [`release`](Self::release) to see them.
+#[must_use = "the disk is unmounted as soon as the guard is dropped"]
+pub struct MountGuard {
+    id: u64,
+    image: PathBuf,
+    mount_point: PathBuf,
+    device: Option<PathBuf>,
+    armed: bool,
+}
+
+impl MountGuard {
+    /// Takes ownership of a mount. An `owned` guard tears it down later; one
+    /// for a mount that was already there before leaves it alone.
+    pub(crate) fn new(image: &Path, mount_point: &Path, device: Option<PathBuf>, owned: bool) -> MountGuard {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        if owned {
+            install_handler();
+            ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).push(Active {
+                id,
+                image: image.to_path_buf(),
+                mount_point: mount_point.to_path_buf(),
+                device: device.clone(),
+            });
+        }
+        MountGuard { id, image: image.to_path_buf(), mount_point: mount_point.to_path_buf(), device, armed: owned }
+    }
+
+    pub fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    pub fn loop_device(&self) -> Option<&Path> {
+        self.device.as_deref()
+    }
+
+    /// Whether this guard did the mounting and will undo it.
+    pub fn is_owned(&self) -> bool {
+        self.armed
+    }
+
+    /// Leaves the disk mounted after the guard is gone.
+    pub fn keep(mut self) {
+        if self.armed {
+            unregister(self.id);
+            self.armed = false;
+        }
+    }
+
+    /// Unmounts now and reports any failure.
+    pub fn release(mut self) -> Result<()> {
+        self.disarm_and_teardown()
+    }
+
+    fn disarm_and_teardown(&mut self) -> Result<()> {
+        if !std::mem::take(&mut self.armed) || !unregister(self.id) {
+            return Ok(());
+        }
+        teardown(&self.image, &self.mount_point, self.device.as_deref())
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        let _ = self.disarm_and_teardown();
+    }
+}
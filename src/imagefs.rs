@@ -0,0 +1,226 @@
+//! Filesystems written directly into an image file, without mkfs or mount.
+
+use crate::blockdev::BlockDevice;
+use crate::ext4;
+use crate::fat;
+use crate::filesystem::{Filesystem, FormatOptions};
+use crate::ops::DirEntry;
+use nix::sys::stat;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Ownership, permissions and timestamps for a new entry in an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeMeta {
+    /// Permission bits, including setuid/setgid/sticky.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Seconds and nanoseconds since the epoch.
+    pub atime: (i64, u32),
+    pub mtime: (i64, u32),
+    pub ctime: (i64, u32),
+}
+
+impl NodeMeta {
+    /// Root-owned entry with the given permissions, stamped with the current time.
+    pub fn now(mode: u32) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let t = (now.as_secs() as i64, now.subsec_nanos());
+        NodeMeta { mode, uid: 0, gid: 0, atime: t, mtime: t, ctime: t }
+    }
+
+    pub fn from_host(meta: &fs::Metadata) -> Self {
+        NodeMeta {
+            mode: meta.mode() & 0o7777,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            atime: (meta.atime(), meta.atime_nsec() as u32),
+            mtime: (meta.mtime(), meta.mtime_nsec() as u32),
+            ctime: (meta.ctime(), meta.ctime_nsec() as u32),
+        }
+    }
+}
+
+/// Non-regular, non-directory inode types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialKind {
+    Fifo,
+    Socket,
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+}
+
+/// Operations on the filesystem tree inside an image. Paths are relative
+/// to the image's root directory.
+pub trait ImageFs {
+    fn create_dir(&mut self, path: &Path, meta: &NodeMeta) -> io::Result<()>;
+    /// Creates a regular file and fills it from `data` until EOF.
+    fn create_file(&mut self, path: &Path, data: &mut dyn Read, meta: &NodeMeta) -> io::Result<()>;
+    fn create_symlink(&mut self, path: &Path, target: &Path, meta: &NodeMeta) -> io::Result<()>;
+
+    /// Adds another name for the existing file at `existing`.
+    fn create_hardlink(&mut self, path: &Path, existing: &Path) -> io::Result<()> {
+        let _ = (path, existing);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "hard links are not supported"))
+    }
+
+    fn create_special(&mut self, path: &Path, kind: SpecialKind, meta: &NodeMeta) -> io::Result<()> {
+        let _ = (path, kind, meta);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "special files are not supported"))
+    }
+
+    /// Streams the regular file at `path` into `out`, returning its length.
+    fn read_file(&mut self, path: &Path, out: &mut dyn Write) -> io::Result<u64>;
+    fn list(&mut self, path: &Path) -> io::Result<Vec<DirEntry>>;
+    /// Describes the entry at `path` without following a final symlink.
+    fn metadata(&mut self, path: &Path) -> io::Result<DirEntry>;
+    /// Removes a file, symlink or empty directory.
+    fn remove(&mut self, path: &Path) -> io::Result<()>;
+    /// Moves the non-directory at `from` to `to`, replacing any file there
+    /// with a single directory update.
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Writes all cached metadata back to the device.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// Copies the contents of host directory `source` into the root of `fs`.
+pub fn populate(fs: &mut dyn ImageFs, source: &Path) -> io::Result<()> {
+    let mut links = HashMap::new();
+    populate_dir(fs, source, Path::new(""), &mut links)?;
+    fs.sync()
+}
+
+fn populate_dir(
+    fs: &mut dyn ImageFs,
+    host: &Path,
+    image: &Path,
+    links: &mut HashMap<(u64, u64), std::path::PathBuf>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(host)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let host_path = entry.path();
+        let image_path = image.join(entry.file_name());
+        let meta = fs::symlink_metadata(&host_path)?;
+        let node = NodeMeta::from_host(&meta);
+        let file_type = meta.file_type();
+
+        if file_type.is_dir() {
+            fs.create_dir(&image_path, &node)?;
+            populate_dir(fs, &host_path, &image_path, links)?;
+            continue;
+        }
+
+        if meta.nlink() > 1 {
+            if let Some(first) = links.get(&(meta.dev(), meta.ino())) {
+                match fs.create_hardlink(&image_path, first) {
+                    Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+                    other => {
+                        other?;
+                        continue;
+                    }
+                }
+            } else {
+                links.insert((meta.dev(), meta.ino()), image_path.clone());
+            }
+        }
+
+        if file_type.is_file() {
+            fs.create_file(&image_path, &mut File::open(&host_path)?, &node)?;
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&host_path)?;
+            match fs.create_symlink(&image_path, &target, &node) {
+                // Filesystems without symlinks get a copy of the file the link points at
+                Err(e) if e.kind() == io::ErrorKind::Unsupported && host_path.is_file() => {
+                    let node = NodeMeta::from_host(&fs::metadata(&host_path)?);
+                    fs.create_file(&image_path, &mut File::open(&host_path)?, &node)?;
+                }
+                other => other?,
+            }
+        } else {
+            let (major, minor) = (stat::major(meta.rdev()) as u32, stat::minor(meta.rdev()) as u32);
+            let kind = if file_type.is_fifo() {
+                SpecialKind::Fifo
+            } else if file_type.is_socket() {
+                SpecialKind::Socket
+            } else if file_type.is_char_device() {
+                SpecialKind::CharDevice { major, minor }
+            } else {
+                SpecialKind::BlockDevice { major, minor }
+            };
+            fs.create_special(&image_path, kind, &node)?;
+        }
+    }
+    Ok(())
+}
+
+/// Formats the image at `path` in-process and optionally fills it from `source`.
+pub fn format_image(path: &Path, filesystem: Filesystem, options: &FormatOptions, source: Option<&Path>) -> io::Result<()> {
+    let dev = fs::OpenOptions::new().read(true).write(true).open(path)?;
+    format_device(dev, filesystem, options, source)
+}
+
+/// Like [`format_image`], on any block device.
+pub fn format_device<D: BlockDevice + 'static>(
+    dev: D,
+    filesystem: Filesystem,
+    options: &FormatOptions,
+    source: Option<&Path>,
+) -> io::Result<()> {
+    let mut fs: Box<dyn ImageFs> = match filesystem {
+        Filesystem::Ext4 => Box::new(ext4::mkfs::format(dev, options)?),
+        Filesystem::Vfat => Box::new(fat::mkfs::format(dev, options)?),
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} cannot be built in-process", other),
+            ))
+        }
+    };
+    match source {
+        Some(dir) => populate(fs.as_mut(), dir),
+        None => fs.sync(),
+    }
+}
+
+/// Whether images of `filesystem` can be built and edited in-process.
+pub fn is_supported(filesystem: Filesystem) -> bool {
+    matches!(filesystem, Filesystem::Ext4 | Filesystem::Vfat)
+}
+
+/// Opens the filesystem inside the image at `path` for direct access.
+pub fn open_image(path: &Path, filesystem: Filesystem, writable: bool) -> io::Result<Box<dyn ImageFs>> {
+    let dev = fs::OpenOptions::new().read(true).write(writable).open(path)?;
+    open_device(dev, filesystem, writable)
+}
+
+/// Like [`open_image`], on any block device.
+pub fn open_device<D: BlockDevice + 'static>(
+    dev: D,
+    filesystem: Filesystem,
+    writable: bool,
+) -> io::Result<Box<dyn ImageFs>> {
+    match filesystem {
+        Filesystem::Ext4 => Ok(Box::new(ext4::Ext4::open(dev, writable)?)),
+        Filesystem::Vfat => Ok(Box::new(fat::Fat::open(dev)?)),
+        other => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} images cannot be accessed without mounting", other),
+        )),
+    }
+}
+
+/// Splits an image path into its parent directory and final component.
+pub(crate) fn split_parent(path: &Path) -> io::Result<(&Path, &std::ffi::OsStr)> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok((parent, name)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid path {}", path.display()))),
+    }
+}
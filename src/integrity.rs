@@ -0,0 +1,234 @@
+//! Checksummed copies onto a mounted disk and the manifest that lets later
+//! runs detect corruption.
+
+use crate::transfer::{Metered, Progress};
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+/// Manifest file kept in the root of the disk.
+pub const MANIFEST_NAME: &str = ".mizzle-manifest.json";
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        })
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" | "sha-256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!("unknown checksum '{}' (expected sha256 or blake3)", s)),
+        }
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    fn hex(self) -> String {
+        let bytes: Vec<u8> = match self {
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+        };
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Drops cached pages of `file` so the next read comes from the device.
+fn evict(file: &File) -> io::Result<()> {
+    posix_fadvise(file.as_raw_fd(), 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED).map_err(io::Error::from)
+}
+
+/// Hashes the file at `path` as stored on its device rather than in the page cache.
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    hash_stored(&File::open(path)?, algorithm)
+}
+
+fn hash_stored(file: &File, algorithm: HashAlgorithm) -> io::Result<String> {
+    evict(file)?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut offset = 0;
+    loop {
+        let n = file.read_at(&mut buffer, offset)?;
+        if n == 0 {
+            return Ok(hasher.hex());
+        }
+        hasher.update(&buffer[..n]);
+        offset += n as u64;
+    }
+}
+
+/// Checksum and size of one file as it was copied onto the disk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub algorithm: HashAlgorithm,
+    pub digest: String,
+    pub size: u64,
+}
+
+/// Checksums of verified copies, keyed by absolute path on the disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+/// The manifest key for `path`: absolute, with `.` components dropped.
+pub fn manifest_key(path: &Path) -> String {
+    let mut key = String::new();
+    for component in path.components() {
+        if let Component::Normal(name) = component {
+            key.push('/');
+            key.push_str(&name.to_string_lossy());
+        }
+    }
+    if key.is_empty() {
+        key.push('/');
+    }
+    key
+}
+
+impl Manifest {
+    /// Reads the manifest from the disk mounted at `mount_point`; a disk
+    /// without one has an empty manifest.
+    pub fn load(mount_point: &Path) -> io::Result<Manifest> {
+        match fs::read(mount_point.join(MANIFEST_NAME)) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replaces the manifest on the disk atomically and durably.
+    pub fn save(&self, mount_point: &Path) -> io::Result<()> {
+        let path = mount_point.join(MANIFEST_NAME);
+        let tmp = mount_point.join(format!("{}.tmp", MANIFEST_NAME));
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        File::open(mount_point)?.sync_all()
+    }
+}
+
+/// Copies `source` into the empty, readable file `output` while hashing it,
+/// syncs the copy to the device, reads it back from there and checks the two
+/// digests agree. Returns the manifest entry for the copy.
+pub fn copy_verified(
+    source: &Path,
+    mut output: &File,
+    algorithm: HashAlgorithm,
+    progress: &mut dyn FnMut(&Progress),
+) -> io::Result<ManifestEntry> {
+    let input = File::open(source)?;
+    let total = input.metadata()?.len();
+    let mut reader = Metered::new(input, total, progress);
+
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut size = 0;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        output.write_all(&buffer[..n])?;
+        size += n as u64;
+    }
+    output.sync_all()?;
+
+    let digest = hasher.hex();
+    let written = hash_stored(output, algorithm)?;
+    if written != digest {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("read back {} {} but wrote {}", algorithm, written, digest),
+        ));
+    }
+    Ok(ManifestEntry { algorithm, digest, size })
+}
+
+/// Outcome of checking one manifest entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyStatus {
+    Ok,
+    /// The file's contents no longer match the recorded digest.
+    Mismatch { expected: String, actual: String },
+    /// The file was recorded but is gone.
+    Missing,
+    /// The path was asked for but never recorded in the manifest.
+    Unrecorded,
+}
+
+/// Checks files on the disk mounted at `mount_point` against its manifest:
+/// the given `paths`, or everything recorded when none are given.
+pub fn verify(mount_point: &Path, paths: &[&Path]) -> io::Result<Vec<(String, VerifyStatus)>> {
+    let manifest = Manifest::load(mount_point)?;
+    let keys: Vec<String> = if paths.is_empty() {
+        manifest.files.keys().cloned().collect()
+    } else {
+        paths.iter().map(|p| manifest_key(p)).collect()
+    };
+
+    let mut results = Vec::with_capacity(keys.len());
+    for key in keys {
+        let status = match manifest.files.get(&key) {
+            None => VerifyStatus::Unrecorded,
+            // Normalised again in case the manifest was edited by hand
+            Some(entry) => match hash_file(&mount_point.join(&manifest_key(Path::new(&key))[1..]), entry.algorithm) {
+                Ok(actual) if actual == entry.digest => VerifyStatus::Ok,
+                Ok(actual) => VerifyStatus::Mismatch { expected: entry.digest.clone(), actual },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => VerifyStatus::Missing,
+                Err(e) => return Err(e),
+            },
+        };
+        results.push((key, status));
+    }
+    Ok(results)
+}
@@ -0,0 +1,43 @@
+//! Virtual disk management: create an image file, format it, mount it and
+//! move files on and off it.
+
+pub mod alloc;
+pub mod blockdev;
+mod bytes;
+mod checksum;
+pub mod container;
+pub mod convert;
+pub mod crypt;
+mod disk;
+mod error;
+pub mod ext4;
+pub mod fat;
+mod filesystem;
+pub mod fsck;
+mod guard;
+pub mod imagefs;
+pub mod integrity;
+pub mod loopdev;
+pub mod nbd;
+pub mod ops;
+pub mod qcow2;
+pub mod registry;
+pub mod size;
+pub mod snapshot;
+#[cfg(test)]
+mod testutil;
+pub mod transfer;
+pub mod tree;
+mod uuid;
+pub mod vhd;
+pub mod vhdx;
+pub mod vmdk;
+
+pub use disk::{DiskState, ImageFormat, VirtualDisk};
+pub use error::{MizzleError, Result};
+pub use filesystem::{Filesystem, FormatOptions};
+pub use guard::MountGuard;
+
+pub const IMAGE_PATH: &str = "/tmp/virtual_disk.img";
+pub const MOUNT_POINT: &str = "/tmp/virtual_disk";
+pub const DISK_SIZE: u64 = 10 * 1024 * 1024 * 1024; // 10GB
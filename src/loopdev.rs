@@ -0,0 +1,283 @@
+//! Loop devices: exposing an image file as a block device through
+//! `/dev/loop-control` instead of relying on mount(8)'s implicit setup.
+
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+const LOOP_SET_FD: libc::Ioctl = 0x4C00;
+const LOOP_CLR_FD: libc::Ioctl = 0x4C01;
+const LOOP_SET_STATUS64: libc::Ioctl = 0x4C04;
+const LOOP_GET_STATUS64: libc::Ioctl = 0x4C05;
+const LOOP_SET_CAPACITY: libc::Ioctl = 0x4C07;
+const LOOP_SET_DIRECT_IO: libc::Ioctl = 0x4C08;
+const LOOP_CONFIGURE: libc::Ioctl = 0x4C0A;
+const LOOP_CTL_GET_FREE: libc::Ioctl = 0x4C82;
+
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+const LO_FLAGS_PARTSCAN: u32 = 8;
+const LO_FLAGS_DIRECT_IO: u32 = 16;
+
+const LOOP_MAJOR: u64 = 7;
+const LO_NAME_SIZE: usize = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LoopInfo64 {
+    device: u64,
+    inode: u64,
+    rdevice: u64,
+    offset: u64,
+    size_limit: u64,
+    number: u32,
+    encrypt_type: u32,
+    encrypt_key_size: u32,
+    flags: u32,
+    file_name: [u8; LO_NAME_SIZE],
+    crypt_name: [u8; LO_NAME_SIZE],
+    encrypt_key: [u8; 32],
+    init: [u64; 2],
+}
+
+#[repr(C)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8],
+}
+
+impl LoopInfo64 {
+    fn zeroed() -> Self {
+        // SAFETY: plain integers and byte arrays; all-zero is a valid value
+        unsafe { std::mem::zeroed() }
+    }
+}
+
+/// How an image is attached to a loop device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopOptions {
+    pub read_only: bool,
+    /// Release the device automatically once the last user (e.g. a mount) closes it.
+    pub autoclear: bool,
+    /// Bypass the host page cache for I/O to the backing file.
+    pub direct_io: bool,
+    /// Scan the device for a partition table.
+    pub partscan: bool,
+}
+
+impl Default for LoopOptions {
+    fn default() -> Self {
+        LoopOptions { read_only: false, autoclear: true, direct_io: false, partscan: false }
+    }
+}
+
+impl LoopOptions {
+    fn flags(&self) -> u32 {
+        let mut flags = 0;
+        if self.read_only {
+            flags |= LO_FLAGS_READ_ONLY;
+        }
+        if self.autoclear {
+            flags |= LO_FLAGS_AUTOCLEAR;
+        }
+        if self.direct_io {
+            flags |= LO_FLAGS_DIRECT_IO;
+        }
+        if self.partscan {
+            flags |= LO_FLAGS_PARTSCAN;
+        }
+        flags
+    }
+}
+
+/// State of a bound loop device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopInfo {
+    pub device: PathBuf,
+    pub backing_file: PathBuf,
+    pub offset: u64,
+    pub size_limit: u64,
+    pub read_only: bool,
+    pub autoclear: bool,
+    pub direct_io: bool,
+    pub partscan: bool,
+}
+
+pub(crate) fn ioctl(fd: RawFd, request: libc::Ioctl, arg: libc::c_ulong) -> io::Result<libc::c_int> {
+    // SAFETY: every request used here takes either an integer or a pointer to
+    // a properly sized repr(C) struct that outlives the call
+    let ret = unsafe { libc::ioctl(fd, request, arg) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn device_number(path: &Path) -> Option<u32> {
+    path.file_name()?.to_str()?.strip_prefix("loop")?.parse().ok()
+}
+
+/// Opens `/dev/loopN`, creating the node first when the container's /dev lacks it.
+fn open_node(number: u32, read_only: bool) -> io::Result<(PathBuf, File)> {
+    let path = PathBuf::from(format!("/dev/loop{}", number));
+    let open = || OpenOptions::new().read(true).write(!read_only).open(&path);
+    match open() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            mknod(&path, SFlag::S_IFBLK, Mode::from_bits_truncate(0o660), makedev(LOOP_MAJOR, number as u64))?;
+            Ok((path.clone(), open()?))
+        }
+        other => Ok((path, other?)),
+    }
+}
+
+pub struct LoopDevice {
+    path: PathBuf,
+    file: File,
+}
+
+impl LoopDevice {
+    /// Binds `image` to a free loop device.
+    pub fn attach(image: &Path, options: &LoopOptions) -> io::Result<LoopDevice> {
+        let backing = OpenOptions::new().read(true).write(!options.read_only).open(image)?;
+        let control = OpenOptions::new().read(true).write(true).open("/dev/loop-control")?;
+
+        // Another process can grab the device between GET_FREE and binding it
+        for _ in 0..16 {
+            let number = ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE, 0)? as u32;
+            let (path, file) = open_node(number, options.read_only)?;
+            let device = LoopDevice { path, file };
+            match device.configure(&backing, image, options) {
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => continue,
+                other => return other.map(|_| device),
+            }
+        }
+        Err(io::Error::from_raw_os_error(libc::EBUSY))
+    }
+
+    /// Opens an existing loop device, bound or not.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<LoopDevice> {
+        let path = path.into();
+        let file = File::open(&path)?;
+        Ok(LoopDevice { path, file })
+    }
+
+    fn configure(&self, backing: &File, image: &Path, options: &LoopOptions) -> io::Result<()> {
+        let mut info = LoopInfo64::zeroed();
+        info.flags = options.flags();
+        let name = fs::canonicalize(image).unwrap_or_else(|_| image.to_path_buf());
+        let name = name.as_os_str().as_bytes();
+        let len = name.len().min(LO_NAME_SIZE - 1);
+        info.file_name[..len].copy_from_slice(&name[..len]);
+
+        let config = LoopConfig { fd: backing.as_raw_fd() as u32, block_size: 0, info, reserved: [0; 8] };
+        match ioctl(self.fd(), LOOP_CONFIGURE, &config as *const _ as libc::c_ulong) {
+            Ok(_) => Ok(()),
+            // Kernels before 5.8 only have the SET_FD + SET_STATUS pair
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTTY) | Some(libc::EINVAL)) => {
+                ioctl(self.fd(), LOOP_SET_FD, backing.as_raw_fd() as libc::c_ulong)?;
+                let setup = || -> io::Result<()> {
+                    let mut status = info;
+                    status.flags &= LO_FLAGS_AUTOCLEAR | LO_FLAGS_PARTSCAN;
+                    ioctl(self.fd(), LOOP_SET_STATUS64, &status as *const _ as libc::c_ulong)?;
+                    if options.direct_io {
+                        self.set_direct_io(true)?;
+                    }
+                    Ok(())
+                };
+                setup().inspect_err(|_| {
+                    let _ = ioctl(self.fd(), LOOP_CLR_FD, 0);
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queries the device; fails with ENXIO when nothing is bound to it.
+    pub fn info(&self) -> io::Result<LoopInfo> {
+        let mut raw = LoopInfo64::zeroed();
+        ioctl(self.fd(), LOOP_GET_STATUS64, &mut raw as *mut _ as libc::c_ulong)?;
+
+        // The ioctl truncates the name to 64 bytes; sysfs has the full path
+        let sysfs = self
+            .path
+            .file_name()
+            .map(|name| Path::new("/sys/block").join(name).join("loop/backing_file"));
+        let backing_file = match sysfs.and_then(|p| fs::read(p).ok()) {
+            Some(name) => PathBuf::from(OsStr::from_bytes(name.trim_ascii_end())),
+            None => {
+                let end = raw.file_name.iter().position(|&b| b == 0).unwrap_or(LO_NAME_SIZE);
+                PathBuf::from(OsStr::from_bytes(&raw.file_name[..end]))
+            }
+        };
+
+        Ok(LoopInfo {
+            device: self.path.clone(),
+            backing_file,
+            offset: raw.offset,
+            size_limit: raw.size_limit,
+            read_only: raw.flags & LO_FLAGS_READ_ONLY != 0,
+            autoclear: raw.flags & LO_FLAGS_AUTOCLEAR != 0,
+            direct_io: raw.flags & LO_FLAGS_DIRECT_IO != 0,
+            partscan: raw.flags & LO_FLAGS_PARTSCAN != 0,
+        })
+    }
+
+    pub fn set_direct_io(&self, enabled: bool) -> io::Result<()> {
+        ioctl(self.fd(), LOOP_SET_DIRECT_IO, enabled as libc::c_ulong).map(drop)
+    }
+
+    /// Makes the device pick up a changed backing file size.
+    pub fn set_capacity(&self) -> io::Result<()> {
+        ioctl(self.fd(), LOOP_SET_CAPACITY, 0).map(drop)
+    }
+
+    /// Unbinds the backing file. A device that is still in use (mounted) is
+    /// switched to autoclear and released when its last user goes away.
+    pub fn detach(self) -> io::Result<()> {
+        ioctl(self.fd(), LOOP_CLR_FD, 0).map(drop)
+    }
+}
+
+/// All bound loop devices.
+pub fn list() -> io::Result<Vec<LoopInfo>> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir("/sys/block")? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(number) = device_number(Path::new(&name)) else {
+            continue;
+        };
+        if !entry.path().join("loop").exists() {
+            continue;
+        }
+        let path = PathBuf::from(format!("/dev/loop{}", number));
+        match LoopDevice::open(&path).and_then(|dev| dev.info()) {
+            Ok(info) => devices.push(info),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENXIO) | Some(libc::ENOENT)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    devices.sort_by_key(|info| device_number(&info.device));
+    Ok(devices)
+}
+
+/// Loop devices currently backed by `image`.
+pub fn find(image: &Path) -> io::Result<Vec<LoopInfo>> {
+    let image = fs::canonicalize(image)?;
+    Ok(list()?.into_iter().filter(|info| info.backing_file == image).collect())
+}
@@ -1,134 +1,1075 @@
-use nix::errno::Errno;
-use nix::mount::{mount, MsFlags, MntFlags};
-use nix::sys::stat::Mode;
-use nix::unistd::{close, ftruncate, mkfifo, write};
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read};
-use std::os::unix::io::{AsRawFd, BorrowedFd, RawFd};
-use std::path::Path;
-use std::process::Command;
-use std::io::Write;
+use clap::{Args, Parser, Subcommand};
+use mizzlestore::alloc::AllocationPolicy;
+use mizzlestore::blockdev::BlockDevice;
+use mizzlestore::convert::Format;
+use mizzlestore::crypt::{KeyKind, KeyRecord, KeySource};
+use mizzlestore::fsck::{CheckMode, CheckReport, CheckStatus};
+use mizzlestore::integrity::{HashAlgorithm, VerifyStatus};
+use mizzlestore::loopdev::{self, LoopDevice, LoopInfo, LoopOptions};
+use mizzlestore::nbd;
+use mizzlestore::ops::{Overwrite, Placed};
+use mizzlestore::qcow2;
+use mizzlestore::registry::{self, DiskRecord, Registry};
+use mizzlestore::size::{human, DiskSize};
+use mizzlestore::transfer::Progress;
+use mizzlestore::tree::{CopyOptions, CopyReport};
+use mizzlestore::{
+    DiskState, Filesystem, FormatOptions, ImageFormat, MizzleError, Result, VirtualDisk, DISK_SIZE, IMAGE_PATH,
+    MOUNT_POINT,
+};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 
+#[derive(Parser)]
+#[command(name = "mizzlestore", version, about = "Create, format, mount and fill virtual disk images")]
+struct Cli {
+    /// Disk registry file [default: $MIZZLESTORE_REGISTRY or $XDG_STATE_HOME/mizzlestore/disks.json]
+    #[arg(long, global = true)]
+    registry: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Cmd,
+}
 
-const IMAGE_PATH: &str = "/tmp/virtual_disk.img";
-const MOUNT_POINT: &str = "/tmp/virtual_disk";
-const DISK_SIZE: u64 = 10 * 1024 * 1024 * 1024; // 10GB
-
-fn create_fully_allocated_file(path: &str, size: u64) -> io::Result<()> {
-    let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(path)?;
-
-    let fd: RawFd = file.as_raw_fd();
+#[derive(Args)]
+struct DiskArgs {
+    /// Name the disk is registered under
+    #[arg(short, long)]
+    name: Option<String>,
+    /// Image file [default: /tmp/virtual_disk.img, or /tmp/<name>.img for a new named disk]
+    #[arg(short, long)]
+    image: Option<PathBuf>,
+    /// [default: /tmp/virtual_disk, or /tmp/<name> for a new named disk]
+    #[arg(short, long)]
+    mount_point: Option<PathBuf>,
+    /// Filesystem: ext4, xfs, btrfs, vfat or exfat [default: ext4]
+    #[arg(short = 't', long = "fs")]
+    filesystem: Option<Filesystem>,
+    /// Key file for an encrypted disk [default: ask for the passphrase]
+    #[arg(long, value_name = "FILE")]
+    key_file: Option<PathBuf>,
+}
 
-    // Allocate the file to the full size using ftruncate
-    let borrowed_fd = BorrowedFd::borrow_raw(fd);
-    unsafe {
-        ftruncate(borrowed_fd, size as i64)?;
+impl DiskArgs {
+    /// Finds the disk in the registry by name, or by image path when no name
+    /// is given, and applies any flags on top of what was recorded. An
+    /// unregistered disk is named after its image file.
+    fn resolve(&self, registry: &Registry, size: Option<u64>) -> Result<(String, VirtualDisk)> {
+        let name = match (&self.name, &self.image) {
+            (Some(name), _) => name.clone(),
+            (None, image) => {
+                let image = image.clone().unwrap_or_else(|| PathBuf::from(IMAGE_PATH));
+                match registry.find_image(&image) {
+                    Some(name) => name.to_string(),
+                    None => image.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+                }
+            }
+        };
+        let record = match registry.get(&name) {
+            Some(record) => record.clone(),
+            // Only `create` and `import` may introduce a new named disk
+            None if self.name.is_some() && size.is_none() => return Err(MizzleError::UnknownDisk { name }),
+            None => {
+                let (image, mount_point) = match &self.name {
+                    Some(name) => (format!("/tmp/{}.img", name), format!("/tmp/{}", name)),
+                    None => (IMAGE_PATH.to_string(), MOUNT_POINT.to_string()),
+                };
+                DiskRecord {
+                    image: image.into(),
+                    size: DISK_SIZE,
+                    filesystem: Filesystem::default(),
+                    uuid: None,
+                    mount_point: mount_point.into(),
+                    loop_device: None,
+                    state: DiskState::Absent,
+                    snapshots: Default::default(),
+                    keys: Default::default(),
+                }
+            }
+        };
+        let record = DiskRecord {
+            image: self.image.clone().unwrap_or(record.image),
+            size: size.unwrap_or(record.size),
+            filesystem: self.filesystem.unwrap_or(record.filesystem),
+            mount_point: self.mount_point.clone().unwrap_or(record.mount_point),
+            ..record
+        };
+        Ok((name, VirtualDisk::from_record(&record).with_key(self.key())))
     }
 
-    // Optionally, write a zero byte at the end to ensure space is allocated
-    unsafe {
-        lseek(fd, size as i64 - 1, libc::SEEK_SET)?;
-        write(borrowed_fd, &[0])?;
+    fn key(&self) -> KeySource {
+        match &self.key_file {
+            Some(path) => KeySource::KeyFile(path.clone()),
+            None => KeySource::Prompt,
+        }
     }
+}
 
-    close(fd)?;
-    Ok(())
+#[derive(Args)]
+struct FormatArgs {
+    #[arg(short = 'L', long)]
+    label: Option<String>,
+    #[arg(short = 'U', long)]
+    uuid: Option<String>,
+    /// Block size in bytes (cluster size for vfat/exfat)
+    #[arg(short, long)]
+    block_size: Option<u32>,
+    /// Bytes per inode (ext4)
+    #[arg(long)]
+    inode_ratio: Option<u32>,
+    /// Percentage of blocks reserved for root (ext4)
+    #[arg(long)]
+    reserved_percent: Option<u8>,
 }
 
-fn lseek(fd: RawFd, offset: i64, whence: i32) -> io::Result<i64> {
-    let ret = unsafe { libc::lseek(fd, offset, whence) };
-    if ret == -1 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(ret)
+impl From<FormatArgs> for FormatOptions {
+    fn from(args: FormatArgs) -> Self {
+        FormatOptions {
+            label: args.label,
+            uuid: args.uuid,
+            block_size: args.block_size,
+            inode_ratio: args.inode_ratio,
+            reserved_percent: args.reserved_percent,
+        }
     }
 }
 
-fn format_virtual_disk(path: &str) -> io::Result<()> {
-    let status = Command::new("mkfs.ext4")
-        .arg(path)
-        .status()?;
+#[derive(Args)]
+struct TreeArgs {
+    /// Copy a whole directory tree, keeping ownership, modes, times, links,
+    /// xattrs and holes (mounts the disk if needed)
+    #[arg(short, long)]
+    recursive: bool,
+    /// Only copy files matching this glob (repeatable; implies --recursive)
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Skip files and directories matching this glob (repeatable; implies --recursive)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+}
 
-    if !status.success() {
-        return Err(io::Error::new(io::ErrorKind::Other, "mkfs.ext4 failed"));
+impl TreeArgs {
+    /// The copy options, if a tree copy was asked for.
+    fn options(self, overwrite: Overwrite) -> Option<CopyOptions> {
+        let recursive = self.recursive || !self.include.is_empty() || !self.exclude.is_empty();
+        recursive.then_some(CopyOptions { include: self.include, exclude: self.exclude, overwrite })
     }
+}
 
-    Ok(())
+fn print_check(report: &CheckReport, verbose: bool) {
+    if verbose {
+        print!("{}", report.output);
+    }
+    println!("{} filesystem: {}", report.filesystem, report.status);
+    if let Some(usage) = report.usage {
+        println!(
+            "{}/{} inodes, {}/{} blocks in use",
+            usage.inodes_used, usage.inodes_total, usage.blocks_used, usage.blocks_total
+        );
+    }
+    if !verbose && !report.problems.is_empty() {
+        let heading = match report.status {
+            CheckStatus::Fixed => "Repaired:",
+            _ => "Problems:",
+        };
+        println!("{}", heading);
+        for problem in &report.problems {
+            println!("  {}", problem);
+        }
+    }
 }
 
-fn mount_virtual_disk() -> nix::Result<()> {
-    let source = Path::new(IMAGE_PATH);
-    let target = Path::new(MOUNT_POINT);
+/// Reports a single-file copy `to` or `from` the disk.
+fn print_placed(placed: &Placed, destination: &Path, direction: &str) {
+    match placed {
+        Placed::Created | Placed::Replaced => println!("File copied {} virtual disk.", direction),
+        Placed::Renamed(path) => {
+            println!("File copied {} virtual disk as {} ({} exists).", direction, path.display(), destination.display())
+        }
+        Placed::Skipped => println!("{} already exists; nothing copied.", destination.display()),
+    }
+}
 
-    if !target.exists() {
-        std::fs::create_dir_all(target).map_err(|e| Errno::from_i32(e.raw_os_error().unwrap_or(1)))?;
+/// Redraws a one-line progress report on stderr, at most five times a
+/// second, when stderr is a terminal.
+fn progress_bar() -> impl FnMut(&Progress) {
+    let tty = io::stderr().is_terminal();
+    let mut last: Option<Instant> = None;
+    let mut finished = false;
+    move |p: &Progress| {
+        if !tty || finished {
+            return;
+        }
+        let now = Instant::now();
+        if !p.is_done() && last.is_some_and(|t| now - t < Duration::from_millis(200)) {
+            return;
+        }
+        last = Some(now);
+        let percent = (p.copied * 100).checked_div(p.total).unwrap_or(100);
+        let eta = match p.eta() {
+            Some(eta) => format!("{}:{:02}", eta.as_secs() / 60, eta.as_secs() % 60),
+            None => "--:--".to_string(),
+        };
+        eprint!(
+            "\r{:>10} / {:<10} {:>3}%  {:>10}/s  ETA {}  ",
+            human(p.copied),
+            human(p.total),
+            percent,
+            human(p.rate() as u64),
+            eta
+        );
+        if p.is_done() {
+            finished = true;
+            eprintln!();
+        }
     }
+}
 
-    mount(Some(source), target, Some("ext4"), MsFlags::empty(), None::<&str>)?;
-    Ok(())
+fn print_copy(report: &CopyReport) {
+    println!(
+        "Copied {} files ({} bytes), {} directories, {} symlinks, {} hard links, {} special files; skipped {}.",
+        report.files, report.bytes, report.dirs, report.symlinks, report.hardlinks, report.special, report.skipped
+    );
 }
 
-fn copy_file_to_mount(source_file: &str, destination: &str) -> io::Result<()> {
-    let mut source = File::open(source_file)?;
-    let destination_path = Path::new(MOUNT_POINT).join(destination);
-    let mut destination = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(destination_path)?;
+#[derive(Args)]
+struct LoopArgs {
+    /// Attach the image read-only
+    #[arg(long)]
+    read_only: bool,
+    /// Bypass the host page cache for loop I/O
+    #[arg(long)]
+    direct_io: bool,
+    /// Keep the loop device bound after its last user closes it
+    #[arg(long)]
+    no_autoclear: bool,
+}
 
-    let mut buffer = vec![0; 4096];
-    loop {
-        let n = source.read(&mut buffer)?;
-        if n == 0 {
-            break;
+impl From<LoopArgs> for LoopOptions {
+    fn from(args: LoopArgs) -> Self {
+        LoopOptions {
+            read_only: args.read_only,
+            autoclear: !args.no_autoclear,
+            direct_io: args.direct_io,
+            ..LoopOptions::default()
         }
-        destination.write_all(&buffer[..n])?;
     }
+}
 
-    Ok(())
+#[derive(Subcommand)]
+enum LoopCmd {
+    /// Bind the image to a free loop device and print its path
+    Attach {
+        #[command(flatten)]
+        disk: DiskArgs,
+        #[command(flatten)]
+        options: LoopArgs,
+    },
+    /// Release a loop device
+    Detach { device: PathBuf },
+    /// Show bound loop devices, or those backed by the image with --image
+    Status {
+        #[arg(short, long)]
+        image: Option<PathBuf>,
+    },
 }
 
-fn unmount_virtual_disk() -> nix::Result<()> {
-    let target = Path::new(MOUNT_POINT);
-    umount2(target, MntFlags::empty())?;
-    Ok(())
+#[derive(Subcommand)]
+enum SnapshotCmd {
+    /// Snapshot the disk image; a mounted disk is frozen while it is copied
+    Create {
+        snapshot: String,
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
+    /// List the disk's snapshots
+    List {
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
+    /// Roll the disk image back to a snapshot (the disk must not be mounted)
+    Restore {
+        snapshot: String,
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
+    /// Delete a snapshot
+    Delete {
+        snapshot: String,
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
 }
 
-fn main() -> io::Result<()> {
-    // 1. Create a fully allocated 10GB file
-    create_fully_allocated_file(IMAGE_PATH, DISK_SIZE)?;
-    println!("Fully allocated 10GB virtual disk image created.");
+#[derive(Subcommand)]
+enum KeyCmd {
+    /// Add a passphrase, or a key file with --new-key-file, unlocking the disk with its current key
+    Add {
+        #[command(flatten)]
+        disk: DiskArgs,
+        /// Key file to add [default: ask for a new passphrase]
+        #[arg(long, value_name = "FILE")]
+        new_key_file: Option<PathBuf>,
+    },
+    /// Remove the key in a key slot (never the last one)
+    Remove {
+        slot: usize,
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
+    /// List the key slots in use
+    List {
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
+    /// Generate a recovery key, add it and print it; it is not stored anywhere
+    Recovery {
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
+    /// Re-encrypt the disk under a new master key, keeping only the key used
+    /// to unlock it; run it again to finish an interrupted rotation
+    Rotate {
+        #[command(flatten)]
+        disk: DiskArgs,
+        /// Go ahead when the disk has other keys, recovery keys included,
+        /// which cannot be carried over and are removed
+        #[arg(long)]
+        drop_keys: bool,
+    },
+}
 
-    // 2. Format the file with ext4 filesystem
-    format_virtual_disk(IMAGE_PATH)?;
-    println!("Virtual disk image formatted as ext4.");
+#[derive(Subcommand)]
+enum Cmd {
+    /// Create a disk image
+    Create {
+        #[command(flatten)]
+        disk: DiskArgs,
+        /// Image size, e.g. 512M, 10GiB or 1.5T (K/M/G/T alone or with iB are
+        /// powers of 1024, KB/MB/GB/TB powers of 1000) [default: 10G, or the
+        /// size of the backing file]
+        #[arg(short, long)]
+        size: Option<DiskSize>,
+        /// Block allocation: sparse, fallocate or zero (raw images only)
+        #[arg(short, long, default_value_t = AllocationPolicy::Preallocate)]
+        alloc: AllocationPolicy,
+        /// Replace an existing image of a different size
+        #[arg(long)]
+        force: bool,
+        /// Keep the image in an encrypted container that is opened in-process,
+        /// for hosts without dm-crypt (uses --key-file or asks for a passphrase)
+        #[arg(long, conflicts_with_all = ["format", "backing"])]
+        container: bool,
+        /// Image format: raw, or qcow2, which grows as it is written and is
+        /// mounted through NBD
+        #[arg(short = 'f', long, default_value_t = ImageFormat::Raw)]
+        format: ImageFormat,
+        /// Start a qcow2 image as an overlay on this raw or qcow2 image, which
+        /// is only read from (implies --format qcow2)
+        #[arg(short, long, value_name = "FILE")]
+        backing: Option<PathBuf>,
+    },
+    /// Format a disk image
+    Format {
+        #[command(flatten)]
+        disk: DiskArgs,
+        #[command(flatten)]
+        options: FormatArgs,
+        /// Build the filesystem in-process (ext4 and vfat), without mkfs or root
+        #[arg(long)]
+        native: bool,
+        /// Copy the contents of this host directory into the new filesystem (implies --native)
+        #[arg(long, value_name = "DIR")]
+        from: Option<PathBuf>,
+        /// Put the filesystem inside a LUKS2 volume, unlocked with --key-file or a passphrase
+        #[arg(long, conflicts_with_all = ["native", "from"])]
+        encrypt: bool,
+        /// Format even if the image already holds a filesystem
+        #[arg(long)]
+        force: bool,
+    },
+    /// Mount a disk image through a loop device, or an NBD device for qcow2
+    Mount {
+        #[command(flatten)]
+        disk: DiskArgs,
+        #[command(flatten)]
+        options: LoopArgs,
+    },
+    /// Unmount a mounted disk image
+    Unmount {
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
+    /// Check the filesystem on an unmounted disk image. Exits 0 if clean, 1 if
+    /// errors were fixed, 4 if errors remain and 8 if the check could not run
+    Check {
+        #[command(flatten)]
+        disk: DiskArgs,
+        /// Fix the problems found instead of only reporting them
+        #[arg(long)]
+        repair: bool,
+        /// Print everything the checker printed
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Grow or shrink a disk image and its filesystem (growing works while mounted)
+    Resize {
+        #[command(flatten)]
+        disk: DiskArgs,
+        /// New image size, e.g. 20G
+        #[arg(short, long)]
+        size: DiskSize,
+        /// Block allocation for added space: sparse, fallocate or zero
+        #[arg(short, long, default_value_t = AllocationPolicy::Preallocate)]
+        alloc: AllocationPolicy,
+    },
+    /// Copy a host file onto the disk, writing into the image directly when it is not mounted
+    Put {
+        source: PathBuf,
+        /// Destination path inside the disk
+        destination: PathBuf,
+        #[command(flatten)]
+        disk: DiskArgs,
+        #[command(flatten)]
+        tree: TreeArgs,
+        /// What to do with files that already exist: replace, skip, fail or rename
+        #[arg(long, value_name = "MODE", default_value_t = Overwrite::Replace)]
+        overwrite: Overwrite,
+        /// Checksum the file (sha256 or blake3), read it back after syncing and
+        /// record the digest in the disk's manifest (mounts the disk if needed)
+        #[arg(
+            long,
+            value_name = "ALGO",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "sha256",
+            conflicts_with_all = ["recursive", "include", "exclude"]
+        )]
+        verify: Option<HashAlgorithm>,
+    },
+    /// Copy a file from the disk to the host
+    Get {
+        /// Source path inside the disk
+        source: PathBuf,
+        destination: PathBuf,
+        #[command(flatten)]
+        disk: DiskArgs,
+        #[command(flatten)]
+        tree: TreeArgs,
+        /// What to do with files that already exist: replace, skip, fail or rename
+        #[arg(long, value_name = "MODE", default_value_t = Overwrite::Replace)]
+        overwrite: Overwrite,
+    },
+    /// List a directory on the disk
+    Ls {
+        path: Option<PathBuf>,
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
+    /// Remove a file or empty directory from the disk
+    Rm {
+        path: PathBuf,
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
+    /// Show image size, allocation and mount status
+    Info {
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
+    /// Serve the unmounted disk to NBD clients (nbd-client, qemu) on a Unix
+    /// socket or TCP port, until the client disconnects
+    Serve {
+        #[command(flatten)]
+        disk: DiskArgs,
+        /// Listen on this Unix socket instead of a TCP port
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["port", "bind"])]
+        socket: Option<PathBuf>,
+        #[arg(short, long, default_value_t = 10809)]
+        port: u16,
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: String,
+        /// Export name clients ask for [default: the disk's name]
+        #[arg(short = 'x', long)]
+        export_name: Option<String>,
+        /// Reject writes
+        #[arg(short, long)]
+        read_only: bool,
+        /// Keep serving, one client at a time, after a client disconnects
+        #[arg(long)]
+        persistent: bool,
+    },
+    /// Convert the unmounted disk to a VMDK, VHD, VHDX or zstd/xz-compressed
+    /// image for other virtualisation stacks
+    Export {
+        destination: PathBuf,
+        #[command(flatten)]
+        disk: DiskArgs,
+        /// Image format: vmdk, vhd (dynamic), vhd-fixed, vhdx, zstd or xz
+        /// [default: from the destination's extension]
+        #[arg(short = 'f', long)]
+        format: Option<Format>,
+        /// Replace an existing destination file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Make the disk a raw copy of a VMDK, VHD, VHDX or zstd/xz-compressed
+    /// image, once its footers and checksums check out
+    Import {
+        source: PathBuf,
+        #[command(flatten)]
+        disk: DiskArgs,
+        /// Replace an existing image
+        #[arg(long)]
+        force: bool,
+    },
+    /// Manage loop devices
+    #[command(subcommand)]
+    Loop(LoopCmd),
+    /// Take, list, restore and delete snapshots of a disk image
+    #[command(subcommand)]
+    Snapshot(SnapshotCmd),
+    /// Manage the keys of an encrypted disk
+    #[command(subcommand)]
+    Key(KeyCmd),
+    /// Check files on the disk against the checksums recorded by `put --verify`
+    Verify {
+        /// Paths inside the disk [default: everything in the manifest]
+        paths: Vec<PathBuf>,
+        #[command(flatten)]
+        disk: DiskArgs,
+    },
+    /// List registered disks
+    List,
+    /// Drop a disk from the registry, leaving its image in place
+    Forget { name: String },
+}
 
-    // 3. Mount the disk image
-    match mount_virtual_disk() {
-        Ok(_) => println!("Virtual disk mounted."),
-        Err(e) => {
-            eprintln!("Failed to mount virtual disk: {:?}", e);
-            return Err(io::Error::new(io::ErrorKind::Other, "Mount failed"));
+fn print_loop(info: &LoopInfo) {
+    let mut flags = Vec::new();
+    if info.read_only {
+        flags.push("ro");
+    }
+    if info.autoclear {
+        flags.push("autoclear");
+    }
+    if info.direct_io {
+        flags.push("dio");
+    }
+    println!("{}: {} {}", info.device.display(), info.backing_file.display(), flags.join(","));
+}
+
+/// The size of the disk held by `image`, a raw or qcow2 image.
+fn image_size(image: &Path) -> Result<u64> {
+    let size = || -> io::Result<u64> {
+        if qcow2::is_qcow2(image)? {
+            return qcow2::info(image).map(|info| info.virtual_size);
+        }
+        fs::metadata(image).map(|meta| meta.len())
+    };
+    size().map_err(|source| MizzleError::Io { path: image.into(), source })
+}
+
+/// Serves `device` over NBD to the clients `accept` returns, one at a time,
+/// and only to the first unless `persistent`.
+fn serve_nbd<S>(
+    mut accept: impl FnMut() -> io::Result<S>,
+    device: &mut dyn BlockDevice,
+    export: &str,
+    read_only: bool,
+    persistent: bool,
+) -> io::Result<()>
+where
+    for<'a> &'a S: Read + Write,
+{
+    loop {
+        let stream = accept()?;
+        if let Err(e) = nbd::serve(&stream, device, export, read_only) {
+            if !persistent {
+                return Err(e);
+            }
+            eprintln!("mizzlestore: {}", e);
+        }
+        if !persistent {
+            return Ok(());
         }
     }
+}
+
+/// Seconds since the Unix epoch as a UTC date and time.
+fn utc(secs: u64) -> String {
+    // Civil date from a day count, after Howard Hinnant's `civil_from_days`
+    let (days, time) = (secs / 86400, secs % 86400);
+    let z = days + 719_468;
+    let (era, doe) = (z / 146_097, z % 146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!("{}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
 
-    // 4. Transfer a file into the mounted virtual disk
-    let source_file = "/path/to/your/source/file.txt"; // Change this to an actual file
-    let destination = "file.txt";
-    match copy_file_to_mount(source_file, destination) {
-        Ok(_) => println!("File copied to virtual disk."),
-        Err(e) => eprintln!("Failed to copy file: {:?}", e),
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("mizzlestore: {}", e);
+            if matches!(e, MizzleError::Refused { operation: "create" | "format", .. }) {
+                eprintln!("mizzlestore: pass --force to overwrite it");
+            }
+            ExitCode::FAILURE
+        }
     }
+}
 
-    // 5. Unmount the virtual disk
-    match unmount_virtual_disk() {
-        Ok(_) => println!("Virtual disk unmounted."),
-        Err(e) => eprintln!("Failed to unmount virtual disk: {:?}", e),
+fn run(cli: Cli) -> Result<ExitCode> {
+    let mut registry = Registry::load(cli.registry.unwrap_or_else(registry::default_path))?;
+    match cli.command {
+        Cmd::Create { disk: args, size, alloc, force, container, format, backing } => {
+            let format = if backing.is_some() { ImageFormat::Qcow2 } else { format };
+            let size = match (size, &backing) {
+                (Some(size), _) => size.bytes(),
+                (None, Some(backing)) => image_size(backing)?,
+                (None, None) => DISK_SIZE,
+            };
+            let (name, disk) = args.resolve(&registry, Some(size))?;
+            let mut disk = disk
+                .with_allocation(alloc)
+                .with_container(container)
+                .with_image_format(format)
+                .with_backing_file(backing.clone())
+                .with_force(force);
+            let report = disk.create()?;
+            registry.record(&name, &disk);
+            if container && report.is_some() {
+                // A new container starts out with its key in the first slot
+                registry.set_keys(&name, BTreeMap::from([(0, KeyRecord::from_source(&args.key()))]))?;
+            }
+            registry.save()?;
+            let kind = match (format, &backing) {
+                (ImageFormat::Qcow2, Some(backing)) => format!("qcow2 on {}", backing.display()),
+                (ImageFormat::Qcow2, None) => "qcow2".to_string(),
+                (ImageFormat::Raw, _) => report.as_ref().map(|r| r.policy.to_string()).unwrap_or_default(),
+            };
+            match report {
+                Some(report) => println!(
+                    "Virtual disk image of {} created ({}), {} allocated on the host.",
+                    human(report.size),
+                    kind,
+                    human(report.allocated)
+                ),
+                None => println!("Virtual disk image of {} already exists.", human(disk.size())),
+            }
+        }
+        Cmd::Format { disk: args, options, native, from, encrypt, force } => {
+            let (name, disk) = args.resolve(&registry, None)?;
+            let mut disk = disk.with_format_options(options.into()).with_encryption(encrypt).with_force(force);
+            let formatted = if native || from.is_some() {
+                disk.format_in_process(from.as_deref())?
+            } else {
+                disk.format()?
+            };
+            registry.record(&name, &disk);
+            if formatted && encrypt {
+                // luksFormat puts the key in the first slot
+                registry.set_keys(&name, BTreeMap::from([(0, KeyRecord::from_source(&args.key()))]))?;
+            }
+            registry.save()?;
+            if formatted && encrypt {
+                println!("Virtual disk image encrypted (LUKS2) and formatted as {}.", disk.filesystem());
+            } else if formatted {
+                println!("Virtual disk image formatted as {}.", disk.filesystem());
+            } else {
+                println!("Virtual disk image is already formatted as {}.", disk.filesystem());
+            }
+        }
+        Cmd::Mount { disk, options } => {
+            let (name, disk) = disk.resolve(&registry, None)?;
+            let mut disk = disk.with_loop_options(options.into());
+            if disk.mount()? {
+                println!("Virtual disk mounted.");
+            } else {
+                println!("Virtual disk is already mounted on {}.", disk.mount_point().display());
+            }
+            registry.record(name, &disk);
+            registry.save()?;
+        }
+        Cmd::Unmount { disk } => {
+            let (name, mut disk) = disk.resolve(&registry, None)?;
+            disk.unmount()?;
+            registry.record(name, &disk);
+            registry.save()?;
+            println!("Virtual disk unmounted.");
+        }
+        Cmd::Check { disk, repair, verbose } => {
+            let mode = if repair { CheckMode::Repair } else { CheckMode::ReadOnly };
+            let report = match disk.resolve(&registry, None).and_then(|(_, disk)| disk.check(mode)) {
+                Ok(report) => report,
+                Err(e) => {
+                    eprintln!("mizzlestore: {}", e);
+                    return Ok(ExitCode::from(8));
+                }
+            };
+            print_check(&report, verbose);
+            return Ok(ExitCode::from(report.status.exit_code()));
+        }
+        Cmd::Resize { disk, size, alloc } => {
+            let (name, disk) = disk.resolve(&registry, None)?;
+            let mut disk = disk.with_allocation(alloc);
+            let report = disk.resize(size.bytes())?;
+            registry.record(name, &disk);
+            registry.save()?;
+            match report {
+                Some(report) => println!(
+                    "Virtual disk resized to {}, {} allocated on the host.",
+                    human(report.size),
+                    human(report.allocated)
+                ),
+                None => println!("Virtual disk is already {}.", human(size.bytes())),
+            }
+        }
+        Cmd::Put { source, destination, disk, tree, overwrite, verify } => {
+            let disk = disk.resolve(&registry, None)?.1;
+            match (tree.options(overwrite), verify) {
+                (Some(options), _) => print_copy(&disk.put_tree(&source, &destination, &options)?),
+                (None, Some(algorithm)) => {
+                    let (placed, entry) =
+                        disk.put_verified(&source, &destination, algorithm, overwrite, &mut progress_bar())?;
+                    print_placed(&placed, &destination, "to");
+                    if let Some(entry) = entry {
+                        println!("Verified ({} {}).", entry.algorithm, entry.digest);
+                    }
+                }
+                (None, None) => {
+                    let placed = disk.put_with_progress(&source, &destination, overwrite, &mut progress_bar())?;
+                    print_placed(&placed, &destination, "to");
+                }
+            }
+        }
+        Cmd::Get { source, destination, disk, tree, overwrite } => {
+            let disk = disk.resolve(&registry, None)?.1;
+            match tree.options(overwrite) {
+                Some(options) => print_copy(&disk.get_tree(&source, &destination, &options)?),
+                None => {
+                    let placed = disk.get_with_progress(&source, &destination, overwrite, &mut progress_bar())?;
+                    print_placed(&placed, &destination, "from");
+                }
+            }
+        }
+        Cmd::Ls { path, disk } => {
+            for entry in disk.resolve(&registry, None)?.1.list(&path.unwrap_or_default())? {
+                let kind = if entry.is_dir { 'd' } else if entry.is_symlink { 'l' } else { '-' };
+                println!("{} {:>12} {}", kind, entry.len, entry.name);
+            }
+        }
+        Cmd::Rm { path, disk } => {
+            disk.resolve(&registry, None)?.1.remove(&path)?;
+            println!("Removed {} from virtual disk.", path.display());
+        }
+        Cmd::Info { disk } => {
+            let (name, disk) = disk.resolve(&registry, None)?;
+            println!("name:        {}", name);
+            println!("image:       {}", disk.image().display());
+            let meta = fs::metadata(disk.image())
+                .map_err(|source| MizzleError::Io { path: disk.image().into(), source })?;
+            if disk.is_qcow2() {
+                let info = qcow2::info(disk.image())
+                    .map_err(|source| MizzleError::Io { path: disk.image().into(), source })?;
+                println!("format:      qcow2 (version {}, {} clusters)", info.version, human(info.cluster_size));
+                println!("size:        {} bytes", info.virtual_size);
+                println!("file size:   {} bytes", meta.len());
+                if let Some(backing) = &info.backing_file {
+                    let format = info.backing_format.as_deref().unwrap_or("probed");
+                    println!("backing:     {} ({})", backing.display(), format);
+                }
+                if info.snapshots > 0 {
+                    println!("snapshots:   {} (internal)", info.snapshots);
+                }
+                if info.dirty || info.corrupt {
+                    let why = if info.corrupt { "marked corrupt" } else { "dirty, refcounts need a repair" };
+                    println!("warning:     {}", why);
+                }
+            } else {
+                println!("size:        {} bytes", meta.len());
+            }
+            println!("allocated:   {} bytes", disk.allocated()?);
+            println!("filesystem:  {}", disk.filesystem());
+            if disk.is_encrypted() {
+                println!("encryption:  LUKS");
+            } else if disk.is_container() {
+                println!("encryption:  container (AES-256-CTR, HMAC-SHA256)");
+            }
+            if let Some(uuid) = disk.uuid() {
+                println!("uuid:        {}", uuid);
+            }
+            println!("mount point: {}", disk.mount_point().display());
+            println!("state:       {}", disk.state());
+            if let Some(device) = disk.loop_device() {
+                println!("device:      {}", device.display());
+            }
+            if registry.get(&name).is_none() {
+                println!("(not registered)");
+            }
+        }
+        Cmd::Serve { disk, socket, port, bind, export_name, read_only, persistent } => {
+            let (name, disk) = disk.resolve(&registry, None)?;
+            let mut device = disk.open_block_device(!read_only)?;
+            let export = export_name.unwrap_or(name);
+            match socket {
+                Some(path) => {
+                    let io_err = |source| MizzleError::Io { path: path.clone(), source };
+                    let listener = UnixListener::bind(&path).map_err(io_err)?;
+                    println!("Serving {} over NBD on {}.", export, path.display());
+                    let accept = || listener.accept().map(|(stream, _)| stream);
+                    let served = serve_nbd(accept, device.as_mut(), &export, read_only, persistent);
+                    let _ = fs::remove_file(&path);
+                    served.map_err(io_err)?;
+                }
+                None => {
+                    let address = format!("{}:{}", bind, port);
+                    let io_err = |source| MizzleError::Io { path: PathBuf::from(&address), source };
+                    let listener = TcpListener::bind(&address).map_err(io_err)?;
+                    println!("Serving {} over NBD on {}.", export, address);
+                    // Requests and replies are small; do not hold them back
+                    let accept = || listener.accept().and_then(|(stream, _)| stream.set_nodelay(true).map(|_| stream));
+                    serve_nbd(accept, device.as_mut(), &export, read_only, persistent).map_err(io_err)?;
+                }
+            }
+        }
+        Cmd::Export { destination, disk, format, force } => {
+            let Some(format) = format.or_else(|| Format::from_extension(&destination)) else {
+                return Err(MizzleError::Refused {
+                    path: destination,
+                    operation: "write",
+                    reason: "its extension names no image format; pass --format".to_string(),
+                });
+            };
+            let (_, disk) = disk.resolve(&registry, None)?;
+            disk.with_force(force).export(&destination, format, &mut progress_bar())?;
+            let allocated = fs::metadata(&destination).map(|meta| meta.blocks() * 512).unwrap_or(0);
+            println!(
+                "Virtual disk exported to {} ({}), {} allocated on the host.",
+                destination.display(),
+                format,
+                human(allocated)
+            );
+        }
+        Cmd::Import { source, disk: args, force } => {
+            let (name, disk) = args.resolve(&registry, Some(0))?;
+            let mut disk = disk.with_force(force);
+            let size = disk.import(&source, &mut progress_bar())?;
+            registry.record(&name, &disk);
+            registry.save()?;
+            let allocated = fs::metadata(disk.image()).map(|meta| meta.blocks() * 512).unwrap_or(0);
+            println!(
+                "Virtual disk image of {} imported from {}, {} allocated on the host.",
+                human(size),
+                source.display(),
+                human(allocated)
+            );
+        }
+        Cmd::Loop(LoopCmd::Attach { disk, options }) => {
+            // Autoclear would release the device as soon as this process exits
+            let options = LoopOptions { autoclear: false, ..options.into() };
+            let device = disk.resolve(&registry, None)?.1.with_loop_options(options).attach()?;
+            println!("{}", device.path().display());
+        }
+        Cmd::Loop(LoopCmd::Detach { device }) => {
+            LoopDevice::open(&device)
+                .and_then(LoopDevice::detach)
+                .map_err(|source| MizzleError::Loop { path: device, operation: "detach", source })?;
+        }
+        Cmd::Loop(LoopCmd::Status { image }) => {
+            let devices = match &image {
+                Some(image) => loopdev::find(image),
+                None => loopdev::list(),
+            };
+            let devices = devices.map_err(|source| MizzleError::Loop {
+                path: image.unwrap_or_else(|| PathBuf::from("/dev/loop-control")),
+                operation: "query",
+                source,
+            })?;
+            for info in &devices {
+                print_loop(info);
+            }
+        }
+        Cmd::Snapshot(SnapshotCmd::Create { snapshot, disk }) => {
+            let (name, disk) = disk.resolve(&registry, None)?;
+            let taken = disk.snapshot(&snapshot)?;
+            let reflink = taken.reflink;
+            registry.record(&name, &disk);
+            registry.add_snapshot(&name, &snapshot, taken)?;
+            registry.save()?;
+            let how = if reflink { "reflinked" } else { "copied" };
+            println!("Snapshot {} of virtual disk taken ({}).", snapshot, how);
+        }
+        Cmd::Snapshot(SnapshotCmd::List { disk }) => {
+            let (name, _) = disk.resolve(&registry, None)?;
+            let snapshots = registry.get(&name).map(|record| &record.snapshots).filter(|s| !s.is_empty());
+            let Some(snapshots) = snapshots else {
+                println!("No snapshots of this disk.");
+                return Ok(ExitCode::SUCCESS);
+            };
+            println!("{:<16} {:<19} {:>10} {:>10}  TYPE", "NAME", "CREATED (UTC)", "SIZE", "ALLOCATED");
+            for (snapshot, taken) in snapshots {
+                let allocated = match fs::metadata(&taken.path) {
+                    Ok(meta) => human(meta.blocks() * 512),
+                    Err(_) => "missing".to_string(),
+                };
+                println!(
+                    "{:<16} {:<19} {:>10} {:>10}  {}",
+                    snapshot,
+                    utc(taken.created),
+                    human(taken.size),
+                    allocated,
+                    if taken.reflink { "reflink" } else { "copy" }
+                );
+            }
+        }
+        Cmd::Snapshot(SnapshotCmd::Restore { snapshot, disk }) => {
+            let (name, mut disk) = disk.resolve(&registry, None)?;
+            let taken = registry.snapshot(&name, &snapshot)?.clone();
+            disk.restore(&taken)?;
+            registry.record(&name, &disk);
+            registry.save()?;
+            println!("Virtual disk restored to snapshot {} from {}.", snapshot, utc(taken.created));
+        }
+        Cmd::Snapshot(SnapshotCmd::Delete { snapshot, disk }) => {
+            let (name, _) = disk.resolve(&registry, None)?;
+            let taken = registry.remove_snapshot(&name, &snapshot)?;
+            taken.delete().map_err(|source| MizzleError::Io { path: taken.path.clone(), source })?;
+            registry.save()?;
+            println!("Deleted snapshot {}.", snapshot);
+        }
+        Cmd::Key(KeyCmd::Add { disk, new_key_file }) => {
+            let (name, disk) = disk.resolve(&registry, None)?;
+            let new = match new_key_file {
+                Some(path) => KeySource::KeyFile(path),
+                None => KeySource::Prompt,
+            };
+            let slot = disk.add_key(&new)?;
+            registry.record(&name, &disk);
+            registry.add_key(&name, slot, KeyRecord::from_source(&new))?;
+            registry.save()?;
+            println!("Key added in slot {}.", slot);
+        }
+        Cmd::Key(KeyCmd::Remove { slot, disk }) => {
+            let (name, disk) = disk.resolve(&registry, None)?;
+            disk.remove_key(slot)?;
+            if registry.get(&name).is_some() {
+                registry.remove_key(&name, slot)?;
+                registry.save()?;
+            }
+            println!("Key in slot {} removed.", slot);
+        }
+        Cmd::Key(KeyCmd::List { disk }) => {
+            let (name, disk) = disk.resolve(&registry, None)?;
+            let keys = registry.get(&name).map(|record| record.keys.clone()).unwrap_or_default();
+            println!("{:<4} {:<12} {:<19}  KEY FILE", "SLOT", "KIND", "ADDED (UTC)");
+            for slot in disk.key_slots()? {
+                // Keys added some other way are not in the registry
+                let line = match keys.get(&slot) {
+                    Some(key) => format!(
+                        "{:<4} {:<12} {:<19}  {}",
+                        slot,
+                        key.kind.to_string(),
+                        utc(key.added),
+                        key.key_file.as_deref().map_or(String::new(), |path| path.display().to_string())
+                    ),
+                    None => format!("{:<4} {:<12} -", slot, "unknown"),
+                };
+                println!("{}", line.trim_end());
+            }
+        }
+        Cmd::Key(KeyCmd::Recovery { disk }) => {
+            let (name, disk) = disk.resolve(&registry, None)?;
+            let (slot, key) = disk.add_recovery_key()?;
+            registry.record(&name, &disk);
+            registry.add_key(&name, slot, KeyRecord::new(KeyKind::Recovery, None))?;
+            registry.save()?;
+            println!("Recovery key added in slot {}. Write it down and keep it safe; it is not stored anywhere:", slot);
+            println!();
+            println!("    {}", key);
+            println!();
+            println!("Type it in like a passphrase to unlock the disk.");
+        }
+        Cmd::Key(KeyCmd::Rotate { disk, drop_keys }) => {
+            let (name, disk) = disk.resolve(&registry, None)?;
+            let report = disk.rotate_key(drop_keys, &mut progress_bar())?;
+            registry.record(&name, &disk);
+            for &slot in &report.removed {
+                registry.remove_key(&name, slot)?;
+            }
+            registry.save()?;
+            if report.resumed {
+                println!("Interrupted key rotation finished; the disk is under a new master key.");
+            } else {
+                println!("Virtual disk re-encrypted under a new master key.");
+            }
+            println!("The key in slot {} unlocks it.", report.slot);
+            if !report.removed.is_empty() {
+                let removed: Vec<String> = report.removed.iter().map(usize::to_string).collect();
+                let slots = if removed.len() == 1 { "slot" } else { "slots" };
+                println!("Removed the keys in {} {}; add them again with `key add`.", slots, removed.join(", "));
+            }
+        }
+        Cmd::Verify { paths, disk } => {
+            let disk = disk.resolve(&registry, None)?.1;
+            let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+            let results = disk.verify(&paths)?;
+            if results.is_empty() {
+                println!("No verified files recorded on the disk.");
+            }
+            let mut failed = 0;
+            for (path, status) in &results {
+                match status {
+                    VerifyStatus::Ok => println!("OK        {}", path),
+                    VerifyStatus::Mismatch { expected, actual } => {
+                        println!("FAILED    {} (expected {}, found {})", path, expected, actual)
+                    }
+                    VerifyStatus::Missing => println!("MISSING   {}", path),
+                    VerifyStatus::Unrecorded => println!("UNKNOWN   {} (not in manifest)", path),
+                }
+                if *status != VerifyStatus::Ok {
+                    failed += 1;
+                }
+            }
+            if failed > 0 {
+                return Err(MizzleError::Verify { failed, checked: results.len() });
+            }
+        }
+        Cmd::List => {
+            println!("{:<16} {:<10} {:<6} {:>14}  IMAGE", "NAME", "STATE", "FS", "SIZE");
+            for (name, record) in registry.iter() {
+                // Show what is on the host now rather than what was last recorded
+                let disk = VirtualDisk::from_record(record);
+                println!(
+                    "{:<16} {:<10} {:<6} {:>14}  {}",
+                    name,
+                    disk.state().to_string(),
+                    record.filesystem.to_string(),
+                    record.size,
+                    record.image.display()
+                );
+            }
+        }
+        Cmd::Forget { name } => {
+            if registry.remove(&name).is_none() {
+                return Err(MizzleError::UnknownDisk { name });
+            }
+            registry.save()?;
+            println!("Forgot disk {}.", name);
+        }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
-
@@ -0,0 +1,572 @@
+//! The NBD protocol, for images the kernel cannot read by itself: a server
+//! speaking to NBD clients such as `nbd-client` and qemu, and `/dev/nbdN`
+//! devices fed by a daemon process so that the image can be mounted.
+
+use crate::blockdev::BlockDevice;
+use crate::bytes::{be16, be32, be64};
+use crate::loopdev;
+use crate::qcow2::{self, Qcow2Image};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+// Handshake flags
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const CLIENT_NO_ZEROES: u32 = 1 << 1;
+
+// Options and their replies
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const REP_ERR_INVALID: u32 = (1 << 31) + 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+const INFO_EXPORT: u16 = 0;
+
+// Transmission flags
+const HAS_FLAGS: u16 = 1 << 0;
+const READ_ONLY: u16 = 1 << 1;
+const SEND_FLUSH: u16 = 1 << 2;
+const SEND_FUA: u16 = 1 << 3;
+const SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+// Commands
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_WRITE_ZEROES: u16 = 6;
+const CMD_FLAG_FUA: u16 = 1 << 0;
+
+/// Largest read or write one request may ask for, as qemu-nbd allows.
+const MAX_REQUEST: u32 = 32 << 20;
+
+// Kernel client ioctls
+const NBD_SET_SOCK: libc::Ioctl = 0xAB00;
+const NBD_SET_BLKSIZE: libc::Ioctl = 0xAB01;
+const NBD_DO_IT: libc::Ioctl = 0xAB03;
+const NBD_CLEAR_SOCK: libc::Ioctl = 0xAB04;
+const NBD_CLEAR_QUE: libc::Ioctl = 0xAB05;
+const NBD_SET_SIZE_BLOCKS: libc::Ioctl = 0xAB07;
+const NBD_DISCONNECT: libc::Ioctl = 0xAB08;
+const NBD_SET_FLAGS: libc::Ioctl = 0xAB0A;
+
+const BLOCK_SIZE: u64 = 512;
+
+fn read_u16(input: &mut impl Read) -> io::Result<u16> {
+    let mut b = [0; 2];
+    input.read_exact(&mut b)?;
+    Ok(be16(&b, 0))
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0; 4];
+    input.read_exact(&mut b)?;
+    Ok(be32(&b, 0))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut b = [0; 8];
+    input.read_exact(&mut b)?;
+    Ok(be64(&b, 0))
+}
+
+fn protocol(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// The transmission flags an export is offered with.
+fn transmission_flags(read_only: bool) -> u16 {
+    let flags = HAS_FLAGS | SEND_FLUSH;
+    if read_only {
+        flags | READ_ONLY
+    } else {
+        flags | SEND_FUA | SEND_WRITE_ZEROES
+    }
+}
+
+fn option_reply(output: &mut impl Write, option: u32, reply: u32, data: &[u8]) -> io::Result<()> {
+    output.write_all(&OPTION_REPLY_MAGIC.to_be_bytes())?;
+    output.write_all(&option.to_be_bytes())?;
+    output.write_all(&reply.to_be_bytes())?;
+    output.write_all(&(data.len() as u32).to_be_bytes())?;
+    output.write_all(data)?;
+    output.flush()
+}
+
+/// Runs the fixed-newstyle handshake for a single export called `name`.
+/// Returns whether the client went on to transmission.
+fn handshake(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    name: &str,
+    size: u64,
+    read_only: bool,
+) -> io::Result<bool> {
+    output.write_all(&NBDMAGIC.to_be_bytes())?;
+    output.write_all(&IHAVEOPT.to_be_bytes())?;
+    output.write_all(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes())?;
+    output.flush()?;
+    let client_flags = read_u32(input)?;
+    let flags = transmission_flags(read_only);
+    // Any name reaches the one export, as with qemu-nbd
+    let known = |requested: &[u8]| requested.is_empty() || requested == name.as_bytes();
+
+    loop {
+        if read_u64(input)? != IHAVEOPT {
+            return Err(protocol("bad option magic"));
+        }
+        let option = read_u32(input)?;
+        let len = read_u32(input)?;
+        if len > 64 * 1024 {
+            return Err(protocol("option too long"));
+        }
+        let mut data = vec![0; len as usize];
+        input.read_exact(&mut data)?;
+        match option {
+            OPT_EXPORT_NAME => {
+                if !known(&data) {
+                    return Ok(false);
+                }
+                output.write_all(&size.to_be_bytes())?;
+                output.write_all(&flags.to_be_bytes())?;
+                if client_flags & CLIENT_NO_ZEROES == 0 {
+                    output.write_all(&[0; 124])?;
+                }
+                output.flush()?;
+                return Ok(true);
+            }
+            OPT_ABORT => {
+                option_reply(output, option, REP_ACK, &[])?;
+                return Ok(false);
+            }
+            OPT_LIST => {
+                let mut reply = (name.len() as u32).to_be_bytes().to_vec();
+                reply.extend_from_slice(name.as_bytes());
+                option_reply(output, option, REP_SERVER, &reply)?;
+                option_reply(output, option, REP_ACK, &[])?;
+            }
+            OPT_INFO | OPT_GO => {
+                // The name, then a count of information requests
+                let name_len = if data.len() >= 4 { be32(&data, 0) as usize } else { usize::MAX };
+                if data.len() < 4 || name_len > data.len() - 4 || data.len() - 4 - name_len < 2 {
+                    option_reply(output, option, REP_ERR_INVALID, &[])?;
+                    continue;
+                }
+                if !known(&data[4..4 + name_len]) {
+                    option_reply(output, option, REP_ERR_UNKNOWN, &[])?;
+                    continue;
+                }
+                let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                info.extend_from_slice(&size.to_be_bytes());
+                info.extend_from_slice(&flags.to_be_bytes());
+                option_reply(output, option, REP_INFO, &info)?;
+                option_reply(output, option, REP_ACK, &[])?;
+                if option == OPT_GO {
+                    return Ok(true);
+                }
+            }
+            _ => option_reply(output, option, REP_ERR_UNSUP, &[])?,
+        }
+    }
+}
+
+/// The errno an NBD client is told for a failed request.
+fn errno(e: &io::Error) -> u32 {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => libc::EPERM as u32,
+        io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof => libc::EINVAL as u32,
+        io::ErrorKind::StorageFull => libc::ENOSPC as u32,
+        _ => libc::EIO as u32,
+    }
+}
+
+/// Serves requests for `device` until the client disconnects.
+fn transmit(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    device: &mut dyn BlockDevice,
+    read_only: bool,
+) -> io::Result<()> {
+    let size = device.len()?;
+    let mut buf = Vec::new();
+    loop {
+        let magic = match read_u32(input) {
+            Ok(magic) => magic,
+            // A client that just goes away has disconnected too
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return device.flush(),
+            Err(e) => return Err(e),
+        };
+        if magic != REQUEST_MAGIC {
+            return Err(protocol("bad request magic"));
+        }
+        let flags = read_u16(input)?;
+        let command = read_u16(input)?;
+        let handle = read_u64(input)?;
+        let offset = read_u64(input)?;
+        let len = read_u32(input)?;
+        if len > MAX_REQUEST {
+            return Err(protocol(format!("request of {} bytes is too large", len)));
+        }
+        let in_range = offset.checked_add(u64::from(len)).is_some_and(|end| end <= size);
+        buf.resize(len as usize, 0);
+
+        let result = match command {
+            CMD_READ if in_range => device.read_at(offset, &mut buf),
+            CMD_WRITE => {
+                input.read_exact(&mut buf)?;
+                match (read_only, in_range) {
+                    (true, _) => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+                    (false, false) => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+                    (false, true) => device.write_at(offset, &buf),
+                }
+            }
+            CMD_WRITE_ZEROES if !read_only && in_range => {
+                buf.fill(0);
+                device.write_at(offset, &buf)
+            }
+            CMD_FLUSH => device.flush(),
+            CMD_DISC => return device.flush(),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+        let result = match result {
+            Ok(()) if flags & CMD_FLAG_FUA != 0 && command != CMD_READ => device.flush(),
+            other => other,
+        };
+
+        output.write_all(&SIMPLE_REPLY_MAGIC.to_be_bytes())?;
+        output.write_all(&result.as_ref().map_or_else(errno, |_| 0).to_be_bytes())?;
+        output.write_all(&handle.to_be_bytes())?;
+        if command == CMD_READ && result.is_ok() {
+            output.write_all(&buf)?;
+        }
+        output.flush()?;
+    }
+}
+
+/// Serves `device` to one NBD client on `stream` as the export `name`,
+/// from the handshake until the client disconnects.
+pub fn serve<S>(stream: &S, device: &mut dyn BlockDevice, name: &str, read_only: bool) -> io::Result<()>
+where
+    for<'a> &'a S: Read + Write,
+{
+    let mut input = BufReader::new(stream);
+    let mut output = BufWriter::new(stream);
+    let size = device.len()?;
+    if handshake(&mut input, &mut output, name, size, read_only)? {
+        transmit(&mut input, &mut output, device, read_only)?;
+    }
+    Ok(())
+}
+
+/// Whether `device` is an NBD device.
+pub fn is_nbd(device: &Path) -> bool {
+    device.file_name().is_some_and(|n| n.to_string_lossy().starts_with("nbd"))
+}
+
+fn sys_block(device: &Path) -> PathBuf {
+    Path::new("/sys/block").join(device.file_name().unwrap_or_default())
+}
+
+/// The process serving NBD device `device`, if it is connected.
+fn server_pid(device: &Path) -> Option<u32> {
+    fs::read_to_string(sys_block(device).join("pid")).ok()?.trim().parse().ok()
+}
+
+/// The files the process serving NBD device `device` has open: the image
+/// behind it and its backing files.
+pub fn served_files(device: &Path) -> Vec<PathBuf> {
+    let Some(pid) = server_pid(device) else {
+        return Vec::new();
+    };
+    let Ok(fds) = fs::read_dir(format!("/proc/{}/fd", pid)) else {
+        return Vec::new();
+    };
+    fds.filter_map(|fd| fs::read_link(fd.ok()?.path()).ok()).filter(|target| target.is_absolute()).collect()
+}
+
+/// The image NBD device `device` serves: the one of the files its server
+/// has open that is not a backing file of another.
+pub fn served_image(device: &Path) -> Option<PathBuf> {
+    let files: Vec<PathBuf> = served_files(device).into_iter().filter(|f| f.is_file()).collect();
+    let backing: Vec<PathBuf> = files
+        .iter()
+        .flat_map(|f| qcow2::backing_chain(f).unwrap_or_default())
+        .filter_map(|b| fs::canonicalize(b).ok())
+        .collect();
+    files.into_iter().find(|f| !backing.contains(f))
+}
+
+/// The first NBD device nothing is connected to.
+fn free_device() -> io::Result<PathBuf> {
+    let mut devices: Vec<u32> = fs::read_dir("/sys/block")?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.strip_prefix("nbd")?.parse().ok())
+        .collect();
+    if devices.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no NBD devices; load the nbd kernel module"));
+    }
+    devices.sort_unstable();
+    devices
+        .into_iter()
+        .map(|n| PathBuf::from(format!("/dev/nbd{}", n)))
+        .find(|device| server_pid(device).is_none())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::ResourceBusy, "every NBD device is in use"))
+}
+
+/// Hands `kernel`, one end of the socket the daemon serves, to the NBD
+/// device `nbd` for an export of `size` bytes.
+fn set_up(nbd: &File, kernel: UnixStream, size: u64, read_only: bool) -> io::Result<()> {
+    let fd = nbd.as_raw_fd();
+    loopdev::ioctl(fd, NBD_CLEAR_SOCK, 0)?;
+    loopdev::ioctl(fd, NBD_SET_BLKSIZE, BLOCK_SIZE as libc::c_ulong)?;
+    loopdev::ioctl(fd, NBD_SET_SIZE_BLOCKS, (size / BLOCK_SIZE) as libc::c_ulong)?;
+    loopdev::ioctl(fd, NBD_SET_FLAGS, libc::c_ulong::from(transmission_flags(read_only)))?;
+    loopdev::ioctl(fd, NBD_SET_SOCK, kernel.as_raw_fd() as libc::c_ulong).map(drop)
+}
+
+/// The helper program that serves NBD devices, installed next to the
+/// mizzlestore executable.
+pub const HELPER: &str = "mizzlestore-nbd";
+
+/// Environment variable naming the helper program for programs that use
+/// this library and are not installed next to it.
+pub const HELPER_ENV: &str = "MIZZLESTORE_NBD_HELPER";
+
+/// The helper program: the one [`HELPER_ENV`] names, or else [`HELPER`] in
+/// the running executable's directory.
+fn helper() -> io::Result<PathBuf> {
+    if let Some(program) = std::env::var_os(HELPER_ENV) {
+        return Ok(PathBuf::from(program));
+    }
+    let helper = std::env::current_exe()?.with_file_name(HELPER);
+    if !helper.is_file() {
+        let message = format!("{} not found; install it there or set {} to where it is", helper.display(), HELPER_ENV);
+        return Err(io::Error::new(io::ErrorKind::NotFound, message));
+    }
+    Ok(helper)
+}
+
+/// Connects the qcow2 image `image` to a free `/dev/nbdN` and returns its
+/// path. The device is served by a daemon, the helper program run as
+/// `mizzlestore-nbd DEVICE IMAGE [--read-only]`; it lives until
+/// [`disconnect`] and flushes the image before it exits.
+pub fn connect(image: &Path, read_only: bool) -> io::Result<PathBuf> {
+    let path = free_device()?;
+    let mut command = Command::new(helper()?);
+    command.arg(&path).arg(image);
+    if read_only {
+        command.arg("--read-only");
+    }
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn()?;
+
+    // The daemon closes its stdout once the device is up, or says why it failed
+    let mut message = String::new();
+    child.stdout.take().expect("stdout is piped").read_to_string(&mut message)?;
+    let exited = child.try_wait()?;
+    // Reap the daemon whenever it exits
+    thread::spawn(move || child.wait());
+    if !message.is_empty() {
+        return Err(io::Error::other(message));
+    }
+    match (server_pid(&path), exited) {
+        (Some(_), _) => Ok(path),
+        (None, Some(status)) => Err(io::Error::other(format!("the NBD daemon failed ({})", status))),
+        (None, None) => Err(io::Error::other(format!("{} did not come up", path.display()))),
+    }
+}
+
+/// The body of the daemon [`connect`] starts: connects `image` to the NBD
+/// device `path` and serves it until the device is disconnected. Whether
+/// the device came up is reported on stdout, which is closed once it has.
+pub fn run_daemon(path: &Path, image: &Path, read_only: bool) -> io::Result<()> {
+    // SAFETY: only detaches this process from the caller's session
+    unsafe { libc::setsid() };
+    let set_up_device = || -> io::Result<(Qcow2Image, File, UnixStream)> {
+        let mut device = Qcow2Image::open(image, !read_only)?;
+        let nbd = OpenOptions::new().read(true).write(true).open(path)?;
+        let (socket, kernel) = UnixStream::pair()?;
+        set_up(&nbd, kernel, device.len()?, read_only)?;
+        Ok((device, nbd, socket))
+    };
+    let (mut device, nbd, socket) = match set_up_device() {
+        Ok(set_up) => set_up,
+        Err(e) => {
+            let mut out = io::stdout().lock();
+            let _ = write!(out, "{}", e).and_then(|()| out.flush());
+            return Err(e);
+        }
+    };
+    // DO_IT blocks until the device is disconnected, so it gets a thread
+    // of its own while this one answers the kernel's requests
+    let kernel_side = thread::spawn(move || {
+        let fd = nbd.as_raw_fd();
+        let done = loopdev::ioctl(fd, NBD_DO_IT, 0);
+        let _ = loopdev::ioctl(fd, NBD_CLEAR_QUE, 0);
+        let _ = loopdev::ioctl(fd, NBD_CLEAR_SOCK, 0);
+        done
+    });
+    let started = Instant::now();
+    while server_pid(path).is_none() && !kernel_side.is_finished() && started.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    // SAFETY: replaces stdout with /dev/null, which tells the caller we are up
+    if unsafe { libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let served = transmit(&mut BufReader::new(&socket), &mut BufWriter::new(&socket), &mut device, read_only);
+    drop(socket);
+    let done = kernel_side.join().unwrap_or_else(|_| Err(io::Error::other("the NBD client panicked")));
+    served.and(done.map(drop))
+}
+
+/// Disconnects the NBD device `device` and waits for the daemon serving it
+/// to let go.
+pub fn disconnect(device: &Path) -> io::Result<()> {
+    let nbd = OpenOptions::new().read(true).write(true).open(device)?;
+    loopdev::ioctl(nbd.as_raw_fd(), NBD_DISCONNECT, 0)?;
+    let started = Instant::now();
+    while server_pid(device).is_some() {
+        if started.elapsed() > Duration::from_secs(30) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "the NBD server did not stop"));
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytes::put_be32;
+    use crate::testutil::{pattern, raw_disk};
+
+    const SIZE: u64 = 64 * 1024;
+
+    /// Runs `serve` on one end of a socket pair over a scratch disk with a
+    /// [`pattern`] of `len` bytes at its start, handing back the other end
+    /// once the greeting is read.
+    fn start(name: &str, len: usize) -> (UnixStream, thread::JoinHandle<io::Result<()>>) {
+        let file = raw_disk(&format!("nbd-{}", name), SIZE, &[(0, len, 6)]);
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            let mut file = file;
+            serve(&server, &mut file, "disk", true)
+        });
+        assert_eq!(read_u64(&mut client).unwrap(), NBDMAGIC);
+        assert_eq!(read_u64(&mut client).unwrap(), IHAVEOPT);
+        assert_eq!(read_u16(&mut client).unwrap(), FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES);
+        client.write_all(&CLIENT_NO_ZEROES.to_be_bytes()).unwrap();
+        (client, handle)
+    }
+
+    fn send_option(client: &mut UnixStream, option: u32, data: &[u8]) {
+        client.write_all(&IHAVEOPT.to_be_bytes()).unwrap();
+        client.write_all(&option.to_be_bytes()).unwrap();
+        client.write_all(&(data.len() as u32).to_be_bytes()).unwrap();
+        client.write_all(data).unwrap();
+    }
+
+    /// Reads one option reply, returning its type and data.
+    fn reply(client: &mut UnixStream, option: u32) -> (u32, Vec<u8>) {
+        assert_eq!(read_u64(client).unwrap(), OPTION_REPLY_MAGIC);
+        assert_eq!(read_u32(client).unwrap(), option);
+        let reply = read_u32(client).unwrap();
+        let mut data = vec![0; read_u32(client).unwrap() as usize];
+        client.read_exact(&mut data).unwrap();
+        (reply, data)
+    }
+
+    /// An `OPT_INFO` or `OPT_GO` payload naming `name` and asking for nothing more.
+    fn go(name: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 4];
+        put_be32(&mut data, 0, name.len() as u32);
+        data.extend_from_slice(name);
+        data.extend_from_slice(&[0, 0]);
+        data
+    }
+
+    #[test]
+    fn malformed_options_are_refused_and_the_handshake_carries_on() {
+        let (mut client, server) = start("options", 4096);
+
+        // Too short to hold a name length, a name length past the end, and no room for the request count
+        let mut past_end = go(b"disk");
+        put_be32(&mut past_end, 0, 1000);
+        let mut no_count = go(b"disk");
+        no_count.truncate(8);
+        for (option, payload) in [(OPT_GO, vec![0, 0]), (OPT_INFO, past_end), (OPT_GO, no_count)] {
+            send_option(&mut client, option, &payload);
+            assert_eq!(reply(&mut client, option), (REP_ERR_INVALID, Vec::new()));
+        }
+        send_option(&mut client, OPT_GO, &go(b"other"));
+        assert_eq!(reply(&mut client, OPT_GO).0, REP_ERR_UNKNOWN);
+        send_option(&mut client, 99, &[]);
+        assert_eq!(reply(&mut client, 99).0, REP_ERR_UNSUP);
+
+        send_option(&mut client, OPT_GO, &go(b"disk"));
+        let (kind, info) = reply(&mut client, OPT_GO);
+        assert_eq!(kind, REP_INFO);
+        assert_eq!(be16(&info, 0), INFO_EXPORT);
+        assert_eq!(be64(&info, 2), SIZE);
+        assert_eq!(be16(&info, 10), transmission_flags(true));
+        assert_eq!(reply(&mut client, OPT_GO), (REP_ACK, Vec::new()));
+
+        // A read, a write the read-only export refuses, then a disconnect
+        for (command, offset, len) in [(CMD_READ, 100u64, 512u32), (CMD_WRITE, 0, 0), (CMD_DISC, 0, 0)] {
+            client.write_all(&REQUEST_MAGIC.to_be_bytes()).unwrap();
+            client.write_all(&0u16.to_be_bytes()).unwrap();
+            client.write_all(&command.to_be_bytes()).unwrap();
+            client.write_all(&u64::from(command).to_be_bytes()).unwrap();
+            client.write_all(&offset.to_be_bytes()).unwrap();
+            client.write_all(&len.to_be_bytes()).unwrap();
+            if command == CMD_DISC {
+                break;
+            }
+            assert_eq!(read_u32(&mut client).unwrap(), SIMPLE_REPLY_MAGIC);
+            let error = read_u32(&mut client).unwrap();
+            assert_eq!(read_u64(&mut client).unwrap(), u64::from(command));
+            if command == CMD_READ {
+                assert_eq!(error, 0);
+                let mut buf = vec![0; len as usize];
+                client.read_exact(&mut buf).unwrap();
+                assert_eq!(buf, pattern(4096, 6)[100..612]);
+            } else {
+                assert_eq!(error, libc::EPERM as u32);
+            }
+        }
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn a_bad_option_magic_or_an_oversized_option_ends_the_handshake() {
+        let (mut client, server) = start("magic", 0);
+        client.write_all(&NBDMAGIC.to_be_bytes()).unwrap();
+        assert_eq!(server.join().unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let (mut client, server) = start("long", 0);
+        // The header in one write: the server hangs up as soon as it has read the length
+        let mut header = IHAVEOPT.to_be_bytes().to_vec();
+        header.extend_from_slice(&OPT_GO.to_be_bytes());
+        header.extend_from_slice(&u32::MAX.to_be_bytes());
+        client.write_all(&header).unwrap();
+        assert_eq!(server.join().unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
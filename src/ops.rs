@@ -0,0 +1,660 @@
+use crate::alloc::{self, AllocationPolicy, AllocationReport};
+use crate::error::{MizzleError, Result};
+use crate::filesystem::{Filesystem, FormatOptions};
+use crate::size;
+use crate::transfer::{self, Progress};
+use nix::errno::Errno;
+use nix::fcntl::{renameat2, RenameFlags};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::unistd::ftruncate;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::AsFd;
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+pub fn create_fully_allocated_file(path: &Path, size: u64) -> Result<()> {
+    create_image(path, size, AllocationPolicy::Preallocate).map(drop)
+}
+
+/// Creates (or resizes) the image at `path` according to `policy` and reports
+/// how much host storage it ends up using.
+pub fn create_image(path: &Path, size: u64, policy: AllocationPolicy) -> Result<AllocationReport> {
+    size::validate(size).map_err(|reason| MizzleError::InvalidSize { size, reason })?;
+    let allocate_err = |source| MizzleError::Allocate { path: path.to_path_buf(), source };
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(allocate_err)?;
+
+    if policy.reserves_space() {
+        alloc::ensure_free_space(path, &file, size)?;
+    }
+
+    // Set the logical size, then back it with real storage as the policy asks
+    ftruncate(file.as_fd(), size as i64).map_err(|e| allocate_err(e.into()))?;
+    match policy {
+        AllocationPolicy::Sparse => {}
+        AllocationPolicy::Preallocate => alloc::reserve(&mut file, size).map_err(allocate_err)?,
+        AllocationPolicy::ZeroFill => alloc::zero_fill(&mut file, size).map_err(allocate_err)?,
+    }
+
+    if policy.reserves_space() {
+        alloc::verify_reserved(path, &file, size)?;
+    }
+
+    let allocated = alloc::allocated_bytes(&file).map_err(allocate_err)?;
+    Ok(AllocationReport { policy, size, allocated })
+}
+
+pub fn format_virtual_disk(path: &Path, filesystem: Filesystem, options: &FormatOptions) -> Result<()> {
+    let program = filesystem.mkfs_program();
+    let output = Command::new(program)
+        .args(filesystem.mkfs_args(path, options)?)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|source| MizzleError::Spawn { program: program.to_string(), source })?;
+
+    if !output.status.success() {
+        return Err(MizzleError::Format {
+            path: path.to_path_buf(),
+            program: program.to_string(),
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Grows or shrinks the image at `path` to `size` bytes. Space added at the
+/// end is provided as `policy` says; the existing contents are left alone.
+pub fn resize_image(path: &Path, size: u64, policy: AllocationPolicy) -> Result<AllocationReport> {
+    size::validate(size).map_err(|reason| MizzleError::InvalidSize { size, reason })?;
+    let resize_err = |source| MizzleError::Allocate { path: path.to_path_buf(), source };
+    let mut file = OpenOptions::new().write(true).open(path).map_err(resize_err)?;
+    let old = file.metadata().map_err(resize_err)?.len();
+
+    if size > old && policy.reserves_space() {
+        let (required, available) = (size - old, alloc::free_space(path)?);
+        if required > available {
+            return Err(MizzleError::InsufficientSpace { path: path.to_path_buf(), required, available });
+        }
+    }
+    ftruncate(file.as_fd(), size as i64).map_err(|e| resize_err(e.into()))?;
+    if size > old {
+        match policy {
+            AllocationPolicy::Sparse => {}
+            AllocationPolicy::Preallocate => alloc::reserve_range(&mut file, old, size - old).map_err(resize_err)?,
+            AllocationPolicy::ZeroFill => alloc::zero_fill_range(&mut file, old, size - old).map_err(resize_err)?,
+        }
+    }
+    file.sync_all().map_err(resize_err)?;
+
+    let allocated = alloc::allocated_bytes(&file).map_err(resize_err)?;
+    Ok(AllocationReport { policy, size, allocated })
+}
+
+/// Runs a filesystem tool on `path` and returns its standard output. Exit
+/// codes other than those in `ok` are errors.
+pub fn run_tool(program: &str, args: &[OsString], path: &Path, ok: &[i32]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|source| MizzleError::Spawn { program: program.to_string(), source })?;
+
+    if !output.status.code().is_some_and(|code| ok.contains(&code)) {
+        return Err(MizzleError::Tool {
+            path: path.to_path_buf(),
+            program: program.to_string(),
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Forces a full check of the unmounted ext4 filesystem in `image`, fixing
+/// what can be fixed safely, as resize2fs insists on before touching it.
+pub fn check_ext4(image: &Path) -> Result<()> {
+    // 1: errors were corrected
+    run_tool("e2fsck", &["-f".into(), "-p".into(), image.into()], image, &[0, 1]).map(drop)
+}
+
+/// The smallest size in bytes resize2fs can shrink the ext4 filesystem in
+/// `image` to, given the data on it.
+pub fn ext4_minimum_size(image: &Path) -> Result<u64> {
+    let output = run_tool("resize2fs", &["-P".into(), image.into()], image, &[0])?;
+    let blocks = output
+        .lines()
+        .find_map(|line| line.strip_prefix("Estimated minimum size of the filesystem:"))
+        .and_then(|n| n.trim().parse::<u64>().ok())
+        .ok_or_else(|| MizzleError::io(image, io::Error::new(io::ErrorKind::InvalidData, "unexpected resize2fs -P output")))?;
+    let block_size = Filesystem::Ext4.block_size(image).map_err(|e| MizzleError::io(image, e))?;
+    Ok(blocks * block_size)
+}
+
+/// Shrinks or grows the unmounted ext4 filesystem in `image` to `size` bytes.
+pub fn resize_ext4(image: &Path, size: u64) -> Result<()> {
+    run_tool("resize2fs", &[image.into(), format!("{}K", size / 1024).into()], image, &[0]).map(drop)
+}
+
+/// Grows `filesystem` to fill its device: `device` for ext4, which may be
+/// mounted or not, and `mount_point` for the others, which must be mounted.
+pub fn grow_filesystem(filesystem: Filesystem, device: &Path, mount_point: &Path) -> Result<()> {
+    let (program, args) = filesystem.grow_command(device, mount_point)?;
+    run_tool(program, &args, device, &[0]).map(drop)
+}
+
+/// Mounts the block device `device` (normally a loop device backed by the image).
+pub fn mount_virtual_disk(device: &Path, mount_point: &Path, fstype: &str, read_only: bool) -> Result<()> {
+    if !mount_point.exists() {
+        fs::create_dir_all(mount_point).map_err(|e| MizzleError::io(mount_point, e))?;
+    }
+
+    let flags = if read_only { MsFlags::MS_RDONLY } else { MsFlags::empty() };
+    mount(Some(device), mount_point, Some(fstype), flags, None::<&str>).map_err(|source| {
+        MizzleError::Mount { image: device.to_path_buf(), target: mount_point.to_path_buf(), source }
+    })
+}
+
+/// What a copy does when its destination already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overwrite {
+    /// Replace the existing file with the new contents.
+    #[default]
+    Replace,
+    /// Leave the existing file alone and copy nothing.
+    Skip,
+    /// Stop with an error.
+    Fail,
+    /// Copy to the first free name of the form `name (1).ext`.
+    Rename,
+}
+
+impl fmt::Display for Overwrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Overwrite::Replace => "replace",
+            Overwrite::Skip => "skip",
+            Overwrite::Fail => "fail",
+            Overwrite::Rename => "rename",
+        })
+    }
+}
+
+impl FromStr for Overwrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "replace" | "overwrite" | "truncate" => Ok(Overwrite::Replace),
+            "skip" | "no-clobber" => Ok(Overwrite::Skip),
+            "fail" | "error" => Ok(Overwrite::Fail),
+            "rename" => Ok(Overwrite::Rename),
+            _ => Err(format!("unknown overwrite mode '{}' (expected replace, skip, fail or rename)", s)),
+        }
+    }
+}
+
+/// Where a single-file copy ended up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Placed {
+    Created,
+    Replaced,
+    /// The destination was taken, so the copy went to this path instead.
+    Renamed(PathBuf),
+    /// The destination was taken and left alone.
+    Skipped,
+}
+
+/// `path` on the disk made relative to its root, with `.` dropped and `..`
+/// applied. Fails if `..` climbs above the root.
+pub fn disk_relative(path: &Path) -> Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::ParentDir if relative.pop() => {}
+            Component::ParentDir => return Err(MizzleError::OutsideDisk { path: path.to_path_buf() }),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    Ok(relative)
+}
+
+/// Where `path` on the disk lives inside an unmounted image, normalised
+/// the way [`disk_path`] does for a mount.
+pub(crate) fn image_path(path: &Path) -> Result<PathBuf> {
+    Ok(Path::new("/").join(disk_relative(path)?))
+}
+
+/// Where `path` on the disk mounted at `mount_point` lives on the host;
+/// absolute paths are taken relative to the root of the disk. Fails if the
+/// path, or a symlink along it, leads outside the mount.
+pub fn disk_path(mount_point: &Path, path: &Path) -> Result<PathBuf> {
+    let target = mount_point.join(disk_relative(path)?);
+    let root = fs::canonicalize(mount_point).map_err(|e| MizzleError::io(mount_point, e))?;
+    // Resolve as much of the path as already exists
+    let mut existing = target.as_path();
+    let resolved = loop {
+        match fs::canonicalize(existing) {
+            Ok(resolved) => break resolved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(MizzleError::io(existing, e)),
+        }
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => break root.clone(),
+        }
+    };
+    if !resolved.starts_with(&root) {
+        return Err(MizzleError::OutsideDisk { path: path.to_path_buf() });
+    }
+    Ok(target)
+}
+
+/// The first of `path (1).ext`, `path (2).ext`, ... for which `taken` is false.
+pub(crate) fn free_name(path: &Path, mut taken: impl FnMut(&Path) -> io::Result<bool>) -> io::Result<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    for n in 1.. {
+        let candidate = path.with_file_name(format!("{} ({}){}", stem, n, extension));
+        if !taken(&candidate)? {
+            return Ok(candidate);
+        }
+    }
+    unreachable!()
+}
+
+/// Decides where a copy to the host path `target` goes under `overwrite`;
+/// `None` means it is skipped.
+pub(crate) fn place(target: &Path, overwrite: Overwrite) -> Result<Option<(PathBuf, Placed)>> {
+    let exists = |path: &Path| match fs::symlink_metadata(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    };
+    match fs::symlink_metadata(target) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Some((target.to_path_buf(), Placed::Created))),
+        Err(e) => Err(MizzleError::io(target, e)),
+        Ok(meta) if meta.is_dir() => Err(MizzleError::io(target, io::Error::from_raw_os_error(libc::EISDIR))),
+        Ok(_) => match overwrite {
+            Overwrite::Replace => Ok(Some((target.to_path_buf(), Placed::Replaced))),
+            Overwrite::Skip => Ok(None),
+            Overwrite::Fail => Err(already_exists(target)),
+            Overwrite::Rename => {
+                let renamed = free_name(target, exists).map_err(|e| MizzleError::io(target, e))?;
+                Ok(Some((renamed.clone(), Placed::Renamed(renamed))))
+            }
+        },
+    }
+}
+
+pub(crate) fn already_exists(path: &Path) -> MizzleError {
+    MizzleError::Refused { path: path.to_path_buf(), operation: "overwrite", reason: "it already exists".into() }
+}
+
+/// Writes `target` by filling a temporary file next to it, syncing it and
+/// renaming it into place, so readers see either the old file or the whole
+/// new one. Unless `replace` is set an existing `target` is never clobbered.
+/// An existing file's permissions carry over to its replacement.
+pub(crate) fn write_atomically<T>(
+    target: &Path,
+    replace: bool,
+    fill: impl FnOnce(&File) -> io::Result<T>,
+) -> io::Result<T> {
+    let name = target.file_name().ok_or_else(|| io::Error::from_raw_os_error(libc::EISDIR))?;
+    let dir = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let tmp = dir.join(format!(".{}.{}.mizzle-tmp", name.to_string_lossy(), process::id()));
+    let file = OpenOptions::new().read(true).write(true).create_new(true).open(&tmp)?;
+
+    let write = || -> io::Result<T> {
+        if replace {
+            match fs::metadata(target) {
+                Ok(meta) => file.set_permissions(meta.permissions())?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let out = fill(&file)?;
+        file.sync_all()?;
+        if replace {
+            fs::rename(&tmp, target)?;
+        } else {
+            renameat2(None, &tmp, None, target, RenameFlags::RENAME_NOREPLACE)?;
+        }
+        File::open(dir)?.sync_all()?;
+        Ok(out)
+    };
+    write().inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// Copies a host file onto the disk mounted at `mount_point`, handling an
+/// existing destination as `overwrite` says.
+pub fn copy_file_to_mount(
+    mount_point: &Path,
+    source_file: &Path,
+    destination: &Path,
+    overwrite: Overwrite,
+    progress: &mut dyn FnMut(&Progress),
+) -> Result<Placed> {
+    let Some((target, placed)) = place(&disk_path(mount_point, destination)?, overwrite)? else {
+        return Ok(Placed::Skipped);
+    };
+    let mut copy = || -> io::Result<()> {
+        let source = File::open(source_file)?;
+        write_atomically(&target, placed == Placed::Replaced, |out| transfer::copy_file(&source, out, progress))
+            .map(drop)
+    };
+
+    copy().map_err(|source| MizzleError::Copy { from: source_file.to_path_buf(), to: target.clone(), source })?;
+    Ok(match placed {
+        Placed::Renamed(_) => Placed::Renamed(Path::new("/").join(target.strip_prefix(mount_point).unwrap_or(&target))),
+        other => other,
+    })
+}
+
+/// Copies a file off the disk mounted at `mount_point` to the host, handling
+/// an existing destination as `overwrite` says.
+pub fn copy_file_from_mount(
+    mount_point: &Path,
+    source_file: &Path,
+    destination: &Path,
+    overwrite: Overwrite,
+    progress: &mut dyn FnMut(&Progress),
+) -> Result<Placed> {
+    let source_path = disk_path(mount_point, source_file)?;
+    let Some((target, placed)) = place(destination, overwrite)? else {
+        return Ok(Placed::Skipped);
+    };
+    let mut copy = || -> io::Result<()> {
+        let source = File::open(&source_path)?;
+        write_atomically(&target, placed == Placed::Replaced, |out| transfer::copy_file(&source, out, progress))
+            .map(drop)
+    };
+
+    copy().map_err(|source| MizzleError::Copy { from: source_path.clone(), to: target.clone(), source })?;
+    Ok(placed)
+}
+
+/// One entry of a directory listing on the mounted disk.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub is_symlink: bool,
+    pub len: u64,
+}
+
+pub fn list_mount(mount_point: &Path, dir: &Path) -> Result<Vec<DirEntry>> {
+    let path = disk_path(mount_point, dir)?;
+    let list = || -> io::Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            entries.push(DirEntry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                is_dir: meta.is_dir(),
+                is_symlink: meta.file_type().is_symlink(),
+                len: meta.len(),
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    };
+
+    list().map_err(|e| MizzleError::io(&path, e))
+}
+
+pub fn remove_from_mount(mount_point: &Path, path: &Path) -> Result<()> {
+    let target = disk_path(mount_point, path)?;
+    let remove = || -> io::Result<()> {
+        if target.symlink_metadata()?.is_dir() {
+            fs::remove_dir(&target)
+        } else {
+            fs::remove_file(&target)
+        }
+    };
+
+    remove().map_err(|e| MizzleError::io(&target, e))
+}
+
+/// Unmounts `mount_point`, retrying while it is busy and finally detaching it
+/// lazily so the kernel finishes the unmount once the last user lets go.
+pub fn unmount_with_retry(mount_point: &Path) -> Result<()> {
+    const ATTEMPTS: u64 = 5;
+    for attempt in 1..=ATTEMPTS {
+        match umount2(mount_point, MntFlags::empty()) {
+            Err(Errno::EBUSY) if attempt < ATTEMPTS => thread::sleep(Duration::from_millis(100 * attempt)),
+            Err(Errno::EBUSY) => break,
+            other => {
+                return other.map_err(|source| MizzleError::Unmount { target: mount_point.to_path_buf(), source })
+            }
+        }
+    }
+    umount2(mount_point, MntFlags::MNT_DETACH)
+        .map_err(|source| MizzleError::Unmount { target: mount_point.to_path_buf(), source })
+}
+
+/// One line of `/proc/self/mountinfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub fstype: String,
+    pub read_only: bool,
+}
+
+/// Undoes the octal escaping (`\040` for a space) the kernel applies to paths.
+fn unescape(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes.get(i + 1..i + 4).and_then(|oct| u8::from_str_radix(std::str::from_utf8(oct).ok()?, 8).ok());
+        match code {
+            Some(c) if bytes[i] == b'\\' => {
+                out.push(c);
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(out))
+}
+
+/// Everything mounted in this process's namespace, in mount order.
+pub fn mounts() -> io::Result<Vec<MountInfo>> {
+    Ok(parse_mountinfo(&fs::read_to_string("/proc/self/mountinfo")?))
+}
+
+/// The mounts listed in the text of a mountinfo file; lines that do not
+/// parse are left out.
+fn parse_mountinfo(info: &str) -> Vec<MountInfo> {
+    info.lines()
+        .filter_map(|line| {
+            // id parent major:minor root target options [optional...] - fstype source super-options
+            let (left, right) = line.split_once(" - ")?;
+            let mut left = left.split(' ').skip(4);
+            let target = unescape(left.next()?);
+            let read_only = left.next()?.split(',').any(|o| o == "ro");
+            let mut right = right.split(' ');
+            let fstype = right.next()?.to_string();
+            let source = unescape(right.next()?);
+            Some(MountInfo { source, target, fstype, read_only })
+        })
+        .collect()
+}
+
+/// The topmost mount at `path`, if anything is mounted there.
+pub fn mount_at(path: &Path) -> io::Result<Option<MountInfo>> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    Ok(mounts()?.into_iter().rev().find(|m| m.target == path))
+}
+
+/// The device mounted at `path`, if anything is.
+pub fn mount_source(path: &Path) -> io::Result<Option<PathBuf>> {
+    Ok(mount_at(path)?.map(|m| m.source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{pattern, ScratchDir};
+    use nix::sys::stat::Mode;
+    use nix::unistd::mkfifo;
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::symlink;
+
+    fn outside(result: Result<PathBuf>) -> bool {
+        matches!(result, Err(MizzleError::OutsideDisk { .. }))
+    }
+
+    #[test]
+    fn disk_relative_applies_dots_and_refuses_climbing_out() {
+        let cases = [("/a/./b/../c", "a/c"), ("a/b", "a/b"), ("/", ""), ("a/..", ""), ("//a//b/", "a/b")];
+        for (path, relative) in cases {
+            assert_eq!(disk_relative(Path::new(path)).unwrap(), Path::new(relative), "{}", path);
+        }
+        for path in ["..", "../x", "/a/../../x", "a/b/../../../etc"] {
+            assert!(outside(disk_relative(Path::new(path))), "{}", path);
+        }
+        assert_eq!(image_path(Path::new("a/../b")).unwrap(), Path::new("/b"));
+    }
+
+    #[test]
+    fn disk_path_keeps_paths_and_symlinks_inside_the_mount() {
+        let dir = ScratchDir::new("ops-escape");
+        let (mount, elsewhere) = (dir.join("mnt"), dir.join("elsewhere"));
+        fs::create_dir_all(mount.join("dir")).unwrap();
+        fs::create_dir(&elsewhere).unwrap();
+        symlink("../elsewhere", mount.join("relative")).unwrap();
+        symlink(&elsewhere, mount.join("absolute")).unwrap();
+        symlink("dir", mount.join("inner")).unwrap();
+
+        assert_eq!(disk_path(&mount, Path::new("/dir/new.txt")).unwrap(), mount.join("dir/new.txt"));
+        // Absolute paths are relative to the disk, not the host
+        assert_eq!(disk_path(&mount, Path::new("/etc/passwd")).unwrap(), mount.join("etc/passwd"));
+        assert_eq!(disk_path(&mount, Path::new("/inner/x")).unwrap(), mount.join("inner/x"));
+        for path in ["../elsewhere/x", "/relative", "/relative/x", "/absolute/missing/deeper", "/dir/../../x"] {
+            assert!(outside(disk_path(&mount, Path::new(path))), "{}", path);
+        }
+    }
+
+    #[test]
+    fn overwrite_modes_decide_what_happens_to_an_existing_file() {
+        let dir = ScratchDir::new("ops-overwrite");
+        let (mount, source) = (dir.join("mnt"), dir.join("source"));
+        fs::create_dir_all(mount.join("sub")).unwrap();
+        fs::write(&source, b"new").unwrap();
+        let put = |overwrite| copy_file_to_mount(&mount, &source, Path::new("/f.txt"), overwrite, &mut |_| {});
+
+        assert_eq!(put(Overwrite::Fail).unwrap(), Placed::Created);
+        fs::write(mount.join("f.txt"), b"old").unwrap();
+        assert!(matches!(put(Overwrite::Fail), Err(MizzleError::Refused { .. })));
+        assert_eq!(put(Overwrite::Skip).unwrap(), Placed::Skipped);
+        assert_eq!(fs::read(mount.join("f.txt")).unwrap(), b"old");
+        assert_eq!(put(Overwrite::Rename).unwrap(), Placed::Renamed("/f (1).txt".into()));
+        assert_eq!(put(Overwrite::Rename).unwrap(), Placed::Renamed("/f (2).txt".into()));
+        assert_eq!(fs::read(mount.join("f (2).txt")).unwrap(), b"new");
+        assert_eq!(fs::read(mount.join("f.txt")).unwrap(), b"old");
+        assert_eq!(put(Overwrite::Replace).unwrap(), Placed::Replaced);
+        assert_eq!(fs::read(mount.join("f.txt")).unwrap(), b"new");
+
+        let sub = copy_file_to_mount(&mount, &source, Path::new("/sub"), Overwrite::Replace, &mut |_| {});
+        assert!(sub.is_err());
+        assert_eq!(fs::read_dir(mount.join("sub")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn unescape_decodes_octal_escapes() {
+        let cases = [
+            ("/mnt/plain", "/mnt/plain"),
+            ("/mnt/my\\040disk", "/mnt/my disk"),
+            ("/mnt/tab\\011here", "/mnt/tab\there"),
+            ("/mnt/back\\134slash", "/mnt/back\\slash"),
+            ("/mnt/new\\012line", "/mnt/new\nline"),
+            ("\\040\\040", "  "),
+            // Not escapes: too short, not octal, or over a byte
+            ("/mnt/end\\04", "/mnt/end\\04"),
+            ("/mnt/\\089", "/mnt/\\089"),
+            ("/mnt/\\777", "/mnt/\\777"),
+        ];
+        for (field, path) in cases {
+            assert_eq!(unescape(field), Path::new(path), "{}", field);
+        }
+        assert_eq!(unescape("/mnt/\\377").as_os_str().as_bytes(), b"/mnt/\xff");
+    }
+
+    #[test]
+    fn mountinfo_lines_are_parsed_and_unescaped() {
+        let info = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+97 22 7:3 / /mnt/my\\040disk ro,nosuid master:5 shared:7 - vfat /tmp/a\\011b.img ro,fmask=0022
+98 22 0:50 / /mnt/tab\\011and\\134slash rw - tmpfs tmpfs rw
+truncated line without separator
+";
+        let mount = |source: &str, target: &str, fstype: &str, read_only| MountInfo {
+            source: source.into(),
+            target: target.into(),
+            fstype: fstype.into(),
+            read_only,
+        };
+        assert_eq!(
+            parse_mountinfo(info),
+            [
+                mount("/dev/sda1", "/", "ext4", false),
+                mount("/tmp/a\tb.img", "/mnt/my disk", "vfat", true),
+                mount("tmpfs", "/mnt/tab\tand\\slash", "tmpfs", false),
+            ]
+        );
+        assert!(mounts().unwrap().iter().any(|m| m.target == Path::new("/")));
+    }
+
+    #[test]
+    fn overwrite_modes_parse_their_names_and_aliases() {
+        for mode in [Overwrite::Replace, Overwrite::Skip, Overwrite::Fail, Overwrite::Rename] {
+            assert_eq!(mode.to_string().parse::<Overwrite>().unwrap(), mode);
+        }
+        assert_eq!("no-clobber".parse::<Overwrite>().unwrap(), Overwrite::Skip);
+        assert_eq!("truncate".parse::<Overwrite>().unwrap(), Overwrite::Replace);
+        assert!("clobber".parse::<Overwrite>().is_err());
+    }
+
+    #[test]
+    fn put_reads_a_fifo_to_the_end() {
+        let dir = ScratchDir::new("ops-fifo");
+        let (mount, fifo) = (dir.join("mnt"), dir.join("fifo"));
+        fs::create_dir(&mount).unwrap();
+        mkfifo(&fifo, Mode::from_bits_truncate(0o600)).unwrap();
+        let data = pattern(300_000, 2);
+        let writer = thread::spawn({
+            let (fifo, data) = (fifo.clone(), data.clone());
+            move || File::options().write(true).open(fifo)?.write_all(&data)
+        });
+        let placed = copy_file_to_mount(&mount, &fifo, Path::new("/piped"), Overwrite::Fail, &mut |_| {}).unwrap();
+        writer.join().unwrap().unwrap();
+        assert_eq!(placed, Placed::Created);
+        assert_eq!(fs::read(mount.join("piped")).unwrap(), data);
+    }
+}
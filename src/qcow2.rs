@@ -0,0 +1,778 @@
+//! qcow2 images: image files that only grow as they are written and can
+//! sit on top of a read-only backing file. Read and written in-process as a
+//! [`BlockDevice`]; [`nbd`](crate::nbd) hands them to the kernel.
+//!
+//! Version 2 and 3 images with 16-bit refcounts, as qemu-img makes them.
+//! Compressed clusters can be read; a write to one stores it uncompressed.
+//! Clusters shared with internal snapshots are copied before they are
+//! written, so the snapshots stay intact.
+
+use crate::blockdev::BlockDevice;
+use crate::bytes::{be16, be32, be64, put_be16, put_be32, put_be64};
+use flate2::read::DeflateDecoder;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"QFI\xfb";
+/// 64 KiB clusters, the qemu-img default.
+pub const DEFAULT_CLUSTER_BITS: u32 = 16;
+/// Backing chains longer than this are taken for a loop.
+const MAX_BACKING_DEPTH: usize = 16;
+
+// Header fields
+const H_VERSION: usize = 4;
+const H_BACKING_OFFSET: usize = 8;
+const H_BACKING_SIZE: usize = 16;
+const H_CLUSTER_BITS: usize = 20;
+const H_SIZE: usize = 24;
+const H_CRYPT_METHOD: usize = 32;
+const H_L1_SIZE: usize = 36;
+const H_L1_OFFSET: usize = 40;
+const H_REFCOUNT_OFFSET: usize = 48;
+const H_REFCOUNT_CLUSTERS: usize = 56;
+const H_SNAPSHOTS: usize = 60;
+const H_INCOMPATIBLE: usize = 72;
+const H_AUTOCLEAR: usize = 88;
+const H_REFCOUNT_ORDER: usize = 96;
+const H_LENGTH: usize = 100;
+const V2_HEADER_LEN: usize = 72;
+const V3_HEADER_LEN: usize = 104;
+
+// Header extensions
+const EXT_END: u32 = 0;
+const EXT_BACKING_FORMAT: u32 = 0xE279_2ACA;
+
+// Incompatible features
+const DIRTY: u64 = 1 << 0;
+const CORRUPT: u64 = 1 << 1;
+
+// Table entries
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+const ZERO: u64 = 1;
+
+/// What the header of a qcow2 image says about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Qcow2Info {
+    pub version: u32,
+    pub virtual_size: u64,
+    pub cluster_size: u64,
+    /// As written in the image; relative paths are relative to its directory.
+    pub backing_file: Option<PathBuf>,
+    pub backing_format: Option<String>,
+    /// Internal snapshots, made by qemu.
+    pub snapshots: u32,
+    /// Refcounts may be stale after a crash (lazy refcounts).
+    pub dirty: bool,
+    /// qemu found the metadata inconsistent.
+    pub corrupt: bool,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn unsupported(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, message.into())
+}
+
+/// Whether `path` holds a qcow2 image.
+pub fn is_qcow2(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the header of the qcow2 image in `file`.
+fn read_header(file: &File) -> io::Result<(Vec<u8>, Qcow2Info)> {
+    let mut header = vec![0; V3_HEADER_LEN];
+    file.read_exact_at(&mut header[..V2_HEADER_LEN], 0)?;
+    if !header.starts_with(MAGIC) {
+        return Err(invalid("not a qcow2 image"));
+    }
+    let version = be32(&header, H_VERSION);
+    match version {
+        2 => {}
+        3 => file.read_exact_at(&mut header[V2_HEADER_LEN..], V2_HEADER_LEN as u64)?,
+        _ => return Err(unsupported(format!("qcow2 version {}", version))),
+    }
+    let cluster_bits = be32(&header, H_CLUSTER_BITS);
+    if !(9..=21).contains(&cluster_bits) {
+        return Err(invalid(format!("bad cluster size 2^{}", cluster_bits)));
+    }
+    let header_len = if version == 2 { V2_HEADER_LEN } else { be32(&header, H_LENGTH) as usize };
+    if header_len < header.len() || header_len as u64 >= 1 << cluster_bits {
+        return Err(invalid("bad qcow2 header length"));
+    }
+
+    let backing_offset = be64(&header, H_BACKING_OFFSET);
+    let backing_file = match be32(&header, H_BACKING_SIZE) {
+        0 => None,
+        len if len > 1023 => return Err(invalid("backing file name too long")),
+        len => {
+            let mut name = vec![0; len as usize];
+            file.read_exact_at(&mut name, backing_offset)?;
+            Some(PathBuf::from(String::from_utf8_lossy(&name).into_owned()))
+        }
+    };
+
+    // Header extensions run from the end of the header to the end of the first cluster
+    let mut backing_format = None;
+    if version == 3 {
+        let end = if backing_offset > 0 { backing_offset } else { 1 << cluster_bits };
+        let mut offset = header_len as u64;
+        while offset + 8 <= end {
+            let mut ext = [0; 8];
+            file.read_exact_at(&mut ext, offset)?;
+            let (kind, len) = (be32(&ext, 0), u64::from(be32(&ext, 4)));
+            if kind == EXT_END {
+                break;
+            }
+            if offset + 8 + len > end {
+                return Err(invalid("a qcow2 header extension runs past the end of the header"));
+            }
+            if kind == EXT_BACKING_FORMAT {
+                let mut name = vec![0; len as usize];
+                file.read_exact_at(&mut name, offset + 8)?;
+                backing_format = Some(String::from_utf8_lossy(&name).into_owned());
+            }
+            offset += 8 + len.div_ceil(8) * 8;
+        }
+    }
+
+    let incompatible = if version == 3 { be64(&header, H_INCOMPATIBLE) } else { 0 };
+    let info = Qcow2Info {
+        version,
+        virtual_size: be64(&header, H_SIZE),
+        cluster_size: 1 << cluster_bits,
+        backing_file,
+        backing_format,
+        snapshots: be32(&header, H_SNAPSHOTS),
+        dirty: incompatible & DIRTY != 0,
+        corrupt: incompatible & CORRUPT != 0,
+    };
+    Ok((header, info))
+}
+
+/// Reads the header of the qcow2 image at `path`.
+pub fn info(path: &Path) -> io::Result<Qcow2Info> {
+    read_header(&File::open(path)?).map(|(_, info)| info)
+}
+
+/// The backing file `name` of the image at `image`, resolved against the
+/// image's directory.
+fn backing_path(image: &Path, name: &Path) -> PathBuf {
+    match image.parent() {
+        Some(dir) if name.is_relative() => dir.join(name),
+        _ => name.to_path_buf(),
+    }
+}
+
+/// The backing files under the image at `path`, nearest first.
+pub fn backing_chain(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut chain = Vec::new();
+    let mut image = path.to_path_buf();
+    while is_qcow2(&image)? {
+        let info = info(&image)?;
+        let Some(name) = info.backing_file else {
+            break;
+        };
+        if chain.len() == MAX_BACKING_DEPTH {
+            return Err(invalid("the backing chain is too long or loops"));
+        }
+        image = backing_path(&image, &name);
+        chain.push(image.clone());
+        if info.backing_format.as_deref() == Some("raw") {
+            break;
+        }
+    }
+    Ok(chain)
+}
+
+/// Opens a backing file read-only, as a qcow2 image or as a raw one.
+fn open_backing(path: &Path, format: Option<&str>, depth: usize) -> io::Result<Box<dyn BlockDevice>> {
+    let qcow2 = match format {
+        Some("qcow2") => true,
+        Some("raw") => false,
+        Some(other) => return Err(unsupported(format!("backing file format {}", other))),
+        None => is_qcow2(path)?,
+    };
+    if qcow2 {
+        Ok(Box::new(Qcow2Image::open_at_depth(path, false, depth + 1)?))
+    } else {
+        Ok(Box::new(File::open(path)?))
+    }
+}
+
+/// Creates a qcow2 image of `size` bytes at `path`, which must not exist.
+/// With a `backing` file, everything not written in the new image reads
+/// through to it.
+pub fn create(path: &Path, size: u64, backing: Option<&Path>) -> io::Result<()> {
+    let bits = DEFAULT_CLUSTER_BITS;
+    let cluster = 1u64 << bits;
+    if size == 0 || !size.is_multiple_of(512) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "qcow2 sizes are whole 512-byte sectors"));
+    }
+    let l2_entries = cluster / 8;
+    let refcount_entries = cluster / 2;
+    let l1_size = size.div_ceil(cluster * l2_entries);
+    let l1_clusters = (l1_size * 8).div_ceil(cluster).max(1);
+    // Enough refcount blocks for every cluster the image can grow to, twice
+    // over for clusters copied away from snapshots
+    let max_clusters = 2 * (size.div_ceil(cluster) + l1_size + 64);
+    let refcount_clusters = (max_clusters.div_ceil(refcount_entries) * 8).div_ceil(cluster);
+    let l1_offset = cluster;
+    let refcount_offset = l1_offset + l1_clusters * cluster;
+    let block_offset = refcount_offset + refcount_clusters * cluster;
+    let used = block_offset / cluster + 1;
+    if used > refcount_entries {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the image is too large for qcow2 metadata"));
+    }
+
+    let mut header = vec![0; cluster as usize];
+    header[..4].copy_from_slice(MAGIC);
+    put_be32(&mut header, H_VERSION, 3);
+    put_be32(&mut header, H_CLUSTER_BITS, bits);
+    put_be64(&mut header, H_SIZE, size);
+    put_be32(&mut header, H_CRYPT_METHOD, 0);
+    put_be32(&mut header, H_L1_SIZE, l1_size as u32);
+    put_be64(&mut header, H_L1_OFFSET, l1_offset);
+    put_be64(&mut header, H_REFCOUNT_OFFSET, refcount_offset);
+    put_be32(&mut header, H_REFCOUNT_CLUSTERS, refcount_clusters as u32);
+    put_be32(&mut header, H_REFCOUNT_ORDER, 4);
+    put_be32(&mut header, H_LENGTH, V3_HEADER_LEN as u32);
+    let mut offset = V3_HEADER_LEN;
+    if let Some(backing) = backing {
+        let format: &[u8] = if is_qcow2(backing)? { b"qcow2" } else { b"raw" };
+        put_be32(&mut header, offset, EXT_BACKING_FORMAT);
+        put_be32(&mut header, offset + 4, format.len() as u32);
+        header[offset + 8..offset + 8 + format.len()].copy_from_slice(format);
+        offset += 8 + format.len().div_ceil(8) * 8;
+    }
+    put_be32(&mut header, offset, EXT_END);
+    offset += 8;
+    if let Some(backing) = backing {
+        let name = backing.as_os_str().as_encoded_bytes();
+        if name.len() > 1023 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "backing file name too long"));
+        }
+        put_be64(&mut header, H_BACKING_OFFSET, offset as u64);
+        put_be32(&mut header, H_BACKING_SIZE, name.len() as u32);
+        header[offset..offset + name.len()].copy_from_slice(name);
+    }
+
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.set_len(block_offset + cluster)?;
+    file.write_all_at(&header, 0)?;
+    let mut table = vec![0; 8];
+    put_be64(&mut table, 0, block_offset);
+    file.write_all_at(&table, refcount_offset)?;
+    // Everything up to and including the first refcount block is in use
+    let mut block = vec![0; (used * 2) as usize];
+    for i in 0..used as usize {
+        put_be16(&mut block, i * 2, 1);
+    }
+    file.write_all_at(&block, block_offset)?;
+    file.sync_all()
+}
+
+/// A qcow2 image opened for reading, or writing, in-process.
+pub struct Qcow2Image {
+    file: File,
+    writable: bool,
+    version: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1: Vec<u64>,
+    l1_offset: u64,
+    refcount_table: Vec<u64>,
+    refcount_offset: u64,
+    backing: Option<Box<dyn BlockDevice>>,
+    backing_len: u64,
+    /// The L2 table last used, by its offset.
+    l2_cache: Option<(u64, Vec<u64>)>,
+    /// Where the next cluster goes: new clusters are appended.
+    next_free: u64,
+}
+
+impl Qcow2Image {
+    /// Opens the image at `path`, and its backing chain read-only.
+    pub fn open(path: &Path, writable: bool) -> io::Result<Self> {
+        Qcow2Image::open_at_depth(path, writable, 0)
+    }
+
+    fn open_at_depth(path: &Path, writable: bool, depth: usize) -> io::Result<Self> {
+        if depth > MAX_BACKING_DEPTH {
+            return Err(invalid("the backing chain is too long or loops"));
+        }
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+        let (mut header, info) = read_header(&file)?;
+        if be32(&header, H_CRYPT_METHOD) != 0 {
+            return Err(unsupported("encrypted qcow2 images"));
+        }
+        if info.version == 3 {
+            let incompatible = be64(&header, H_INCOMPATIBLE);
+            if incompatible & !(DIRTY | CORRUPT) != 0 {
+                return Err(unsupported(format!("qcow2 features {:#x}", incompatible & !(DIRTY | CORRUPT))));
+            }
+            if writable && (info.dirty || info.corrupt) {
+                return Err(invalid("the image needs repairing first (qemu-img check -r all)"));
+            }
+            if writable && be32(&header, H_REFCOUNT_ORDER) != 4 {
+                return Err(unsupported("writing qcow2 images without 16-bit refcounts"));
+            }
+            // Extensions we do not keep up to date have to be dropped on the first write
+            if writable && be64(&header, H_AUTOCLEAR) != 0 {
+                put_be64(&mut header, H_AUTOCLEAR, 0);
+                file.write_all_at(&header[H_AUTOCLEAR..H_AUTOCLEAR + 8], H_AUTOCLEAR as u64)?;
+            }
+        }
+
+        let cluster_bits = be32(&header, H_CLUSTER_BITS);
+        let file_len = file.metadata()?.len();
+        let read_table = |name: &str, offset: u64, entries: u64| -> io::Result<Vec<u64>> {
+            if entries * 8 > file_len.saturating_sub(offset) {
+                return Err(invalid(format!("the {} table runs past the end of the image", name)));
+            }
+            let mut raw = vec![0; (entries * 8) as usize];
+            file.read_exact_at(&mut raw, offset)?;
+            Ok((0..entries as usize).map(|i| be64(&raw, i * 8)).collect())
+        };
+        let l1_offset = be64(&header, H_L1_OFFSET);
+        let l1 = read_table("L1", l1_offset, u64::from(be32(&header, H_L1_SIZE)))?;
+        if (l1.len() as u64) << (2 * cluster_bits - 3) < info.virtual_size {
+            return Err(invalid("the L1 table is too small for the image"));
+        }
+        let refcount_offset = be64(&header, H_REFCOUNT_OFFSET);
+        let refcount_entries = (u64::from(be32(&header, H_REFCOUNT_CLUSTERS)) << cluster_bits) / 8;
+        let refcount_table = read_table("refcount", refcount_offset, refcount_entries)?;
+
+        let (backing, backing_len) = match &info.backing_file {
+            Some(name) => {
+                let mut backing = open_backing(&backing_path(path, name), info.backing_format.as_deref(), depth)?;
+                let len = backing.len()?;
+                (Some(backing), len)
+            }
+            None => (None, 0),
+        };
+        let cluster = 1u64 << cluster_bits;
+        let next_free = file_len.div_ceil(cluster) * cluster;
+        Ok(Qcow2Image {
+            file,
+            writable,
+            version: info.version,
+            cluster_bits,
+            size: info.virtual_size,
+            l1,
+            l1_offset,
+            refcount_table,
+            refcount_offset,
+            backing,
+            backing_len,
+            l2_cache: None,
+            next_free,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    fn l2_table(&mut self, offset: u64) -> io::Result<&mut Vec<u64>> {
+        if self.l2_cache.as_ref().is_none_or(|(cached, _)| *cached != offset) {
+            let mut raw = vec![0; self.cluster_size() as usize];
+            self.file.read_exact_at(&mut raw, offset)?;
+            let table = (0..raw.len() / 8).map(|i| be64(&raw, i * 8)).collect();
+            self.l2_cache = Some((offset, table));
+        }
+        Ok(&mut self.l2_cache.as_mut().expect("just filled").1)
+    }
+
+    /// The L2 entry for guest cluster `cluster`, 0 if it has none.
+    fn l2_entry(&mut self, cluster: u64) -> io::Result<u64> {
+        let l2_entries = self.l2_entries();
+        let l2_offset = self.l1[(cluster / l2_entries) as usize] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        Ok(self.l2_table(l2_offset)?[(cluster % l2_entries) as usize])
+    }
+
+    fn is_zero(&self, entry: u64) -> bool {
+        self.version >= 3 && entry & ZERO != 0 && entry & COMPRESSED == 0
+    }
+
+    /// Reads `buf.len()` bytes at `within` into guest cluster `cluster`.
+    fn read_cluster(&mut self, cluster: u64, within: u64, buf: &mut [u8]) -> io::Result<()> {
+        let entry = self.l2_entry(cluster)?;
+        if entry & COMPRESSED != 0 {
+            let data = self.decompress(entry)?;
+            buf.copy_from_slice(&data[within as usize..within as usize + buf.len()]);
+            return Ok(());
+        }
+        let offset = entry & OFFSET_MASK;
+        if self.is_zero(entry) {
+            buf.fill(0);
+        } else if offset != 0 {
+            self.file.read_exact_at(buf, offset + within)?;
+        } else {
+            // Not written here: whatever the backing file holds, zeros past its end
+            let pos = (cluster << self.cluster_bits) + within;
+            buf.fill(0);
+            if let Some(backing) = &mut self.backing {
+                if pos < self.backing_len {
+                    let len = (self.backing_len - pos).min(buf.len() as u64) as usize;
+                    backing.read_at(pos, &mut buf[..len])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Inflates the compressed cluster `entry` points at.
+    fn decompress(&self, entry: u64) -> io::Result<Vec<u8>> {
+        let shift = 62 - (self.cluster_bits - 8);
+        let offset = entry & ((1 << shift) - 1);
+        let sectors = ((entry & !(COPIED | COMPRESSED)) >> shift) + 1;
+        let len = sectors * 512 - (offset & 511);
+        let mut raw = vec![0; len as usize];
+        // The last compressed cluster may end before its last sector does
+        let read = self.file.read_at(&mut raw, offset)?;
+        raw.truncate(read);
+        let mut data = vec![0; self.cluster_size() as usize];
+        DeflateDecoder::new(raw.as_slice())
+            .read_exact(&mut data)
+            .map_err(|_| invalid(format!("compressed cluster at {} is damaged", offset)))?;
+        Ok(data)
+    }
+
+    fn refcount_block_entries(&self) -> u64 {
+        self.cluster_size() / 2
+    }
+
+    /// The refcount of the host cluster at `offset`.
+    fn refcount(&self, offset: u64) -> io::Result<u16> {
+        let index = offset >> self.cluster_bits;
+        let block = match self.refcount_table.get((index / self.refcount_block_entries()) as usize) {
+            Some(&block) if block & OFFSET_MASK != 0 => block & OFFSET_MASK,
+            _ => return Ok(0),
+        };
+        let mut count = [0; 2];
+        self.file.read_exact_at(&mut count, block + (index % self.refcount_block_entries()) * 2)?;
+        Ok(be16(&count, 0))
+    }
+
+    /// Sets the refcount of the host cluster at `offset`, adding a refcount
+    /// block at the end of the file if its range has none yet, and growing
+    /// the refcount table if it does not reach that far.
+    fn set_refcount(&mut self, offset: u64, count: u16) -> io::Result<()> {
+        let index = offset >> self.cluster_bits;
+        let slot = (index / self.refcount_block_entries()) as usize;
+        if slot >= self.refcount_table.len() {
+            self.grow_refcount_table(slot)?;
+        }
+        if self.refcount_table[slot] & OFFSET_MASK == 0 {
+            let block = self.next_free;
+            self.next_free += self.cluster_size();
+            self.file.write_all_at(&vec![0; self.cluster_size() as usize], block)?;
+            self.refcount_table[slot] = block;
+            let mut entry = [0; 8];
+            put_be64(&mut entry, 0, block);
+            self.file.write_all_at(&entry, self.refcount_offset + slot as u64 * 8)?;
+            // The new block is counted in itself, or in the block after
+            self.set_refcount(block, 1)?;
+        }
+        let block = self.refcount_table[slot] & OFFSET_MASK;
+        let mut entry = [0; 2];
+        put_be16(&mut entry, 0, count);
+        self.file.write_all_at(&entry, block + (index % self.refcount_block_entries()) * 2)
+    }
+
+    /// Moves the refcount table to the end of the file, with room for
+    /// `slot` and for the refcount blocks of the new table itself. The
+    /// header only switches over once the new table is complete, so a crash
+    /// part way leaks the new clusters at worst.
+    fn grow_refcount_table(&mut self, slot: usize) -> io::Result<()> {
+        let cluster = self.cluster_size();
+        let reach = self.refcount_block_entries() * cluster;
+        let start = self.next_free;
+        let mut entries = (slot as u64 + 1).max(2 * self.refcount_table.len() as u64);
+        // Room for the table plus one new refcount block per cluster of it, at worst
+        while entries * reach < start + 2 * (entries * 8).div_ceil(cluster) * cluster + cluster {
+            entries *= 2;
+        }
+        let clusters = (entries * 8).div_ceil(cluster);
+        let entries = clusters * cluster / 8;
+
+        let mut table = self.refcount_table.clone();
+        table.resize(entries as usize, 0);
+        let mut raw = vec![0; (clusters * cluster) as usize];
+        for (i, &entry) in table.iter().enumerate() {
+            put_be64(&mut raw, i * 8, entry);
+        }
+        self.file.write_all_at(&raw, start)?;
+        self.next_free += clusters * cluster;
+        let (old_offset, old_clusters) = (self.refcount_offset, (self.refcount_table.len() as u64 * 8) / cluster);
+        self.refcount_table = table;
+        self.refcount_offset = start;
+        for i in 0..clusters {
+            self.set_refcount(start + i * cluster, 1)?;
+        }
+        self.file.sync_data()?;
+
+        let mut fields = [0; 12];
+        put_be64(&mut fields, 0, start);
+        put_be32(&mut fields, 8, clusters as u32);
+        self.file.write_all_at(&fields, H_REFCOUNT_OFFSET as u64)?;
+        self.file.sync_data()?;
+        for i in 0..old_clusters {
+            self.set_refcount(old_offset + i * cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Drops one reference to the host cluster at `offset`.
+    fn release(&mut self, offset: u64) -> io::Result<()> {
+        let count = self.refcount(offset)?;
+        self.set_refcount(offset, count.saturating_sub(1))
+    }
+
+    /// Allocates a cluster at the end of the file and writes `data` to it.
+    fn allocate(&mut self, data: &[u8]) -> io::Result<u64> {
+        let offset = self.next_free;
+        self.next_free += self.cluster_size();
+        self.set_refcount(offset, 1)?;
+        self.file.write_all_at(data, offset)?;
+        Ok(offset)
+    }
+
+    /// The offset of the L2 table for L1 entry `index`, allocating it, or
+    /// copying it away from a snapshot, so that it can be written.
+    fn writable_l2(&mut self, index: usize) -> io::Result<u64> {
+        let entry = self.l1[index];
+        let old = entry & OFFSET_MASK;
+        if old != 0 && entry & COPIED != 0 {
+            return Ok(old);
+        }
+        let mut raw = vec![0; self.cluster_size() as usize];
+        if old != 0 {
+            self.file.read_exact_at(&mut raw, old)?;
+        }
+        let new = self.allocate(&raw)?;
+        self.l1[index] = new | COPIED;
+        let mut bytes = [0; 8];
+        put_be64(&mut bytes, 0, new | COPIED);
+        self.file.write_all_at(&bytes, self.l1_offset + index as u64 * 8)?;
+        if old != 0 {
+            self.release(old)?;
+        }
+        Ok(new)
+    }
+
+    /// Writes `data` at `within` into guest cluster `cluster`, in place if
+    /// the cluster is this image's alone, else to a newly allocated one.
+    fn write_cluster(&mut self, cluster: u64, within: u64, data: &[u8]) -> io::Result<()> {
+        let entry = self.l2_entry(cluster)?;
+        let offset = entry & OFFSET_MASK;
+        if entry & (COPIED | COMPRESSED) == COPIED && offset != 0 && !self.is_zero(entry) {
+            return self.file.write_all_at(data, offset + within);
+        }
+
+        // The rest of the cluster comes from wherever it reads from now
+        let mut full = vec![0; self.cluster_size() as usize];
+        if data.len() < full.len() {
+            self.read_cluster(cluster, 0, &mut full)?;
+        }
+        full[within as usize..within as usize + data.len()].copy_from_slice(data);
+        let new = if entry & COPIED != 0 && entry & COMPRESSED == 0 && offset != 0 {
+            // A preallocated cluster that reads as zeros
+            self.file.write_all_at(&full, offset)?;
+            offset
+        } else {
+            self.allocate(&full)?
+        };
+
+        let l2_entries = self.l2_entries();
+        let l2_offset = self.writable_l2((cluster / l2_entries) as usize)?;
+        let index = (cluster % l2_entries) as usize;
+        let mut bytes = [0; 8];
+        put_be64(&mut bytes, 0, new | COPIED);
+        self.file.write_all_at(&bytes, l2_offset + index as u64 * 8)?;
+        self.l2_table(l2_offset)?[index] = new | COPIED;
+        // Compressed clusters may share host clusters with their neighbours; leave them be
+        if offset != 0 && new != offset && entry & COMPRESSED == 0 {
+            self.release(offset)?;
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> io::Result<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        }
+    }
+}
+
+impl BlockDevice for Qcow2Image {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.check_range(offset, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + (done as u64);
+            let within = pos & (self.cluster_size() - 1);
+            let len = ((self.cluster_size() - within) as usize).min(buf.len() - done);
+            self.read_cluster(pos >> self.cluster_bits, within, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if !self.writable {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the image is open read-only"));
+        }
+        self.check_range(offset, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + (done as u64);
+            let within = pos & (self.cluster_size() - 1);
+            let len = ((self.cluster_size() - within) as usize).min(buf.len() - done);
+            self.write_cluster(pos >> self.cluster_bits, within, &buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{pattern, ScratchDir};
+    use std::fs;
+
+    const MIB: u64 = 1 << 20;
+
+    fn read(image: &mut Qcow2Image, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        image.read_at(offset, &mut buf).unwrap();
+        buf
+    }
+
+    /// Every cluster up to `next_free` has a refcount of exactly 1: each
+    /// one in use is counted once and nothing is leaked.
+    fn assert_refcounts(image: &Qcow2Image) {
+        for offset in (0..image.next_free).step_by(image.cluster_size() as usize) {
+            assert_eq!(image.refcount(offset).unwrap(), 1, "cluster at {:#x}", offset);
+        }
+    }
+
+    #[test]
+    fn writes_go_to_the_image_and_the_rest_reads_through_to_the_backing_file() {
+        let scratch = ScratchDir::new("qcow2-cow");
+        let (base, top) = (scratch.join("base.raw"), scratch.join("top.qcow2"));
+        let backing = pattern(MIB as usize, 3);
+        fs::write(&base, &backing).unwrap();
+        create(&top, 2 * MIB, Some(&base)).unwrap();
+
+        let mut image = Qcow2Image::open(&top, true).unwrap();
+        assert_eq!(read(&mut image, 0, MIB as usize), backing);
+        assert_eq!(read(&mut image, MIB, MIB as usize), vec![0; MIB as usize]);
+        // Partial clusters, one of them straddling the end of the backing file
+        let (a, b) = (pattern(100, 9), pattern(70_000, 11));
+        image.write_at(70_000, &a).unwrap();
+        image.write_at(MIB - 1000, &b).unwrap();
+        let mut expected = backing.clone();
+        expected.resize(2 * MIB as usize, 0);
+        expected[70_000..70_100].copy_from_slice(&a);
+        expected[(MIB - 1000) as usize..(MIB + 69_000) as usize].copy_from_slice(&b);
+        assert_eq!(read(&mut image, 0, expected.len()), expected);
+        assert_refcounts(&image);
+
+        // Writing a cluster the image already holds does it in place
+        let next_free = image.next_free;
+        image.write_at(70_050, &a[..10]).unwrap();
+        expected[70_050..70_060].copy_from_slice(&a[..10]);
+        assert_eq!(image.next_free, next_free);
+        drop(image);
+
+        assert_eq!(fs::read(&base).unwrap(), backing);
+        let mut image = Qcow2Image::open(&top, false).unwrap();
+        assert_eq!(read(&mut image, 0, expected.len()), expected);
+        assert_refcounts(&image);
+        assert!(image.write_at(0, &a).is_err());
+    }
+
+    #[test]
+    fn the_refcount_table_grows_when_the_file_outgrows_it() {
+        let scratch = ScratchDir::new("qcow2-grow");
+        let path = scratch.join("image.qcow2");
+        create(&path, 4 * MIB, None).unwrap();
+        let data = pattern(200_000, 5);
+
+        let mut image = Qcow2Image::open(&path, true).unwrap();
+        let old_offset = image.refcount_offset;
+        // Pretend the table only reaches the first refcount block and the file has just filled it
+        image.refcount_table.truncate(1);
+        let reach = image.refcount_block_entries() * image.cluster_size();
+        image.next_free = reach;
+        image.write_at(MIB + 123, &data).unwrap();
+        assert!(image.refcount_offset >= reach);
+        assert!(image.refcount_table.len() > 1);
+        assert_eq!(image.refcount(image.refcount_offset).unwrap(), 1);
+        for offset in (reach..image.next_free).step_by(image.cluster_size() as usize) {
+            assert_eq!(image.refcount(offset).unwrap(), 1, "cluster at {:#x}", offset);
+        }
+        drop(image);
+
+        let (header, _) = read_header(&File::open(&path).unwrap()).unwrap();
+        assert_ne!(be64(&header, H_REFCOUNT_OFFSET), old_offset);
+        let mut image = Qcow2Image::open(&path, false).unwrap();
+        assert_eq!(image.refcount_offset, be64(&header, H_REFCOUNT_OFFSET));
+        assert_eq!(read(&mut image, MIB + 123, data.len()), data);
+        assert_eq!(read(&mut image, 0, 1000), vec![0; 1000]);
+    }
+
+    #[test]
+    fn damaged_headers_are_refused() {
+        let scratch = ScratchDir::new("qcow2-damaged");
+        let path = scratch.join("image.qcow2");
+        create(&path, 4 * MIB, None).unwrap();
+        let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let mut field = [0; 8];
+
+        // An L1 table far larger than the file
+        put_be32(&mut field, 0, u32::MAX);
+        file.write_all_at(&field[..4], H_L1_SIZE as u64).unwrap();
+        let e = Qcow2Image::open(&path, false).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        put_be32(&mut field, 0, 1);
+        file.write_all_at(&field[..4], H_L1_SIZE as u64).unwrap();
+        assert!(Qcow2Image::open(&path, false).is_ok());
+
+        // A header extension longer than the first cluster
+        put_be32(&mut field, 0, EXT_BACKING_FORMAT);
+        put_be32(&mut field, 4, 1 << 17);
+        file.write_all_at(&field, V3_HEADER_LEN as u64).unwrap();
+        let e = info(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
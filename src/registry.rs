@@ -0,0 +1,318 @@
+//! Persistent record of the disks we manage, keyed by name and kept as JSON
+//! so other tools can read it.
+
+use crate::crypt::KeyRecord;
+use crate::disk::{DiskState, VirtualDisk};
+use crate::error::{MizzleError, Result};
+use crate::filesystem::Filesystem;
+use crate::snapshot::Snapshot;
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// What the registry remembers about one disk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskRecord {
+    pub image: PathBuf,
+    pub size: u64,
+    pub filesystem: Filesystem,
+    /// Filesystem UUID (volume id for vfat), once formatted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    pub mount_point: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loop_device: Option<PathBuf>,
+    pub state: DiskState,
+    /// Snapshots of the image by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub snapshots: BTreeMap<String, Snapshot>,
+    /// The keys of an encrypted disk by key slot, as far as they were added here.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub keys: BTreeMap<usize, KeyRecord>,
+}
+
+#[derive(Debug, Default)]
+pub struct Registry {
+    path: PathBuf,
+    disks: BTreeMap<String, DiskRecord>,
+    /// Disks added, changed or removed since loading; saving writes just
+    /// these over the file, so other processes' updates to other disks stay.
+    changed: BTreeSet<String>,
+}
+
+/// `$MIZZLESTORE_REGISTRY`, else `disks.json` under `$XDG_STATE_HOME` or
+/// `~/.local/state`.
+pub fn default_path() -> PathBuf {
+    if let Some(path) = env::var_os("MIZZLESTORE_REGISTRY") {
+        return PathBuf::from(path);
+    }
+    let state = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .unwrap_or_else(|| PathBuf::from("/var/lib"));
+    state.join("mizzlestore/disks.json")
+}
+
+/// `path` with `suffix` appended to its file name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// The disks recorded in the file at `path`; a missing file records none.
+fn read_disks(path: &Path) -> io::Result<BTreeMap<String, DiskRecord>> {
+    match fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+impl Registry {
+    /// Loads the registry at `path`; a missing file is an empty registry.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Registry> {
+        let path = path.into();
+        match read_disks(&path) {
+            Ok(disks) => Ok(Registry { path, disks, changed: BTreeSet::new() }),
+            Err(source) => Err(MizzleError::Registry { path, source }),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the changes made since loading into the file, replacing it
+    /// atomically. Saves are serialised by a lock next to the file, and each
+    /// applies its changes to what the last one wrote, so processes updating
+    /// different disks at once do not undo each other. Afterwards the
+    /// registry holds every disk in the file.
+    pub fn save(&mut self) -> Result<()> {
+        let write = || -> io::Result<BTreeMap<String, DiskRecord>> {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let lock = fs::File::options().write(true).create(true).truncate(false).open(sibling(&self.path, ".lock"))?;
+            let _lock = Flock::lock(lock, FlockArg::LockExclusive).map_err(|(_, errno)| io::Error::from(errno))?;
+            let mut disks = read_disks(&self.path)?;
+            for name in &self.changed {
+                match self.disks.get(name) {
+                    Some(record) => disks.insert(name.clone(), record.clone()),
+                    None => disks.remove(name),
+                };
+            }
+            let tmp = sibling(&self.path, ".tmp");
+            let mut file = fs::File::create(&tmp)?;
+            serde_json::to_writer_pretty(&mut file, &disks)?;
+            file.write_all(b"\n")?;
+            file.sync_all()?;
+            fs::rename(&tmp, &self.path)?;
+            Ok(disks)
+        };
+        self.disks = write().map_err(|source| MizzleError::Registry { path: self.path.clone(), source })?;
+        self.changed.clear();
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&DiskRecord> {
+        self.disks.get(name)
+    }
+
+    /// The name a disk with this image is registered under.
+    pub fn find_image(&self, image: &Path) -> Option<&str> {
+        let image = fs::canonicalize(image).unwrap_or_else(|_| image.to_path_buf());
+        self.disks
+            .iter()
+            .find(|(_, record)| record.image == image)
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &DiskRecord)> {
+        self.disks.iter().map(|(name, record)| (name.as_str(), record))
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, record: DiskRecord) {
+        let name = name.into();
+        self.changed.insert(name.clone());
+        self.disks.insert(name, record);
+    }
+
+    /// Records the current state of `disk` under `name`.
+    pub fn record(&mut self, name: impl Into<String>, disk: &VirtualDisk) {
+        let name = name.into();
+        // The UUID can only be read while the image is reachable; keep the old one otherwise
+        let old = self.disks.get(&name);
+        let uuid = disk.uuid().or_else(|| old.and_then(|r| r.uuid.clone()));
+        let snapshots = old.map(|r| r.snapshots.clone()).unwrap_or_default();
+        let keys = old.map(|r| r.keys.clone()).unwrap_or_default();
+        let record = DiskRecord { uuid, snapshots, keys, ..disk.record() };
+        self.insert(name, record);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<DiskRecord> {
+        let removed = self.disks.remove(name);
+        if removed.is_some() {
+            self.changed.insert(name.to_string());
+        }
+        removed
+    }
+
+    fn disk_mut(&mut self, name: &str) -> Result<&mut DiskRecord> {
+        let record = self.disks.get_mut(name).ok_or_else(|| MizzleError::UnknownDisk { name: name.to_string() })?;
+        self.changed.insert(name.to_string());
+        Ok(record)
+    }
+
+    /// The snapshot of disk `name` called `snapshot`.
+    pub fn snapshot(&self, name: &str, snapshot: &str) -> Result<&Snapshot> {
+        let record = self.disks.get(name).ok_or_else(|| MizzleError::UnknownDisk { name: name.to_string() })?;
+        record.snapshots.get(snapshot).ok_or_else(|| MizzleError::UnknownSnapshot {
+            disk: name.to_string(),
+            name: snapshot.to_string(),
+        })
+    }
+
+    pub fn add_snapshot(&mut self, name: &str, snapshot: impl Into<String>, record: Snapshot) -> Result<()> {
+        self.disk_mut(name)?.snapshots.insert(snapshot.into(), record);
+        Ok(())
+    }
+
+    pub fn remove_snapshot(&mut self, name: &str, snapshot: &str) -> Result<Snapshot> {
+        self.disk_mut(name)?.snapshots.remove(snapshot).ok_or_else(|| MizzleError::UnknownSnapshot {
+            disk: name.to_string(),
+            name: snapshot.to_string(),
+        })
+    }
+
+    /// Replaces the keys recorded for disk `name`.
+    pub fn set_keys(&mut self, name: &str, keys: BTreeMap<usize, KeyRecord>) -> Result<()> {
+        self.disk_mut(name)?.keys = keys;
+        Ok(())
+    }
+
+    pub fn add_key(&mut self, name: &str, slot: usize, key: KeyRecord) -> Result<()> {
+        self.disk_mut(name)?.keys.insert(slot, key);
+        Ok(())
+    }
+
+    pub fn remove_key(&mut self, name: &str, slot: usize) -> Result<Option<KeyRecord>> {
+        Ok(self.disk_mut(name)?.keys.remove(&slot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::KeyKind;
+    use crate::testutil::ScratchDir;
+    use std::thread;
+
+    fn record(image: &str) -> DiskRecord {
+        DiskRecord {
+            image: PathBuf::from(image),
+            size: 64 << 20,
+            filesystem: Filesystem::Ext4,
+            uuid: None,
+            mount_point: PathBuf::from("/mnt/disk"),
+            loop_device: None,
+            state: DiskState::Formatted,
+            snapshots: BTreeMap::new(),
+            keys: BTreeMap::new(),
+        }
+    }
+
+    fn names(registry: &Registry) -> Vec<&str> {
+        registry.iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn saved_registries_load_back() {
+        let dir = ScratchDir::new("registry-round-trip");
+        let path = dir.join("state/disks.json");
+        let mut registry = Registry::load(&path).unwrap();
+        assert!(names(&registry).is_empty());
+
+        let mut full = record("/images/full.img");
+        full.uuid = Some("0f1e2d3c-4b5a-6978-8796-a5b4c3d2e1f0".into());
+        full.loop_device = Some("/dev/loop3".into());
+        full.state = DiskState::Mounted;
+        let snapshot = Snapshot { path: "/images/full.img.snapshots/s".into(), created: 1, size: 2, reflink: true };
+        full.snapshots.insert("s".into(), snapshot);
+        full.keys.insert(1, KeyRecord::new(KeyKind::KeyFile, Some("/keys/k".into())));
+        registry.insert("full", full.clone());
+        registry.insert("bare", record("/images/bare.img"));
+        registry.save().unwrap();
+
+        let loaded = Registry::load(&path).unwrap();
+        assert_eq!(names(&loaded), ["bare", "full"]);
+        assert_eq!(loaded.get("full"), Some(&full));
+        assert_eq!(loaded.get("bare"), Some(&record("/images/bare.img")));
+        assert_eq!(loaded.find_image(Path::new("/images/full.img")), Some("full"));
+        assert!(fs::read(&path).unwrap().ends_with(b"}\n"));
+    }
+
+    #[test]
+    fn corrupted_files_are_reported_and_kept() {
+        let dir = ScratchDir::new("registry-corrupted");
+        let path = dir.join("disks.json");
+        let mut registry = Registry::load(&path).unwrap();
+        registry.insert("disk", record("/images/disk.img"));
+        registry.save().unwrap();
+        let saved = fs::read(&path).unwrap();
+
+        for garbage in [&b"{ not json"[..], &saved[..saved.len() / 2], b"[1, 2]", b"{\"disk\": {\"size\": 1}}"] {
+            fs::write(&path, garbage).unwrap();
+            match Registry::load(&path) {
+                Err(MizzleError::Registry { path: p, .. }) => assert_eq!(p, path),
+                other => panic!("loaded {:?}", other),
+            }
+            // Saving over the damage would lose whatever else it recorded
+            registry.insert("other", record("/images/other.img"));
+            assert!(matches!(registry.save(), Err(MizzleError::Registry { .. })));
+            assert_eq!(fs::read(&path).unwrap(), garbage);
+        }
+    }
+
+    #[test]
+    fn concurrent_updates_to_different_disks_are_all_kept() {
+        let dir = ScratchDir::new("registry-concurrent");
+        let path = dir.join("disks.json");
+        let mut registry = Registry::load(&path).unwrap();
+        registry.insert("old", record("/images/old.img"));
+        registry.insert("kept", record("/images/kept.img"));
+        registry.save().unwrap();
+
+        let mut first = Registry::load(&path).unwrap();
+        let mut second = Registry::load(&path).unwrap();
+        first.insert("new", record("/images/new.img"));
+        first.add_key("kept", 0, KeyRecord::new(KeyKind::Passphrase, None)).unwrap();
+        second.remove("old");
+        first.save().unwrap();
+        second.save().unwrap();
+        assert_eq!(names(&second), ["kept", "new"]);
+        assert_eq!(second.get("kept").unwrap().keys.len(), 1);
+
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    let mut registry = Registry::load(&path).unwrap();
+                    registry.insert(format!("worker{}", i), record(&format!("/images/{}.img", i)));
+                    registry.save().unwrap();
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let loaded = Registry::load(&path).unwrap();
+        assert_eq!(names(&loaded).len(), 10);
+        assert!((0..8).all(|i| loaded.get(&format!("worker{}", i)).is_some()));
+    }
+}
@@ -0,0 +1,202 @@
+//! Disk sizes written the way people write them: `512M`, `10GiB`, `1.5T`.
+
+use std::fmt;
+use std::str::FromStr;
+
+pub const KIB: u64 = 1 << 10;
+pub const MIB: u64 = 1 << 20;
+pub const GIB: u64 = 1 << 30;
+pub const TIB: u64 = 1 << 40;
+pub const PIB: u64 = 1 << 50;
+pub const EIB: u64 = 1 << 60;
+
+/// Largest size a file can have: `off_t` is signed.
+pub const MAX_FILE_SIZE: u64 = i64::MAX as u64;
+
+/// Image sizes must be whole sectors for loop devices to see all of them.
+pub const SECTOR_SIZE: u64 = 512;
+
+const BINARY_UNITS: [(&str, u64); 7] =
+    [("E", EIB), ("P", PIB), ("T", TIB), ("G", GIB), ("M", MIB), ("K", KIB), ("", 1)];
+
+/// A size in bytes. Parses a number with an optional unit: `K`, `M`, `G`,
+/// `T`, `P` and `E` alone or with `iB` are powers of 1024, with just `B`
+/// powers of 1000 (as in coreutils). Fractions are allowed and rounded
+/// down to a whole byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct DiskSize(pub u64);
+
+impl DiskSize {
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for DiskSize {
+    fn from(bytes: u64) -> Self {
+        DiskSize(bytes)
+    }
+}
+
+/// Bytes per unit for a suffix, or `None` if it is not one we know.
+fn multiplier(unit: &str) -> Option<u64> {
+    let unit = unit.trim().to_ascii_uppercase();
+    let (prefix, decimal) = match unit.as_str() {
+        "" | "B" => return Some(1),
+        u if u.len() == 1 => (u, false),
+        u if u.len() == 3 && u.ends_with("IB") => (&u[..1], false),
+        u if u.len() == 2 && u.ends_with('B') => (&u[..1], true),
+        _ => return None,
+    };
+    let power = "KMGTPE".find(prefix)? as u32 + 1;
+    Some(if decimal { 1000u64.pow(power) } else { 1024u64.pow(power) })
+}
+
+impl FromStr for DiskSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(format!("invalid size '{}': expected a number such as 512M or 1.5G", s));
+        }
+        let multiplier = multiplier(unit).ok_or_else(|| {
+            format!("invalid size '{}': unknown unit '{}' (expected K, M, G, T, P or E)", s, unit.trim())
+        })?;
+        let too_large = || format!("invalid size '{}': larger than the maximum file size", s);
+
+        let whole: u64 = match whole {
+            "" => 0,
+            digits => digits.parse().map_err(|_| too_large())?,
+        };
+        let mut bytes = whole.checked_mul(multiplier).ok_or_else(too_large)?;
+        if !fraction.is_empty() {
+            if fraction.contains('.') || fraction.len() > 18 {
+                return Err(format!("invalid size '{}'", s));
+            }
+            let digits: u128 = fraction.parse().map_err(|_| format!("invalid size '{}'", s))?;
+            let part = digits * multiplier as u128 / 10u128.pow(fraction.len() as u32);
+            bytes = bytes.checked_add(part as u64).ok_or_else(too_large)?;
+        }
+        if bytes > MAX_FILE_SIZE {
+            return Err(too_large());
+        }
+        Ok(DiskSize(bytes))
+    }
+}
+
+/// The shortest exact form, e.g. `10G` or `1536K`; parses back to the same size.
+impl fmt::Display for DiskSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (unit, size) in BINARY_UNITS {
+            if self.0 >= size && self.0.is_multiple_of(size) {
+                return write!(f, "{}{}", self.0 / size, unit);
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Checks that `bytes` can be the size of an image file.
+pub fn validate(bytes: u64) -> Result<(), String> {
+    if bytes == 0 {
+        Err("a disk cannot be empty".to_string())
+    } else if bytes > MAX_FILE_SIZE {
+        Err(format!("larger than the maximum file size of {} bytes", MAX_FILE_SIZE))
+    } else if !bytes.is_multiple_of(SECTOR_SIZE) {
+        Err(format!("not a multiple of {} bytes", SECTOR_SIZE))
+    } else {
+        Ok(())
+    }
+}
+
+/// `bytes` with a binary unit and one decimal, e.g. `1.5 GiB`.
+pub fn human(bytes: u64) -> String {
+    const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<u64, String> {
+        s.parse::<DiskSize>().map(DiskSize::bytes)
+    }
+
+    #[test]
+    fn parses_binary_and_decimal_units() {
+        assert_eq!(parse("512"), Ok(512));
+        assert_eq!(parse("512B"), Ok(512));
+        assert_eq!(parse("4k"), Ok(4 * KIB));
+        assert_eq!(parse("512M"), Ok(512 * MIB));
+        assert_eq!(parse("10GiB"), Ok(10 * GIB));
+        assert_eq!(parse("2 TiB"), Ok(2 * TIB));
+        assert_eq!(parse("3KB"), Ok(3000));
+        assert_eq!(parse("1GB"), Ok(1_000_000_000));
+        assert_eq!(parse(" 7P "), Ok(7 * PIB));
+    }
+
+    #[test]
+    fn rounds_fractions_down_to_whole_bytes() {
+        assert_eq!(parse("1.5T"), Ok(TIB + TIB / 2));
+        assert_eq!(parse(".5K"), Ok(512));
+        assert_eq!(parse("1.5"), Ok(1));
+        assert_eq!(parse("0.001K"), Ok(1));
+        assert_eq!(parse("0.0001K"), Ok(0));
+        assert_eq!(parse("1.333333333333333333G"), Ok(GIB + GIB / 3));
+    }
+
+    #[test]
+    fn rejects_malformed_sizes() {
+        for s in ["", "G", ".", "-1G", "1.2.3G", "12Q", "1GiBB", "1 G B", "1.0000000000000000000G"] {
+            assert!(parse(s).is_err(), "{:?} parsed", s);
+        }
+    }
+
+    #[test]
+    fn rejects_sizes_past_the_largest_file() {
+        assert!(parse("8E").is_err());
+        assert!(parse("99999999999999999999").is_err());
+        assert!(parse("16777216T").is_err());
+        assert_eq!(parse("7E"), Ok(7 * EIB));
+    }
+
+    #[test]
+    fn display_parses_back_to_the_same_size() {
+        for bytes in [1, 512, 1536 * KIB, 10 * GIB, 3 * TIB + MIB, 1000, MAX_FILE_SIZE] {
+            let shown = DiskSize(bytes).to_string();
+            assert_eq!(parse(&shown), Ok(bytes), "{} shown as {}", bytes, shown);
+        }
+        assert_eq!(DiskSize(10 * GIB).to_string(), "10G");
+        assert_eq!(DiskSize(1536 * KIB).to_string(), "1536K");
+    }
+
+    #[test]
+    fn validates_image_sizes() {
+        assert!(validate(0).is_err());
+        assert!(validate(1000).is_err());
+        assert!(validate(MAX_FILE_SIZE + 1).is_err());
+        assert_eq!(validate(64 * MIB), Ok(()));
+    }
+
+    #[test]
+    fn humanises_with_one_decimal() {
+        assert_eq!(human(999), "999 B");
+        assert_eq!(human(1536), "1.5 KiB");
+        assert_eq!(human(10 * GIB), "10.0 GiB");
+    }
+}
@@ -0,0 +1,115 @@
+//! Point-in-time copies of an image file, kept next to it. Where the host
+//! filesystem supports reflinks (btrfs, xfs) a snapshot shares its blocks
+//! with the image until either is written; elsewhere it is a sparse copy.
+
+use crate::loopdev;
+use crate::ops;
+use crate::transfer;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const FIFREEZE: libc::Ioctl = 0xC004_5877;
+const FITHAW: libc::Ioctl = 0xC004_5878;
+
+/// One snapshot of a disk image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    pub size: u64,
+    /// Whether the snapshot shares its blocks with the image it was taken of.
+    pub reflink: bool,
+}
+
+/// `<image>.snapshots`, the directory snapshots of `image` are kept in.
+pub fn snapshot_dir(image: &Path) -> PathBuf {
+    let mut dir = image.as_os_str().to_owned();
+    dir.push(".snapshots");
+    PathBuf::from(dir)
+}
+
+/// Checks that `name` can name a snapshot file.
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        Err("a snapshot needs a name".to_string())
+    } else if name.contains('/') || name.contains('\0') {
+        Err(format!("snapshot name '{}' contains a '/'", name))
+    } else if name.starts_with('.') {
+        Err(format!("snapshot name '{}' starts with a '.'", name))
+    } else {
+        Ok(())
+    }
+}
+
+/// Makes `output` a copy of `input`: a reflink if the host filesystem can,
+/// otherwise a copy that keeps holes. Returns whether it was a reflink.
+pub fn clone_file(input: &File, output: &File) -> io::Result<bool> {
+    match loopdev::ioctl(output.as_raw_fd(), libc::FICLONE, input.as_raw_fd() as libc::c_ulong) {
+        Ok(_) => Ok(true),
+        Err(e) if matches!(
+            e.raw_os_error(),
+            Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY)
+        ) => {
+            transfer::copy_sparse(input, output, input.metadata()?.len())?;
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Copies `image` to a new snapshot file at `path`, which must not exist yet.
+pub fn take(image: &Path, path: &Path) -> io::Result<Snapshot> {
+    let input = File::open(image)?;
+    let size = input.metadata()?.len();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let reflink = ops::write_atomically(path, false, |output| clone_file(&input, output))?;
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    Ok(Snapshot { path: path.to_path_buf(), created, size, reflink })
+}
+
+/// Replaces `image` with a copy of `snapshot`.
+pub fn restore(snapshot: &Snapshot, image: &Path) -> io::Result<bool> {
+    let input = File::open(&snapshot.path)?;
+    ops::write_atomically(image, true, |output| clone_file(&input, output))
+}
+
+impl Snapshot {
+    /// Deletes the snapshot file, and the snapshot directory once it is empty.
+    pub fn delete(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if let Some(dir) = self.path.parent() {
+            // Fails harmlessly while other snapshots remain
+            let _ = fs::remove_dir(dir);
+        }
+        Ok(())
+    }
+}
+
+/// A mounted filesystem frozen so that its image is consistent on the host;
+/// thawed again on drop.
+pub(crate) struct Frozen(File);
+
+impl Frozen {
+    pub(crate) fn freeze(mount_point: &Path) -> io::Result<Frozen> {
+        let dir = File::open(mount_point)?;
+        loopdev::ioctl(dir.as_raw_fd(), FIFREEZE, 0)?;
+        Ok(Frozen(dir))
+    }
+}
+
+impl Drop for Frozen {
+    fn drop(&mut self) {
+        let _ = loopdev::ioctl(self.0.as_raw_fd(), FITHAW, 0);
+    }
+}
@@ -0,0 +1,104 @@
+//! Scratch files, scratch directories and test data shared by the unit tests.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{symlink, FileExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// A path in the temp directory that is this process's alone; `name` has
+/// to be unique across the whole test binary, as tests run in parallel.
+pub fn scratch_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("mizzlestore-{}-{}", std::process::id(), name))
+}
+
+/// An empty file opened read-write and unlinked straight away, so that
+/// nothing is left behind however the test ends.
+pub fn scratch_file(name: &str) -> File {
+    let path = scratch_path(name);
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    file
+}
+
+/// A scratch directory, removed with everything in it when dropped.
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    pub fn new(name: &str) -> ScratchDir {
+        let dir = scratch_path(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        ScratchDir(dir)
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// `len` bytes that never repeat within 256 and differ with `seed`.
+pub fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+/// A sparse raw disk of `size` bytes in a scratch file, holding
+/// [`pattern`]s of `(offset, len, seed)` and zeros everywhere else.
+pub fn raw_disk(name: &str, size: u64, extents: &[(u64, usize, u8)]) -> File {
+    let file = scratch_file(name);
+    file.set_len(size).unwrap();
+    for &(offset, len, seed) in extents {
+        file.write_all_at(&pattern(len, seed), offset).unwrap();
+    }
+    file
+}
+
+/// Data in six 40 KiB runs with 40 KiB of zeros between them, which an
+/// image stores as six pieces with holes in between.
+pub fn gapped_data() -> Vec<u8> {
+    let mut data = Vec::new();
+    for seed in 0..6 {
+        data.extend(pattern(40 * 1024, seed + 1));
+        data.resize(data.len() + 40 * 1024, 0);
+    }
+    data
+}
+
+/// Fills `root` with a host tree to build an image from: nested and empty
+/// directories, a file of [`gapped_data`], a hard link and symlinks to a
+/// file and, with `dir_links`, to a directory.
+pub fn host_tree(root: &Path, dir_links: bool) {
+    fs::create_dir_all(root.join("a/b/c")).unwrap();
+    fs::create_dir(root.join("a/empty")).unwrap();
+    fs::write(root.join("top.txt"), b"hello").unwrap();
+    fs::write(root.join("a/b/c/deep.txt"), pattern(5000, 9)).unwrap();
+    fs::write(root.join("big.bin"), gapped_data()).unwrap();
+    fs::hard_link(root.join("top.txt"), root.join("a/hard.txt")).unwrap();
+    symlink("top.txt", root.join("link")).unwrap();
+    if dir_links {
+        symlink("a/b", root.join("dirlink")).unwrap();
+    }
+}
+
+/// Runs the checker `program` with `args` on `image` and fails the test if
+/// it finds anything wrong; does nothing if `program` is not installed.
+pub fn fsck(program: &str, args: &[&str], image: &Path) {
+    let output = Command::new(program).args(args).arg(image).stdin(Stdio::null()).output();
+    match output {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => panic!("running {}: {}", program, e),
+        Ok(output) => assert!(
+            output.status.success(),
+            "{} found errors:\n{}{}",
+            program,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+    }
+}
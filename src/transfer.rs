@@ -0,0 +1,263 @@
+//! Moving file data with in-kernel copies where the kernel allows it, and
+//! reporting how far along a copy is.
+
+use nix::errno::Errno;
+use nix::fcntl::copy_file_range;
+use nix::sys::sendfile::sendfile64;
+use nix::unistd::{lseek, Whence};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+/// Largest piece handed to the kernel at once, so progress keeps ticking.
+const CHUNK: u64 = 64 * 1024 * 1024;
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A snapshot of a running copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub copied: u64,
+    pub total: u64,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Average throughput so far in bytes per second.
+    pub fn rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.copied as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Time left at the average rate so far.
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        (rate > 0.0).then(|| Duration::from_secs_f64(self.total.saturating_sub(self.copied) as f64 / rate))
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.copied >= self.total
+    }
+}
+
+/// Tracks a copy and turns byte counts into [`Progress`] reports.
+pub(crate) struct Tracker<'a> {
+    total: u64,
+    copied: u64,
+    start: Instant,
+    progress: &'a mut dyn FnMut(&Progress),
+}
+
+impl<'a> Tracker<'a> {
+    pub(crate) fn new(total: u64, progress: &'a mut dyn FnMut(&Progress)) -> Self {
+        Tracker { total, copied: 0, start: Instant::now(), progress }
+    }
+
+    pub(crate) fn advance(&mut self, n: u64) {
+        self.copied += n;
+        (self.progress)(&Progress { copied: self.copied, total: self.total, elapsed: self.start.elapsed() });
+    }
+}
+
+enum Method {
+    CopyFileRange,
+    Sendfile,
+    Buffered,
+}
+
+/// Errors meaning "this method does not work for these files", as opposed
+/// to a real I/O failure.
+fn unsupported(e: Errno) -> bool {
+    matches!(e, Errno::ENOSYS | Errno::EXDEV | Errno::EOPNOTSUPP | Errno::EINVAL)
+}
+
+/// Copies `len` bytes at `offset` in `input` to the same offset in `output`
+/// with copy_file_range, falling back to sendfile and then a plain buffered
+/// loop. Stops early at end of input; returns the number of bytes copied.
+fn copy_range(input: &File, output: &File, offset: u64, len: u64, tracker: &mut Tracker) -> io::Result<u64> {
+    let mut method = Method::CopyFileRange;
+    let mut buffer = Vec::new();
+    let mut done = 0;
+    while done < len {
+        let pos = offset + done;
+        let want = (len - done).min(CHUNK) as usize;
+        let n = match method {
+            Method::CopyFileRange => {
+                let (mut off_in, mut off_out) = (pos as i64, pos as i64);
+                match copy_file_range(input, Some(&mut off_in), output, Some(&mut off_out), want) {
+                    Err(e) if unsupported(e) => {
+                        method = Method::Sendfile;
+                        continue;
+                    }
+                    other => other?,
+                }
+            }
+            Method::Sendfile => {
+                // sendfile writes at the output's file position
+                let mut out = output;
+                out.seek(SeekFrom::Start(pos))?;
+                let mut off_in = pos as i64;
+                match sendfile64(output, input, Some(&mut off_in), want) {
+                    Err(e) if unsupported(e) => {
+                        method = Method::Buffered;
+                        continue;
+                    }
+                    other => other?,
+                }
+            }
+            Method::Buffered => {
+                buffer.resize(BUFFER_SIZE, 0);
+                let n = input.read_at(&mut buffer[..want.min(BUFFER_SIZE)], pos)?;
+                output.write_all_at(&buffer[..n], pos)?;
+                n
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        done += n as u64;
+        tracker.advance(n as u64);
+    }
+    Ok(done)
+}
+
+/// Copies a byte range between files without reporting progress.
+pub fn copy_file_range_at(input: &File, output: &File, offset: u64, len: u64) -> io::Result<u64> {
+    copy_range(input, output, offset, len, &mut Tracker::new(len, &mut |_| {}))
+}
+
+/// Copies all of `input` to the start of `output`, calling `progress` as it
+/// goes. Returns the number of bytes copied.
+///
+/// The copy runs to the end of `input` rather than to the length it reports
+/// up front, which may be out of date. Pipes, FIFOs and procfs files, which
+/// report no length at all, are read through a buffer.
+pub fn copy_file(input: &File, output: &File, progress: &mut dyn FnMut(&Progress)) -> io::Result<u64> {
+    let meta = input.metadata()?;
+    if !meta.is_file() || meta.len() == 0 {
+        let (mut input, mut output) = (input, output);
+        output.seek(SeekFrom::Start(0))?;
+        let copied = io::copy(&mut input, &mut output)?;
+        // With no length to go by, the only report is the final one
+        Tracker::new(copied, progress).advance(copied);
+        return Ok(copied);
+    }
+    let mut tracker = Tracker::new(meta.len(), progress);
+    copy_range(input, output, 0, u64::MAX, &mut tracker)
+}
+
+/// Copies the first `len` bytes of `input` to `output` skipping holes, so a
+/// sparse file stays sparse, and sets `output` to `len` bytes. Returns the
+/// number of bytes written.
+pub fn copy_sparse(input: &File, output: &File, len: u64) -> io::Result<u64> {
+    let mut written = 0;
+    let mut pos = 0;
+    while pos < len {
+        let (start, end) = match lseek(input.as_raw_fd(), pos as i64, Whence::SeekData) {
+            Ok(data) => {
+                let hole = lseek(input.as_raw_fd(), data, Whence::SeekHole).map_err(io::Error::from)?;
+                (data as u64, (hole as u64).min(len))
+            }
+            // Nothing but a hole up to the end
+            Err(Errno::ENXIO) => break,
+            // No hole support: copy everything
+            Err(Errno::EINVAL) => (pos, len),
+            Err(e) => return Err(e.into()),
+        };
+        written += copy_file_range_at(input, output, start, end - start)?;
+        pos = end;
+    }
+    output.set_len(len)?;
+    Ok(written)
+}
+
+/// A reader or writer that reports how many bytes went through it, for
+/// copies that go through our own filesystem code rather than the kernel.
+pub struct Metered<'a, T> {
+    inner: T,
+    tracker: Tracker<'a>,
+}
+
+impl<'a, T> Metered<'a, T> {
+    /// Wraps `inner` for a copy of `total` bytes.
+    pub fn new(inner: T, total: u64, progress: &'a mut dyn FnMut(&Progress)) -> Self {
+        Metered { inner, tracker: Tracker::new(total, progress) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for Metered<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.tracker.advance(n as u64);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Metered<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.tracker.advance(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{pattern, scratch_file};
+    use std::thread;
+
+    fn contents(file: &File) -> Vec<u8> {
+        let mut buf = vec![0; file.metadata().unwrap().len() as usize];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        buf
+    }
+
+    #[test]
+    fn pipes_are_copied_to_the_end() {
+        let data = pattern(3 * BUFFER_SIZE + 123, 8);
+        let (read, write) = nix::unistd::pipe().unwrap();
+        let writer = thread::spawn({
+            let data = data.clone();
+            move || File::from(write).write_all(&data)
+        });
+        let output = scratch_file("transfer-pipe");
+        let mut reports = Vec::new();
+        let copied = copy_file(&File::from(read), &output, &mut |p| reports.push(*p)).unwrap();
+        writer.join().unwrap().unwrap();
+        assert_eq!(copied, data.len() as u64);
+        assert_eq!(contents(&output), data);
+        assert!(reports.last().unwrap().is_done());
+    }
+
+    #[test]
+    fn files_that_report_no_length_are_copied_to_the_end() {
+        let output = scratch_file("transfer-proc");
+        let copied = copy_file(&File::open("/proc/self/mountinfo").unwrap(), &output, &mut |_| {}).unwrap();
+        assert!(copied > 0);
+        assert_eq!(contents(&output).len() as u64, copied);
+    }
+
+    #[test]
+    fn regular_files_are_copied_whole() {
+        let data = pattern(2 * BUFFER_SIZE + 5, 1);
+        let input = scratch_file("transfer-input");
+        input.write_all_at(&data, 0).unwrap();
+        let output = scratch_file("transfer-output");
+        assert_eq!(copy_file(&input, &output, &mut |_| {}).unwrap(), data.len() as u64);
+        assert_eq!(contents(&output), data);
+    }
+}
@@ -0,0 +1,374 @@
+//! Recursive copies between host directories and a mounted disk, keeping
+//! permissions, ownership, timestamps, links, xattrs and holes.
+
+use crate::error::{MizzleError, Result};
+use crate::ops::{self, Overwrite, Placed};
+use crate::transfer;
+use glob::{MatchOptions, Pattern};
+use nix::sys::stat::{mknod, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, symlink, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Which entries a tree copy takes. Patterns without a `/` match an entry's
+/// name at any depth, others its path relative to the top of the copy, with
+/// `*` staying within one directory and `**/` spanning any number.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CopyOptions {
+    /// Copy only files matching one of these; directories are always walked.
+    pub include: Vec<String>,
+    /// Skip files and whole directories matching any of these.
+    pub exclude: Vec<String>,
+    /// What to do with entries that already exist at the destination.
+    pub overwrite: Overwrite,
+}
+
+/// What a tree copy did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyReport {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    pub hardlinks: u64,
+    /// Fifos and device nodes.
+    pub special: u64,
+    /// Bytes of file data written, not counting holes.
+    pub bytes: u64,
+    /// Entries filtered out, plus sockets, which cannot be copied.
+    pub skipped: u64,
+}
+
+struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    fn new(options: &CopyOptions) -> io::Result<Filter> {
+        let compile = |patterns: &[String]| -> io::Result<Vec<Pattern>> {
+            patterns
+                .iter()
+                .map(|p| {
+                    Pattern::new(p).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", p, e)))
+                })
+                .collect()
+        };
+        Ok(Filter { include: compile(&options.include)?, exclude: compile(&options.exclude)? })
+    }
+
+    fn matches(pattern: &Pattern, relative: &Path) -> bool {
+        if pattern.as_str().contains('/') {
+            let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+            pattern.matches_path_with(relative, options)
+        } else {
+            relative.file_name().is_some_and(|name| pattern.matches(&name.to_string_lossy()))
+        }
+    }
+
+    fn excluded(&self, relative: &Path) -> bool {
+        self.exclude.iter().any(|p| Filter::matches(p, relative))
+    }
+
+    fn included(&self, relative: &Path) -> bool {
+        self.include.is_empty() || self.include.iter().any(|p| Filter::matches(p, relative))
+    }
+}
+
+struct TreeCopy {
+    filter: Filter,
+    overwrite: Overwrite,
+    /// First destination of each multiply-linked source inode.
+    links: HashMap<(u64, u64), PathBuf>,
+    report: CopyReport,
+}
+
+/// Copies `source` (a directory or a single entry) to `destination`,
+/// creating it if needed. Files already in the way are handled as
+/// `options.overwrite` says; directories are merged into.
+pub fn copy_tree(source: &Path, destination: &Path, options: &CopyOptions) -> Result<CopyReport> {
+    let filter = Filter::new(options).map_err(|e| MizzleError::io(source, e))?;
+    let mut copy =
+        TreeCopy { filter, overwrite: options.overwrite, links: HashMap::new(), report: CopyReport::default() };
+    copy.entry(source, destination, Path::new(""))?;
+    Ok(copy.report)
+}
+
+impl TreeCopy {
+    fn entry(&mut self, src: &Path, dst: &Path, relative: &Path) -> Result<()> {
+        let meta = fs::symlink_metadata(src).map_err(|e| MizzleError::io(src, e))?;
+        let top = relative.as_os_str().is_empty();
+        if !top && self.filter.excluded(relative) {
+            self.report.skipped += 1;
+            return Ok(());
+        }
+        if meta.is_dir() {
+            return self.dir(src, dst, relative, &meta);
+        }
+        if !top && !self.filter.included(relative) {
+            self.report.skipped += 1;
+            return Ok(());
+        }
+
+        if meta.file_type().is_socket() {
+            self.report.skipped += 1;
+            return Ok(());
+        }
+        let Some((dst, existed)) = self.claim(dst)? else {
+            return Ok(());
+        };
+        let dst = dst.as_path();
+        let copy_err = |source| MizzleError::Copy { from: src.to_path_buf(), to: dst.to_path_buf(), source };
+        // Regular files are renamed over what is there; everything else replaces it
+        if existed && !meta.is_file() {
+            remove_existing(dst).map_err(copy_err)?;
+        }
+
+        if meta.nlink() > 1 {
+            if let Some(first) = self.links.get(&(meta.dev(), meta.ino())) {
+                if existed && meta.is_file() {
+                    remove_existing(dst).map_err(copy_err)?;
+                }
+                fs::hard_link(first, dst).map_err(copy_err)?;
+                self.report.hardlinks += 1;
+                return Ok(());
+            }
+            self.links.insert((meta.dev(), meta.ino()), dst.to_path_buf());
+        }
+
+        let ft = meta.file_type();
+        if ft.is_symlink() {
+            let target = fs::read_link(src).map_err(copy_err)?;
+            symlink(&target, dst).map_err(copy_err)?;
+            self.report.symlinks += 1;
+        } else if ft.is_file() {
+            self.report.bytes += copy_file(src, dst, &meta, existed).map_err(copy_err)?;
+            self.report.files += 1;
+        } else {
+            let kind = if ft.is_fifo() {
+                SFlag::S_IFIFO
+            } else if ft.is_char_device() {
+                SFlag::S_IFCHR
+            } else {
+                SFlag::S_IFBLK
+            };
+            mknod(dst, kind, Mode::from_bits_truncate(meta.mode()), meta.rdev()).map_err(|e| copy_err(e.into()))?;
+            self.report.special += 1;
+        }
+        apply_metadata(src, dst, &meta).map_err(copy_err)
+    }
+
+    /// Where the entry for `dst` goes, and whether it replaces something
+    /// already there; `None` when it is skipped.
+    fn claim(&mut self, dst: &Path) -> Result<Option<(PathBuf, bool)>> {
+        match ops::place(dst, self.overwrite) {
+            Ok(Some((path, placed))) => Ok(Some((path, placed == Placed::Replaced))),
+            Ok(None) => {
+                self.report.skipped += 1;
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn dir(&mut self, src: &Path, dst: &Path, relative: &Path, meta: &Metadata) -> Result<()> {
+        let dst = match fs::symlink_metadata(dst) {
+            Ok(existing) if existing.is_dir() => dst.to_path_buf(),
+            _ => match self.claim(dst)? {
+                Some((dst, existed)) => {
+                    if existed {
+                        fs::remove_file(&dst).map_err(|e| MizzleError::io(&dst, e))?;
+                    }
+                    fs::create_dir(&dst).map_err(|e| MizzleError::io(&dst, e))?;
+                    dst
+                }
+                None => return Ok(()),
+            },
+        };
+        let dst = dst.as_path();
+        let copy_err = |source| MizzleError::Copy { from: src.to_path_buf(), to: dst.to_path_buf(), source };
+        // Keep the directory writable until its entries are in place
+        fs::set_permissions(dst, fs::Permissions::from_mode(0o700)).map_err(copy_err)?;
+
+        let mut entries = fs::read_dir(src)
+            .and_then(|entries| entries.map(|e| e.map(|e| e.file_name())).collect::<io::Result<Vec<_>>>())
+            .map_err(|e| MizzleError::io(src, e))?;
+        entries.sort();
+        for name in entries {
+            self.entry(&src.join(&name), &dst.join(&name), &relative.join(&name))?;
+        }
+
+        self.report.dirs += 1;
+        apply_metadata(src, dst, meta).map_err(copy_err)
+    }
+}
+
+fn remove_existing(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => Err(io::Error::from_raw_os_error(libc::EISDIR)),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Copies only the data regions of `src`, so holes stay holes, replacing
+/// `dst` atomically if `replace` is set. Returns the number of bytes written.
+fn copy_file(src: &Path, dst: &Path, meta: &Metadata, replace: bool) -> io::Result<u64> {
+    let input = File::open(src)?;
+    ops::write_atomically(dst, replace, |output| {
+        // Private until the source's permissions are applied
+        output.set_permissions(fs::Permissions::from_mode(0o600))?;
+        transfer::copy_sparse(&input, output, meta.len())
+    })
+}
+
+/// Ownership, xattrs, permissions and timestamps, in that order: chown clears
+/// setuid bits, and everything else would bump the timestamps.
+fn apply_metadata(src: &Path, dst: &Path, meta: &Metadata) -> io::Result<()> {
+    // Only root may give files away; like cp -p, carry on without it
+    match lchown(dst, Some(meta.uid()), Some(meta.gid())) {
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+        other => other?,
+    }
+    copy_xattrs(src, dst)?;
+    if !meta.file_type().is_symlink() {
+        fs::set_permissions(dst, fs::Permissions::from_mode(meta.mode() & 0o7777))?;
+    }
+    let atime = TimeSpec::new(meta.atime(), meta.atime_nsec());
+    let mtime = TimeSpec::new(meta.mtime(), meta.mtime_nsec());
+    utimensat(None, dst, &atime, &mtime, UtimensatFlags::NoFollowSymlink).map_err(io::Error::from)
+}
+
+fn cpath(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+/// Calls an xattr function that fills a buffer, growing it until it fits.
+fn read_sized(mut call: impl FnMut(*mut libc::c_void, usize) -> libc::ssize_t) -> io::Result<Vec<u8>> {
+    loop {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let n = call(buf.as_mut_ptr().cast(), buf.len());
+        match n {
+            n if n >= 0 => {
+                buf.truncate(n as usize);
+                return Ok(buf);
+            }
+            // Grew between the two calls
+            _ if io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE) => continue,
+            _ => return Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+fn unsupported(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ENOTSUP) | Some(libc::EPERM) | Some(libc::EACCES))
+}
+
+fn copy_xattrs(src: &Path, dst: &Path) -> io::Result<()> {
+    let (src_c, dst_c) = (cpath(src)?, cpath(dst)?);
+    // SAFETY: the path is NUL-terminated and the buffer is valid for `len` bytes
+    let names = match read_sized(|buf, len| unsafe { libc::llistxattr(src_c.as_ptr(), buf.cast(), len) }) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        other => other?,
+    };
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let name_c = CString::new(name).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        // SAFETY: as above
+        let value = read_sized(|buf, len| unsafe { libc::lgetxattr(src_c.as_ptr(), name_c.as_ptr(), buf, len) })?;
+        // SAFETY: both strings are NUL-terminated and `value` outlives the call
+        let ret = unsafe {
+            libc::lsetxattr(dst_c.as_ptr(), name_c.as_ptr(), value.as_ptr().cast(), value.len(), 0)
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            // Filesystems without xattrs, and namespaces we may not write, are skipped
+            if !unsupported(&e) {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("xattr {}: {}", OsStr::from_bytes(name).to_string_lossy(), e),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::ScratchDir;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> Filter {
+        let strings = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect();
+        let options = CopyOptions { include: strings(include), exclude: strings(exclude), ..CopyOptions::default() };
+        Filter::new(&options).unwrap()
+    }
+
+    #[test]
+    fn patterns_match_names_at_any_depth_and_paths_from_the_top() {
+        let cases: [(&str, &str, bool); 12] = [
+            ("*.rs", "main.rs", true),
+            ("*.rs", "src/deep/er/lib.rs", true),
+            ("*.rs", "main.rs.orig", false),
+            ("target", "target", true),
+            ("target", "sub/target", true),
+            ("src/*.rs", "src/lib.rs", true),
+            ("src/*.rs", "src/bin/tool.rs", false),
+            ("src/*.rs", "crate/src/lib.rs", false),
+            ("src/**/*.rs", "src/lib.rs", true),
+            ("src/**/*.rs", "src/bin/deep/tool.rs", true),
+            ("*/Cargo.toml", "member/Cargo.toml", true),
+            ("*/Cargo.toml", "a/member/Cargo.toml", false),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(filter(&[], &[pattern]).excluded(Path::new(path)), expected, "{} on {}", pattern, path);
+            assert_eq!(filter(&[pattern], &[]).included(Path::new(path)), expected, "{} on {}", pattern, path);
+        }
+        assert!(filter(&[], &[]).included(Path::new("anything/at/all")));
+        assert!(!filter(&[], &[]).excluded(Path::new("anything/at/all")));
+    }
+
+    #[test]
+    fn bad_patterns_are_refused() {
+        let options = CopyOptions { exclude: vec!["[".into()], ..CopyOptions::default() };
+        assert_eq!(Filter::new(&options).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn excluded_directories_are_pruned_and_includes_still_walk_directories() {
+        let dir = ScratchDir::new("tree-filter");
+        let (source, destination) = (dir.join("source"), dir.join("destination"));
+        for path in ["src/bin", "src/gen", "target/debug", "docs"] {
+            fs::create_dir_all(source.join(path)).unwrap();
+        }
+        let files = ["src/lib.rs", "src/bin/tool.rs", "src/gen/out.rs", "target/debug/build.rs", "docs/a.md", "top.rs"];
+        for path in files {
+            fs::write(source.join(path), path).unwrap();
+        }
+        let options = CopyOptions {
+            include: vec!["*.rs".into()],
+            exclude: vec!["target".into(), "src/gen/*".into()],
+            ..CopyOptions::default()
+        };
+        let report = copy_tree(&source, &destination, &options).unwrap();
+
+        for path in ["src/lib.rs", "src/bin/tool.rs", "top.rs"] {
+            assert_eq!(fs::read(destination.join(path)).unwrap(), path.as_bytes());
+        }
+        for path in ["src/gen/out.rs", "target", "docs/a.md"] {
+            assert!(!destination.join(path).exists(), "{} was copied", path);
+        }
+        // Directories are walked even though they match no include
+        assert!(destination.join("docs").is_dir() && destination.join("src/gen").is_dir());
+        assert_eq!((report.files, report.dirs, report.skipped), (3, 5, 3));
+    }
+}
@@ -0,0 +1,96 @@
+use std::io;
+
+/// Fills `buf` from the kernel's CSPRNG. Everything random in the crate,
+/// UUIDs and key material alike, comes from here.
+pub fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        // SAFETY: the pointer and length describe the unfilled tail of `buf`
+        let n = unsafe { libc::getrandom(buf[filled..].as_mut_ptr().cast(), buf.len() - filled, 0) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+            continue;
+        }
+        filled += n as usize;
+    }
+    Ok(())
+}
+
+/// A random (version 4) UUID.
+pub fn random() -> io::Result<[u8; 16]> {
+    let mut u = [0u8; 16];
+    random_bytes(&mut u)?;
+    u[6] = (u[6] & 0x0F) | 0x40;
+    u[8] = (u[8] & 0x3F) | 0x80;
+    Ok(u)
+}
+
+/// Parses the canonical `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` form.
+pub fn parse(s: &str) -> Option<[u8; 16]> {
+    let well_formed = s.len() == 36
+        && s.bytes().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        });
+    if !well_formed {
+        return None;
+    }
+    let hex: Vec<u8> = s.bytes().filter(|&b| b != b'-').collect();
+    let mut u = [0u8; 16];
+    for (byte, pair) in u.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(u)
+}
+
+/// Formats `u` in the canonical hyphenated form.
+pub fn format(u: [u8; 16]) -> String {
+    let hex: String = u.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CANONICAL: &str = "0123abcd-4567-89ef-aBcD-0123456789ef";
+
+    #[test]
+    fn parse_and_format_round_trip() {
+        let u = parse(CANONICAL).unwrap();
+        assert_eq!(u[..4], [0x01, 0x23, 0xab, 0xcd]);
+        assert_eq!(format(u), CANONICAL.to_ascii_lowercase());
+        let r = random().unwrap();
+        assert_eq!(parse(&format(r)), Some(r));
+    }
+
+    #[test]
+    fn random_uuids_are_version_4() {
+        let u = random().unwrap();
+        assert_eq!(u[6] >> 4, 4);
+        assert_eq!(u[8] >> 6, 0b10);
+        assert_ne!(random().unwrap(), u);
+    }
+
+    #[test]
+    fn rejects_malformed_uuids() {
+        for s in [
+            "",
+            "0123abcd4567-89ef-abcd-0123456789ef-",
+            "0123abcd-4567-89ef-abcd-0123456789e",
+            "0123abcd-4567-89ef-abcd-0123456789eff",
+            "0123abcd-4567-89ef-abcd-0123456789eg",
+            "0123abc--4567-89ef-abcd-0123456789ef",
+            "+123abcd-4567-89ef-abcd-0123456789ef",
+            "0123abcd-+567-89ef-abcd-0123456789ef",
+            "0123abcd-4567-89ef-abcd-0123456789é",
+            "0123abcd-4567-89ef-abcd-01234567é9",
+            "{0123abcd-4567-89ef-abcd-0123456789}",
+        ] {
+            assert_eq!(parse(s), None, "{:?} parsed", s);
+        }
+    }
+}
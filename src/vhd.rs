@@ -0,0 +1,354 @@
+//! VHD images as Virtual PC, Hyper-V and Azure use them: fixed ones, which
+//! are the disk followed by a footer, and dynamic ones, which only hold the
+//! blocks that were written.
+
+use crate::blockdev::BlockDevice;
+use crate::bytes::{be32, be64, put_be16, put_be32, put_be64};
+use crate::convert;
+use crate::transfer::Progress;
+use crate::uuid;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const COOKIE: &[u8; 8] = b"conectix";
+const DYNAMIC_COOKIE: &[u8; 8] = b"cxsparse";
+const FOOTER_SIZE: usize = 512;
+const DYNAMIC_HEADER_SIZE: usize = 1024;
+const SECTOR: u64 = 512;
+/// Largest disk a VHD can hold.
+pub const MAX_SIZE: u64 = 2040 << 30;
+const BLOCK_SIZE: u64 = 2 << 20;
+const UNUSED: u32 = 0xFFFF_FFFF;
+/// The Unix time of 2000-01-01, where VHD timestamps start.
+const VHD_EPOCH: u64 = 946_684_800;
+
+// Footer fields
+const F_FEATURES: usize = 8;
+const F_VERSION: usize = 12;
+const F_DATA_OFFSET: usize = 16;
+const F_TIMESTAMP: usize = 24;
+const F_CREATOR: usize = 28;
+const F_CREATOR_VERSION: usize = 32;
+const F_CREATOR_OS: usize = 36;
+const F_ORIGINAL_SIZE: usize = 40;
+const F_CURRENT_SIZE: usize = 48;
+const F_GEOMETRY: usize = 56;
+const F_TYPE: usize = 60;
+const F_CHECKSUM: usize = 64;
+const F_ID: usize = 68;
+
+// Dynamic header fields
+const D_DATA_OFFSET: usize = 8;
+const D_TABLE_OFFSET: usize = 16;
+const D_VERSION: usize = 24;
+const D_MAX_ENTRIES: usize = 28;
+const D_BLOCK_SIZE: usize = 32;
+const D_CHECKSUM: usize = 36;
+
+const TYPE_FIXED: u32 = 2;
+const TYPE_DYNAMIC: u32 = 3;
+const TYPE_DIFFERENCING: u32 = 4;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// The ones' complement of the byte sum, skipping the checksum field at
+/// `field`.
+fn checksum(data: &[u8], field: usize) -> u32 {
+    let sum = data
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(field..field + 4).contains(i))
+        .fold(0u32, |sum, (_, &b)| sum.wrapping_add(u32::from(b)));
+    !sum
+}
+
+/// Cylinders, heads and sectors per track for `size` bytes, as the VHD
+/// specification computes them.
+fn geometry(size: u64) -> (u16, u8, u8) {
+    let total = (size / SECTOR).min(65535 * 16 * 255);
+    let (mut sectors, mut heads, mut cylinder_heads);
+    if total >= 65535 * 16 * 63 {
+        sectors = 255;
+        heads = 16;
+        cylinder_heads = total / sectors;
+    } else {
+        sectors = 17;
+        cylinder_heads = total / sectors;
+        heads = cylinder_heads.div_ceil(1024);
+        if heads < 4 {
+            heads = 4;
+        }
+        if cylinder_heads >= heads * 1024 || heads > 16 {
+            sectors = 31;
+            heads = 16;
+            cylinder_heads = total / sectors;
+        }
+        if cylinder_heads >= heads * 1024 {
+            sectors = 63;
+            heads = 16;
+            cylinder_heads = total / sectors;
+        }
+    }
+    ((cylinder_heads / heads) as u16, heads as u8, sectors as u8)
+}
+
+fn footer(size: u64, disk_type: u32, data_offset: u64) -> io::Result<[u8; FOOTER_SIZE]> {
+    let mut footer = [0; FOOTER_SIZE];
+    footer[..8].copy_from_slice(COOKIE);
+    put_be32(&mut footer, F_FEATURES, 2);
+    put_be32(&mut footer, F_VERSION, 0x0001_0000);
+    put_be64(&mut footer, F_DATA_OFFSET, data_offset);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    put_be32(&mut footer, F_TIMESTAMP, now.saturating_sub(VHD_EPOCH) as u32);
+    // Readers take the size from the footer rather than the geometry only
+    // for images Hyper-V made, and the size here is exact
+    footer[F_CREATOR..F_CREATOR + 4].copy_from_slice(b"win ");
+    put_be32(&mut footer, F_CREATOR_VERSION, 0x000A_0000);
+    footer[F_CREATOR_OS..F_CREATOR_OS + 4].copy_from_slice(b"Wi2k");
+    put_be64(&mut footer, F_ORIGINAL_SIZE, size);
+    put_be64(&mut footer, F_CURRENT_SIZE, size);
+    let (cylinders, heads, sectors) = geometry(size);
+    put_be16(&mut footer, F_GEOMETRY, cylinders);
+    footer[F_GEOMETRY + 2] = heads;
+    footer[F_GEOMETRY + 3] = sectors;
+    put_be32(&mut footer, F_TYPE, disk_type);
+    footer[F_ID..F_ID + 16].copy_from_slice(&uuid::random()?);
+    let sum = checksum(&footer, F_CHECKSUM);
+    put_be32(&mut footer, F_CHECKSUM, sum);
+    Ok(footer)
+}
+
+fn check_size(size: u64) -> io::Result<()> {
+    if !size.is_multiple_of(SECTOR) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a VHD holds whole 512-byte sectors"));
+    }
+    if size > MAX_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a VHD holds at most 2040 GiB"));
+    }
+    Ok(())
+}
+
+/// Writes `source` to `out` as a fixed VHD. Runs of zeros are left as
+/// holes in `out`.
+pub fn write_fixed(source: &mut dyn BlockDevice, out: &File, progress: &mut dyn FnMut(&Progress)) -> io::Result<()> {
+    let size = source.len()?;
+    check_size(size)?;
+    convert::scan(source, progress, |offset, data| out.write_all_at(data, offset))?;
+    out.write_all_at(&footer(size, TYPE_FIXED, u64::MAX)?, size)
+}
+
+/// Writes `source` to `out` as a dynamic VHD, leaving out the blocks that
+/// are all zeros.
+pub fn write_dynamic(source: &mut dyn BlockDevice, out: &File, progress: &mut dyn FnMut(&Progress)) -> io::Result<()> {
+    let size = source.len()?;
+    check_size(size)?;
+    let blocks = size.div_ceil(BLOCK_SIZE);
+    let table_offset = (FOOTER_SIZE + DYNAMIC_HEADER_SIZE) as u64;
+    let table_len = (blocks * 4).next_multiple_of(SECTOR);
+    // Each block is a sector bitmap followed by the data
+    let bitmap_len = (BLOCK_SIZE / SECTOR / 8).next_multiple_of(SECTOR);
+    let mut bat = vec![UNUSED; blocks as usize];
+    let mut next = table_offset + table_len;
+
+    convert::scan(source, progress, |offset, data| {
+        let block = (offset / BLOCK_SIZE) as usize;
+        if bat[block] == UNUSED {
+            bat[block] = (next / SECTOR) as u32;
+            out.write_all_at(&vec![0xFF; bitmap_len as usize], next)?;
+            next += bitmap_len + BLOCK_SIZE;
+        }
+        let start = u64::from(bat[block]) * SECTOR + bitmap_len;
+        out.write_all_at(data, start + offset % BLOCK_SIZE)
+    })?;
+
+    let footer = footer(size, TYPE_DYNAMIC, FOOTER_SIZE as u64)?;
+    let mut header = [0; DYNAMIC_HEADER_SIZE];
+    header[..8].copy_from_slice(DYNAMIC_COOKIE);
+    put_be64(&mut header, D_DATA_OFFSET, u64::MAX);
+    put_be64(&mut header, D_TABLE_OFFSET, table_offset);
+    put_be32(&mut header, D_VERSION, 0x0001_0000);
+    put_be32(&mut header, D_MAX_ENTRIES, blocks as u32);
+    put_be32(&mut header, D_BLOCK_SIZE, BLOCK_SIZE as u32);
+    let sum = checksum(&header, D_CHECKSUM);
+    put_be32(&mut header, D_CHECKSUM, sum);
+    let mut table = vec![0xFF; table_len as usize];
+    for (i, entry) in bat.iter().enumerate() {
+        put_be32(&mut table, i * 4, *entry);
+    }
+    out.write_all_at(&footer, 0)?;
+    out.write_all_at(&header, FOOTER_SIZE as u64)?;
+    out.write_all_at(&table, table_offset)?;
+    out.write_all_at(&footer, next)
+}
+
+/// Whether `path` ends in a VHD footer.
+pub fn is_vhd(path: &Path) -> io::Result<bool> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < FOOTER_SIZE as u64 {
+        return Ok(false);
+    }
+    let mut cookie = [0; 8];
+    file.read_exact_at(&mut cookie, len - FOOTER_SIZE as u64)?;
+    Ok(&cookie == COOKIE)
+}
+
+/// Reads and checks the footer at `offset`.
+fn read_footer(file: &File, offset: u64) -> io::Result<[u8; FOOTER_SIZE]> {
+    let mut footer = [0; FOOTER_SIZE];
+    file.read_exact_at(&mut footer, offset)?;
+    if &footer[..8] != COOKIE {
+        return Err(invalid(format!("no VHD footer at offset {}", offset)));
+    }
+    if be32(&footer, F_CHECKSUM) != checksum(&footer, F_CHECKSUM) {
+        return Err(invalid(format!("the VHD footer at offset {} has a bad checksum", offset)));
+    }
+    Ok(footer)
+}
+
+/// The blocks of a dynamic VHD.
+struct Dynamic {
+    bat: Vec<u32>,
+    block_size: u64,
+    bitmap_len: u64,
+    /// The sector bitmap last read, by block.
+    bitmap: Option<(usize, Vec<u8>)>,
+}
+
+/// A fixed or dynamic VHD, checked and opened for reading.
+pub struct VhdImage {
+    file: File,
+    size: u64,
+    dynamic: Option<Dynamic>,
+}
+
+impl VhdImage {
+    /// Opens the VHD at `path`, checking its footers, and for a dynamic
+    /// image its header and block table.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if len < FOOTER_SIZE as u64 {
+            return Err(invalid("too short for a VHD"));
+        }
+        let footer = read_footer(&file, len - FOOTER_SIZE as u64)?;
+        let size = be64(&footer, F_CURRENT_SIZE);
+        if !size.is_multiple_of(SECTOR) {
+            return Err(invalid(format!("VHD size {} is not whole sectors", size)));
+        }
+        let dynamic = match be32(&footer, F_TYPE) {
+            TYPE_FIXED if len - (FOOTER_SIZE as u64) < size => return Err(invalid("the fixed VHD is truncated")),
+            TYPE_FIXED => None,
+            TYPE_DYNAMIC => Some(Dynamic::open(&file, &footer, len, size)?),
+            TYPE_DIFFERENCING => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "differencing VHDs; merge it into its parent"))
+            }
+            other => return Err(invalid(format!("unknown VHD type {}", other))),
+        };
+        Ok(VhdImage { file, size, dynamic })
+    }
+
+    /// Whether the image is dynamic rather than fixed.
+    pub fn is_dynamic(&self) -> bool {
+        self.dynamic.is_some()
+    }
+}
+
+impl Dynamic {
+    fn open(file: &File, footer: &[u8], len: u64, size: u64) -> io::Result<Dynamic> {
+        if read_footer(file, 0)? != footer {
+            return Err(invalid("the two copies of the VHD footer differ"));
+        }
+        let offset = be64(footer, F_DATA_OFFSET);
+        let mut header = [0; DYNAMIC_HEADER_SIZE];
+        file.read_exact_at(&mut header, offset)?;
+        if &header[..8] != DYNAMIC_COOKIE {
+            return Err(invalid("no dynamic VHD header"));
+        }
+        if be32(&header, D_CHECKSUM) != checksum(&header, D_CHECKSUM) {
+            return Err(invalid("the dynamic VHD header has a bad checksum"));
+        }
+        let block_size = u64::from(be32(&header, D_BLOCK_SIZE));
+        if !block_size.is_power_of_two() || block_size < SECTOR {
+            return Err(invalid(format!("bad VHD block size {}", block_size)));
+        }
+        let entries = be32(&header, D_MAX_ENTRIES) as usize;
+        if (entries as u64) < size.div_ceil(block_size) {
+            return Err(invalid("the VHD block table is too small for the disk"));
+        }
+        let table_offset = be64(&header, D_TABLE_OFFSET);
+        if entries as u64 * 4 > len.saturating_sub(table_offset) {
+            return Err(invalid("the VHD block table runs past the end of the file"));
+        }
+        let mut raw = vec![0; entries * 4];
+        file.read_exact_at(&mut raw, table_offset)?;
+        let bat: Vec<u32> = (0..entries).map(|i| be32(&raw, i * 4)).collect();
+        let bitmap_len = (block_size / SECTOR / 8).next_multiple_of(SECTOR);
+        for (block, &sector) in bat.iter().enumerate() {
+            if sector != UNUSED && u64::from(sector) * SECTOR + bitmap_len + block_size > len - FOOTER_SIZE as u64 {
+                return Err(invalid(format!("VHD block {} lies outside the file", block)));
+            }
+        }
+        Ok(Dynamic { bat, block_size, bitmap_len, bitmap: None })
+    }
+}
+
+impl BlockDevice for VhdImage {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset.checked_add(buf.len() as u64).is_none_or(|end| end > self.size) {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let Some(dynamic) = &mut self.dynamic else {
+            return self.file.read_exact_at(buf, offset);
+        };
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let (block, within) = ((pos / dynamic.block_size) as usize, pos % dynamic.block_size);
+            let len = ((dynamic.block_size - within) as usize).min(buf.len() - done);
+            let out = &mut buf[done..done + len];
+            let sector = dynamic.bat[block];
+            if sector == UNUSED {
+                out.fill(0);
+            } else {
+                let start = u64::from(sector) * SECTOR;
+                if dynamic.bitmap.as_ref().is_none_or(|(cached, _)| *cached != block) {
+                    let mut bitmap = vec![0; dynamic.bitmap_len as usize];
+                    self.file.read_exact_at(&mut bitmap, start)?;
+                    dynamic.bitmap = Some((block, bitmap));
+                }
+                self.file.read_exact_at(out, start + dynamic.bitmap_len + within)?;
+                // Sectors not marked in the bitmap read as zeros
+                let bitmap = &dynamic.bitmap.as_ref().expect("bitmap was just read").1;
+                let mut i = 0;
+                while i < len {
+                    let s = ((within + i as u64) / SECTOR) as usize;
+                    let end = ((s as u64 + 1) * SECTOR - within).min(len as u64) as usize;
+                    if bitmap[s / 8] & (0x80 >> (s % 8)) == 0 {
+                        out[i..end].fill(0);
+                    }
+                    i = end;
+                }
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "VHD images are only read"))
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
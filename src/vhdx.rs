@@ -0,0 +1,397 @@
+//! VHDX images, Hyper-V's successor to VHD: written from a disk with only
+//! the blocks that hold data, and checked and read back as a block device.
+
+use crate::blockdev::BlockDevice;
+use crate::bytes::{le16, le32, le64, put_le16, put_le32, put_le64};
+use crate::checksum::crc32c_raw;
+use crate::convert;
+use crate::transfer::Progress;
+use crate::uuid;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+const SIGNATURE: &[u8; 8] = b"vhdxfile";
+const HEADER_SIGNATURE: &[u8; 4] = b"head";
+const REGION_SIGNATURE: &[u8; 4] = b"regi";
+const METADATA_SIGNATURE: &[u8; 8] = b"metadata";
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const HEADER_OFFSETS: [u64; 2] = [64 * KIB, 128 * KIB];
+const HEADER_SIZE: usize = 4096;
+const REGION_OFFSETS: [u64; 2] = [192 * KIB, 256 * KIB];
+const REGION_SIZE: usize = 64 * 1024;
+const LOG_OFFSET: u64 = MIB;
+const LOG_LENGTH: u64 = MIB;
+const METADATA_OFFSET: u64 = 2 * MIB;
+const METADATA_LENGTH: u64 = MIB;
+const BAT_OFFSET: u64 = 3 * MIB;
+/// Hyper-V uses 32 MiB; smaller blocks keep mostly empty disks small.
+const BLOCK_SIZE: u64 = 8 * MIB;
+const LOGICAL_SECTOR: u64 = 512;
+const PHYSICAL_SECTOR: u32 = 4096;
+/// Largest disk a VHDX can hold.
+pub const MAX_SIZE: u64 = 64 << 40;
+
+// Header fields
+const H_CHECKSUM: usize = 4;
+const H_SEQUENCE: usize = 8;
+const H_FILE_WRITE_GUID: usize = 16;
+const H_DATA_WRITE_GUID: usize = 32;
+const H_LOG_GUID: usize = 48;
+const H_VERSION: usize = 66;
+const H_LOG_LENGTH: usize = 68;
+const H_LOG_OFFSET: usize = 72;
+
+// Metadata entry flags
+const IS_VIRTUAL_DISK: u32 = 1 << 1;
+const IS_REQUIRED: u32 = 1 << 2;
+const HAS_PARENT: u32 = 1 << 1;
+
+// Block states in the BAT
+const NOT_PRESENT: u64 = 0;
+const UNDEFINED: u64 = 1;
+const ZERO: u64 = 2;
+const UNMAPPED: u64 = 3;
+const FULLY_PRESENT: u64 = 6;
+const STATE_MASK: u64 = 7;
+
+/// A GUID in the mixed-endian layout Windows stores them in.
+const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> [u8; 16] {
+    let (a, b, c) = (a.to_le_bytes(), b.to_le_bytes(), c.to_le_bytes());
+    [a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]]
+}
+
+const BAT_REGION: [u8; 16] = guid(0x2DC2_7766, 0xF623, 0x4200, [0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08]);
+const METADATA_REGION: [u8; 16] = guid(0x8B7C_A206, 0x4790, 0x4B9A, [0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E]);
+const FILE_PARAMETERS: [u8; 16] = guid(0xCAA1_6737, 0xFA36, 0x4D43, [0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B]);
+const VIRTUAL_DISK_SIZE: [u8; 16] = guid(0x2FA5_4224, 0xCD1B, 0x4876, [0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8]);
+const VIRTUAL_DISK_ID: [u8; 16] = guid(0xBECA_12AB, 0xB2E6, 0x4523, [0x93, 0xEF, 0xC3, 0x09, 0xE0, 0x00, 0xC7, 0x46]);
+const LOGICAL_SECTOR_SIZE: [u8; 16] =
+    guid(0x8141_BF1D, 0xA96F, 0x4709, [0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F]);
+const PHYSICAL_SECTOR_SIZE: [u8; 16] =
+    guid(0xCDA3_48C7, 0x445D, 0x4471, [0x9C, 0xC9, 0xE9, 0x88, 0x52, 0x51, 0xC5, 0x56]);
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn unsupported(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, message.into())
+}
+
+/// CRC-32C of `data` with the checksum field at `field` taken as zero.
+fn checksum(data: &[u8], field: usize) -> u32 {
+    let crc = crc32c_raw(!0, &data[..field]);
+    let crc = crc32c_raw(crc, &[0; 4]);
+    !crc32c_raw(crc, &data[field + 4..])
+}
+
+/// Payload blocks per sector bitmap block, which the BAT interleaves.
+fn chunk_ratio(block_size: u64, logical_sector: u64) -> u64 {
+    (1 << 23) * logical_sector / block_size
+}
+
+/// Where the entry for payload block `block` is in the BAT.
+fn bat_index(block: u64, ratio: u64) -> usize {
+    (block + block / ratio) as usize
+}
+
+fn bat_entries(size: u64, block_size: u64, ratio: u64) -> u64 {
+    let blocks = size.div_ceil(block_size);
+    blocks + blocks.saturating_sub(1) / ratio
+}
+
+/// Writes `source` to `out` as a dynamic VHDX, leaving out the blocks that
+/// are all zeros.
+pub fn write(source: &mut dyn BlockDevice, out: &File, progress: &mut dyn FnMut(&Progress)) -> io::Result<()> {
+    let size = source.len()?;
+    if !size.is_multiple_of(LOGICAL_SECTOR) || size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a VHDX holds whole 512-byte sectors"));
+    }
+    if size > MAX_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a VHDX holds at most 64 TiB"));
+    }
+    let ratio = chunk_ratio(BLOCK_SIZE, LOGICAL_SECTOR);
+    let entries = bat_entries(size, BLOCK_SIZE, ratio);
+    let bat_length = (entries * 8).next_multiple_of(MIB);
+    let mut bat = vec![0u64; entries as usize];
+    let mut next = BAT_OFFSET + bat_length;
+
+    convert::scan(source, progress, |offset, data| {
+        let index = bat_index(offset / BLOCK_SIZE, ratio);
+        if bat[index] == NOT_PRESENT {
+            bat[index] = next | FULLY_PRESENT;
+            next += BLOCK_SIZE;
+        }
+        out.write_all_at(data, (bat[index] & !(MIB - 1)) + offset % BLOCK_SIZE)
+    })?;
+    // Blocks are only partly written where the data is; the rest stays a hole
+    out.set_len(next)?;
+
+    let mut identifier = vec![0; 64 * 1024];
+    identifier[..8].copy_from_slice(SIGNATURE);
+    for (i, unit) in "mizzlestore".encode_utf16().enumerate() {
+        put_le16(&mut identifier, 8 + i * 2, unit);
+    }
+    out.write_all_at(&identifier, 0)?;
+
+    let (file_write, data_write) = (uuid::random()?, uuid::random()?);
+    for (sequence, offset) in HEADER_OFFSETS.into_iter().enumerate() {
+        let mut header = vec![0; HEADER_SIZE];
+        header[..4].copy_from_slice(HEADER_SIGNATURE);
+        put_le64(&mut header, H_SEQUENCE, sequence as u64);
+        header[H_FILE_WRITE_GUID..H_FILE_WRITE_GUID + 16].copy_from_slice(&file_write);
+        header[H_DATA_WRITE_GUID..H_DATA_WRITE_GUID + 16].copy_from_slice(&data_write);
+        put_le16(&mut header, H_VERSION, 1);
+        put_le32(&mut header, H_LOG_LENGTH, LOG_LENGTH as u32);
+        put_le64(&mut header, H_LOG_OFFSET, LOG_OFFSET);
+        let sum = checksum(&header, H_CHECKSUM);
+        put_le32(&mut header, H_CHECKSUM, sum);
+        out.write_all_at(&header, offset)?;
+    }
+
+    let mut regions = vec![0; REGION_SIZE];
+    regions[..4].copy_from_slice(REGION_SIGNATURE);
+    put_le32(&mut regions, 8, 2);
+    let regions_at = [(BAT_REGION, BAT_OFFSET, bat_length), (METADATA_REGION, METADATA_OFFSET, METADATA_LENGTH)];
+    for (i, (id, offset, length)) in regions_at.into_iter().enumerate() {
+        let entry = 16 + i * 32;
+        regions[entry..entry + 16].copy_from_slice(&id);
+        put_le64(&mut regions, entry + 16, offset);
+        put_le32(&mut regions, entry + 24, length as u32);
+        put_le32(&mut regions, entry + 28, 1);
+    }
+    let sum = checksum(&regions, 4);
+    put_le32(&mut regions, 4, sum);
+    for offset in REGION_OFFSETS {
+        out.write_all_at(&regions, offset)?;
+    }
+
+    let mut metadata = vec![0; 64 * 1024 + 64];
+    metadata[..8].copy_from_slice(METADATA_SIGNATURE);
+    let mut parameters = [0; 8];
+    put_le32(&mut parameters, 0, BLOCK_SIZE as u32);
+    let items: [([u8; 16], u32, Vec<u8>); 5] = [
+        (FILE_PARAMETERS, IS_REQUIRED, parameters.to_vec()),
+        (VIRTUAL_DISK_SIZE, IS_VIRTUAL_DISK | IS_REQUIRED, size.to_le_bytes().to_vec()),
+        (VIRTUAL_DISK_ID, IS_VIRTUAL_DISK | IS_REQUIRED, uuid::random()?.to_vec()),
+        (LOGICAL_SECTOR_SIZE, IS_VIRTUAL_DISK | IS_REQUIRED, (LOGICAL_SECTOR as u32).to_le_bytes().to_vec()),
+        (PHYSICAL_SECTOR_SIZE, IS_VIRTUAL_DISK | IS_REQUIRED, PHYSICAL_SECTOR.to_le_bytes().to_vec()),
+    ];
+    put_le16(&mut metadata, 10, items.len() as u16);
+    let mut data_offset = 64 * 1024;
+    for (i, (id, flags, data)) in items.iter().enumerate() {
+        let entry = 32 + i * 32;
+        metadata[entry..entry + 16].copy_from_slice(id);
+        put_le32(&mut metadata, entry + 16, data_offset as u32);
+        put_le32(&mut metadata, entry + 20, data.len() as u32);
+        put_le32(&mut metadata, entry + 24, *flags);
+        metadata[data_offset..data_offset + data.len()].copy_from_slice(data);
+        data_offset += data.len();
+    }
+    out.write_all_at(&metadata, METADATA_OFFSET)?;
+
+    let mut table = vec![0; (entries * 8) as usize];
+    for (i, entry) in bat.iter().enumerate() {
+        put_le64(&mut table, i * 8, *entry);
+    }
+    out.write_all_at(&table, BAT_OFFSET)
+}
+
+/// Whether `path` starts with the VHDX file signature.
+pub fn is_vhdx(path: &Path) -> io::Result<bool> {
+    let mut signature = [0; 8];
+    match File::open(path)?.read_exact_at(&mut signature, 0) {
+        Ok(()) => Ok(&signature == SIGNATURE),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The current header: the valid one of the two with the higher sequence
+/// number.
+fn read_header(file: &File) -> io::Result<Vec<u8>> {
+    let mut current: Option<Vec<u8>> = None;
+    for offset in HEADER_OFFSETS {
+        let mut header = vec![0; HEADER_SIZE];
+        file.read_exact_at(&mut header, offset)?;
+        let valid = &header[..4] == HEADER_SIGNATURE && le32(&header, H_CHECKSUM) == checksum(&header, H_CHECKSUM);
+        if valid && current.as_ref().is_none_or(|c| le64(&header, H_SEQUENCE) > le64(c, H_SEQUENCE)) {
+            current = Some(header);
+        }
+    }
+    current.ok_or_else(|| invalid("neither VHDX header is valid"))
+}
+
+/// The BAT and metadata regions, as (offset, length), from the first valid
+/// region table.
+fn read_regions(file: &File) -> io::Result<((u64, u64), (u64, u64))> {
+    for offset in REGION_OFFSETS {
+        let mut table = vec![0; REGION_SIZE];
+        file.read_exact_at(&mut table, offset)?;
+        if &table[..4] != REGION_SIGNATURE || le32(&table, 4) != checksum(&table, 4) {
+            continue;
+        }
+        let count = le32(&table, 8) as usize;
+        if count > (REGION_SIZE - 16) / 32 {
+            return Err(invalid("too many VHDX regions"));
+        }
+        let (mut bat, mut metadata) = (None, None);
+        for i in 0..count {
+            let entry = &table[16 + i * 32..16 + (i + 1) * 32];
+            let region = (le64(entry, 16), u64::from(le32(entry, 24)));
+            match entry[..16].try_into().expect("16 bytes") {
+                BAT_REGION => bat = Some(region),
+                METADATA_REGION => metadata = Some(region),
+                _ if le32(entry, 28) & 1 != 0 => return Err(unsupported("a required VHDX region we do not know")),
+                _ => {}
+            }
+        }
+        return match (bat, metadata) {
+            (Some(bat), Some(metadata)) => Ok((bat, metadata)),
+            _ => Err(invalid("the VHDX region table lacks the BAT or the metadata")),
+        };
+    }
+    Err(invalid("neither VHDX region table is valid"))
+}
+
+/// The disk's metadata items that reading it needs.
+struct Metadata {
+    block_size: u64,
+    size: u64,
+    logical_sector: u64,
+}
+
+fn read_metadata(file: &File, (offset, length): (u64, u64)) -> io::Result<Metadata> {
+    if !(64 * KIB..=METADATA_LENGTH * 16).contains(&length) {
+        return Err(invalid(format!("bad VHDX metadata region length {}", length)));
+    }
+    let mut region = vec![0; length as usize];
+    file.read_exact_at(&mut region, offset)?;
+    if &region[..8] != METADATA_SIGNATURE {
+        return Err(invalid("no VHDX metadata table"));
+    }
+    let count = le16(&region, 10) as usize;
+    if count > 2047 {
+        return Err(invalid("too many VHDX metadata entries"));
+    }
+    let (mut parameters, mut size, mut logical_sector) = (None, None, None);
+    for i in 0..count {
+        let entry = &region[32 + i * 32..32 + (i + 1) * 32];
+        let (start, len) = (le32(entry, 16) as usize, le32(entry, 20) as usize);
+        let Some(data) = region.get(start..start + len) else {
+            return Err(invalid("a VHDX metadata item lies outside its region"));
+        };
+        match entry[..16].try_into().expect("16 bytes") {
+            FILE_PARAMETERS if len >= 8 => parameters = Some((u64::from(le32(data, 0)), le32(data, 4))),
+            VIRTUAL_DISK_SIZE if len >= 8 => size = Some(le64(data, 0)),
+            LOGICAL_SECTOR_SIZE if len >= 4 => logical_sector = Some(u64::from(le32(data, 0))),
+            VIRTUAL_DISK_ID | PHYSICAL_SECTOR_SIZE => {}
+            _ if le32(entry, 24) & IS_REQUIRED != 0 => {
+                return Err(unsupported("a required VHDX metadata item we do not know"))
+            }
+            _ => {}
+        }
+    }
+    let (Some((block_size, flags)), Some(size), Some(logical_sector)) = (parameters, size, logical_sector) else {
+        return Err(invalid("the VHDX metadata lacks the block size, disk size or sector size"));
+    };
+    if flags & HAS_PARENT != 0 {
+        return Err(unsupported("differencing VHDX images; merge it into its parent"));
+    }
+    if !block_size.is_power_of_two() || !(MIB..=256 * MIB).contains(&block_size) {
+        return Err(invalid(format!("bad VHDX block size {}", block_size)));
+    }
+    if logical_sector != 512 && logical_sector != 4096 {
+        return Err(invalid(format!("bad VHDX sector size {}", logical_sector)));
+    }
+    if !size.is_multiple_of(logical_sector) || size > MAX_SIZE {
+        return Err(invalid(format!("bad VHDX disk size {}", size)));
+    }
+    Ok(Metadata { block_size, size, logical_sector })
+}
+
+/// A VHDX image, checked and opened for reading.
+pub struct VhdxImage {
+    file: File,
+    size: u64,
+    block_size: u64,
+    ratio: u64,
+    bat: Vec<u64>,
+}
+
+impl VhdxImage {
+    /// Opens the VHDX at `path`, checking the checksums of its headers and
+    /// region tables and that its blocks lie within the file.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        if !is_vhdx(path)? {
+            return Err(invalid("not a VHDX image"));
+        }
+        let header = read_header(&file)?;
+        if le16(&header, H_VERSION) != 1 {
+            return Err(unsupported(format!("VHDX version {}", le16(&header, H_VERSION))));
+        }
+        if header[H_LOG_GUID..H_LOG_GUID + 16].iter().any(|&b| b != 0) {
+            return Err(unsupported("a VHDX with a log to replay; open it in Hyper-V once to settle it"));
+        }
+        let (bat_region, metadata_region) = read_regions(&file)?;
+        let metadata = read_metadata(&file, metadata_region)?;
+        let ratio = chunk_ratio(metadata.block_size, metadata.logical_sector);
+        let entries = bat_entries(metadata.size, metadata.block_size, ratio);
+        if entries * 8 > bat_region.1 {
+            return Err(invalid("the VHDX block table is too small for the disk"));
+        }
+        let mut raw = vec![0; (entries * 8) as usize];
+        file.read_exact_at(&mut raw, bat_region.0)?;
+        let bat: Vec<u64> = (0..entries as usize).map(|i| le64(&raw, i * 8)).collect();
+        let len = file.metadata()?.len();
+        for block in 0..metadata.size.div_ceil(metadata.block_size) {
+            let entry = bat[bat_index(block, ratio)];
+            match entry & STATE_MASK {
+                NOT_PRESENT | UNDEFINED | ZERO | UNMAPPED => {}
+                FULLY_PRESENT if (entry & !(MIB - 1)) + metadata.block_size <= len => {}
+                FULLY_PRESENT => return Err(invalid(format!("VHDX block {} lies outside the file", block))),
+                state => return Err(invalid(format!("VHDX block {} is in state {}", block, state))),
+            }
+        }
+        Ok(VhdxImage { file, size: metadata.size, block_size: metadata.block_size, ratio, bat })
+    }
+}
+
+impl BlockDevice for VhdxImage {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset.checked_add(buf.len() as u64).is_none_or(|end| end > self.size) {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let within = pos % self.block_size;
+            let len = ((self.block_size - within) as usize).min(buf.len() - done);
+            let entry = self.bat[bat_index(pos / self.block_size, self.ratio)];
+            if entry & STATE_MASK == FULLY_PRESENT {
+                self.file.read_exact_at(&mut buf[done..done + len], (entry & !(MIB - 1)) + within)?;
+            } else {
+                buf[done..done + len].fill(0);
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "VHDX images are only read"))
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
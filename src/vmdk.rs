@@ -0,0 +1,430 @@
+//! VMDK sparse extents as VMware products use them: monolithic sparse
+//! images are written from a disk, and those and the compressed
+//! stream-optimized ones found in OVAs are checked and read back.
+
+use crate::blockdev::BlockDevice;
+use crate::bytes::{le32, le64, put_le16, put_le32, put_le64};
+use crate::convert;
+use crate::transfer::Progress;
+use crate::uuid;
+use flate2::read::ZlibDecoder;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"KDMV";
+const SECTOR: u64 = 512;
+/// Grain size in sectors: 64 KiB, as VMware writes them.
+const GRAIN_SECTORS: u64 = 128;
+const GTES_PER_GT: u64 = 512;
+const DESCRIPTOR_OFFSET: u64 = 1;
+const DESCRIPTOR_SECTORS: u64 = 20;
+/// The grain directory is in the footer at the end of the stream.
+const GD_AT_END: u64 = u64::MAX;
+
+// Header fields
+const H_VERSION: usize = 4;
+const H_FLAGS: usize = 8;
+const H_CAPACITY: usize = 12;
+const H_GRAIN_SIZE: usize = 20;
+const H_DESCRIPTOR_OFFSET: usize = 28;
+const H_DESCRIPTOR_SIZE: usize = 36;
+const H_GTES_PER_GT: usize = 44;
+const H_RGD_OFFSET: usize = 48;
+const H_GD_OFFSET: usize = 56;
+const H_OVERHEAD: usize = 64;
+const H_UNCLEAN: usize = 72;
+const H_NEWLINE_TEST: usize = 73;
+const H_COMPRESSION: usize = 77;
+
+// Flags
+const VALID_NEWLINE_TEST: u32 = 1 << 0;
+const REDUNDANT_GT: u32 = 1 << 1;
+const ZEROED_GTE: u32 = 1 << 2;
+const COMPRESSED: u32 = 1 << 16;
+const MARKERS: u32 = 1 << 17;
+const COMPRESS_DEFLATE: u16 = 1;
+
+/// Bytes a text-mode transfer would mangle, which the header carries so
+/// that such damage can be detected.
+const NEWLINE_TEST: &[u8; 4] = b"\n \r\n";
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn unsupported(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, message.into())
+}
+
+/// The descriptor embedded in a monolithic sparse image called `name`.
+fn descriptor(sectors: u64, name: &str) -> io::Result<String> {
+    let cid = u32::from_le_bytes(uuid::random()?[..4].try_into().expect("4 bytes"));
+    // The geometry an IDE adapter reports; it only matters to old guests
+    let cylinders = (sectors / (16 * 63)).min(16383);
+    Ok(format!(
+        "# Disk DescriptorFile\n\
+         version=1\n\
+         CID={:08x}\n\
+         parentCID=ffffffff\n\
+         createType=\"monolithicSparse\"\n\
+         \n\
+         # Extent description\n\
+         RW {} SPARSE \"{}\"\n\
+         \n\
+         # The Disk Data Base\n\
+         #DDB\n\
+         \n\
+         ddb.virtualHWVersion = \"4\"\n\
+         ddb.geometry.cylinders = \"{}\"\n\
+         ddb.geometry.heads = \"16\"\n\
+         ddb.geometry.sectors = \"63\"\n\
+         ddb.adapterType = \"ide\"\n",
+        cid,
+        sectors,
+        name.replace('"', ""),
+        cylinders
+    ))
+}
+
+/// Writes `source` to `out` as a monolithic sparse VMDK whose descriptor
+/// names the file `name`, leaving out the grains that are all zeros.
+pub fn write(
+    source: &mut dyn BlockDevice,
+    out: &File,
+    name: &str,
+    progress: &mut dyn FnMut(&Progress),
+) -> io::Result<()> {
+    let size = source.len()?;
+    if !size.is_multiple_of(SECTOR) || size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a VMDK holds whole 512-byte sectors"));
+    }
+    let capacity = size / SECTOR;
+    let tables = capacity.div_ceil(GTES_PER_GT * GRAIN_SECTORS);
+    let gd_sectors = (tables * 4).div_ceil(SECTOR);
+    let gt_sectors = GTES_PER_GT * 4 / SECTOR;
+    // Redundant directory and tables, then the primary ones, then grains
+    let rgd = DESCRIPTOR_OFFSET + DESCRIPTOR_SECTORS;
+    let gd = rgd + gd_sectors + tables * gt_sectors;
+    let overhead = (gd + gd_sectors + tables * gt_sectors).next_multiple_of(GRAIN_SECTORS);
+    let mut grains = vec![0u32; (tables * GTES_PER_GT) as usize];
+    let mut next = overhead;
+
+    convert::scan(source, progress, |offset, data| {
+        let grain = (offset / (GRAIN_SECTORS * SECTOR)) as usize;
+        if grains[grain] == 0 {
+            grains[grain] = u32::try_from(next).map_err(|_| invalid("the disk is too large for a VMDK"))?;
+            next += GRAIN_SECTORS;
+        }
+        out.write_all_at(data, u64::from(grains[grain]) * SECTOR + offset % (GRAIN_SECTORS * SECTOR))
+    })?;
+    out.set_len(next * SECTOR)?;
+
+    let mut header = [0; SECTOR as usize];
+    header[..4].copy_from_slice(MAGIC);
+    put_le32(&mut header, H_VERSION, 1);
+    put_le32(&mut header, H_FLAGS, VALID_NEWLINE_TEST | REDUNDANT_GT);
+    put_le64(&mut header, H_CAPACITY, capacity);
+    put_le64(&mut header, H_GRAIN_SIZE, GRAIN_SECTORS);
+    put_le64(&mut header, H_DESCRIPTOR_OFFSET, DESCRIPTOR_OFFSET);
+    put_le64(&mut header, H_DESCRIPTOR_SIZE, DESCRIPTOR_SECTORS);
+    put_le32(&mut header, H_GTES_PER_GT, GTES_PER_GT as u32);
+    put_le64(&mut header, H_RGD_OFFSET, rgd);
+    put_le64(&mut header, H_GD_OFFSET, gd);
+    put_le64(&mut header, H_OVERHEAD, overhead);
+    header[H_NEWLINE_TEST..H_NEWLINE_TEST + 4].copy_from_slice(NEWLINE_TEST);
+    put_le16(&mut header, H_COMPRESSION, 0);
+    out.write_all_at(&header, 0)?;
+    out.write_all_at(descriptor(capacity, name)?.as_bytes(), DESCRIPTOR_OFFSET * SECTOR)?;
+
+    let mut table = vec![0; (tables * GTES_PER_GT * 4) as usize];
+    for (i, grain) in grains.iter().enumerate() {
+        put_le32(&mut table, i * 4, *grain);
+    }
+    for directory in [rgd, gd] {
+        let first_table = directory + gd_sectors;
+        let mut entries = vec![0; (gd_sectors * SECTOR) as usize];
+        for t in 0..tables {
+            put_le32(&mut entries, (t * 4) as usize, (first_table + t * gt_sectors) as u32);
+        }
+        out.write_all_at(&entries, directory * SECTOR)?;
+        out.write_all_at(&table, first_table * SECTOR)?;
+    }
+    Ok(())
+}
+
+/// Whether `path` starts with the magic of a VMDK sparse extent.
+pub fn is_vmdk(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    match File::open(path)?.read_exact_at(&mut magic, 0) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `path` is a VMDK text descriptor, which keeps the data in
+/// separate extent files.
+pub fn is_descriptor(path: &Path) -> io::Result<bool> {
+    let mut start = [0; 21];
+    match File::open(path)?.read_exact_at(&mut start, 0) {
+        Ok(()) => Ok(&start == b"# Disk DescriptorFile"),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks the embedded descriptor against the header: one sparse extent
+/// covering the whole capacity.
+fn check_descriptor(text: &str, capacity: u64) -> io::Result<()> {
+    let extents: Vec<Vec<&str>> = text
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|words| matches!(words.first(), Some(&"RW" | &"RDONLY" | &"NOACCESS")))
+        .collect();
+    match extents.as_slice() {
+        [] => Ok(()),
+        [extent] if extent.get(1).and_then(|n| n.parse().ok()) == Some(capacity) => Ok(()),
+        [_] => Err(invalid("the VMDK descriptor and header disagree on the disk size")),
+        _ => Err(unsupported("VMDK images split over several extents")),
+    }
+}
+
+/// Reads the grain directory at `offset`, checking that the grain tables it
+/// points to lie within the file.
+fn read_directory(file: &File, offset: u64, tables: u64, len: u64) -> io::Result<Vec<u32>> {
+    let mut raw = vec![0; (tables * 4) as usize];
+    file.read_exact_at(&mut raw, offset * SECTOR)?;
+    let directory: Vec<u32> = (0..tables as usize).map(|t| le32(&raw, t * 4)).collect();
+    for (t, &table) in directory.iter().enumerate() {
+        if table != 0 && (u64::from(table) + GTES_PER_GT * 4 / SECTOR) * SECTOR > len {
+            return Err(invalid(format!("VMDK grain table {} lies outside the file", t)));
+        }
+    }
+    Ok(directory)
+}
+
+/// Reads grain table `table` of `directory`; a table that is not there has
+/// no grains.
+fn read_table(file: &File, directory: &[u32], table: usize) -> io::Result<Vec<u32>> {
+    let mut grains = vec![0; GTES_PER_GT as usize];
+    if directory[table] != 0 {
+        let mut raw = vec![0; (GTES_PER_GT * 4) as usize];
+        file.read_exact_at(&mut raw, u64::from(directory[table]) * SECTOR)?;
+        for (i, grain) in grains.iter_mut().enumerate() {
+            *grain = le32(&raw, i * 4);
+        }
+    }
+    Ok(grains)
+}
+
+/// A monolithic sparse or stream-optimized VMDK, checked and opened for
+/// reading.
+pub struct VmdkImage {
+    file: File,
+    size: u64,
+    grain_size: u64,
+    compressed: bool,
+    zeroed_gte: bool,
+    /// Size of the file, which the grain markers are checked against.
+    len: u64,
+    directory: Vec<u32>,
+    /// The grain table last read, by index.
+    table: Option<(usize, Vec<u32>)>,
+    /// The grain last decompressed, by index.
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl VmdkImage {
+    /// Opens the VMDK at `path`, checking its header against transfer
+    /// damage and its descriptor, and that the grain tables agree with
+    /// their redundant copies and point within the file.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if is_descriptor(path)? {
+            return Err(unsupported("VMDK descriptors with separate extent files; import the extent itself"));
+        }
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut header = [0; SECTOR as usize];
+        file.read_exact_at(&mut header, 0)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid("not a VMDK sparse extent"));
+        }
+        let version = le32(&header, H_VERSION);
+        if !(1..=3).contains(&version) {
+            return Err(unsupported(format!("VMDK version {}", version)));
+        }
+        let flags = le32(&header, H_FLAGS);
+        if flags & VALID_NEWLINE_TEST != 0 && &header[H_NEWLINE_TEST..H_NEWLINE_TEST + 4] != NEWLINE_TEST {
+            return Err(invalid("the VMDK was damaged by a text-mode transfer"));
+        }
+        if header[H_UNCLEAN] != 0 {
+            return Err(invalid("the VMDK was not closed cleanly"));
+        }
+        let compressed = flags & COMPRESSED != 0;
+        if compressed && u16::from_le_bytes([header[H_COMPRESSION], header[H_COMPRESSION + 1]]) != COMPRESS_DEFLATE {
+            return Err(unsupported("VMDK compression other than deflate"));
+        }
+        if compressed && flags & MARKERS == 0 {
+            return Err(invalid("a compressed VMDK without grain markers"));
+        }
+
+        let descriptor_offset = le64(&header, H_DESCRIPTOR_OFFSET);
+        let descriptor_size = le64(&header, H_DESCRIPTOR_SIZE);
+        let capacity = le64(&header, H_CAPACITY);
+        if descriptor_offset > 0 && descriptor_size > 0 {
+            let end = descriptor_offset.saturating_add(descriptor_size).saturating_mul(SECTOR);
+            if descriptor_size > 2048 || end > len {
+                return Err(invalid("the VMDK descriptor lies outside the file"));
+            }
+            let mut text = vec![0; (descriptor_size * SECTOR) as usize];
+            file.read_exact_at(&mut text, descriptor_offset * SECTOR)?;
+            let end = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+            check_descriptor(&String::from_utf8_lossy(&text[..end]), capacity)?;
+        }
+
+        let mut gd = le64(&header, H_GD_OFFSET);
+        if gd == GD_AT_END {
+            // Stream-optimized: the footer, a copy of the header with the
+            // real offset, sits before the end-of-stream marker
+            if len < 3 * SECTOR {
+                return Err(invalid("the VMDK stream is truncated"));
+            }
+            let mut footer = [0; SECTOR as usize];
+            file.read_exact_at(&mut footer, len - 2 * SECTOR)?;
+            if &footer[..4] != MAGIC || le64(&footer, H_CAPACITY) != capacity {
+                return Err(invalid("the VMDK stream has no valid footer"));
+            }
+            gd = le64(&footer, H_GD_OFFSET);
+        }
+        let grain_size = le64(&header, H_GRAIN_SIZE);
+        if !grain_size.is_power_of_two() || !(8..=2048).contains(&grain_size) {
+            return Err(invalid(format!("bad VMDK grain size of {} sectors", grain_size)));
+        }
+        if u64::from(le32(&header, H_GTES_PER_GT)) != GTES_PER_GT {
+            return Err(unsupported(format!("{} entries per VMDK grain table", le32(&header, H_GTES_PER_GT))));
+        }
+        let tables = capacity.div_ceil(GTES_PER_GT * grain_size);
+        let directory_outside =
+            |offset: u64| offset == 0 || offset.saturating_mul(SECTOR).saturating_add(tables * 4) > len;
+        if directory_outside(gd) {
+            return Err(invalid("the VMDK grain directory lies outside the file"));
+        }
+        let directory = read_directory(&file, gd, tables, len)?;
+        let rgd = le64(&header, H_RGD_OFFSET);
+        let redundant = match flags & REDUNDANT_GT != 0 && rgd != 0 && !compressed {
+            true if directory_outside(rgd) => {
+                return Err(invalid("the redundant VMDK grain directory lies outside the file"))
+            }
+            true => Some(read_directory(&file, rgd, tables, len)?),
+            false => None,
+        };
+
+        // Tables are checked one at a time; only the directory is kept
+        let zeroed_gte = flags & ZEROED_GTE != 0;
+        let grain_len = if compressed { 12 } else { grain_size * SECTOR };
+        for t in 0..directory.len() {
+            let grains = read_table(&file, &directory, t)?;
+            if let Some(copy) = &redundant {
+                if read_table(&file, copy, t)? != grains {
+                    return Err(invalid("the VMDK grain tables and their redundant copies differ"));
+                }
+            }
+            for (i, &grain) in grains.iter().enumerate() {
+                let absent = grain == 0 || (zeroed_gte && grain == 1);
+                if !absent && u64::from(grain) * SECTOR + grain_len > len {
+                    return Err(invalid(format!("VMDK grain {} lies outside the file", t * GTES_PER_GT as usize + i)));
+                }
+            }
+        }
+        Ok(VmdkImage {
+            file,
+            size: capacity * SECTOR,
+            grain_size,
+            compressed,
+            zeroed_gte,
+            len,
+            directory,
+            table: None,
+            cache: None,
+        })
+    }
+
+    /// Whether the grains are compressed, as in stream-optimized images.
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// Where grain `index` lies, in sectors.
+    fn grain(&mut self, index: usize) -> io::Result<u32> {
+        let table = index / GTES_PER_GT as usize;
+        if self.table.as_ref().is_none_or(|(cached, _)| *cached != table) {
+            self.table = Some((table, read_table(&self.file, &self.directory, table)?));
+        }
+        Ok(self.table.as_ref().expect("table was just read").1[index % GTES_PER_GT as usize])
+    }
+
+    /// Reads and inflates compressed grain `index`, at sector `grain`,
+    /// checking that its marker names it.
+    fn inflate(&mut self, index: usize, grain: u32) -> io::Result<&[u8]> {
+        if self.cache.as_ref().is_none_or(|(cached, _)| *cached != index) {
+            let offset = u64::from(grain) * SECTOR;
+            let mut marker = [0; 12];
+            self.file.read_exact_at(&mut marker, offset)?;
+            if le64(&marker, 0) != index as u64 * self.grain_size {
+                return Err(invalid(format!("VMDK grain {} has the marker of another grain", index)));
+            }
+            let size = u64::from(le32(&marker, 8));
+            if size > self.len - offset - 12 {
+                return Err(invalid(format!("VMDK grain {} runs past the end of the file", index)));
+            }
+            let mut compressed = vec![0; size as usize];
+            self.file.read_exact_at(&mut compressed, offset + 12)?;
+            let mut grain = Vec::with_capacity((self.grain_size * SECTOR) as usize);
+            ZlibDecoder::new(compressed.as_slice()).take(self.grain_size * SECTOR).read_to_end(&mut grain)?;
+            // The last grain may be cut short at the end of the disk
+            grain.resize((self.grain_size * SECTOR) as usize, 0);
+            self.cache = Some((index, grain));
+        }
+        Ok(&self.cache.as_ref().expect("grain was just read").1)
+    }
+}
+
+impl BlockDevice for VmdkImage {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset.checked_add(buf.len() as u64).is_none_or(|end| end > self.size) {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let grain_bytes = self.grain_size * SECTOR;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let (index, within) = ((pos / grain_bytes) as usize, pos % grain_bytes);
+            let len = ((grain_bytes - within) as usize).min(buf.len() - done);
+            let grain = self.grain(index)?;
+            if grain == 0 || (self.zeroed_gte && grain == 1) {
+                buf[done..done + len].fill(0);
+            } else if self.compressed {
+                let data = self.inflate(index, grain)?;
+                buf[done..done + len].copy_from_slice(&data[within as usize..within as usize + len]);
+            } else {
+                self.file.read_exact_at(&mut buf[done..done + len], u64::from(grain) * SECTOR + within)?;
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "VMDK images are only read"))
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}